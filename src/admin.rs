@@ -0,0 +1,286 @@
+//! # admin
+//!
+//! bot 的管理员命令
+//!
+//! 管理员为环境变量 `SAFC_ADMINS` 中的 telegram 用户 id（逗号分隔），
+//! 每条管理员命令及其结果都会记录在审计日志中（见 [`safc::db::admin`]）。
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use safc::db::SnapshotCache;
+use safc::service;
+use teloxide::prelude::*;
+use teloxide::types::{InputFile, UpdateKind, User};
+use teloxide::utils::command::BotCommands;
+
+use crate::msg::SAFC_DB;
+
+type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+static ADMINS: OnceLock<Vec<u64>> = OnceLock::new();
+static SNAPSHOTS: OnceLock<SnapshotCache> = OnceLock::new();
+
+/// 广播时两条消息之间的间隔，避免触发 telegram 的限流
+const BROADCAST_INTERVAL: Duration = Duration::from_millis(50);
+/// `/audit_log` 显示的条数
+const AUDIT_LOG_LIMIT: usize = 20;
+
+#[derive(BotCommands, Clone, PartialEq, Debug)]
+#[command(rename_rule = "snake_case", description = "管理员命令：")]
+pub enum AdminCommand {
+    #[command(description = "显示管理员命令")]
+    Admin,
+    #[command(description = "隐藏评价 /hide <id> [理由]")]
+    Hide(String),
+    #[command(description = "恢复被撤回或隐藏的评价 /restore <id>")]
+    Restore(String),
+    #[command(description = "合并重复的客体 /merge_objects <被合并的 id> <保留的 id>")]
+    MergeObjects(String),
+    #[command(description = "封禁用户 /ban <用户 id> [理由]")]
+    Ban(String),
+    #[command(description = "解封用户 /unban <用户 id>")]
+    Unban(String),
+    #[command(description = "重置用户的操作频率限制 /reset_limits <用户 id>")]
+    ResetLimits(String),
+    #[command(description = "统计信息")]
+    Stats,
+    #[command(description = "向所有会话广播 /broadcast <消息>")]
+    Broadcast(String),
+    #[command(rename = "downloaddb", description = "下载数据库")]
+    DownloadDb,
+    #[command(description = "最近的审计日志")]
+    AuditLog,
+}
+
+/// 从环境变量 `SAFC_ADMINS` 读取管理员列表
+pub fn admins() -> &'static [u64] {
+    ADMINS.get_or_init(|| {
+        std::env::var("SAFC_ADMINS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|s| {
+                let s = s.trim();
+                match s.parse() {
+                    Ok(id) => Some(id),
+                    Err(_) if s.is_empty() => None,
+                    Err(_) => {
+                        log::error!("SAFC_ADMINS 中的 {} 不是有效的用户 id", s);
+                        None
+                    }
+                }
+            })
+            .collect()
+    })
+}
+
+pub fn is_admin(user: Option<&User>) -> bool {
+    user.is_some_and(|u| admins().contains(&u.id.0))
+}
+
+/// 消息来自管理员
+pub fn is_admin_message(msg: Message) -> bool {
+    is_admin(msg.from())
+}
+
+/// 来自被封禁用户的更新
+pub fn is_banned_update(upd: Update) -> bool {
+    match upd.user() {
+        Some(u) => SAFC_DB.is_banned(u.id.0 as i64).unwrap_or_else(|e| {
+            log::error!("{}", e);
+            false
+        }),
+        None => false,
+    }
+}
+
+/// 被封禁的用户只会收到提示；按钮与内联查询也要应答，否则客户端会一直等待
+pub async fn banned_update(bot: Bot, upd: Update) -> HandlerResult {
+    const HINT: &str = "🚫 您已被管理员封禁，如有异议请联系 @SAFC_group";
+    match upd.kind {
+        UpdateKind::Message(msg) => {
+            bot.send_message(msg.chat.id, HINT).await?;
+        }
+        UpdateKind::CallbackQuery(q) => {
+            bot.answer_callback_query(q.id)
+                .text(HINT)
+                .show_alert(true)
+                .await?;
+        }
+        UpdateKind::InlineQuery(q) => {
+            bot.answer_inline_query(q.id, vec![])
+                .cache_time(0)
+                .is_personal(true)
+                .await?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// 管理员命令处理函数，执行后记录审计日志并回复结果
+pub async fn admin_command(bot: Bot, msg: Message, cmd: AdminCommand) -> HandlerResult {
+    let admin_id = msg.from().map(|u| u.id.0 as i64).unwrap_or_default();
+    let (action, target) = audit_target(&cmd);
+    let result = run(&bot, &msg, cmd).await;
+    let detail = match &result {
+        Ok(_) => None,
+        Err(e) => Some(format!("失败：{e}")),
+    };
+    SAFC_DB.add_audit_log(admin_id, action, &target, detail.as_deref())?;
+    let text = match result {
+        Ok(text) => text,
+        Err(e) => format!("❌ {e}"),
+    };
+    bot.send_message(msg.chat.id, text)
+        .reply_to_message_id(msg.id)
+        .await?;
+    Ok(())
+}
+
+/// 审计日志中的操作名与操作对象
+fn audit_target(cmd: &AdminCommand) -> (&'static str, String) {
+    match cmd {
+        AdminCommand::Admin => ("admin", String::new()),
+        AdminCommand::Hide(arg) => ("hide", arg.trim().to_string()),
+        AdminCommand::Restore(arg) => ("restore", arg.trim().to_string()),
+        AdminCommand::MergeObjects(arg) => ("merge_objects", arg.trim().to_string()),
+        AdminCommand::Ban(arg) => ("ban", arg.trim().to_string()),
+        AdminCommand::Unban(arg) => ("unban", arg.trim().to_string()),
+        AdminCommand::ResetLimits(arg) => ("reset_limits", arg.trim().to_string()),
+        AdminCommand::Stats => ("stats", String::new()),
+        AdminCommand::Broadcast(arg) => ("broadcast", arg.trim().to_string()),
+        AdminCommand::DownloadDb => ("downloaddb", String::new()),
+        AdminCommand::AuditLog => ("audit_log", String::new()),
+    }
+}
+
+/// 执行管理员命令，返回给管理员的回复
+async fn run(
+    bot: &Bot,
+    msg: &Message,
+    cmd: AdminCommand,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    match cmd {
+        AdminCommand::Admin => Ok(AdminCommand::descriptions().to_string()),
+        AdminCommand::Hide(arg) => {
+            let (id, reason) = split_arg(&arg, "/hide <id> [理由]")?;
+            service::moderate_comment(&SAFC_DB, id, reason)?;
+            Ok(format!("✅ 评价 {id} 已隐藏"))
+        }
+        AdminCommand::Restore(arg) => {
+            let (id, _) = split_arg(&arg, "/restore <id>")?;
+            service::restore_comment(&SAFC_DB, id)?;
+            Ok(format!("✅ 评价 {id} 已恢复"))
+        }
+        AdminCommand::MergeObjects(arg) => {
+            let usage = "/merge_objects <被合并的 id> <保留的 id>";
+            let (from, into) = split_arg(&arg, usage)?;
+            let into = into.ok_or_else(|| usage_error(usage))?;
+            let n = SAFC_DB.merge_objects(from, into)?;
+            Ok(format!("✅ 客体 {from} 已合并到 {into}，转移了 {n} 条评价"))
+        }
+        AdminCommand::Ban(arg) => {
+            let (user, reason) = split_arg(&arg, "/ban <用户 id> [理由]")?;
+            let user_id = parse_user_id(user)?;
+            if admins().contains(&(user_id as u64)) {
+                return Err(safc::Error::Validation("不能封禁管理员".to_string()).into());
+            }
+            Ok(match SAFC_DB.ban_user(user_id, reason)? {
+                true => format!("✅ 用户 {user_id} 已封禁"),
+                false => format!("用户 {user_id} 已经被封禁"),
+            })
+        }
+        AdminCommand::Unban(arg) => {
+            let (user, _) = split_arg(&arg, "/unban <用户 id>")?;
+            let user_id = parse_user_id(user)?;
+            Ok(match SAFC_DB.unban_user(user_id)? {
+                true => format!("✅ 用户 {user_id} 已解封"),
+                false => format!("用户 {user_id} 未被封禁"),
+            })
+        }
+        AdminCommand::ResetLimits(arg) => {
+            let (user, _) = split_arg(&arg, "/reset_limits <用户 id>")?;
+            let user_id = parse_user_id(user)?;
+            SAFC_DB.reset_rate_limits(user_id)?;
+            Ok(format!("✅ 用户 {user_id} 的操作频率限制已重置"))
+        }
+        AdminCommand::Stats => Ok(SAFC_DB.admin_stats()?),
+        AdminCommand::Broadcast(text) => {
+            let text = text.trim().to_string();
+            if text.is_empty() {
+                return Err(usage_error("/broadcast <消息>").into());
+            }
+            let chats = SAFC_DB.find_known_chats()?;
+            let n = chats.len();
+            tokio::spawn(broadcast(bot.clone(), chats, text));
+            Ok(format!("✅ 开始向 {n} 个会话广播"))
+        }
+        AdminCommand::DownloadDb => {
+            // 发送与当前数据库一致的快照，而不是正在写入的数据库文件
+            let snapshots = SNAPSHOTS
+                .get_or_init(|| SnapshotCache::new(SAFC_DB.clone(), SAFC_DB.snapshot_dir("bot")));
+            let path = tokio::task::spawn_blocking(|| snapshots.get()).await??;
+            let filename = std::path::Path::new(&SAFC_DB.get_db_path())
+                .file_name()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "db.sqlite".to_string());
+            bot.send_document(msg.chat.id, InputFile::file(path).file_name(filename))
+                .await?;
+            Ok("数据库文件已成功上传。".to_string())
+        }
+        AdminCommand::AuditLog => {
+            let entries = SAFC_DB.find_audit_log(AUDIT_LOG_LIMIT)?;
+            if entries.is_empty() {
+                return Ok("暂无审计日志".to_string());
+            }
+            Ok(entries
+                .iter()
+                .map(|e| {
+                    format!(
+                        "{} {} {} {} {}",
+                        e.date,
+                        e.admin_id,
+                        e.action,
+                        e.target,
+                        e.detail.as_deref().unwrap_or_default()
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n"))
+        }
+    }
+}
+
+async fn broadcast(bot: Bot, chats: Vec<i64>, text: String) {
+    let mut failed = 0;
+    for chat_id in &chats {
+        if let Err(e) = bot.send_message(ChatId(*chat_id), &text).await {
+            log::warn!("无法向 {} 广播：{}", chat_id, e);
+            failed += 1;
+        }
+        tokio::time::sleep(BROADCAST_INTERVAL).await;
+    }
+    log::info!("广播完成，共 {} 个会话，失败 {} 个", chats.len(), failed);
+}
+
+/// 拆分出第一个参数与其余部分
+fn split_arg<'a>(arg: &'a str, usage: &str) -> Result<(&'a str, Option<&'a str>), safc::Error> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return Err(usage_error(usage));
+    }
+    Ok(match arg.split_once(char::is_whitespace) {
+        Some((first, rest)) => (first, Some(rest.trim()).filter(|r| !r.is_empty())),
+        None => (arg, None),
+    })
+}
+
+fn parse_user_id(s: &str) -> Result<i64, safc::Error> {
+    s.parse()
+        .map_err(|_| safc::Error::Validation(format!("{s} 不是有效的用户 id")))
+}
+
+fn usage_error(usage: &str) -> safc::Error {
+    safc::Error::Validation(format!("使用方法： {usage}"))
+}
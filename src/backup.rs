@@ -0,0 +1,144 @@
+//! # backup
+//!
+//! 定时备份数据库并发送到 telegram 群
+//!
+//! 每隔 `SAFC_BACKUP_INTERVAL_HOURS` 小时（默认 24）建立一份压缩备份（见 [`safc::db::backup`]），
+//! 本地备份目录 `SAFC_BACKUP_DIR`（默认为数据库所在目录下的 backups）中保留最新的
+//! `SAFC_BACKUP_KEEP` 份（默认 7）。设置了 `SAFC_BACKUP_CHAT`（会话 id 或 @频道名）时，
+//! 去掉节点私有数据的快照（见 [`safc::db::backup::PRIVATE_TABLES`]）连同 sha256 与数据库统计一起发送到该会话。
+//!
+//! 启动时最新的本地备份已超过间隔（或没有备份）则立即备份，频繁重启的 bot 也能按时备份。
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use safc::db::{backup, BackupFile};
+use teloxide::prelude::*;
+use teloxide::types::{InputFile, Recipient};
+
+use crate::msg::SAFC_DB;
+
+type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+const DEFAULT_INTERVAL_HOURS: u64 = 24;
+const DEFAULT_KEEP: usize = 7;
+
+/// 备份的配置，来自环境变量
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+    pub interval: Duration,
+    pub dir: PathBuf,
+    pub keep: usize,
+    pub chat: Option<Recipient>,
+}
+
+impl BackupConfig {
+    pub fn from_env() -> Self {
+        let interval_hours = env_parse("SAFC_BACKUP_INTERVAL_HOURS", DEFAULT_INTERVAL_HOURS);
+        let keep = env_parse("SAFC_BACKUP_KEEP", DEFAULT_KEEP);
+        let dir = std::env::var("SAFC_BACKUP_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| {
+                PathBuf::from(SAFC_DB.get_db_path())
+                    .parent()
+                    .unwrap_or(std::path::Path::new(""))
+                    .join("backups")
+            });
+        let chat = std::env::var("SAFC_BACKUP_CHAT")
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .map(|s| match s.parse() {
+                Ok(id) => Recipient::Id(ChatId(id)),
+                Err(_) => Recipient::ChannelUsername(s),
+            });
+        Self {
+            interval: Duration::from_secs(interval_hours.max(1) * 60 * 60),
+            dir,
+            keep: keep.max(1),
+            chat,
+        }
+    }
+}
+
+fn env_parse<T: std::str::FromStr>(key: &str, default: T) -> T {
+    match std::env::var(key) {
+        Ok(s) => s.trim().parse().unwrap_or_else(|_| {
+            log::error!("{} 的值 {} 无效，使用默认值", key, s);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// 定期备份的任务
+pub async fn backup_task(bot: Bot, config: BackupConfig) {
+    log::info!(
+        "backup_task 启动，每 {} 小时备份到 {}",
+        config.interval.as_secs() / 3600,
+        config.dir.display()
+    );
+    loop {
+        tokio::time::sleep(next_backup_in(&config)).await;
+        if let Err(e) = backup_once(&bot, &config).await {
+            log::error!("备份数据库失败：{}", e);
+            // 失败时不立即重试，等待一个间隔
+            tokio::time::sleep(config.interval).await;
+        }
+    }
+}
+
+/// 距下次备份的时间：最新的本地备份的时间加上间隔，没有备份时为 0
+fn next_backup_in(config: &BackupConfig) -> Duration {
+    match backup::latest_backup_time(&config.dir) {
+        Ok(Some(t)) => config
+            .interval
+            .saturating_sub(t.elapsed().unwrap_or_default()),
+        Ok(None) => Duration::ZERO,
+        Err(e) => {
+            log::error!("无法读取备份目录 {}：{}", config.dir.display(), e);
+            config.interval
+        }
+    }
+}
+
+/// 建立一份备份、轮换旧备份，并发送到配置的会话
+pub async fn backup_once(bot: &Bot, config: &BackupConfig) -> HandlerResult {
+    let dir = config.dir.clone();
+    let keep = config.keep;
+    // 备份与压缩是阻塞的文件操作
+    let b = tokio::task::spawn_blocking(move || -> safc::Result<BackupFile> {
+        let b = SAFC_DB.create_backup(&dir)?;
+        backup::rotate_backups(&dir, keep)?;
+        Ok(b)
+    })
+    .await??;
+    log::info!(
+        "数据库已备份到 {}（{} 字节，sha256 {}）",
+        b.path.display(),
+        b.size,
+        b.sha256
+    );
+    if let Some(chat) = &config.chat {
+        // 发到群里的是去掉私有数据的快照，文件名与本地备份相同
+        let tmp = std::env::temp_dir().join(format!("safc_public_backup_{}", std::process::id()));
+        std::fs::create_dir_all(&tmp)?;
+        let dest = tmp.join(b.path.file_name().unwrap_or_default());
+        let r = send_public_backup(bot, chat.clone(), dest).await;
+        std::fs::remove_dir_all(&tmp).ok();
+        r?;
+    }
+    Ok(())
+}
+
+async fn send_public_backup(bot: &Bot, chat: Recipient, dest: PathBuf) -> HandlerResult {
+    let p = tokio::task::spawn_blocking(move || SAFC_DB.create_public_backup(&dest)).await??;
+    bot.send_document(chat, InputFile::file(&p.path))
+        .caption(format!(
+            "🗄 数据库定时备份\nsha256: {}\n{}",
+            p.sha256,
+            SAFC_DB.db_status()?
+        ))
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,255 @@
+//! # SAFC 的运维命令行工具
+//!
+//! 与 bot、web 使用同一个 [`SAFCdb`]，行为与服务一致。数据库路径默认取环境变量 `SAFC_DB_PATH`。
+//!
+//! ```sh
+//! # 新建数据库，或将已有的数据库升级到最新结构；其他命令要求数据库已存在且是最新的结构
+//! safc_admin --db db.sqlite init
+//! # 统计信息；完整性检查，发现问题时以失败状态退出
+//! safc_admin stats
+//! safc_admin check
+//! # 增加客体，隐藏评价
+//! safc_admin add-object 985 清华大学 计算机系 张三 --info "主页：…"
+//! safc_admin hide-comment <评价 id> --reason 人身攻击
+//! # 导出到文件，不给出 -o 时输出到标准输出
+//! safc_admin export --format csv -o safc.csv
+//! # 检查签名格式；列出签名与 OTP 相符的评价
+//! safc_admin verify-signs
+//! safc_admin verify-signs --otp <otp> [评价 id]...
+//! # 试运行：只输出报告，不写入
+//! safc_admin import comments_data.json --format urfire
+//! # 确认无误后写入
+//! safc_admin import comments_data.json --format urfire --commit
+//! # 校验哈希链，发现篡改时以失败状态退出
+//! safc_admin verify-chain
+//! # 合并两个数据库文件，冲突时取 a 的值
+//! safc_admin merge a.sqlite b.sqlite -o out.sqlite --policy ours
+//! ```
+//!
+//! 写入数据库的操作记入审计日志，管理员 id 记为 [`CLI_ADMIN_ID`]。
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+use safc::db::{merge, MergePolicy, SAFCdb, SourceCate};
+use safc::export::{self, ExportFormat};
+use safc::import::{self, ImportFormat};
+use safc::service;
+
+/// 审计日志中命令行操作的管理员 id
+const CLI_ADMIN_ID: i64 = 0;
+
+#[derive(Parser, Debug)]
+#[command(name = "safc_admin", about = "SAFC 数据库运维工具")]
+struct Cli {
+    /// 数据库路径，默认取环境变量 SAFC_DB_PATH
+    #[arg(long, global = true)]
+    db: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 新建数据库，或将已有的数据库升级到最新结构
+    Init,
+    /// 数据库的统计信息
+    Stats,
+    /// 检查数据库文件、外键与孤立的评价，发现问题时以失败状态退出
+    Check,
+    /// 增加客体
+    AddObject {
+        school_cate: String,
+        university: String,
+        department: String,
+        supervisor: String,
+        /// 客体的信息，如主页
+        #[arg(long)]
+        info: Option<String>,
+    },
+    /// 隐藏评价，原内容保存在修订历史中
+    HideComment {
+        id: String,
+        /// 隐藏的理由
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// 导出客体与评价
+    Export {
+        /// 导出格式：json、jsonl、csv、md
+        #[arg(long, default_value = "json")]
+        format: ExportFormat,
+        /// 输出文件，默认输出到标准输出
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// 从外部来源导入客体与评价，默认只试运行
+    Import {
+        /// 输入文件
+        file: String,
+        /// 输入格式：json、jsonl、csv、urfire、pi-review、tg-text，默认按扩展名推断
+        #[arg(long)]
+        format: Option<ImportFormat>,
+        /// 评价的来源分类，默认由格式决定（urfire、pireview、telegram，其余为 admin）
+        #[arg(long)]
+        source: Option<SourceCate>,
+        /// 写入数据库，否则只输出试运行的报告
+        #[arg(long)]
+        commit: bool,
+    },
+    /// 检查评价的作者签名，签名格式错误或与 OTP 不符时以失败状态退出
+    VerifySigns {
+        /// 发布人的 OTP，给出时列出签名与之相符的评价
+        #[arg(long)]
+        otp: Option<String>,
+        /// 要检查的评价，默认检查全部评价
+        ids: Vec<String>,
+    },
+    /// 校验客体与评价的哈希链，发现篡改时以失败状态退出
+    VerifyChain,
+    /// 合并两个数据库文件，按 id 取并集写入新文件，输入文件不会被修改
+    Merge {
+        /// 本地数据库
+        a: PathBuf,
+        /// 对端数据库
+        b: PathBuf,
+        /// 输出文件，不能已存在
+        #[arg(short, long)]
+        output: PathBuf,
+        /// 同一 id 两边不同时的取舍：converge（与同步一致）、ours（取 a 的值）、theirs（取 b 的值）
+        #[arg(long, default_value_t)]
+        policy: MergePolicy,
+    },
+}
+
+fn main() -> ExitCode {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("warn"));
+    let cli = Cli::parse();
+    match run(cli.db, cli.command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("错误：{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// 打开已有的数据库，不新建也不迁移，见 [`SAFCdb::open_existing`]；新建与迁移只由 `init` 进行
+///
+/// 只在需要时调用，以免不用数据库的命令要求数据库存在
+fn open(db: Option<String>, read_only: bool) -> safc::Result<SAFCdb> {
+    SAFCdb::open_existing(db.unwrap_or_else(SAFCdb::default_path), read_only)
+}
+
+fn run(db: Option<String>, command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Init => {
+            let db = SAFCdb::new_with_path(db.unwrap_or_else(SAFCdb::default_path));
+            println!(
+                "✅ {}：数据库结构版本 v{}",
+                db.get_db_path(),
+                db.schema_version()?
+            );
+        }
+        Command::Stats => println!("{}", open(db, true)?.admin_stats()?),
+        Command::Check => {
+            let report = open(db, true)?.check_integrity()?;
+            print!("{report}");
+            if !report.is_ok() {
+                return Err("完整性检查失败".into());
+            }
+        }
+        Command::AddObject {
+            school_cate,
+            university,
+            department,
+            supervisor,
+            info,
+        } => {
+            let db = &open(db, false)?;
+            let mut obj = service::new_object(&school_cate, &university, &department, &supervisor)?;
+            obj.info = info;
+            db.add_object(&obj)?;
+            db.add_audit_log(CLI_ADMIN_ID, "add_object", &obj.object_id, None)?;
+            println!("✅ 客体已增加，id：{}", obj.object_id);
+        }
+        Command::HideComment { id, reason } => {
+            let db = &open(db, false)?;
+            service::moderate_comment(db, &id, reason.as_deref())?;
+            db.add_audit_log(CLI_ADMIN_ID, "hide", &id, reason.as_deref())?;
+            println!("✅ 评价 {id} 已隐藏");
+        }
+        Command::Export { format, output } => {
+            let db = &open(db, true)?;
+            match output {
+                Some(path) => {
+                    export::export_to(db, format, BufWriter::new(File::create(&path)?))?;
+                    eprintln!("✅ 已导出到 {}", path.display());
+                }
+                None => export::export_to(db, format, io::stdout().lock())?,
+            }
+        }
+        Command::VerifySigns { otp, ids } => {
+            let report = open(db, true)?.verify_signs(&ids, otp.as_deref())?;
+            print!("{report}");
+            if !report.malformed.is_empty() {
+                return Err("有评价的签名格式错误".into());
+            }
+            if let Some(matched) = &report.matched {
+                if !ids.is_empty() && matched.len() < ids.len() {
+                    return Err("有评价的签名与 OTP 不符".into());
+                }
+            }
+        }
+        Command::Import {
+            file,
+            format,
+            source,
+            commit,
+        } => {
+            let db = &open(db, !commit)?;
+            let format = format
+                .or_else(|| ImportFormat::from_extension(&file))
+                .ok_or("无法从扩展名推断格式，请使用 --format 指定")?;
+            let input = std::fs::read_to_string(&file)?;
+            let records = import::read_records(db, format, &input)?;
+            let plan = import::plan(db, records, source.unwrap_or(format.default_source()))?;
+            print!("{plan}");
+            if plan.is_empty() {
+                println!("没有需要写入的内容");
+            } else if commit {
+                import::apply(db, &plan)?;
+                let detail = format!(
+                    "{format}，客体 {}，评价 {}",
+                    plan.new_objects.len(),
+                    plan.new_comments.len()
+                );
+                db.add_audit_log(CLI_ADMIN_ID, "import", &file, Some(&detail))?;
+                println!("✅ 已写入 {}", db.get_db_path());
+            } else {
+                println!("试运行，未写入。确认无误后加上 --commit 写入");
+            }
+        }
+        Command::VerifyChain => {
+            let report = open(db, true)?.verify_chain()?;
+            print!("{report}");
+            if !report.is_valid() {
+                return Err("哈希链校验失败".into());
+            }
+        }
+        Command::Merge {
+            a,
+            b,
+            output,
+            policy,
+        } => {
+            let report = merge::merge_files(&a, &b, &output, policy)?;
+            print!("{report}");
+            println!("✅ 已写入 {}", output.display());
+        }
+    }
+    Ok(())
+}
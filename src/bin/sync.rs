@@ -0,0 +1,135 @@
+//! # SAFC 弱中心节点之间的同步
+//!
+//! 从各个对端的 `GET /api/sync/changes` 增量拉取客体与评价，合并到本地数据库，规则见 [`safc::db::sync`]。
+//! 对端为其 web 服务的地址，在命令行给出，或用环境变量 `SAFC_SYNC_PEERS`（逗号分隔）配置。
+//! 默认只接受对端的撤回与隐藏，接受修改与恢复的可信对端用 `--trusted` 或 `SAFC_SYNC_TRUSTED`（逗号分隔）指定，
+//! 见 [`safc::db::sync::PeerTrust`]。
+//!
+//! ```sh
+//! # 拉取一次
+//! safc_sync http://127.0.0.1:11096 https://safc.example.com
+//! # 每 10 分钟拉取一次，信任 safc.example.com 的修改
+//! SAFC_SYNC_PEERS=https://safc.example.com safc_sync --interval 600 --trusted https://safc.example.com
+//! ```
+
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::Parser;
+use safc::db::sync::{self, PeerTrust, SyncRecord, SyncReport};
+use safc::db::SAFCdb;
+
+#[derive(Parser, Debug)]
+#[command(name = "safc_sync", about = "SAFC 节点间数据库同步")]
+struct Cli {
+    /// 数据库路径，默认取环境变量 SAFC_DB_PATH
+    #[arg(long)]
+    db: Option<String>,
+    /// 每隔多少秒拉取一次，默认只拉取一次
+    #[arg(long)]
+    interval: Option<u64>,
+    /// 接受其修改与恢复的可信对端，可重复，默认取环境变量 SAFC_SYNC_TRUSTED（逗号分隔）
+    #[arg(long)]
+    trusted: Vec<String>,
+    /// 对端 web 服务的地址，默认取环境变量 SAFC_SYNC_PEERS（逗号分隔）
+    peers: Vec<String>,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let cli = Cli::parse();
+    let peers = peer_list(cli.peers, "SAFC_SYNC_PEERS");
+    let trusted = peer_list(cli.trusted, "SAFC_SYNC_TRUSTED");
+    if peers.is_empty() {
+        eprintln!("错误：未配置对端，请在命令行给出或设置 SAFC_SYNC_PEERS");
+        return ExitCode::FAILURE;
+    }
+    let db = match cli.db {
+        Some(path) => SAFCdb::new_with_path(path),
+        None => SAFCdb::new(),
+    };
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(60))
+        .user_agent(concat!("safc_sync/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .expect("无法建立 HTTP 客户端");
+
+    loop {
+        let mut ok = true;
+        for peer in &peers {
+            let trust = if trusted.contains(peer) {
+                PeerTrust::Trusted
+            } else {
+                PeerTrust::TombstoneOnly
+            };
+            match pull(&client, &db, peer, trust).await {
+                Ok(report) => print!("{peer}：{report}"),
+                Err(e) => {
+                    eprintln!("错误：{peer}：{e}");
+                    ok = false;
+                }
+            }
+        }
+        match cli.interval {
+            Some(secs) => tokio::time::sleep(Duration::from_secs(secs)).await,
+            None if ok => return ExitCode::SUCCESS,
+            None => return ExitCode::FAILURE,
+        }
+    }
+}
+
+/// 命令行给出的对端，没有时取环境变量 `var`（逗号分隔）
+///
+/// 游标按对端的地址保存，去掉末尾的 / 以免同一对端记为两个
+fn peer_list(args: Vec<String>, var: &str) -> Vec<String> {
+    let peers: Vec<String> = if args.is_empty() {
+        std::env::var(var)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::to_string)
+            .collect()
+    } else {
+        args
+    };
+    peers
+        .iter()
+        .map(|p| p.trim().trim_end_matches('/').to_string())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+/// 分页拉取对端的全部新记录并合并，返回合计的结果
+async fn pull(
+    client: &reqwest::Client,
+    db: &SAFCdb,
+    peer: &str,
+    trust: PeerTrust,
+) -> Result<SyncReport, Box<dyn std::error::Error>> {
+    let mut total = SyncReport::default();
+    loop {
+        let since = db.sync_cursor(peer)?;
+        let body = client
+            .get(format!("{peer}/api/sync/changes"))
+            .query(&[("since", since)])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let records = sync::parse_jsonl(&body)?;
+        let Some(&SyncRecord::Cursor { seq, head }) = records.last() else {
+            return Err("响应缺少游标，可能被截断".into());
+        };
+        if head < since {
+            // 对端的链比上次短，说明其数据库已被替换，从头拉取
+            log::warn!("{peer} 的链头 #{head} 早于游标 #{since}，从头同步");
+            db.apply_sync(peer, trust, &[SyncRecord::Cursor { seq: 0, head }])?;
+            continue;
+        }
+        total.extend(db.apply_sync(peer, trust, &records)?);
+        if seq >= head {
+            return Ok(total);
+        }
+    }
+}
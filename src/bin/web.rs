@@ -20,35 +20,87 @@
 //!
 
 use actix_cors::Cors;
+use actix_files::NamedFile;
 use actix_web::body::BoxBody;
 use actix_web::http::{header, StatusCode};
 use actix_web::rt;
-use actix_web::{get, post, Responder};
-use actix_web::{web, App, HttpResponse, HttpServer};
+use actix_web::{get, post};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
 
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, Read};
+use std::io;
 use std::path::PathBuf;
 use std::sync::Mutex;
 use std::time::Duration;
 
-use actix_governor::{Governor, GovernorConfigBuilder};
+use actix_governor::GovernorConfigBuilder; // Governor 暂未启用，见 main
 use actix_web::{
     dev::{ServiceRequest, ServiceResponse},
     middleware::{from_fn, Next},
-    Error,
+    ResponseError,
 };
+use safc::db::sync;
 use safc::db::*;
-use safc::sec;
+use safc::export::{ExportCache, ExportFormat};
+use safc::link::DeepLink;
+use safc::service;
+use safc::Error;
 
-const PORT: u16 = 11096;
+const PORT: u16 = 11096; // 可用环境变量 SAFC_WEB_PORT 覆盖
 const MAX_POST_PER_DAY: u64 = 4096; // 每 IP 每天最多 4096 次 POST 请求
+const MAX_SEARCH_LIMIT: usize = 100; // 单次搜索最多返回的结果数
 
 lazy_static! {
     static ref BLOCK_DB: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+    /// telegram bot 的用户名（不含 @），用于生成深度链接，见环境变量 `SAFC_BOT_USERNAME`
+    static ref BOT_USERNAME: Option<String> = std::env::var("SAFC_BOT_USERNAME").ok();
+}
+
+/// 深度链接的完整地址，未配置 bot 用户名时为 `None`
+fn tg_link(link: DeepLink) -> Option<String> {
+    BOT_USERNAME.as_ref().map(|u| link.url(u))
+}
+
+/// 将 [`safc::Error`] 转换为 HTTP 响应，响应体为错误说明的 json 字符串
+#[derive(Debug)]
+struct ApiError(Error);
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        Self(e)
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self.0 {
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::DuplicateId(_) => StatusCode::CONFLICT,
+            Error::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::InvalidEnum { .. }
+            | Error::PoolExhausted(_)
+            | Error::Sqlite(_)
+            | Error::Io(_)
+            | Error::SchemaVersion { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if self.status_code().is_server_error() {
+            log::error!("{}", self.0);
+        }
+        HttpResponse::build(self.status_code()).json(self.0.to_string())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -59,6 +111,27 @@ struct ApiQuery {
     supervisor: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct SearchQuery {
+    q: String,
+    kind: Option<SearchKind>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportQuery {
+    /// json、jsonl、csv、md（markdown），默认 json
+    format: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SyncQuery {
+    /// 游标，即上次响应最后一行的 seq，默认 0
+    since: Option<i64>,
+    /// 最多包含的链上记录数，默认且至多为 [`sync::SYNC_PAGE_SIZE`]
+    limit: Option<usize>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct CreateCommentReq {
     school_cate: String,
@@ -66,138 +139,265 @@ struct CreateCommentReq {
     department: String,
     supervisor: String,
     content: String,
+    /// 分项评星，每项 1 到 5 星，均可省略
+    #[serde(default)]
+    ratings: Option<Ratings>,
+    /// 发布人 OTP，日后可凭此修改或撤回评价
+    otp: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EditCommentReq {
+    id: String,
+    content: String,
+    otp: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeleteCommentReq {
+    id: String,
+    otp: String,
 }
 
 #[get("/api")]
-async fn hello(db: web::Data<SAFCdb>) -> impl Responder {
-    let result = db.db_status().unwrap();
-    HttpResponse::Ok().json(result)
+async fn hello(db: web::Data<SAFCdb>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(db.db_status()?))
 }
 
+/// 下载数据库：一份与当前数据库一致、去掉了节点私有数据的快照（见 [`SnapshotCache`]）
+///
+/// 数据库未变化时快照不变，响应带有 ETag 与 Last-Modified，支持 304 与断点续传
 #[get("/api/download/db")]
-async fn download_file(db: web::Data<SAFCdb>) -> impl Responder {
-    let db_path = PathBuf::from(db.get_db_path());
-
-    // 安全检查：确保文件存在且可读
-    match File::open(&db_path) {
-        Ok(mut file) => {
-            let mut contents = Vec::new();
-            match file.read_to_end(&mut contents) {
-                Ok(_) => {
-                    // 从路径中提取文件名
-                    let filename = db_path
-                        .file_name()
-                        .and_then(|name| name.to_str())
-                        .unwrap_or("database.db");
-
-                    // 返回数据库文件
-                    HttpResponse::Ok()
-                        .append_header(header::ContentType::octet_stream())
-                        .append_header(header::ContentDisposition::attachment(filename))
-                        .body(contents)
-                }
-                Err(_) => HttpResponse::InternalServerError().json("无法读取数据库文件"),
-            }
-        }
-        Err(_) => HttpResponse::NotFound().json("数据库文件不存在"),
-    }
+async fn download_file(
+    req: HttpRequest,
+    db: web::Data<SAFCdb>,
+    snapshots: web::Data<SnapshotCache>,
+) -> Result<HttpResponse, ApiError> {
+    let path = web::block(move || snapshots.get())
+        .await
+        .map_err(|e| Error::Io(io::Error::other(e.to_string())))??;
+
+    // 下载的文件名与数据库文件相同
+    let filename = PathBuf::from(db.get_db_path())
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("database.db")
+        .to_string();
+    let mut resp = NamedFile::open(path)
+        .map_err(Error::from)?
+        .set_content_disposition(header::ContentDisposition::attachment(filename))
+        .into_response(&req);
+    // 允许缓存，但每次使用前都需要验证
+    resp.headers_mut().insert(
+        header::CACHE_CONTROL,
+        header::HeaderValue::from_static("no-cache"),
+    );
+    Ok(resp)
+}
+
+#[derive(Debug, Serialize)]
+struct ObjectResp {
+    object: ObjTeacher,
+    comments: Vec<ObjComment>,
+    /// 评星汇总
+    ratings: RatingSummary,
+    /// 在 telegram bot 中打开此客体的链接
+    tg_link: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CommentResp {
+    comment: ObjComment,
+    /// 嵌套评价
+    replies: Vec<ObjComment>,
+    /// 评价所属的客体，找不到时为空
+    object: Option<ObjTeacher>,
+    /// 在 telegram bot 中打开此评价的链接
+    tg_link: Option<String>,
+}
+
+/// 按 id 获取客体及其评价
+#[get("/api/object/{id}")]
+async fn api_object(
+    db: web::Data<SAFCdb>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let object = db
+        .find_objteacher_with_id(&id)?
+        .ok_or_else(|| Error::NotFound(format!("客体 {id}")))?;
+    Ok(HttpResponse::Ok().json(ObjectResp {
+        comments: db.find_comment(&id)?,
+        ratings: db.rating_summary(&id)?,
+        tg_link: tg_link(DeepLink::Object(id)),
+        object,
+    }))
+}
+
+/// 按 id 获取评价、其嵌套评价与所属客体
+#[get("/api/comment/{id}")]
+async fn api_comment(
+    db: web::Data<SAFCdb>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let comment = db
+        .find_comment_with_id(&id)?
+        .ok_or_else(|| Error::NotFound(format!("评价 {id}")))?;
+    Ok(HttpResponse::Ok().json(CommentResp {
+        comment,
+        replies: db.find_comment(&id)?,
+        object: db.find_root_object(&id)?,
+        tg_link: tg_link(DeepLink::Comment(id)),
+    }))
 }
 
 #[get("/api/query")]
-async fn api_query(db: web::Data<SAFCdb>, item: web::Query<ApiQuery>) -> impl Responder {
+async fn api_query(
+    db: web::Data<SAFCdb>,
+    item: web::Query<ApiQuery>,
+) -> Result<HttpResponse, ApiError> {
     let q = item.into_inner();
-    if q.school_cate.is_none() {
-        return HttpResponse::Ok().json(db.find_school_cate().unwrap());
-    }
-    if q.university.is_none() {
-        return HttpResponse::Ok().json(db.find_university(&q.school_cate.unwrap()).unwrap());
-    }
-    if q.department.is_none() {
-        return HttpResponse::Ok().json(
-            db.find_department(&q.school_cate.unwrap(), &q.university.unwrap())
-                .unwrap(),
-        );
-    }
-    if q.supervisor.is_none() {
-        return HttpResponse::Ok().json(
-            db.find_supervisor(
-                &q.school_cate.unwrap(),
-                &q.university.unwrap(),
-                &q.department.unwrap(),
-            )
-            .unwrap(),
-        );
-    }
-    let obj_teacher = match db
-        .find_object_with_path(
-            &q.university.unwrap(),
-            &q.department.unwrap(),
-            &q.supervisor.unwrap(),
-        )
-        .unwrap()
-    {
-        Some(t) => t,
-        None => {
-            return HttpResponse::NotFound().json("教师信息未找到");
-        }
-    };
-    HttpResponse::Ok().json(db.find_comment(&obj_teacher.object_id).unwrap())
+    let (university, department, supervisor) =
+        match (q.school_cate, q.university, q.department, q.supervisor) {
+            (None, ..) => return Ok(HttpResponse::Ok().json(db.find_school_cate()?)),
+            (Some(s_c), None, ..) => {
+                return Ok(HttpResponse::Ok().json(db.find_university(&s_c)?));
+            }
+            (Some(s_c), Some(u), None, _) => {
+                return Ok(HttpResponse::Ok().json(db.find_department(&s_c, &u)?));
+            }
+            (Some(s_c), Some(u), Some(d), None) => {
+                return Ok(HttpResponse::Ok().json(db.find_supervisor(&s_c, &u, &d)?));
+            }
+            // object id 不包含学校类别
+            (Some(_), Some(u), Some(d), Some(s)) => (u, d, s),
+        };
+    let obj_teacher = db
+        .find_object_with_path(&university, &department, &supervisor)?
+        .ok_or_else(|| Error::NotFound("教师信息".to_string()))?;
+    Ok(HttpResponse::Ok().json(db.find_comment(&obj_teacher.object_id)?))
     // todo 这里需初步的格式化一下以显示嵌套评价
 }
 
-#[post("/api/new/comment")]
-async fn new_comment(db: web::Data<SAFCdb>, form: web::Json<CreateCommentReq>) -> HttpResponse {
-    let exist_teacher =
-        match db.find_object_with_path(&form.university, &form.department, &form.supervisor) {
-            Err(e) => {
-                return HttpResponse::InternalServerError().json(e.to_string());
-            }
-            Ok(o) => match o {
-                Some(t) => t,
-                None => {
-                    // 需要创建实体
-                    let date = get_current_date();
-                    let school_cate = form.school_cate.clone();
-                    let university = form.university.clone();
-                    let department = form.department.clone();
-                    let supervisor = form.supervisor.clone();
-                    let object_id = sec::hash_object_id(&university, &department, &supervisor);
-
-                    let teacher = ObjTeacher {
-                        school_cate,
-                        university,
-                        department,
-                        supervisor,
-                        date,
-                        info: None,
-                        object_id,
-                    };
-                    if let Err(e) = db.add_object(&teacher) {
-                        return HttpResponse::InternalServerError().json(e.to_string());
-                    };
-                    teacher
-                }
-            },
-        };
+/// 全文搜索，`kind` 默认为 object
+#[get("/api/search")]
+async fn api_search(
+    db: web::Data<SAFCdb>,
+    item: web::Query<SearchQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let q = item.into_inner();
+    let limit = q.limit.unwrap_or(20).min(MAX_SEARCH_LIMIT);
+    let hits = db.search(&q.q, q.kind.unwrap_or(SearchKind::Object), limit)?;
+    Ok(HttpResponse::Ok().json(hits))
+}
 
-    let obj_comment = ObjComment::new_with_otp(
-        exist_teacher.object_id,
-        form.content.clone(),
-        SourceCate::Web,
-        CommentType::Teacher,
-        "".to_string(), // TODO: 需要 OTP
-    );
+/// 导出整个数据库，见 [`safc::export`]
+///
+/// 导出内容是确定的，ETag 为内容的 sha256。数据库未变化时复用已生成的内容（见 [`ExportCache`]）
+#[get("/api/export")]
+async fn api_export(
+    req: HttpRequest,
+    exports: web::Data<ExportCache>,
+    item: web::Query<ExportQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let format = match item.format.as_deref() {
+        None => ExportFormat::Json,
+        Some(s) => s
+            .parse()
+            .map_err(|_| Error::Validation(format!("未知的导出格式：{s}")))?,
+    };
+    let rendered = web::block(move || exports.get(format))
+        .await
+        .map_err(|e| Error::Io(io::Error::other(e.to_string())))??;
 
-    match db.add_comment(&obj_comment) {
-        Ok(_) => HttpResponse::Ok().json("评论成功"),
-        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    let etag = header::EntityTag::new_strong(rendered.sha256);
+    let not_modified = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|t| t.trim() == etag.to_string()));
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .finish());
     }
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, format.content_type()))
+        .insert_header(header::ContentDisposition::attachment(format.file_name()))
+        .insert_header(header::ETag(etag))
+        .body(rendered.body))
+}
+
+/// 供其他节点增量同步，见 [`safc::db::sync`]
+///
+/// 返回 JSONL，最后一行为下次请求的游标
+#[get("/api/sync/changes")]
+async fn sync_changes(
+    db: web::Data<SAFCdb>,
+    q: web::Query<SyncQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let since = q.since.unwrap_or(0);
+    let limit = q
+        .limit
+        .unwrap_or(sync::SYNC_PAGE_SIZE)
+        .min(sync::SYNC_PAGE_SIZE);
+    let body = web::block(move || db.sync_changes(since, limit))
+        .await
+        .map_err(|e| Error::Io(io::Error::other(e.to_string())))??;
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "application/x-ndjson; charset=utf-8"))
+        .body(sync::to_jsonl(&body)))
+}
+
+#[post("/api/new/comment")]
+async fn new_comment(
+    db: web::Data<SAFCdb>,
+    form: web::Json<CreateCommentReq>,
+) -> Result<HttpResponse, ApiError> {
+    let teacher = service::find_or_create_object(
+        &db,
+        &form.school_cate,
+        &form.university,
+        &form.department,
+        &form.supervisor,
+    )?;
+    service::post_rated_comment(
+        &db,
+        &teacher.object_id,
+        &form.content,
+        form.ratings,
+        SourceCate::Web,
+        form.otp.as_deref().unwrap_or_default(),
+        None,
+    )?;
+    Ok(HttpResponse::Ok().json("评论成功"))
+}
+
+/// 发布人凭 OTP 修改评价
+#[post("/api/edit/comment")]
+async fn edit_comment(
+    db: web::Data<SAFCdb>,
+    form: web::Json<EditCommentReq>,
+) -> Result<HttpResponse, ApiError> {
+    let c = service::edit_comment(&db, &form.id, &form.content, &form.otp)?;
+    Ok(HttpResponse::Ok().json(c))
+}
+
+/// 发布人凭 OTP 撤回评价
+#[post("/api/delete/comment")]
+async fn delete_comment(
+    db: web::Data<SAFCdb>,
+    form: web::Json<DeleteCommentReq>,
+) -> Result<HttpResponse, ApiError> {
+    service::retract_comment(&db, &form.id, &form.otp)?;
+    Ok(HttpResponse::Ok().json("评价已撤回"))
 }
 
 async fn block_middleware(
     req: ServiceRequest,
     next: Next<BoxBody>,
-) -> Result<ServiceResponse<BoxBody>, Error> {
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
     let headers = req.headers();
     let method = req.method();
 
@@ -247,18 +447,25 @@ async fn main() -> io::Result<()> {
     // 初始化日志库
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
-    log::info!("Starting SAFT web server at PORT{} ... by Framecraft", PORT);
+    let port = std::env::var("SAFC_WEB_PORT")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(PORT);
+    log::info!("Starting SAFT web server at PORT{} ... by Framecraft", port);
 
     // 启动清理任务
     rt::spawn(clean_block_db());
 
     // connect to SQLite DB
     let db = SAFCdb::new();
+    // 供下载的数据库快照，所有 worker 共用
+    let snapshots = SnapshotCache::new(db.clone(), db.snapshot_dir("web"));
+    let exports = ExportCache::new(db.clone());
 
     // start HTTP server
     HttpServer::new(move || {
         // 限流配置：每个 IP 每分钟最多 1000 次请求
-        let governor_conf = GovernorConfigBuilder::default()
+        let _governor_conf = GovernorConfigBuilder::default()
             // TODO: 这些参数最好从配置文件获取，目前只是临时设置的
             .per_second(100) // 每秒请求数提高到 100
             .burst_size(1000) // 突发请求上限提高到 1000
@@ -275,14 +482,23 @@ async fn main() -> io::Result<()> {
         App::new()
             .wrap(from_fn(block_middleware))
             .wrap(cors)
-            // .wrap(Governor::new(&governor_conf)) // 添加限流中间件
+            // .wrap(actix_governor::Governor::new(&_governor_conf)) // 添加限流中间件
             .app_data(web::Data::new(db.clone()))
+            .app_data(web::Data::new(snapshots.clone()))
+            .app_data(web::Data::new(exports.clone()))
             .service(hello)
             .service(api_query)
+            .service(api_search)
+            .service(api_object)
+            .service(api_comment)
             .service(download_file)
+            .service(api_export)
+            .service(sync_changes)
             .service(new_comment)
+            .service(edit_comment)
+            .service(delete_comment)
     })
-    .bind(("127.0.0.1", PORT))?
+    .bind(("127.0.0.1", port))?
     .run()
     .await
 }
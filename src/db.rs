@@ -9,6 +9,14 @@
 //! 【客体表】objects
 //! _学校类别 < _学校 < _学院 < _导师 - _日期 - _信息 - object (key)
 //!           | 包含学院本身 self 下同
+//!
+//! 客体的层级已拆分为规范化的关系表（见 [`migrate`] v2），objects 现在是同名视图：
+//! - school_categories (id, name UNIQUE)
+//! - universities (id, school_cate_id -> school_categories, name) UNIQUE (school_cate_id, name)
+//! - departments (id, university_id -> universities, name) UNIQUE (university_id, name)
+//! - subjects (object PRIMARY KEY, department_id -> departments, supervisor, date, info)
+//!
+//! 视图 objects 的列：
 //! - school_cate TEXT NOT NULL,
 //! - university TEXT NOT NULL,
 //! - department TEXT NOT NULL,
@@ -16,7 +24,9 @@
 //! - date TEXT NOT NULL,
 //! - info TEXT,
 //! - object TEXT NOT NULL,
-//! - PRIMARY KEY (object)
+//!
+//! 对视图的插入、对 date/info 的更新由触发器写入上述各表，层级中缺少的学校等会自动建立
+//!
 //! object：仅在第一次添加客体时计算，所以其他字段也可是可变的
 //! sha256( 学校 | 学院 | 导师 )[:8byte]
 //!
@@ -37,19 +47,80 @@
 //! 发布人签名 可为空 = sha256( 评价 id | sha256(salt + 发布人一次性密语).hex )
 //! salt: SAFC_salt
 //!
+//! 【评价修订表】comment_revisions
+//! 评价 id < 修订号 - 修订前的评价 - 日期 - 动作（edit, retract）
+//! 发布人凭 OTP 修改评价时评价 id 不变，原内容存入此表
+//!
+//! 评价被撤回（retracted）或隐藏（moderated）时不删除，而是在 comments 中留下墓碑：
+//! - tombstone TEXT, 为空表示正常
+//! - tombstone_reason TEXT,
+//! - tombstone_date TEXT,
+//!
+//! 评价可附带分项评星（学术水平、科研经费、师生关系、学生前途、学生补助），1 到 5 星，NULL 表示未评：
+//! - rating_academic INTEGER, rating_funding INTEGER, rating_relationship INTEGER,
+//!   rating_prospects INTEGER, rating_stipend INTEGER
+//!
+//! 评星不参与评价 id 的计算，客体的评星汇总见 [`rating`]
+//!
+//! - edited TEXT, 最后一次修改、撤回、隐藏或恢复的时间（UTC 的 RFC 3339 时间，见 [`get_current_time`]），
+//!   未修改过为空；节点间同步时以此决定取舍，见 [`sync`]
+//!
+//! 【会话表】dialogues
+//! telegram bot 的会话状态，chat_id (key) - state（json）- updated（unix 时间戳）
+//!
+//! 数据库结构的版本与迁移见 [`migrate`]，打开数据库时会自动升级到最新版本
+//!
+//! 客体与评价的全文搜索见 [`search`]
+//!
+//! 【关注表】subscriptions
+//! telegram 会话关注的客体，chat_id - object - 日期，新评价的通知见 [`subscription`]
+//!
+//! 【管理表】audit_log、banned_users
+//! 管理员操作的审计日志与被封禁的 telegram 用户，见 [`admin`]
 //!
-//! TODO 转换为严格的关系型数据库，目前为了敏捷开发，使用 ~2NF
+//! 【限流表】rate_limits
+//! 按 telegram 用户、按操作的令牌桶，见 [`rate_limit`]
 //!
-//! TODO 备份与发布
+//! TODO 评价表的规范化
 //!
-//! TODO 区块链、分布式数据库？- 基于 telegram 通讯
+//! 数据库的备份见 [`backup`]
 //!
-//! TODO 参考：
-//! https://course.rs/advance/errors.html - 归一化不同的错误类型
+//! 【哈希链】chain_log
+//! 客体与评价的每次写入都追加到只能追加的哈希链中，用于发现篡改，见 [`chain`]
+//!
+//! 【同步表】sync_peers、sync_provenance
+//! 弱中心节点之间按哈希链的序号增量同步，各对端的游标与同步来的数据的来源，见 [`sync`]
+//!
+//!
+//! 所有操作返回 [`crate::Result`]，错误类型见 [`crate::Error`]
 //!
 
+pub mod admin;
+pub mod backup;
+pub mod chain;
+pub mod merge;
+pub mod migrate;
+pub mod rate_limit;
+pub mod rating;
+pub mod search;
+pub mod subscription;
+pub mod sync;
+
+pub use admin::{AuditEntry, IntegrityReport, SignReport};
+pub use backup::{BackupFile, SnapshotCache};
+pub use chain::{ChainHead, ChainReport};
+pub use merge::{MergePolicy, MergeReport};
+pub use rate_limit::RateAction;
+pub use rating::{RatingDimension, RatingSummary, Ratings};
+pub use search::{SearchHit, SearchKind};
+pub use subscription::Digest;
+pub use sync::{SyncRecord, SyncReport};
+
 use crate::sec::*;
-use rusqlite::params;
+use crate::{Error, Result};
+use chain::ChainOp;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{params, OpenFlags, OptionalExtension, Row, TransactionBehavior};
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use strum_macros::{Display, EnumString};
@@ -59,8 +130,29 @@ use chrono;
 use r2d2_sqlite::{self, SqliteConnectionManager};
 
 pub type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
-type HandlerResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
-// type HandlerResult<T, E = Box<dyn std::error::Error + Send + Sync>> > = std::result::Result<T, E>;
+
+/// 以字符串形式存取的枚举，无法识别的值读取为 [`Error::InvalidEnum`]
+macro_rules! impl_sql_for_enum {
+    ($t:ty) => {
+        impl ToSql for $t {
+            fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+                Ok(ToSqlOutput::from(self.to_string()))
+            }
+        }
+
+        impl FromSql for $t {
+            fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+                let s = value.as_str()?;
+                <$t>::from_str(s).map_err(|_| {
+                    FromSqlError::Other(Box::new(Error::InvalidEnum {
+                        name: stringify!($t),
+                        value: s.to_string(),
+                    }))
+                })
+            }
+        }
+    };
+}
 
 /// 数据来源分类
 #[derive(Debug, EnumString, Display, PartialEq, Clone, Serialize, Deserialize)]
@@ -101,6 +193,19 @@ pub struct ObjTeacher {
 }
 
 impl ObjTeacher {
+    /// 从 [`OBJECT_COLUMNS`] 查询到的行中读取
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(ObjTeacher {
+            school_cate: row.get("school_cate")?,
+            university: row.get("university")?,
+            department: row.get("department")?,
+            supervisor: row.get("supervisor")?,
+            date: row.get("date")?,
+            info: row.get("info")?,
+            object_id: row.get("object")?,
+        })
+    }
+
     pub fn display_path(&self) -> String {
         format!(
             "🧭 {} 🏫 {} 🏢 {} 👔 {}",
@@ -121,6 +226,9 @@ pub enum CommentType {
     Info,
 }
 
+impl_sql_for_enum!(SourceCate);
+impl_sql_for_enum!(CommentType);
+
 /// 对应数据库中的【评价表】comments
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ObjComment {
@@ -131,9 +239,63 @@ pub struct ObjComment {
     pub comment_type: CommentType,
     pub author_sign: Option<String>,
     pub id: String,
+    /// 被撤回或隐藏的评价的墓碑，此时 `description` 为空
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tombstone: Option<Tombstone>,
+    /// 分项评星，一项都未评时为 `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ratings: Option<Ratings>,
+    /// 最后一次修改、撤回、隐藏或恢复的日期，未修改过为 `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited: Option<String>,
+}
+
+/// 评价墓碑的种类
+#[derive(Debug, EnumString, Display, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum TombstoneKind {
+    /// 发布人撤回
+    Retracted,
+    /// 管理员隐藏
+    Moderated,
+}
+
+impl_sql_for_enum!(TombstoneKind);
+
+/// 评价的墓碑：评价被撤回或隐藏后保留 id 与位置，嵌套评价仍能找到上下文
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Tombstone {
+    pub kind: TombstoneKind,
+    pub reason: Option<String>,
+    pub date: String,
 }
 
 impl ObjComment {
+    /// 从 [`COMMENT_COLUMNS`] 查询到的行中读取
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(ObjComment {
+            object: row.get("object")?,
+            description: row.get("description")?,
+            date: row.get("date")?,
+            source_cate: row.get("source_cate")?,
+            comment_type: row.get("type")?,
+            author_sign: row.get("author_sign")?,
+            id: row.get("id")?,
+            tombstone: match row.get::<_, Option<TombstoneKind>>("tombstone")? {
+                Some(kind) => Some(Tombstone {
+                    kind,
+                    reason: row.get("tombstone_reason")?,
+                    date: row.get("tombstone_date")?,
+                }),
+                None => None,
+            },
+            ratings: Ratings::from_row(row)?,
+            edited: row.get("edited")?,
+        })
+    }
+
+    /// 构造新评价，`otp` 为空（或只有空白）时视为放弃签名，不记录作者签名
     pub fn new_with_otp(
         object_id: String,
         comment: String,
@@ -143,7 +305,7 @@ impl ObjComment {
     ) -> Self {
         let date = get_current_date();
         let id = hash_comment_id(&object_id, &comment, &date);
-        let author_sign = Some(hash_author_sign(&id, &otp));
+        let author_sign = (!otp.trim().is_empty()).then(|| hash_author_sign(&id, &otp));
         ObjComment {
             object: object_id,
             description: comment,
@@ -152,10 +314,51 @@ impl ObjComment {
             comment_type,
             author_sign,
             id,
+            tombstone: None,
+            ratings: None,
+            edited: None,
         }
     }
 }
 
+/// objects 表查询时使用的列，不要使用 `SELECT *`，以免表结构变化后错位
+const OBJECT_COLUMNS: &str = "school_cate, university, department, supervisor, date, info, object";
+
+/// comments 表查询时使用的列
+const COMMENT_COLUMNS: &str = "object, description, date, source_cate, type, author_sign, id, \
+    tombstone, tombstone_reason, tombstone_date, \
+    rating_academic, rating_funding, rating_relationship, rating_prospects, rating_stipend, edited";
+
+/// 评价修订历史中的动作
+#[derive(Debug, EnumString, Display, PartialEq, Clone, Serialize, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+pub enum RevisionAction {
+    /// 发布人修改
+    Edit,
+    /// 发布人撤回
+    Retract,
+    /// 管理员隐藏
+    Moderate,
+}
+
+impl_sql_for_enum!(RevisionAction);
+
+/// 对应数据库中的【评价修订表】comment_revisions
+/// 保存的是修改、撤回或隐藏 *之前* 的内容
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CommentRevision {
+    pub comment_id: String,
+    /// 从 1 开始的修订号
+    pub revision: u32,
+    pub description: String,
+    /// 此次修订发生的日期
+    pub date: String,
+    pub action: RevisionAction,
+    /// 此次修订之前的评星
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ratings: Option<Ratings>,
+}
+
 pub struct SAFCdb {
     db_path: String,
     pool: Pool,
@@ -178,50 +381,115 @@ impl Default for SAFCdb {
 
 impl SAFCdb {
     pub fn new() -> Self {
-        // TODO 检查当前文件是否存在
-        let db_path = std::env::var("SAFC_DB_PATH").unwrap_or_else(|_| {
+        Self::new_with_path(Self::default_path())
+    }
+
+    /// 环境变量 `SAFC_DB_PATH` 给出的数据库路径，未设置时为 ./db.sqlite
+    pub fn default_path() -> String {
+        std::env::var("SAFC_DB_PATH").unwrap_or_else(|_| {
             log::warn!("SAFC_DB_PATH 未设置，默认设置为：./db.sqlite");
             "db.sqlite".to_string()
-        });
-
-        Self::new_with_path(db_path)
+        })
     }
 
+    /// 打开（或新建）数据库，并迁移到最新的结构版本
     pub fn new_with_path(db_path: String) -> Self {
-        let manager = SqliteConnectionManager::file(db_path.clone());
+        let manager = SqliteConnectionManager::file(db_path.clone())
+            .with_init(|c| c.execute_batch("PRAGMA foreign_keys = ON;"));
         let pool = Pool::new(manager).unwrap();
+        let from = migrate::migrate(&mut pool.get().unwrap()).expect("数据库迁移失败");
+        if from < migrate::latest_version() {
+            log::info!(
+                "数据库 {} 已从 v{} 升级到 v{}",
+                db_path,
+                from,
+                migrate::latest_version()
+            );
+        }
         SAFCdb { db_path, pool }
     }
 
+    /// 打开已有的数据库，既不新建也不迁移，`read_only` 时以只读方式打开
+    ///
+    /// 文件不存在时返回 [`Error::NotFound`]，结构版本与程序不一致时返回 [`Error::SchemaVersion`]，
+    /// 新建与迁移只由 [`SAFCdb::new_with_path`] 进行
+    pub fn open_existing(db_path: String, read_only: bool) -> Result<Self> {
+        if !std::path::Path::new(&db_path).is_file() {
+            return Err(Error::NotFound(format!("数据库文件 {db_path}")));
+        }
+        let mode = if read_only {
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+        } else {
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+        };
+        let manager = SqliteConnectionManager::file(db_path.clone())
+            .with_flags(mode | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+            .with_init(|c| c.execute_batch("PRAGMA foreign_keys = ON;"));
+        let pool = Pool::new(manager)?;
+        let found = migrate::schema_version(&*pool.get()?)?;
+        if found != migrate::latest_version() {
+            return Err(Error::SchemaVersion {
+                found,
+                expected: migrate::latest_version(),
+            });
+        }
+        Ok(SAFCdb { db_path, pool })
+    }
+
     pub fn get_db_path(&self) -> String {
         self.db_path.clone()
     }
 
-    pub fn find_school_cate(&self) -> HandlerResult<Vec<String>> {
+    /// 数据库结构版本
+    pub fn schema_version(&self) -> Result<u32> {
         let conn = self.pool.clone().get()?;
-        let mut stmt = conn.prepare("SELECT DISTINCT school_cate FROM objects")?;
+        Ok(migrate::schema_version(&conn)?)
+    }
+
+    pub fn find_school_cate(&self) -> Result<Vec<String>> {
+        let conn = self.pool.clone().get()?;
+        let mut stmt = conn.prepare("SELECT name FROM school_categories ORDER BY id")?;
         let rows = stmt.query_map([], |row| row.get::<usize, String>(0))?;
 
         // rows.collect::<Result<Vec<_>, _>>() // ? 可以将错误进行隐式的强制转换
         Ok(rows.collect::<Result<Vec<_>, _>>()?)
     }
 
-    pub fn find_university(&self, s_c: &String) -> HandlerResult<Vec<String>> {
+    pub fn find_university(&self, s_c: &String) -> Result<Vec<String>> {
         let conn = self.pool.clone().get()?;
 
-        let mut stmt =
-            conn.prepare("SELECT DISTINCT university FROM objects WHERE school_cate=(?1)")?;
+        let mut stmt = conn.prepare(
+            "SELECT u.name FROM universities u \
+            JOIN school_categories c ON c.id = u.school_cate_id \
+            WHERE c.name=(?1) ORDER BY u.id",
+        )?;
         let rows = stmt.query_map([s_c], |row| row.get(0))?;
 
         Ok(rows.collect::<Result<Vec<_>, _>>()?)
     }
 
-    pub fn find_department(&self, s_c: &String, university: &String) -> HandlerResult<Vec<String>> {
+    /// 学校所属的学校类别，学校不存在时返回 `None`；同名学校属于多个类别时取最早的一个
+    pub fn find_school_cate_of_university(&self, university: &str) -> Result<Option<String>> {
+        let conn = self.pool.clone().get()?;
+        Ok(conn
+            .query_row(
+                "SELECT c.name FROM universities u \
+                JOIN school_categories c ON c.id = u.school_cate_id \
+                WHERE u.name = ? ORDER BY u.id LIMIT 1",
+                [university],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn find_department(&self, s_c: &String, university: &String) -> Result<Vec<String>> {
         let conn = self.pool.clone().get()?;
 
         let mut stmt = conn.prepare(
-            "SELECT DISTINCT department FROM objects WHERE \
-        school_cate=(?1) AND university=(?2)",
+            "SELECT d.name FROM departments d \
+            JOIN universities u ON u.id = d.university_id \
+            JOIN school_categories c ON c.id = u.school_cate_id \
+            WHERE c.name=(?1) AND u.name=(?2) ORDER BY d.id",
         )?;
         let rows = stmt.query_map([s_c, university], |row| row.get::<_, String>(0))?;
         Ok(rows.collect::<Result<Vec<_>, _>>()?)
@@ -232,104 +500,71 @@ impl SAFCdb {
         s_c: &String,
         university: &String,
         department: &String,
-    ) -> HandlerResult<Vec<String>> {
+    ) -> Result<Vec<String>> {
         let conn = self.pool.clone().get()?;
 
         let mut stmt = conn.prepare(
-            "SELECT DISTINCT supervisor FROM objects WHERE \
-        school_cate=(?1) AND university=(?2)  AND department=(?3)",
+            "SELECT DISTINCT s.supervisor FROM subjects s \
+            JOIN departments d ON d.id = s.department_id \
+            JOIN universities u ON u.id = d.university_id \
+            JOIN school_categories c ON c.id = u.school_cate_id \
+            WHERE c.name=(?1) AND u.name=(?2) AND d.name=(?3) ORDER BY s.rowid",
         )?;
         let rows = stmt.query_map([s_c, university, department], |row| row.get::<_, String>(0))?;
         Ok(rows.collect::<Result<Vec<_>, _>>()?)
     }
 
     /// 模糊搜索
+    /// 推荐使用 [`SAFCdb::search`]
     /// 百分号（%）代表零个、一个或多个字符。下划线（_）代表一个单一的字符。这些符号可以被组合使用。
     ///
     /// 返回搜到的 [`ObjTeacher`] 列表，可为空
-    pub fn find_supervisor_like(&self, s: &String) -> HandlerResult<Vec<ObjTeacher>> {
+    pub fn find_supervisor_like(&self, s: &String) -> Result<Vec<ObjTeacher>> {
         let conn = self.pool.clone().get()?;
 
-        let mut stmt = conn.prepare(
-            "SELECT * FROM objects WHERE \
-            supervisor LIKE (?1)",
-        )?;
-        // let rows = stmt.query_map([s], |row| row.get(0))?;
-        let rows = stmt.query_map([s], |row| {
-            Ok(ObjTeacher {
-                school_cate: row.get::<_, String>(0)?,
-                university: row.get::<_, String>(1)?,
-                department: row.get::<_, String>(2)?,
-                supervisor: row.get::<_, String>(3)?,
-                date: row.get::<_, String>(4)?,
-                info: row.get::<_, String>(5).ok(),
-                object_id: row.get::<_, String>(6)?,
-            })
-        })?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {OBJECT_COLUMNS} FROM objects WHERE supervisor LIKE (?1)"
+        ))?;
+        let rows = stmt.query_map([s], ObjTeacher::from_row)?;
         Ok(rows.collect::<Result<Vec<_>, _>>()?)
     }
 
     /// 查找客体 用路径的方式
     /// 【客体表】objects  _学校类别 < 学校 < 学院 < 导师 - _日期 - _信息 - object (key)
-    /// - school_cate TEXT NOT NULL,
-    /// - university TEXT NOT NULL,
-    /// - department TEXT NOT NULL,
-    /// - supervisor TEXT NOT NULL,
-    /// - date TEXT NOT NULL,
-    /// - info TEXT,
-    /// - object TEXT NOT NULL,
-    /// - PRIMARY KEY (object)
+    /// 学校类别不参与查找，object id 也不包含学校类别
     pub fn find_object_with_path(
         &self,
         university: &String,
         department: &String,
         supervisor: &String,
-    ) -> HandlerResult<Option<ObjTeacher>> {
+    ) -> Result<Option<ObjTeacher>> {
         let conn = self.pool.clone().get()?;
 
-        let mut stmt = conn.prepare(
-            "SELECT * FROM objects WHERE \
-        supervisor=(?1) AND university=(?2) AND department=(?3)",
-        )?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {OBJECT_COLUMNS} FROM objects WHERE \
+            supervisor=(?1) AND university=(?2) AND department=(?3)"
+        ))?;
 
-        let rows = stmt.query_map([supervisor, university, department], |row| {
-            Ok(ObjTeacher {
-                school_cate: row.get::<_, String>(0)?,
-                university: row.get::<_, String>(1)?,
-                department: row.get::<_, String>(2)?,
-                supervisor: row.get::<_, String>(3)?,
-                date: row.get::<_, String>(4)?,
-                info: row.get::<_, String>(5).ok(),
-                object_id: row.get::<_, String>(6)?,
-            })
-        })?;
+        let rows = stmt.query_map([supervisor, university, department], ObjTeacher::from_row)?;
         Ok(rows.collect::<Result<Vec<_>, _>>()?.first().cloned())
     }
 
     /// 查找客体 用 id 的方式
     /// 【客体表】objects  _学校类别 < 学校 < 学院 < 导师 - _日期 - _信息 - object (key)
-    pub fn find_objteacher_with_id(&self, object_id: &str) -> HandlerResult<Option<ObjTeacher>> {
+    pub fn find_objteacher_with_id(&self, object_id: &str) -> Result<Option<ObjTeacher>> {
         let conn = self.pool.clone().get()?;
 
-        let mut stmt = conn.prepare("SELECT * FROM objects WHERE object=(?1)")?;
-
-        let rows = stmt.query_map([object_id], |row| {
-            Ok(ObjTeacher {
-                school_cate: row.get::<_, String>(0)?,
-                university: row.get::<_, String>(1)?,
-                department: row.get::<_, String>(2)?,
-                supervisor: row.get::<_, String>(3)?,
-                date: row.get::<_, String>(4)?,
-                info: row.get::<_, String>(5).ok(),
-                object_id: row.get::<_, String>(6)?,
-            })
-        })?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {OBJECT_COLUMNS} FROM objects WHERE object=(?1)"
+        ))?;
+
+        let rows = stmt.query_map([object_id], ObjTeacher::from_row)?;
         Ok(rows.collect::<Result<Vec<_>, _>>()?.first().cloned())
     }
 
     /// object 是否存在
     /// TODO 重构：数据库加入客体的种类
-    pub fn if_object_exists(&self, object: &str) -> HandlerResult<Option<CommentType>> {
+    pub fn if_object_exists(&self, object: &str) -> Result<Option<CommentType>> {
         let conn = self.pool.clone().get()?;
 
         let exists1: bool = conn.query_row(
@@ -367,90 +602,292 @@ impl SAFCdb {
     /// - id TEXT NOT NULL,
     ///
     /// 返回 [`ObjComment`]
-    pub fn find_comment(&self, object_id: &String) -> HandlerResult<Vec<ObjComment>> {
+    pub fn find_comment(&self, object_id: &String) -> Result<Vec<ObjComment>> {
         let conn = self.pool.clone().get()?;
 
-        let mut stmt = conn.prepare("SELECT * FROM comments WHERE object=? ")?;
-        let rows = stmt.query_map([object_id], |row| {
-            Ok(ObjComment {
-                object: row.get::<_, String>(0)?,
-                description: row.get::<_, String>(1)?,
-                date: row.get::<_, String>(2)?,
-                source_cate: SourceCate::from_str(row.get::<_, String>(3)?.as_str()).unwrap(),
-                comment_type: CommentType::from_str(row.get::<_, String>(4)?.as_str()).unwrap(),
-                author_sign: row.get::<_, String>(5).ok(),
-                id: row.get::<_, String>(6)?,
-            })
-        })?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {COMMENT_COLUMNS} FROM comments WHERE object=?"
+        ))?;
+        let rows = stmt.query_map([object_id], ObjComment::from_row)?;
         Ok(rows.collect::<Result<Vec<_>, _>>()?)
     }
 
-    /// 查找评价 - like 方式
-    pub fn find_comment_like(&self, s: &String) -> HandlerResult<Vec<ObjComment>> {
+    /// 客体下的评价数，包括嵌套评价与墓碑
+    pub fn count_comments(&self, object_id: &str) -> Result<usize> {
         let conn = self.pool.clone().get()?;
 
-        let mut stmt = conn.prepare("SELECT * FROM comments WHERE description LIKE ? ")?;
-        let rows = stmt.query_map([s], |row| {
-            Ok(ObjComment {
-                object: row.get::<_, String>(0)?,
-                description: row.get::<_, String>(1)?,
-                date: row.get::<_, String>(2)?,
-                source_cate: SourceCate::from_str(row.get::<_, String>(3)?.as_str()).unwrap(),
-                comment_type: CommentType::from_str(row.get::<_, String>(4)?.as_str()).unwrap(),
-                author_sign: match row.get::<_, String>(5) {
-                    Ok(s) => Some(s),
-                    Err(_) => None,
-                },
-                id: row.get::<_, String>(6)?,
-            })
-        })?;
+        // depth 用于防止环形引用
+        let n: i64 = conn.query_row(
+            "WITH RECURSIVE down(id, depth) AS ( \
+                SELECT id, 0 FROM comments WHERE object = ?1 \
+                UNION \
+                SELECT c.id, down.depth + 1 FROM comments c \
+                JOIN down ON c.object = down.id WHERE down.depth < 64 \
+            ) \
+            SELECT COUNT(DISTINCT id) FROM down",
+            [object_id],
+            |row| row.get(0),
+        )?;
+        Ok(n as usize)
+    }
+
+    /// 通过评价 id 查找评价
+    pub fn find_comment_with_id(&self, id: &str) -> Result<Option<ObjComment>> {
+        let conn = self.pool.clone().get()?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {COMMENT_COLUMNS} FROM comments WHERE id=?"
+        ))?;
+        let rows = stmt.query_map([id], ObjComment::from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?.first().cloned())
+    }
+
+    /// 沿嵌套评价向上查找评价所属的客体，找不到时返回 `None`
+    pub fn find_root_object(&self, comment_id: &str) -> Result<Option<ObjTeacher>> {
+        let conn = self.pool.clone().get()?;
+
+        // depth 用于防止环形引用
+        let mut stmt = conn.prepare(&format!(
+            "WITH RECURSIVE up(id, object, depth) AS ( \
+                SELECT id, object, 0 FROM comments WHERE id = ?1 \
+                UNION ALL \
+                SELECT c.id, c.object, up.depth + 1 FROM comments c \
+                JOIN up ON c.id = up.object WHERE up.depth < 64 \
+            ) \
+            SELECT {OBJECT_COLUMNS} FROM objects WHERE object IN (SELECT object FROM up) LIMIT 1"
+        ))?;
+        let rows = stmt.query_map([comment_id], ObjTeacher::from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?.pop())
+    }
+
+    /// 所有客体，按学校、学院、导师、id 排序，用于导出
+    pub fn find_all_objects(&self) -> Result<Vec<ObjTeacher>> {
+        let conn = self.pool.clone().get()?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {OBJECT_COLUMNS} FROM objects \
+            ORDER BY university, department, supervisor, object"
+        ))?;
+        let rows = stmt.query_map([], ObjTeacher::from_row)?;
         Ok(rows.collect::<Result<Vec<_>, _>>()?)
     }
 
-    /// 增加评价客体，有一些值在函数内计算
-    pub fn add_object(&self, obj_teacher: &ObjTeacher) -> HandlerResult<()> {
+    /// 所有评价（含嵌套评价与墓碑），按日期、id 排序，用于导出
+    pub fn find_all_comments(&self) -> Result<Vec<ObjComment>> {
         let conn = self.pool.clone().get()?;
-        conn.execute(
-            "INSERT INTO objects (school_cate, university, department, supervisor, date, info, object) 
-        VALUES (?, ?, ?, ?, ?, ?, ?)",
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {COMMENT_COLUMNS} FROM comments ORDER BY date, id"
+        ))?;
+        let rows = stmt.query_map([], ObjComment::from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// 查找评价 - like 方式
+    /// 推荐使用 [`SAFCdb::search`]
+    pub fn find_comment_like(&self, s: &String) -> Result<Vec<ObjComment>> {
+        let conn = self.pool.clone().get()?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {COMMENT_COLUMNS} FROM comments WHERE description LIKE ?"
+        ))?;
+        let rows = stmt.query_map([s], ObjComment::from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// 增加评价客体，id 已存在时返回 [`Error::DuplicateId`]
+    /// 写入 objects 视图，由触发器建立缺少的学校类别、学校、学院
+    pub fn add_object(&self, obj_teacher: &ObjTeacher) -> Result<()> {
+        let mut conn = self.pool.clone().get()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        insert_object(&tx, obj_teacher)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// 增加评价，id 已存在时返回 [`Error::DuplicateId`]
+    pub fn add_comment(&self, obj_comment: &ObjComment) -> Result<()> {
+        let mut conn = self.pool.clone().get()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        insert_comment(&tx, obj_comment)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// 在一个事务中增加一批客体与评价，任一 id 已存在时全部回滚并返回 [`Error::DuplicateId`]
+    ///
+    /// 嵌套评价应排在其上级评价之后
+    pub fn add_batch(&self, objects: &[ObjTeacher], comments: &[ObjComment]) -> Result<()> {
+        let mut conn = self.pool.clone().get()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        for t in objects {
+            insert_object(&tx, t)?;
+        }
+        for c in comments {
+            insert_comment(&tx, c)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// 修改评价正文，原内容存入修订历史，评价 id 不变
+    pub fn edit_comment(&self, id: &str, description: &str) -> Result<()> {
+        let mut conn = self.pool.clone().get()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        archive_comment(&tx, id, RevisionAction::Edit)?;
+        tx.execute(
+            "UPDATE comments SET description = ?, edited = ? WHERE id = ?",
+            params![description, get_current_time(), id],
+        )?;
+        chain::append_comment(&tx, ChainOp::EditComment, id)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// 撤回或隐藏评价：原内容存入修订历史，正文置空并留下墓碑，评价 id 与嵌套关系不变
+    pub fn tombstone_comment(
+        &self,
+        id: &str,
+        kind: TombstoneKind,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let action = match kind {
+            TombstoneKind::Retracted => RevisionAction::Retract,
+            TombstoneKind::Moderated => RevisionAction::Moderate,
+        };
+        let tombstone = Tombstone {
+            kind,
+            reason: reason.map(str::to_string),
+            date: get_current_date(),
+        };
+        let mut conn = self.pool.clone().get()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        archive_comment(&tx, id, action)?;
+        // 评星与正文一样存入修订历史后清空，不再公开
+        tx.execute(
+            "UPDATE comments SET description = '', \
+            rating_academic = NULL, rating_funding = NULL, rating_relationship = NULL, \
+            rating_prospects = NULL, rating_stipend = NULL, \
+            tombstone = ?1, tombstone_reason = ?2, tombstone_date = ?3, edited = ?4 WHERE id = ?5",
             params![
-                obj_teacher.school_cate,
-                obj_teacher.university,
-                obj_teacher.department,
-                obj_teacher.supervisor,
-                obj_teacher.date,
-                obj_teacher.info,
-                obj_teacher.object_id
+                tombstone.kind,
+                tombstone.reason,
+                tombstone.date,
+                get_current_time(),
+                id
             ],
         )?;
+        chain::append_comment(&tx, ChainOp::TombstoneComment, id)?;
+        tx.commit()?;
+        Ok(())
+    }
 
+    /// 恢复被撤回或隐藏的评价：正文恢复为墓碑前的最后一个修订，并移除墓碑
+    ///
+    /// 评价不存在时返回 [`Error::NotFound`]，未被撤回或隐藏、或没有修订历史可恢复时返回 [`Error::Validation`]
+    pub fn restore_comment(&self, id: &str) -> Result<()> {
+        let mut conn = self.pool.clone().get()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let tombstone: Option<Option<String>> = tx
+            .query_row("SELECT tombstone FROM comments WHERE id = ?", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        match tombstone {
+            None => return Err(Error::NotFound(format!("评价 {id}"))),
+            Some(None) => return Err(Error::Validation("此评价未被撤回或隐藏".to_string())),
+            Some(Some(_)) => {}
+        }
+        // 从其他节点同步来的墓碑在本地没有修订历史
+        let (description, ratings): (String, Option<Ratings>) = tx
+            .query_row(
+                "SELECT description, rating_academic, rating_funding, rating_relationship, \
+                rating_prospects, rating_stipend FROM comment_revisions WHERE comment_id = ? \
+                ORDER BY revision DESC LIMIT 1",
+                [id],
+                |row| Ok((row.get(0)?, Ratings::from_row(row)?)),
+            )
+            .optional()?
+            .ok_or_else(|| Error::Validation("没有可恢复的内容".to_string()))?;
+        let r = ratings.unwrap_or_default();
+        tx.execute(
+            "UPDATE comments SET description = ?, \
+            rating_academic = ?, rating_funding = ?, rating_relationship = ?, \
+            rating_prospects = ?, rating_stipend = ?, edited = ?, \
+            tombstone = NULL, tombstone_reason = NULL, tombstone_date = NULL WHERE id = ?",
+            params![
+                description,
+                r.academic,
+                r.funding,
+                r.relationship,
+                r.prospects,
+                r.stipend,
+                get_current_time(),
+                id
+            ],
+        )?;
+        chain::append_comment(&tx, ChainOp::RestoreComment, id)?;
+        tx.commit()?;
         Ok(())
     }
 
-    /// 增加评价
-    pub fn add_comment(&self, obj_comment: &ObjComment) -> HandlerResult<()> {
+    /// 评价的修订历史，按修订号升序
+    pub fn find_comment_revisions(&self, id: &str) -> Result<Vec<CommentRevision>> {
+        let conn = self.pool.clone().get()?;
+        let mut stmt = conn.prepare(
+            "SELECT comment_id, revision, description, date, action, \
+            rating_academic, rating_funding, rating_relationship, rating_prospects, rating_stipend \
+            FROM comment_revisions WHERE comment_id = ? ORDER BY revision",
+        )?;
+        let rows = stmt.query_map([id], |row| {
+            Ok(CommentRevision {
+                comment_id: row.get(0)?,
+                revision: row.get(1)?,
+                description: row.get(2)?,
+                date: row.get(3)?,
+                action: row.get(4)?,
+                ratings: Ratings::from_row(row)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// 读取 bot 的会话状态（json）
+    pub fn get_dialogue(&self, chat_id: i64) -> Result<Option<String>> {
+        let conn = self.pool.clone().get()?;
+        let mut stmt = conn.prepare("SELECT state FROM dialogues WHERE chat_id = ?")?;
+        let rows = stmt.query_map([chat_id], |row| row.get(0))?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?.pop())
+    }
+
+    /// 保存 bot 的会话状态（json），同时刷新其更新时间
+    pub fn update_dialogue(&self, chat_id: i64, state: &str) -> Result<()> {
         let conn = self.pool.clone().get()?;
         conn.execute(
-            "INSERT INTO comments
-        (object, description, date, source_cate, type, author_sign, id)
-        VALUES (?, ?, ?, ?, ?, ?, ?)",
-            params![
-                obj_comment.object,
-                obj_comment.description,
-                obj_comment.date,
-                obj_comment.source_cate.to_string(),
-                obj_comment.comment_type.to_string(),
-                obj_comment.author_sign,
-                obj_comment.id
-            ],
+            "INSERT INTO dialogues (chat_id, state, updated) VALUES (?1, ?2, ?3) \
+            ON CONFLICT (chat_id) DO UPDATE SET state = ?2, updated = ?3",
+            params![chat_id, state, chrono::Utc::now().timestamp()],
         )?;
+        Ok(())
+    }
 
+    /// 删除 bot 的会话状态
+    pub fn remove_dialogue(&self, chat_id: i64) -> Result<()> {
+        let conn = self.pool.clone().get()?;
+        conn.execute("DELETE FROM dialogues WHERE chat_id = ?", [chat_id])?;
         Ok(())
     }
 
+    /// 删除超过 `ttl` 未更新的会话，返回删除的数量
+    pub fn purge_dialogues(&self, ttl: chrono::Duration) -> Result<usize> {
+        let conn = self.pool.clone().get()?;
+        let before = (chrono::Utc::now() - ttl).timestamp();
+        Ok(conn.execute("DELETE FROM dialogues WHERE updated < ?", [before])?)
+    }
+
     /// 统计数据库的信息
     /// 总条目数，最近一月新增的条目数...
-    pub fn db_status(&self) -> HandlerResult<String> {
+    pub fn db_status(&self) -> Result<String> {
         let conn = self.pool.clone().get()?;
 
         let o_count =
@@ -479,19 +916,105 @@ impl SAFCdb {
         let days = duration.num_days();
         let duration_str = format!("{} 天", days);
 
+        // 链头的哈希取前 16 位，足以与其他副本比对
+        let head = match chain::head(&conn)? {
+            Some(h) => format!("#{} {}", h.seq, &h.hash[..16]),
+            None => "空".to_string(),
+        };
+
         Ok(format!(
-            "评价总数：{}, 实体客体总数：{}, 月新增客体数：{}, 月增评价数：{}, 项目存续时间：{}",
-            c_count, o_count, o_new, m_new, duration_str
+            "评价总数：{}, 实体客体总数：{}, 月新增客体数：{}, 月增评价数：{}, 项目存续时间：{}, 链头：{}",
+            c_count, o_count, o_new, m_new, duration_str, head
         ))
     }
 }
 
 impl ObjComment {}
 
+/// 写入客体并追加到哈希链，应在事务中调用
+fn insert_object(conn: &rusqlite::Connection, obj_teacher: &ObjTeacher) -> Result<()> {
+    conn.execute(
+        "INSERT INTO objects (school_cate, university, department, supervisor, date, info, object) 
+    VALUES (?, ?, ?, ?, ?, ?, ?)",
+        params![
+            obj_teacher.school_cate,
+            obj_teacher.university,
+            obj_teacher.department,
+            obj_teacher.supervisor,
+            obj_teacher.date,
+            obj_teacher.info,
+            obj_teacher.object_id
+        ],
+    )
+    .map_err(|e| Error::from_insert(e, &obj_teacher.object_id))?;
+    chain::append_object(conn, obj_teacher)?;
+
+    Ok(())
+}
+
+/// 写入评价并追加到哈希链，应在事务中调用
+fn insert_comment(conn: &rusqlite::Connection, obj_comment: &ObjComment) -> Result<()> {
+    let ratings = obj_comment.ratings.unwrap_or_default();
+    let tombstone = obj_comment.tombstone.as_ref();
+    conn.execute(
+        "INSERT INTO comments
+    (object, description, date, source_cate, type, author_sign, id,
+    tombstone, tombstone_reason, tombstone_date,
+    rating_academic, rating_funding, rating_relationship, rating_prospects, rating_stipend,
+    edited)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            obj_comment.object,
+            obj_comment.description,
+            obj_comment.date,
+            obj_comment.source_cate,
+            obj_comment.comment_type,
+            obj_comment.author_sign,
+            obj_comment.id,
+            tombstone.map(|t| t.kind),
+            tombstone.and_then(|t| t.reason.as_deref()),
+            tombstone.map(|t| t.date.as_str()),
+            ratings.academic,
+            ratings.funding,
+            ratings.relationship,
+            ratings.prospects,
+            ratings.stipend,
+            obj_comment.edited
+        ],
+    )
+    .map_err(|e| Error::from_insert(e, &obj_comment.id))?;
+    chain::append_comment(conn, ChainOp::AddComment, &obj_comment.id)?;
+
+    Ok(())
+}
+
+/// 将评价的当前内容存入修订历史，评价不存在时返回 [`Error::NotFound`]
+fn archive_comment(tx: &rusqlite::Transaction, id: &str, action: RevisionAction) -> Result<()> {
+    let n = tx.execute(
+        "INSERT INTO comment_revisions (comment_id, revision, description, date, action,
+            rating_academic, rating_funding, rating_relationship, rating_prospects, rating_stipend)
+        SELECT id,
+            (SELECT COUNT(*) FROM comment_revisions WHERE comment_id = ?1) + 1,
+            description, ?2, ?3,
+            rating_academic, rating_funding, rating_relationship, rating_prospects, rating_stipend
+        FROM comments WHERE id = ?1",
+        params![id, get_current_date(), action],
+    )?;
+    if n == 0 {
+        return Err(Error::NotFound(format!("评价 {id}")));
+    }
+    Ok(())
+}
+
 pub fn get_current_date() -> String {
     chrono::Local::now().format("%Y-%m-%d").to_string()
 }
 
+/// 当前的 UTC 时间，精确到秒，如 `2023-09-18T08:00:00Z`，格式固定，可以直接按字典序比较
+pub fn get_current_time() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
 #[test]
 fn test_find_object() {
     let db = SAFCdb::new();
@@ -533,8 +1056,214 @@ fn my_test() {
     println!("{:#?}", db.find_objteacher_with_id("918863e1af3b1e67"));
 }
 
+#[test]
+fn test_typed_errors() {
+    let db = temp_db();
+    let c = ObjComment::new_with_otp(
+        "o".to_string(),
+        "c".to_string(),
+        SourceCate::Web,
+        CommentType::Teacher,
+        "otp".to_string(),
+    );
+    db.add_comment(&c).unwrap();
+    assert!(matches!(db.add_comment(&c), Err(Error::DuplicateId(id)) if id == c.id));
+
+    // 数据库中未知的枚举值不应 panic
+    db.pool
+        .get()
+        .unwrap()
+        .execute("UPDATE comments SET source_cate = 'unknown'", [])
+        .unwrap();
+    assert!(matches!(
+        db.find_comment(&"o".to_string()),
+        Err(Error::InvalidEnum {
+            name: "SourceCate",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_dialogues() {
+    let db = temp_db();
+    assert_eq!(None, db.get_dialogue(42).unwrap());
+    db.update_dialogue(42, "\"Start\"").unwrap();
+    db.update_dialogue(42, "\"StartCb\"").unwrap();
+    assert_eq!(
+        Some("\"StartCb\"".to_string()),
+        db.get_dialogue(42).unwrap()
+    );
+    assert_eq!(0, db.purge_dialogues(chrono::Duration::days(1)).unwrap());
+    assert_eq!(
+        1,
+        db.purge_dialogues(chrono::Duration::seconds(-1)).unwrap()
+    );
+    db.remove_dialogue(42).unwrap();
+    assert_eq!(None, db.get_dialogue(42).unwrap());
+}
+
 #[test]
 fn my_test2() {
     assert_eq!("admin".to_owned(), SourceCate::Admin.to_string());
     assert_eq!("nest".to_owned(), CommentType::Nest.to_string());
 }
+
+/// 测试用的临时数据库，每次调用都是一个新的文件
+#[cfg(test)]
+pub(crate) fn temp_db() -> SAFCdb {
+    let path =
+        std::env::temp_dir().join(format!("safc_test_{:016x}.sqlite", rand::random::<u64>()));
+    SAFCdb::new_with_path(path.to_string_lossy().into_owned())
+}
+
+/// 测试用的客体：985 清华大学 self 张三
+#[cfg(test)]
+pub(crate) fn temp_object(db: &SAFCdb) -> ObjTeacher {
+    crate::service::create_object(db, "985", "清华大学", "self", "张三").unwrap()
+}
+
+#[test]
+fn test_new_db_is_migrated() {
+    let db = temp_db();
+    assert_eq!(migrate::latest_version(), db.schema_version().unwrap());
+    let t = ObjTeacher {
+        school_cate: "985".to_string(),
+        university: "清华大学".to_string(),
+        department: "self".to_string(),
+        supervisor: "张三".to_string(),
+        date: get_current_date(),
+        info: None,
+        object_id: hash_object_id(
+            &"清华大学".to_string(),
+            &"self".to_string(),
+            &"张三".to_string(),
+        ),
+    };
+    db.add_object(&t).unwrap();
+    let found = db.find_objteacher_with_id(&t.object_id).unwrap().unwrap();
+    assert_eq!(t.supervisor, found.supervisor);
+    assert_eq!(None, found.info);
+}
+
+#[test]
+fn test_open_existing() {
+    let db = temp_db();
+    let t = temp_object(&db);
+    let ro = SAFCdb::open_existing(db.get_db_path(), true).unwrap();
+    assert!(ro.find_objteacher_with_id(&t.object_id).unwrap().is_some());
+    assert!(ro.subscribe(1, &t.object_id).is_err());
+
+    let missing = format!("{}-missing", db.get_db_path());
+    assert!(matches!(
+        SAFCdb::open_existing(missing.clone(), true),
+        Err(Error::NotFound(_))
+    ));
+    assert!(!std::path::Path::new(&missing).exists());
+
+    // 旧版本的数据库不会被迁移
+    let old = format!("{}-old", db.get_db_path());
+    rusqlite::Connection::open(&old)
+        .unwrap()
+        .pragma_update(None, "user_version", 3)
+        .unwrap();
+    assert!(matches!(
+        SAFCdb::open_existing(old.clone(), false),
+        Err(Error::SchemaVersion { found: 3, .. })
+    ));
+    let conn = rusqlite::Connection::open(&old).unwrap();
+    assert_eq!(3, migrate::schema_version(&conn).unwrap());
+}
+
+#[test]
+fn test_find_root_object() {
+    let db = temp_db();
+    let t = temp_object(&db);
+    assert_eq!(0, db.count_comments(&t.object_id).unwrap());
+    let mut parent = t.object_id.clone();
+    for (i, ty) in [CommentType::Teacher, CommentType::Nest, CommentType::Nest]
+        .into_iter()
+        .enumerate()
+    {
+        let c = ObjComment::new_with_otp(
+            parent,
+            format!("第 {i} 层"),
+            SourceCate::Web,
+            ty,
+            "otp".to_string(),
+        );
+        db.add_comment(&c).unwrap();
+        parent = c.id;
+    }
+    // 嵌套评价也计入客体的评价数
+    assert_eq!(3, db.count_comments(&t.object_id).unwrap());
+    let root = db.find_root_object(&parent).unwrap().unwrap();
+    assert_eq!(t.object_id, root.object_id);
+    assert!(db.find_root_object("nonexistent").unwrap().is_none());
+}
+
+#[test]
+fn test_restore_without_revision() {
+    let db = temp_db();
+    let t = temp_object(&db);
+    let c = ObjComment::new_with_otp(
+        t.object_id,
+        "评价".to_string(),
+        SourceCate::Web,
+        CommentType::Teacher,
+        "otp".to_string(),
+    );
+    db.add_comment(&c).unwrap();
+    db.tombstone_comment(&c.id, TombstoneKind::Retracted, None)
+        .unwrap();
+    // 模拟同步来的墓碑：本地没有修订历史
+    db.pool
+        .get()
+        .unwrap()
+        .execute(
+            "DELETE FROM comment_revisions WHERE comment_id = ?",
+            [&c.id],
+        )
+        .unwrap();
+    assert!(matches!(
+        db.restore_comment(&c.id),
+        Err(Error::Validation(_))
+    ));
+}
+
+#[test]
+fn test_find_normalized() {
+    let db = temp_db();
+    for (u, d, s) in [
+        ("清华大学", "self", "张三"),
+        ("清华大学", "计算机系", "李四"),
+        ("北京大学", "self", "王五"),
+    ] {
+        let (u, d, s) = (u.to_string(), d.to_string(), s.to_string());
+        db.add_object(&ObjTeacher {
+            school_cate: "985".to_string(),
+            object_id: hash_object_id(&u, &d, &s),
+            university: u,
+            department: d,
+            supervisor: s,
+            date: get_current_date(),
+            info: None,
+        })
+        .unwrap();
+    }
+    let c = "985".to_string();
+    let u = "清华大学".to_string();
+    assert_eq!(vec!["985"], db.find_school_cate().unwrap());
+    assert_eq!(
+        vec!["清华大学", "北京大学"],
+        db.find_university(&c).unwrap()
+    );
+    assert_eq!(
+        vec!["self", "计算机系"],
+        db.find_department(&c, &u).unwrap()
+    );
+    assert_eq!(
+        vec!["李四"],
+        db.find_supervisor(&c, &u, &"计算机系".to_string()).unwrap()
+    );
+}
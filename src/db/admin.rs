@@ -0,0 +1,412 @@
+//! # admin
+//!
+//! 管理员相关的数据库操作：审计日志、封禁用户、合并客体，以及 `safc_admin` 使用的完整性与签名检查
+//!
+//! 表结构见 [`super::migrate`] v8
+
+use std::fmt;
+
+use rusqlite::{params, Transaction, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+
+use super::{get_current_date, ObjComment, SAFCdb};
+use crate::sec::verify_author_sign;
+use crate::{Error, Result};
+
+/// 审计日志的一条记录
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub date: String,
+    /// 执行操作的管理员的 telegram 用户 id，`safc_admin` 的操作为 0
+    pub admin_id: i64,
+    /// 操作名，如 hide、ban
+    pub action: String,
+    /// 操作对象，如评价 id、用户 id
+    pub target: String,
+    /// 附加说明，如理由、执行结果
+    pub detail: Option<String>,
+}
+
+impl SAFCdb {
+    /// 记录一条管理员操作
+    pub fn add_audit_log(
+        &self,
+        admin_id: i64,
+        action: &str,
+        target: &str,
+        detail: Option<&str>,
+    ) -> Result<()> {
+        let conn = self.pool.clone().get()?;
+        conn.execute(
+            "INSERT INTO audit_log (date, admin_id, action, target, detail) \
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+                admin_id,
+                action,
+                target,
+                detail
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// 最近的 `limit` 条管理员操作，新的在前
+    pub fn find_audit_log(&self, limit: usize) -> Result<Vec<AuditEntry>> {
+        let conn = self.pool.clone().get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, date, admin_id, action, target, detail FROM audit_log \
+            ORDER BY id DESC LIMIT ?",
+        )?;
+        let rows = stmt.query_map([limit as i64], |row| {
+            Ok(AuditEntry {
+                id: row.get(0)?,
+                date: row.get(1)?,
+                admin_id: row.get(2)?,
+                action: row.get(3)?,
+                target: row.get(4)?,
+                detail: row.get(5)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// 封禁 telegram 用户，已封禁时返回 `false`
+    pub fn ban_user(&self, user_id: i64, reason: Option<&str>) -> Result<bool> {
+        let conn = self.pool.clone().get()?;
+        let n = conn.execute(
+            "INSERT OR IGNORE INTO banned_users (user_id, date, reason) VALUES (?1, ?2, ?3)",
+            params![user_id, get_current_date(), reason],
+        )?;
+        Ok(n > 0)
+    }
+
+    /// 解封 telegram 用户，未封禁时返回 `false`
+    pub fn unban_user(&self, user_id: i64) -> Result<bool> {
+        let conn = self.pool.clone().get()?;
+        let n = conn.execute("DELETE FROM banned_users WHERE user_id = ?", [user_id])?;
+        Ok(n > 0)
+    }
+
+    /// telegram 用户是否被封禁
+    pub fn is_banned(&self, user_id: i64) -> Result<bool> {
+        let conn = self.pool.clone().get()?;
+        Ok(conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM banned_users WHERE user_id = ?)",
+            [user_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// 与 bot 有过会话或关注了客体的 telegram 会话，用于广播
+    pub fn find_known_chats(&self) -> Result<Vec<i64>> {
+        let conn = self.pool.clone().get()?;
+        let mut stmt = conn.prepare(
+            "SELECT chat_id FROM dialogues UNION SELECT chat_id FROM subscriptions ORDER BY 1",
+        )?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// 将客体 `from` 合并到 `into`：评价与关注转移到 `into`，然后删除 `from`
+    ///
+    /// 返回转移的评价数。任一客体不存在时返回 [`Error::NotFound`]
+    pub fn merge_objects(&self, from: &str, into: &str) -> Result<usize> {
+        if from == into {
+            return Err(Error::Validation("不能将客体合并到自身".to_string()));
+        }
+        for id in [from, into] {
+            if self.find_objteacher_with_id(id)?.is_none() {
+                return Err(Error::NotFound(format!("客体 {id}")));
+            }
+        }
+        let mut conn = self.pool.clone().get()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let n = merge_in(&tx, from, into)?;
+        tx.commit()?;
+        Ok(n)
+    }
+
+    /// 管理员使用的统计信息，在 [`SAFCdb::db_status`] 之外
+    pub fn admin_stats(&self) -> Result<String> {
+        let conn = self.pool.clone().get()?;
+        let count = |sql: &str| -> Result<i64> { Ok(conn.query_row(sql, [], |row| row.get(0))?) };
+        Ok(format!(
+            "{}\n\
+            会话数：{}, 关注数：{}, 待通知评价数：{}, 被隐藏评价数：{}, 封禁用户数：{}, 数据库版本：{}",
+            self.db_status()?,
+            count("SELECT COUNT(*) FROM dialogues")?,
+            count("SELECT COUNT(*) FROM subscriptions")?,
+            count("SELECT COUNT(*) FROM comment_events")?,
+            count("SELECT COUNT(*) FROM comments WHERE tombstone IS NOT NULL")?,
+            count("SELECT COUNT(*) FROM banned_users")?,
+            self.schema_version()?,
+        ))
+    }
+}
+
+/// [`SAFCdb::check_integrity`] 的结果
+#[derive(Debug, Clone, Default)]
+pub struct IntegrityReport {
+    /// `PRAGMA integrity_check` 与 `PRAGMA foreign_key_check` 发现的问题
+    pub sqlite: Vec<String>,
+    /// 上级既不是客体也不是评价的评价，（评价 id，上级 id）
+    pub orphans: Vec<(String, String)>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.sqlite.is_empty() && self.orphans.is_empty()
+    }
+}
+
+impl fmt::Display for IntegrityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return writeln!(f, "✅ 数据库完整，没有孤立的评价");
+        }
+        for p in &self.sqlite {
+            writeln!(f, "- {p}")?;
+        }
+        for (id, object) in &self.orphans {
+            writeln!(f, "- 孤立的评价 {id}：上级 {object} 不存在")?;
+        }
+        writeln!(
+            f,
+            "❌ 数据库问题 {} 处，孤立的评价 {} 条",
+            self.sqlite.len(),
+            self.orphans.len()
+        )
+    }
+}
+
+/// [`SAFCdb::verify_signs`] 的结果
+#[derive(Debug, Clone, Default)]
+pub struct SignReport {
+    /// 检查的评价数
+    pub checked: usize,
+    /// 没有签名的评价数，如导入的评价
+    pub unsigned: usize,
+    /// 签名不是 64 位十六进制的评价
+    pub malformed: Vec<String>,
+    /// 给出 OTP 时，签名与之相符的评价
+    pub matched: Option<Vec<String>>,
+}
+
+impl fmt::Display for SignReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "检查评价 {} 条：有签名 {}，无签名 {}，签名格式错误 {}",
+            self.checked,
+            self.checked - self.unsigned,
+            self.unsigned,
+            self.malformed.len()
+        )?;
+        for id in &self.malformed {
+            writeln!(f, "- 签名格式错误：{id}")?;
+        }
+        if let Some(matched) = &self.matched {
+            writeln!(f, "与 OTP 相符 {} 条", matched.len())?;
+            for id in matched {
+                writeln!(f, "- {id}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SAFCdb {
+    /// 检查数据库文件与外键，并找出上级既不是客体也不是评价的评价
+    pub fn check_integrity(&self) -> Result<IntegrityReport> {
+        let conn = self.pool.clone().get()?;
+        let mut report = IntegrityReport::default();
+        let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+        for row in stmt.query_map([], |row| row.get::<_, String>(0))? {
+            let row = row?;
+            if row != "ok" {
+                report.sqlite.push(row);
+            }
+        }
+        let mut stmt = conn.prepare("PRAGMA foreign_key_check")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(format!(
+                "{} 第 {} 行引用的 {} 不存在",
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<i64>>(1)?.unwrap_or_default(),
+                row.get::<_, String>(2)?
+            ))
+        })?;
+        for row in rows {
+            report.sqlite.push(row?);
+        }
+        let mut stmt = conn.prepare(
+            "SELECT id, object FROM comments \
+            WHERE object NOT IN (SELECT object FROM subjects) \
+            AND object NOT IN (SELECT id FROM comments) ORDER BY rowid",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        report.orphans = rows.collect::<Result<Vec<_>, _>>()?;
+        Ok(report)
+    }
+
+    /// 检查评价的作者签名，`ids` 为空时检查全部评价
+    ///
+    /// 给出 `otp` 时列出签名与之相符的评价，用于确认发布人。评价不存在时返回 [`Error::NotFound`]
+    pub fn verify_signs(&self, ids: &[String], otp: Option<&str>) -> Result<SignReport> {
+        let comments: Vec<ObjComment> = if ids.is_empty() {
+            self.find_all_comments()?
+        } else {
+            ids.iter()
+                .map(|id| {
+                    self.find_comment_with_id(id)?
+                        .ok_or_else(|| Error::NotFound(format!("评价 {id}")))
+                })
+                .collect::<Result<_>>()?
+        };
+        let mut report = SignReport {
+            checked: comments.len(),
+            matched: otp.map(|_| vec![]),
+            ..Default::default()
+        };
+        for c in &comments {
+            let Some(sign) = c.author_sign.as_deref().filter(|s| !s.is_empty()) else {
+                report.unsigned += 1;
+                continue;
+            };
+            if sign.len() != 64 || !sign.bytes().all(|b| b.is_ascii_hexdigit()) {
+                report.malformed.push(c.id.clone());
+            }
+            if let (Some(otp), Some(matched)) = (otp, report.matched.as_mut()) {
+                if verify_author_sign(&c.id, &otp.to_string(), sign) {
+                    matched.push(c.id.clone());
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// 在事务 `tx` 中将客体 `from` 合并到 `into`，返回转移的评价数，见 [`SAFCdb::merge_objects`]
+pub(super) fn merge_in(tx: &Transaction, from: &str, into: &str) -> Result<usize> {
+    let n = tx.execute(
+        "UPDATE comments SET object = ?1 WHERE object = ?2",
+        params![into, from],
+    )?;
+    tx.execute(
+        "INSERT OR IGNORE INTO subscriptions (chat_id, object, date) \
+        SELECT chat_id, ?1, date FROM subscriptions WHERE object = ?2",
+        params![into, from],
+    )?;
+    // 剩余的关注随客体级联删除
+    tx.execute("DELETE FROM subjects WHERE object = ?", [from])?;
+    super::chain::append_merge(tx, from, into)?;
+    Ok(n)
+}
+
+#[test]
+fn test_admin() {
+    use super::*;
+    let db = temp_db();
+    db.add_audit_log(1, "ban", "42", Some("spam")).unwrap();
+    db.add_audit_log(1, "unban", "42", None).unwrap();
+    let log = db.find_audit_log(10).unwrap();
+    assert_eq!("unban", log[0].action);
+    assert_eq!(Some("spam".to_string()), log[1].detail);
+
+    assert!(!db.is_banned(42).unwrap());
+    assert!(db.ban_user(42, Some("spam")).unwrap());
+    assert!(!db.ban_user(42, None).unwrap());
+    assert!(db.is_banned(42).unwrap());
+    assert!(db.unban_user(42).unwrap());
+    assert!(!db.is_banned(42).unwrap());
+
+    db.update_dialogue(7, "\"Start\"").unwrap();
+    assert_eq!(vec![7], db.find_known_chats().unwrap());
+    assert!(db.admin_stats().unwrap().contains("会话数：1"));
+}
+
+#[test]
+fn test_merge_objects() {
+    use super::*;
+    let db = temp_db();
+    let new_object = |supervisor: &str| {
+        let t = ObjTeacher {
+            school_cate: "985".to_string(),
+            university: "清华大学".to_string(),
+            department: "self".to_string(),
+            supervisor: supervisor.to_string(),
+            date: get_current_date(),
+            info: None,
+            object_id: supervisor.to_string(),
+        };
+        db.add_object(&t).unwrap();
+    };
+    new_object("张三");
+    new_object("张三 ");
+    let c = ObjComment::new_with_otp(
+        "张三 ".to_string(),
+        "重复的客体".to_string(),
+        SourceCate::Web,
+        CommentType::Teacher,
+        "otp".to_string(),
+    );
+    db.add_comment(&c).unwrap();
+    db.subscribe(1, "张三 ").unwrap();
+    db.subscribe(2, "张三 ").unwrap();
+    db.subscribe(2, "张三").unwrap();
+
+    assert!(matches!(
+        db.merge_objects("张三", "张三"),
+        Err(Error::Validation(_))
+    ));
+    assert!(matches!(
+        db.merge_objects("nonexistent", "张三"),
+        Err(Error::NotFound(_))
+    ));
+    assert_eq!(1, db.merge_objects("张三 ", "张三").unwrap());
+    assert!(db.find_objteacher_with_id("张三 ").unwrap().is_none());
+    assert_eq!(c.id, db.find_comment(&"张三".to_string()).unwrap()[0].id);
+    assert_eq!(vec![1, 2], db.find_subscribers("张三").unwrap());
+    assert!(db.find_subscribers("张三 ").unwrap().is_empty());
+}
+
+#[test]
+fn test_check() {
+    use super::*;
+    use crate::service::*;
+    let db = temp_db();
+    let t = temp_object(&db);
+    let c = post_comment(&db, &t.object_id, "评价", SourceCate::Web, "otp").unwrap();
+    let n = reply_to_comment(&db, &c.id, "回复", SourceCate::Web, "otp2", None).unwrap();
+    assert!(db.check_integrity().unwrap().is_ok());
+
+    let conn = db.pool.get().unwrap();
+    conn.execute(
+        "INSERT INTO comments (object, description, date, source_cate, type, author_sign, id) \
+        VALUES ('missing', '孤立', '2023-09-01', 'admin', 'teacher', 'bad', 'orphan')",
+        [],
+    )
+    .unwrap();
+    let report = db.check_integrity().unwrap();
+    assert_eq!(
+        vec![("orphan".to_string(), "missing".to_string())],
+        report.orphans
+    );
+    assert!(report.to_string().contains("孤立的评价 1 条"));
+
+    let report = db.verify_signs(&[], None).unwrap();
+    assert_eq!(
+        (3, 0, vec!["orphan".to_string()]),
+        (report.checked, report.unsigned, report.malformed)
+    );
+    assert!(report.matched.is_none());
+    let report = db.verify_signs(&[], Some("otp")).unwrap();
+    assert_eq!(Some(vec![c.id.clone()]), report.matched);
+    let report = db
+        .verify_signs(std::slice::from_ref(&n.id), Some("otp"))
+        .unwrap();
+    assert_eq!((1, Some(vec![])), (report.checked, report.matched));
+    assert!(db.verify_signs(&["missing".to_string()], None).is_err());
+}
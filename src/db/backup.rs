@@ -0,0 +1,391 @@
+//! # backup
+//!
+//! 数据库备份
+//!
+//! 使用 sqlite 的在线备份 API 取得一致的快照（写入中的数据库也可备份），
+//! 再以 gzip 压缩，文件名为 `safc-<日期时间>.sqlite.gz`。
+//! 备份目录中只保留最新的若干份，恢复时解压后替换 db.sqlite 即可。
+//!
+//! 本地备份是完整的数据库；发到群里或供公开下载的是去掉了节点私有数据的快照（见 [`PRIVATE_TABLES`]、
+//! [`SAFCdb::public_snapshot`]），供下载的数据库见 [`SnapshotCache`]。
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use flate2::{write::GzEncoder, Compression};
+use rusqlite::{Connection, DatabaseName};
+use sha2::{Digest, Sha256};
+
+use super::SAFCdb;
+use crate::Result;
+
+const BACKUP_PREFIX: &str = "safc-";
+const BACKUP_SUFFIX: &str = ".sqlite.gz";
+
+/// 节点私有的表，公开的快照中清空：
+/// bot 会话、关注与通知队列、审计日志与封禁、限流、评价的修订历史（含被撤回、隐藏的原文）、同步的对端与来源
+pub const PRIVATE_TABLES: &[&str] = &[
+    "dialogues",
+    "subscriptions",
+    "comment_events",
+    "audit_log",
+    "banned_users",
+    "rate_limits",
+    "comment_revisions",
+    "sync_peers",
+    "sync_provenance",
+];
+
+/// 一份已完成的备份
+#[derive(Debug, Clone)]
+pub struct BackupFile {
+    pub path: PathBuf,
+    /// 压缩后文件的 sha256（hex）
+    pub sha256: String,
+    /// 压缩后文件的字节数
+    pub size: u64,
+}
+
+impl SAFCdb {
+    /// 以在线备份 API 将数据库快照写入 `dest`（未压缩的 sqlite 文件）
+    pub fn snapshot(&self, dest: &Path) -> Result<()> {
+        let conn = self.pool.clone().get()?;
+        conn.backup(DatabaseName::Main, dest, None)?;
+        Ok(())
+    }
+
+    /// 可以公开的快照：清空 [`PRIVATE_TABLES`]，并重建全文索引、整理文件，使删除的内容不留在空闲页中
+    ///
+    /// 快照的结构与版本号不变，可以直接作为新中心的数据库使用
+    pub fn public_snapshot(&self, dest: &Path) -> Result<()> {
+        self.snapshot(dest)?;
+        let mut conn = Connection::open(dest)?;
+        conn.pragma_update(None, "journal_mode", "DELETE")?;
+        let tx = conn.transaction()?;
+        for table in PRIVATE_TABLES {
+            tx.execute(&format!("DELETE FROM {table}"), [])?;
+        }
+        for fts in ["objects_fts", "comments_fts"] {
+            tx.execute(
+                &format!("INSERT INTO {fts} ({fts}) VALUES ('optimize')"),
+                [],
+            )?;
+        }
+        tx.commit()?;
+        conn.execute("VACUUM", [])?;
+        Ok(())
+    }
+
+    /// 在 `dir` 中建立一份完整的压缩备份，轮换旧备份见 [`rotate_backups`]
+    pub fn create_backup(&self, dir: &Path) -> Result<BackupFile> {
+        std::fs::create_dir_all(dir)?;
+        let name = chrono::Local::now().format("%Y%m%d-%H%M%S");
+        let path = dir.join(format!("{BACKUP_PREFIX}{name}{BACKUP_SUFFIX}"));
+        self.write_backup(&path, false)?;
+        let (sha256, size) = sha256_file(&path)?;
+        Ok(BackupFile { path, sha256, size })
+    }
+
+    /// 将可以公开的快照（见 [`SAFCdb::public_snapshot`]）压缩写入 `dest`，用于发到群里
+    pub fn create_public_backup(&self, dest: &Path) -> Result<BackupFile> {
+        self.write_backup(dest, true)?;
+        let (sha256, size) = sha256_file(dest)?;
+        Ok(BackupFile {
+            path: dest.to_path_buf(),
+            sha256,
+            size,
+        })
+    }
+
+    fn write_backup(&self, path: &Path, public: bool) -> Result<()> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = path.with_file_name(format!(".{name}.sqlite"));
+        let r = if public {
+            self.public_snapshot(&tmp)
+        } else {
+            self.snapshot(&tmp)
+        };
+        let r = r.and_then(|_| gzip_file(&tmp, path));
+        // 无论成功与否都删除未压缩的快照
+        std::fs::remove_file(&tmp).ok();
+        r
+    }
+}
+
+/// 供下载的数据库快照
+///
+/// 直接读取正在使用的数据库文件可能读到写了一半的内容，也会漏掉 WAL 中的数据，
+/// 所以下载的总是一份快照，并且是去掉了节点私有数据的公开快照（见 [`SAFCdb::public_snapshot`]）。
+/// 公开内容不变（见 [`SAFCdb::content_fingerprint`]）时复用同一份快照，会话、限流等私有数据的写入不会使快照失效，
+/// 这样同一份快照的 ETag 与 Last-Modified 也不变，镜像站可以低成本地轮询。
+///
+/// 每个 `SnapshotCache` 独占其目录（一般为 [`SAFCdb::snapshot_dir`]），目录中的旧快照会被删除，
+/// 重启后内容未变时沿用目录中已有的快照。
+#[derive(Clone)]
+pub struct SnapshotCache {
+    db: SAFCdb,
+    dir: PathBuf,
+    /// 当前快照对应的内容指纹与路径
+    current: Arc<Mutex<Option<(String, PathBuf)>>>,
+}
+
+impl SnapshotCache {
+    pub fn new(db: SAFCdb, dir: PathBuf) -> Self {
+        Self {
+            db,
+            dir,
+            current: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// 与当前数据库一致的快照文件，数据库变化后重新生成
+    ///
+    /// 生成快照是阻塞的文件操作
+    pub fn get(&self) -> Result<PathBuf> {
+        // 生成期间持有锁，并发的请求等待同一份快照
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        let fingerprint = self.db.content_fingerprint()?;
+        if let Some((f, path)) = current.as_ref() {
+            if *f == fingerprint && path.exists() {
+                return Ok(path.clone());
+            }
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        let name = hex::encode(&Sha256::digest(fingerprint.as_bytes())[..8]);
+        let path = self
+            .dir
+            .join(format!("{BACKUP_PREFIX}snapshot-{name}.sqlite"));
+        // 先写到临时文件再改名，目录中已有的快照都是完整的
+        if !path.exists() {
+            let tmp = self
+                .dir
+                .join(format!(".{BACKUP_PREFIX}snapshot-{name}.sqlite"));
+            let r = self
+                .db
+                .public_snapshot(&tmp)
+                .and_then(|_| Ok(std::fs::rename(&tmp, &path)?));
+            if r.is_err() {
+                std::fs::remove_file(&tmp).ok();
+            }
+            r?;
+        }
+        // 正在下载旧快照的请求已打开文件，删除不影响其读取
+        for entry in std::fs::read_dir(&self.dir)? {
+            let old = entry?.path();
+            let is_snapshot = old
+                .file_name()
+                .and_then(|s| s.to_str())
+                .is_some_and(|s| s.starts_with(&format!("{BACKUP_PREFIX}snapshot-")));
+            if is_snapshot && old != path {
+                std::fs::remove_file(old).ok();
+            }
+        }
+        *current = Some((fingerprint, path.clone()));
+        Ok(path)
+    }
+}
+
+impl SAFCdb {
+    /// 公开内容的指纹：哈希链的最后一条记录
+    ///
+    /// 本程序对客体与评价的每次修改都会记入哈希链（见 [`super::chain`]），私有数据的写入则不会，
+    /// 所以链头不变即公开内容不变；不经本程序直接改写数据库的内容不会被察觉
+    pub fn content_fingerprint(&self) -> Result<String> {
+        Ok(match self.chain_head()? {
+            Some(h) => format!("{}:{}", h.seq, h.hash),
+            None => "genesis".to_string(),
+        })
+    }
+
+    /// 名为 `name` 的 [`SnapshotCache`] 使用的目录：数据库文件旁的 `<数据库文件名>-snapshots/<name>`
+    ///
+    /// 与数据库放在一起，不同的数据库、不同的用户不会共用同一个目录
+    pub fn snapshot_dir(&self, name: &str) -> PathBuf {
+        PathBuf::from(format!("{}-snapshots", self.db_path)).join(name)
+    }
+}
+
+/// 只保留 `dir` 中最新的 `keep` 份备份，返回删除的份数
+pub fn rotate_backups(dir: &Path, keep: usize) -> Result<usize> {
+    let mut backups = list_backups(dir)?;
+    let n = backups.len().saturating_sub(keep);
+    for path in backups.drain(..n) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(n)
+}
+
+/// `dir` 中最新一份备份的修改时间，没有备份（或目录不存在）时为 `None`
+pub fn latest_backup_time(dir: &Path) -> Result<Option<std::time::SystemTime>> {
+    if !dir.exists() {
+        return Ok(None);
+    }
+    match list_backups(dir)?.last() {
+        Some(path) => Ok(Some(std::fs::metadata(path)?.modified()?)),
+        None => Ok(None),
+    }
+}
+
+/// `dir` 中的备份，旧的在前
+pub fn list_backups(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut backups = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_backup = path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .is_some_and(|s| s.starts_with(BACKUP_PREFIX) && s.ends_with(BACKUP_SUFFIX));
+        if is_backup {
+            backups.push(path);
+        }
+    }
+    // 文件名中的日期时间可以直接按字典序排序
+    backups.sort();
+    Ok(backups)
+}
+
+fn gzip_file(src: &Path, dest: &Path) -> Result<()> {
+    let mut reader = BufReader::new(File::open(src)?);
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(dest)?), Compression::default());
+    std::io::copy(&mut reader, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    Ok(())
+}
+
+/// 文件的 sha256（hex）与字节数
+pub fn sha256_file(path: &Path) -> Result<(String, u64)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 8192];
+    let mut size = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((hex::encode(hasher.finalize()), size))
+}
+
+#[test]
+fn test_backup() {
+    use super::*;
+    use flate2::read::GzDecoder;
+    let db = temp_db();
+    let t = temp_object(&db);
+
+    let dir = std::env::temp_dir().join(format!("safc_backup_{:016x}", rand::random::<u64>()));
+    let b = db.create_backup(&dir).unwrap();
+    assert_eq!((b.sha256.clone(), b.size), sha256_file(&b.path).unwrap());
+
+    // 解压后是可以直接打开的数据库
+    let restored = dir.join("restored.sqlite");
+    let mut decoder = GzDecoder::new(File::open(&b.path).unwrap());
+    std::io::copy(&mut decoder, &mut File::create(&restored).unwrap()).unwrap();
+    let restored = SAFCdb::new_with_path(restored.to_string_lossy().into_owned());
+    assert!(restored
+        .find_objteacher_with_id(&t.object_id)
+        .unwrap()
+        .is_some());
+
+    for name in ["20000101-000000", "20000102-000000"] {
+        File::create(dir.join(format!("{BACKUP_PREFIX}{name}{BACKUP_SUFFIX}"))).unwrap();
+    }
+    assert_eq!(3, list_backups(&dir).unwrap().len());
+    assert_eq!(2, rotate_backups(&dir, 1).unwrap());
+    assert_eq!(vec![b.path], list_backups(&dir).unwrap());
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_snapshot_cache() {
+    use super::*;
+    let db = temp_db();
+    let dir = std::env::temp_dir().join(format!("safc_snapshot_{:016x}", rand::random::<u64>()));
+    let cache = SnapshotCache::new(db.clone(), dir.clone());
+    let first = cache.get().unwrap();
+    // 数据库未变化时复用快照
+    assert_eq!(first, cache.get().unwrap());
+
+    let t = temp_object(&db);
+    let second = cache.get().unwrap();
+    assert_ne!(first, second);
+    assert!(!first.exists());
+    let snapshot = SAFCdb::new_with_path(second.to_string_lossy().into_owned());
+    assert!(snapshot
+        .find_objteacher_with_id(&t.object_id)
+        .unwrap()
+        .is_some());
+    // 私有数据的写入不使快照失效，私有数据也不在快照中
+    db.subscribe(1, &t.object_id).unwrap();
+    assert_eq!(second, cache.get().unwrap());
+    assert!(snapshot.find_subscriptions(1).unwrap().is_empty());
+
+    // 重启后沿用目录中已有的快照
+    let cache = SnapshotCache::new(db.clone(), dir.clone());
+    assert_eq!(second, cache.get().unwrap());
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_public_snapshot() {
+    use super::*;
+    let db = temp_db();
+    let t = temp_object(&db);
+    let c =
+        crate::service::post_comment(&db, &t.object_id, "评价", SourceCate::Web, "otp").unwrap();
+    crate::service::edit_comment(&db, &c.id, "改稿", "otp").unwrap();
+    db.subscribe(1, &t.object_id).unwrap();
+    db.update_dialogue(1, "\"half-written-secret\"").unwrap();
+    db.add_audit_log(1, "ban", "42", Some("ban-reason-secret"))
+        .unwrap();
+    db.ban_user(42, None).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("safc_public_{:016x}", rand::random::<u64>()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("public.sqlite");
+    db.public_snapshot(&path).unwrap();
+    let conn = Connection::open(&path).unwrap();
+    for table in PRIVATE_TABLES {
+        let n: i64 = conn
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(0, n, "{table}");
+    }
+    drop(conn);
+    // 删除的内容不留在文件中
+    let bytes = std::fs::read(&path).unwrap();
+    for secret in ["half-written-secret", "ban-reason-secret"] {
+        assert!(!bytes.windows(secret.len()).any(|w| w == secret.as_bytes()));
+    }
+    let public = SAFCdb::new_with_path(path.to_string_lossy().into_owned());
+    assert_eq!(
+        "改稿",
+        public
+            .find_comment_with_id(&c.id)
+            .unwrap()
+            .unwrap()
+            .description
+    );
+    assert_eq!(
+        1,
+        public
+            .search("改稿", SearchKind::Comment, 10)
+            .unwrap()
+            .len()
+    );
+
+    // 压缩的公开备份；原数据库不受影响
+    let b = db
+        .create_public_backup(&dir.join("public.sqlite.gz"))
+        .unwrap();
+    assert_eq!((b.sha256.clone(), b.size), sha256_file(&b.path).unwrap());
+    assert_eq!(1, db.find_subscriptions(1).unwrap().len());
+    std::fs::remove_dir_all(&dir).ok();
+}
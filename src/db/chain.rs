@@ -0,0 +1,618 @@
+//! # chain
+//!
+//! 客体与评价的哈希链日志，用于发现对历史数据的篡改
+//!
+//! 每次新增客体、新增评价，以及修改、撤回、隐藏、恢复评价、合并与修改客体、同步评价，都在同一个事务中
+//! 向 chain_log 表（见 [`super::migrate`] v11）追加一条记录：
+//!
+//! 序号 seq (key) - 操作 op - 对象 target - 内容 payload（json）- prev_hash - hash
+//!
+//! hash 的算法见 [`crate::sec::hash_chain_entry`]，第一条记录的 prev_hash 为
+//! [`crate::sec::CHAIN_GENESIS_HASH`]。chain_log 只能追加，触发器禁止修改与删除。
+//! 升级到 v11 时已有的客体与评价按原顺序补录为链的开头。
+//!
+//! 评价的每条记录都是操作后该评价的 [`ChainCommentState`]，正文与评星只记录哈希，
+//! 撤回或隐藏的原文不会随链留在数据库副本与镜像中。
+//!
+//! 拿到一份数据库副本的节点用 [`SAFCdb::verify_chain`] 检查：链本身的哈希与链接是否完整，
+//! 以及按链重放得到的每个客体、每条评价是否仍在表中且内容一致，
+//! 所以悄悄修改或删除历史评价都会被发现。再与可信来源公布的链头（见 [`SAFCdb::db_status`]）比对，
+//! 即可确认链本身没有被整体重写。不经本程序直接写入数据库的行（如脚本）不在链中，报告为未入链。
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+use super::{ObjComment, ObjTeacher, Ratings, SAFCdb};
+use crate::sec::{hash_chain_entry, hash_comment_content, CHAIN_GENESIS_HASH};
+use crate::Result;
+
+/// 报告中最多列出的问题数
+const MAX_REPORTED_PROBLEMS: usize = 50;
+
+/// 链上记录的操作
+#[derive(Debug, EnumString, Display, PartialEq, Clone, Copy)]
+#[strum(serialize_all = "snake_case")]
+pub enum ChainOp {
+    /// 新增客体，内容为 [`ChainObject`]
+    AddObject,
+    /// 新增评价，内容为 [`ChainCommentState`]
+    AddComment,
+    /// 修改评价正文，内容为修改后的 [`ChainCommentState`]
+    EditComment,
+    /// 撤回或隐藏评价，内容为撤回或隐藏后的 [`ChainCommentState`]，评星随之清空
+    TombstoneComment,
+    /// 恢复评价，内容为恢复后的 [`ChainCommentState`]
+    RestoreComment,
+    /// 合并客体，内容为 `{from, into}`
+    MergeObjects,
+    /// 从其他节点同步评价的新状态，内容为 [`ChainCommentState`]，见 [`super::sync`]
+    SyncComment,
+    /// 合并数据库或同步时修改客体的可变字段，内容为 [`ChainObject`]，见 [`super::merge`]
+    UpdateObject,
+}
+
+/// 链上记录的客体，字段与数据库中的值一致
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainObject {
+    pub school_cate: String,
+    pub university: String,
+    pub department: String,
+    pub supervisor: String,
+    pub date: String,
+    pub info: Option<String>,
+    pub object: String,
+}
+
+/// 链上记录的评价，字段与数据库中的值一致（枚举为其在数据库中的字符串）
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainComment {
+    pub object: String,
+    pub description: String,
+    pub date: String,
+    pub source_cate: String,
+    #[serde(rename = "type")]
+    pub comment_type: String,
+    pub author_sign: Option<String>,
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tombstone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tombstone_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tombstone_date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ratings: Option<Ratings>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited: Option<String>,
+}
+
+/// 链上记录的评价状态，字段与 [`ChainComment`] 相同，但正文与评星只记录二者的哈希
+/// content（见 [`crate::sec::hash_comment_content`]）
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainCommentState {
+    pub object: String,
+    pub date: String,
+    pub source_cate: String,
+    #[serde(rename = "type")]
+    pub comment_type: String,
+    pub author_sign: Option<String>,
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tombstone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tombstone_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tombstone_date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited: Option<String>,
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct Merge {
+    pub(super) from: String,
+    pub(super) into: String,
+}
+
+/// 查询 [`ChainObject`] 使用的列
+pub(super) const CHAIN_OBJECT_SQL: &str =
+    "SELECT school_cate, university, department, supervisor, date, info, object FROM objects";
+
+/// 查询 [`ChainComment`] 使用的列
+pub(super) const CHAIN_COMMENT_SQL: &str =
+    "SELECT object, description, date, source_cate, type, author_sign, id, \
+    tombstone, tombstone_reason, tombstone_date, \
+    rating_academic, rating_funding, rating_relationship, rating_prospects, rating_stipend, \
+    edited FROM comments";
+
+impl ChainObject {
+    pub(super) fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            school_cate: row.get(0)?,
+            university: row.get(1)?,
+            department: row.get(2)?,
+            supervisor: row.get(3)?,
+            date: row.get(4)?,
+            info: row.get(5)?,
+            object: row.get(6)?,
+        })
+    }
+
+    /// 层级与 id 是否一致；日期与信息可以修改，不比较
+    pub(super) fn same_identity(&self, other: &Self) -> bool {
+        (
+            &self.school_cate,
+            &self.university,
+            &self.department,
+            &self.supervisor,
+        ) == (
+            &other.school_cate,
+            &other.university,
+            &other.department,
+            &other.supervisor,
+        )
+    }
+}
+
+impl From<&ObjTeacher> for ChainObject {
+    fn from(t: &ObjTeacher) -> Self {
+        Self {
+            school_cate: t.school_cate.clone(),
+            university: t.university.clone(),
+            department: t.department.clone(),
+            supervisor: t.supervisor.clone(),
+            date: t.date.clone(),
+            info: t.info.clone(),
+            object: t.object_id.clone(),
+        }
+    }
+}
+
+impl ChainComment {
+    pub(super) fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let ratings = Ratings {
+            academic: row.get(10)?,
+            funding: row.get(11)?,
+            relationship: row.get(12)?,
+            prospects: row.get(13)?,
+            stipend: row.get(14)?,
+        };
+        Ok(Self {
+            object: row.get(0)?,
+            description: row.get(1)?,
+            date: row.get(2)?,
+            source_cate: row.get(3)?,
+            comment_type: row.get(4)?,
+            author_sign: row.get(5)?,
+            id: row.get(6)?,
+            tombstone: row.get(7)?,
+            tombstone_reason: row.get(8)?,
+            tombstone_date: row.get(9)?,
+            ratings: (!ratings.is_empty()).then_some(ratings),
+            edited: row.get(15)?,
+        })
+    }
+}
+
+impl From<&ChainComment> for ChainCommentState {
+    fn from(c: &ChainComment) -> Self {
+        Self {
+            object: c.object.clone(),
+            date: c.date.clone(),
+            source_cate: c.source_cate.clone(),
+            comment_type: c.comment_type.clone(),
+            author_sign: c.author_sign.clone(),
+            id: c.id.clone(),
+            tombstone: c.tombstone.clone(),
+            tombstone_reason: c.tombstone_reason.clone(),
+            tombstone_date: c.tombstone_date.clone(),
+            edited: c.edited.clone(),
+            content: hash_comment_content(&c.description, c.ratings.as_ref()),
+        }
+    }
+}
+
+impl From<&ObjComment> for ChainComment {
+    fn from(c: &ObjComment) -> Self {
+        Self {
+            object: c.object.clone(),
+            description: c.description.clone(),
+            date: c.date.clone(),
+            source_cate: c.source_cate.to_string(),
+            comment_type: c.comment_type.to_string(),
+            author_sign: c.author_sign.clone(),
+            id: c.id.clone(),
+            tombstone: c.tombstone.as_ref().map(|t| t.kind.to_string()),
+            tombstone_reason: c.tombstone.as_ref().and_then(|t| t.reason.clone()),
+            tombstone_date: c.tombstone.as_ref().map(|t| t.date.clone()),
+            // 未评任何一项的评星存为 NULL
+            ratings: c.ratings.filter(|r| !r.is_empty()),
+            edited: c.edited.clone(),
+        }
+    }
+}
+
+/// 链头：最后一条记录
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChainHead {
+    pub seq: i64,
+    pub hash: String,
+}
+
+impl fmt::Display for ChainHead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} {}", self.seq, self.hash)
+    }
+}
+
+/// 在 `conn` 的事务中追加一条记录，调用方应使用 `BEGIN IMMEDIATE` 以免两个进程同时追加
+fn append(
+    conn: &Connection,
+    op: ChainOp,
+    target: &str,
+    payload: &impl Serialize,
+) -> rusqlite::Result<()> {
+    let payload = serde_json::to_string(payload).expect("链上记录可以序列化");
+    append_raw(conn, &op.to_string(), target, &payload)
+}
+
+/// 追加一条内容已序列化的记录
+fn append_raw(conn: &Connection, op: &str, target: &str, payload: &str) -> rusqlite::Result<()> {
+    let (seq, prev_hash) = match head(conn)? {
+        Some(h) => (h.seq + 1, h.hash),
+        None => (1, CHAIN_GENESIS_HASH.to_string()),
+    };
+    let hash = hash_chain_entry(&prev_hash, seq, op, payload);
+    conn.execute(
+        "INSERT INTO chain_log (seq, op, target, payload, prev_hash, hash) \
+        VALUES (?, ?, ?, ?, ?, ?)",
+        params![seq, op, target, payload, prev_hash, hash],
+    )?;
+    Ok(())
+}
+
+pub(super) fn head(conn: &Connection) -> rusqlite::Result<Option<ChainHead>> {
+    conn.query_row(
+        "SELECT seq, hash FROM chain_log ORDER BY seq DESC LIMIT 1",
+        [],
+        |row| {
+            Ok(ChainHead {
+                seq: row.get(0)?,
+                hash: row.get(1)?,
+            })
+        },
+    )
+    .optional()
+}
+
+pub(super) fn append_object(conn: &Connection, t: &ObjTeacher) -> rusqlite::Result<()> {
+    append(
+        conn,
+        ChainOp::AddObject,
+        &t.object_id,
+        &ChainObject::from(t),
+    )
+}
+
+/// 评价的 `op` 操作后，追加该评价在表中的当前状态
+pub(super) fn append_comment(conn: &Connection, op: ChainOp, id: &str) -> rusqlite::Result<()> {
+    let c = conn.query_row(
+        &format!("{CHAIN_COMMENT_SQL} WHERE id = ?"),
+        [id],
+        ChainComment::from_row,
+    )?;
+    append(conn, op, id, &ChainCommentState::from(&c))
+}
+
+pub(super) fn append_update_object(conn: &Connection, o: &ChainObject) -> rusqlite::Result<()> {
+    append(conn, ChainOp::UpdateObject, &o.object, o)
+}
+
+pub(super) fn append_merge(conn: &Connection, from: &str, into: &str) -> rusqlite::Result<()> {
+    let payload = Merge {
+        from: from.to_string(),
+        into: into.to_string(),
+    };
+    append(conn, ChainOp::MergeObjects, from, &payload)
+}
+
+/// 将已有的客体与评价补录到空的链中，供迁移使用
+pub(super) fn backfill(conn: &Connection) -> rusqlite::Result<usize> {
+    let mut n = 0;
+    let mut stmt = conn.prepare(&format!("{CHAIN_OBJECT_SQL} ORDER BY date, object"))?;
+    let objects = stmt
+        .query_map([], ChainObject::from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for o in objects {
+        append(conn, ChainOp::AddObject, &o.object, &o)?;
+        n += 1;
+    }
+    // 升级到 v11 时还没有 edited 列（v12），补录的评价都视为未修改过
+    let sql = CHAIN_COMMENT_SQL.replace(", edited FROM", ", NULL FROM");
+    let mut stmt = conn.prepare(&format!("{sql} ORDER BY rowid"))?;
+    let comments = stmt
+        .query_map([], ChainComment::from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for c in comments {
+        append(
+            conn,
+            ChainOp::AddComment,
+            &c.id,
+            &ChainCommentState::from(&c),
+        )?;
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// [`SAFCdb::verify_chain`] 的结果
+#[derive(Debug, Clone, Default)]
+pub struct ChainReport {
+    /// 链上的记录数
+    pub entries: usize,
+    pub head: Option<ChainHead>,
+    /// 链不完整、或数据与链不符之处
+    pub problems: Vec<String>,
+    /// 不在链中的客体数
+    pub unchained_objects: usize,
+    /// 不在链中的评价数
+    pub unchained_comments: usize,
+}
+
+impl ChainReport {
+    /// 链完整，且链上的客体与评价都未被修改或删除
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for ChainReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.head {
+            Some(h) => writeln!(f, "链头：{h}，共 {} 条记录", self.entries)?,
+            None => writeln!(f, "链为空")?,
+        }
+        if self.unchained_objects + self.unchained_comments > 0 {
+            writeln!(
+                f,
+                "未入链：客体 {}，评价 {}",
+                self.unchained_objects, self.unchained_comments
+            )?;
+        }
+        if self.is_valid() {
+            return writeln!(f, "✅ 链完整，数据与链一致");
+        }
+        writeln!(f, "❌ 发现 {} 处问题：", self.problems.len())?;
+        for p in self.problems.iter().take(MAX_REPORTED_PROBLEMS) {
+            writeln!(f, "- {p}")?;
+        }
+        if self.problems.len() > MAX_REPORTED_PROBLEMS {
+            writeln!(f, "- ……")?;
+        }
+        Ok(())
+    }
+}
+
+/// 重放链得到的状态
+#[derive(Default)]
+struct Replay {
+    objects: HashMap<String, ChainObject>,
+    comments: HashMap<String, ChainCommentState>,
+}
+
+/// 解析记录的内容
+fn parse<'a, T: Deserialize<'a>>(payload: &'a str) -> std::result::Result<T, String> {
+    serde_json::from_str(payload).map_err(|e| format!("内容无法解析：{e}"))
+}
+
+impl Replay {
+    /// 应用一条记录，内容无法解析或引用了不存在的评价时返回说明
+    fn apply(&mut self, op: &str, payload: &str) -> std::result::Result<(), String> {
+        let op: ChainOp = op.parse().map_err(|_| format!("未知的操作 {op}"))?;
+        match op {
+            ChainOp::AddObject | ChainOp::UpdateObject => {
+                let o: ChainObject = parse(payload)?;
+                self.objects.insert(o.object.clone(), o);
+            }
+            ChainOp::AddComment | ChainOp::SyncComment => {
+                let c: ChainCommentState = parse(payload)?;
+                self.comments.insert(c.id.clone(), c);
+            }
+            ChainOp::EditComment | ChainOp::TombstoneComment | ChainOp::RestoreComment => {
+                let c: ChainCommentState = parse(payload)?;
+                if !self.comments.contains_key(&c.id) {
+                    return Err(format!("引用了链上不存在的评价 {}", c.id));
+                }
+                self.comments.insert(c.id.clone(), c);
+            }
+            ChainOp::MergeObjects => {
+                let m: Merge = parse(payload)?;
+                self.objects.remove(&m.from);
+                for c in self.comments.values_mut().filter(|c| c.object == m.from) {
+                    c.object = m.into.clone();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SAFCdb {
+    /// 链头，链为空时为 `None`
+    pub fn chain_head(&self) -> Result<Option<ChainHead>> {
+        let conn = self.pool.clone().get()?;
+        Ok(head(&conn)?)
+    }
+
+    /// 校验哈希链，并按链重放，检查客体与评价是否仍与链一致
+    ///
+    /// 校验本身出错（如数据库无法读取）时返回错误；发现的问题记在 [`ChainReport::problems`] 中
+    pub fn verify_chain(&self) -> Result<ChainReport> {
+        let conn = self.pool.clone().get()?;
+        let mut report = ChainReport::default();
+        let mut replay = Replay::default();
+
+        let mut stmt =
+            conn.prepare("SELECT seq, op, payload, prev_hash, hash FROM chain_log ORDER BY seq")?;
+        let mut rows = stmt.query([])?;
+        let mut prev = ChainHead {
+            seq: 0,
+            hash: CHAIN_GENESIS_HASH.to_string(),
+        };
+        while let Some(row) = rows.next()? {
+            let (seq, op, payload, prev_hash, hash): (i64, String, String, String, String) = (
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            );
+            if seq != prev.seq + 1 {
+                report
+                    .problems
+                    .push(format!("第 {} 到 {} 条记录缺失", prev.seq + 1, seq - 1));
+            }
+            if prev_hash != prev.hash {
+                report
+                    .problems
+                    .push(format!("第 {seq} 条记录的 prev_hash 与上一条不符"));
+            }
+            if hash != hash_chain_entry(&prev_hash, seq, &op, &payload) {
+                report.problems.push(format!("第 {seq} 条记录的哈希不符"));
+            }
+            if let Err(e) = replay.apply(&op, &payload) {
+                report.problems.push(format!("第 {seq} 条记录：{e}"));
+            }
+            report.entries += 1;
+            prev = ChainHead { seq, hash };
+        }
+        report.head = (prev.seq > 0).then_some(prev);
+
+        let mut stmt = conn.prepare(CHAIN_OBJECT_SQL)?;
+        let objects = stmt
+            .query_map([], ChainObject::from_row)?
+            .map(|o| o.map(|o| (o.object.clone(), o)))
+            .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+        let mut stmt = conn.prepare(CHAIN_COMMENT_SQL)?;
+        let comments = stmt
+            .query_map([], ChainComment::from_row)?
+            .map(|c| c.map(|c| (c.id.clone(), ChainCommentState::from(&c))))
+            .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+
+        let mut chained: Vec<_> = replay.objects.values().collect();
+        chained.sort_by(|a, b| a.object.cmp(&b.object));
+        for o in chained {
+            match objects.get(&o.object) {
+                None => report.problems.push(format!("客体 {} 被删除", o.object)),
+                Some(db) if !db.same_identity(o) => {
+                    report.problems.push(format!("客体 {} 被修改", o.object))
+                }
+                Some(_) => {}
+            }
+        }
+        let mut chained: Vec<_> = replay.comments.values().collect();
+        chained.sort_by(|a, b| a.id.cmp(&b.id));
+        for c in chained {
+            match comments.get(&c.id) {
+                None => report.problems.push(format!("评价 {} 被删除", c.id)),
+                Some(db) if db != c => report.problems.push(format!("评价 {} 被修改", c.id)),
+                Some(_) => {}
+            }
+        }
+        let chained_objects: HashSet<_> = replay.objects.keys().collect();
+        report.unchained_objects = objects
+            .keys()
+            .filter(|k| !chained_objects.contains(k))
+            .count();
+        report.unchained_comments = comments
+            .keys()
+            .filter(|k| !replay.comments.contains_key(*k))
+            .count();
+        Ok(report)
+    }
+}
+
+#[test]
+fn test_chain() {
+    use crate::db::{temp_db, temp_object, SourceCate};
+    use crate::service::*;
+
+    let db = temp_db();
+    assert!(db.chain_head().unwrap().is_none());
+    let t = temp_object(&db);
+    let t2 = create_object(&db, "985", "清华大学", "self", "张三三").unwrap();
+    let c = post_comment(&db, &t.object_id, "初稿", SourceCate::Web, "otp").unwrap();
+    let r = reply_to_comment(&db, &c.id, "回复", SourceCate::Web, "otp", None).unwrap();
+    edit_comment(&db, &c.id, "改稿", "otp").unwrap();
+    moderate_comment(&db, &r.id, Some("广告")).unwrap();
+    restore_comment(&db, &r.id).unwrap();
+    retract_comment(&db, &c.id, "otp").unwrap();
+    db.merge_objects(&t2.object_id, &t.object_id).unwrap();
+
+    let report = db.verify_chain().unwrap();
+    assert!(report.is_valid(), "{report}");
+    assert_eq!(9, report.entries);
+    assert_eq!(report.head, db.chain_head().unwrap());
+    assert!(db
+        .db_status()
+        .unwrap()
+        .contains(&report.head.unwrap().hash[..16]));
+
+    // 链只能追加
+    let conn = db.pool.get().unwrap();
+    assert!(conn
+        .execute("UPDATE chain_log SET payload = '{}' WHERE seq = 1", [])
+        .is_err());
+    assert!(conn.execute("DELETE FROM chain_log", []).is_err());
+
+    // 悄悄修改、删除评价，或绕过程序写入，都会被发现
+    conn.execute(
+        "UPDATE comments SET description = '被篡改' WHERE id = ?",
+        [&r.id],
+    )
+    .unwrap();
+    conn.execute("DELETE FROM comments WHERE id = ?", [&c.id])
+        .unwrap();
+    conn.execute(
+        "INSERT INTO comments (object, description, date, source_cate, type, id) \
+        VALUES (?, '脚本写入', '2023-09-01', 'admin', 'teacher', 'x')",
+        [&t.object_id],
+    )
+    .unwrap();
+    let report = db.verify_chain().unwrap();
+    assert_eq!(
+        vec![
+            format!("评价 {} 被删除", c.id),
+            format!("评价 {} 被修改", r.id)
+        ]
+        .into_iter()
+        .collect::<HashSet<_>>(),
+        report.problems.iter().cloned().collect::<HashSet<_>>()
+    );
+    assert_eq!(1, report.unchained_comments);
+
+    // 链中没有评价的原文
+    let leaked: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM chain_log WHERE payload LIKE '%稿%'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(0, leaked);
+
+    // 篡改链本身
+    conn.execute_batch(
+        "DROP TRIGGER chain_log_no_update; \
+        UPDATE chain_log SET payload = replace(payload, '\"web\"', '\"admin\"') WHERE seq = 3;",
+    )
+    .unwrap();
+    let report = db.verify_chain().unwrap();
+    assert!(report
+        .problems
+        .contains(&"第 3 条记录的哈希不符".to_string()));
+}
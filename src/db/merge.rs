@@ -0,0 +1,337 @@
+//! # merge
+//!
+//! 合并两个数据库
+//!
+//! 离线合并（`safc_admin merge`，见 [`merge_files`]）与节点间同步（见 [`super::sync`]）使用同一套逻辑：
+//! 客体与评价按 id 取并集，同一 id 在两边不同时按 [`MergePolicy`] 取舍，客体的不同字段记为 [`FieldConflict`]。
+//!
+//! 客体的学校、学院、导师参与 id 的计算，不会冲突；可能冲突的是学校类别、日期与信息。
+//! 修改学校类别时客体移到新类别下的同名学校中（见 [`super::migrate`] v13）。
+//! 学校类别的取舍没有客观的规则，[`MergePolicy::Converge`] 只报告冲突，不修改，留给管理员处理。
+
+use std::fmt;
+use std::path::Path;
+
+use rusqlite::{Connection, DatabaseName, OpenFlags};
+use serde::Serialize;
+use strum_macros::{Display, EnumString};
+
+use super::chain::ChainObject;
+use super::sync::{PeerTrust, SyncComment, SyncRecord, SyncReport};
+use super::SAFCdb;
+use crate::{Error, Result};
+
+/// 同一 id 在两边不同时的取舍
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum MergePolicy {
+    /// 与同步相同的确定规则，不论合并的方向结果都一样：
+    /// 学校类别保留本地的值（只报告冲突），日期取较早的，信息取非空的、都非空时取字典序较大的；评价见 [`super::sync`]
+    #[default]
+    Converge,
+    /// 保留本地（合并时为第一个数据库）的值
+    Ours,
+    /// 采用对端（合并时为第二个数据库）的值
+    Theirs,
+}
+
+/// 同一客体在两边不同的字段
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldConflict {
+    pub object: String,
+    /// school_cate、date 或 info
+    pub field: &'static str,
+    pub ours: Option<String>,
+    pub theirs: Option<String>,
+    /// 按策略取的值
+    pub chosen: Option<String>,
+}
+
+impl fmt::Display for FieldConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |v: &Option<String>| v.clone().unwrap_or_else(|| "（空）".to_string());
+        write!(
+            f,
+            "客体 {} 的 {}：本地 {}，对端 {}，取 {}",
+            self.object,
+            self.field,
+            show(&self.ours),
+            show(&self.theirs),
+            show(&self.chosen)
+        )
+    }
+}
+
+impl MergePolicy {
+    /// 合并同一客体两边的可变字段，返回合并后的客体与不同的字段
+    pub fn resolve_object(
+        &self,
+        ours: &ChainObject,
+        theirs: &ChainObject,
+    ) -> (ChainObject, Vec<FieldConflict>) {
+        let mut merged = ours.clone();
+        let mut conflicts = vec![];
+        let mut field = |name: &'static str, a: Option<String>, b: Option<String>| {
+            if a == b {
+                return a;
+            }
+            let chosen = match self {
+                Self::Ours => a.clone(),
+                Self::Theirs => b.clone(),
+                Self::Converge => match name {
+                    // 按字符串顺序取学校类别没有意义，会把整个学校移到错误的类别下
+                    "school_cate" => a.clone(),
+                    "info" => match (&a, &b) {
+                        (Some(x), Some(y)) => Some(x.max(y).clone()),
+                        (x, y) => x.clone().or(y.clone()),
+                    },
+                    _ => a.clone().min(b.clone()),
+                },
+            };
+            conflicts.push(FieldConflict {
+                object: ours.object.clone(),
+                field: name,
+                ours: a,
+                theirs: b,
+                chosen: chosen.clone(),
+            });
+            chosen
+        };
+        merged.school_cate = field(
+            "school_cate",
+            Some(ours.school_cate.clone()),
+            Some(theirs.school_cate.clone()),
+        )
+        .unwrap_or_default();
+        merged.date =
+            field("date", Some(ours.date.clone()), Some(theirs.date.clone())).unwrap_or_default();
+        merged.info = field("info", ours.info.clone(), theirs.info.clone());
+        (merged, conflicts)
+    }
+
+    /// 同一评价两边不同时是否采用对端的版本
+    pub(super) fn prefer_theirs(&self, ours: &SyncComment, theirs: &SyncComment) -> bool {
+        match self {
+            Self::Ours => false,
+            Self::Theirs => true,
+            Self::Converge => theirs.precedence() > ours.precedence(),
+        }
+    }
+}
+
+/// [`merge_files`] 的结果
+#[derive(Debug, Clone)]
+pub struct MergeReport {
+    pub policy: MergePolicy,
+    /// 本地、对端与合并后的（客体数，评价数）
+    pub ours: (usize, usize),
+    pub theirs: (usize, usize),
+    pub merged: (usize, usize),
+    /// 将对端写入合并结果的详情
+    pub sync: SyncReport,
+}
+
+impl fmt::Display for MergeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = &self.sync;
+        writeln!(f, "本地：客体 {}，评价 {}", self.ours.0, self.ours.1)?;
+        writeln!(f, "对端：客体 {}，评价 {}", self.theirs.0, self.theirs.1)?;
+        writeln!(f, "合并后：客体 {}，评价 {}", self.merged.0, self.merged.1)?;
+        writeln!(
+            f,
+            "对端新增：客体 {}，评价 {}；对端合并客体 {}",
+            s.objects_inserted, s.comments_inserted, s.merges
+        )?;
+        writeln!(
+            f,
+            "两边不同（策略 {}）：客体 {} 处字段，评价 {}（采用对端 {}，保留本地 {}）",
+            self.policy,
+            s.conflicts.len(),
+            s.comments_updated + s.comments_kept,
+            s.comments_updated,
+            s.comments_kept
+        )?;
+        for c in &s.conflicts {
+            writeln!(f, "- {c}")?;
+        }
+        for p in &s.problems {
+            writeln!(f, "- 跳过：{p}")?;
+        }
+        Ok(())
+    }
+}
+
+/// 以在线备份 API 复制数据库文件，只读打开源文件，不会迁移或修改它
+fn copy_db(src: &Path, dest: &Path) -> Result<()> {
+    let conn = Connection::open_with_flags(src, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.backup(DatabaseName::Main, dest, None)?;
+    Ok(())
+}
+
+fn counts(db: &SAFCdb) -> Result<(usize, usize)> {
+    Ok((db.find_all_objects()?.len(), db.find_all_comments()?.len()))
+}
+
+/// 将数据库 `ours` 与 `theirs` 合并写入新文件 `out`，两个输入文件都不会被修改
+///
+/// 以 `ours` 的副本为基础，写入 `theirs` 的全部客体、客体的合并与评价（包括不在哈希链中的），
+/// 来源记为 `theirs` 的路径。`out` 已存在时返回 [`Error::Validation`]
+pub fn merge_files(
+    ours: &Path,
+    theirs: &Path,
+    out: &Path,
+    policy: MergePolicy,
+) -> Result<MergeReport> {
+    if out.exists() {
+        return Err(Error::Validation(format!(
+            "输出文件 {} 已存在",
+            out.display()
+        )));
+    }
+    for p in [ours, theirs] {
+        if !p.is_file() {
+            return Err(Error::NotFound(format!("数据库文件 {}", p.display())));
+        }
+    }
+
+    // 对端可能是旧版本的数据库，在副本上迁移后再读取
+    let tmp = std::env::temp_dir().join(format!(
+        "safc-merge-{}-{}.sqlite",
+        std::process::id(),
+        chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+    ));
+    let read_theirs = || -> Result<(Vec<SyncRecord>, (usize, usize))> {
+        copy_db(theirs, &tmp)?;
+        let db = SAFCdb::new_with_path(tmp.to_string_lossy().into_owned());
+        Ok((db.sync_all()?, counts(&db)?))
+    };
+    let r = read_theirs();
+    std::fs::remove_file(&tmp).ok();
+    let (records, theirs_counts) = r?;
+
+    copy_db(ours, out)?;
+    let db = SAFCdb::new_with_path(out.to_string_lossy().into_owned());
+    let ours_counts = counts(&db)?;
+    // 离线合并的两个文件都由管理员提供
+    let sync = db.apply_sync_with(
+        &theirs.to_string_lossy(),
+        PeerTrust::Trusted,
+        &records,
+        policy,
+    )?;
+    Ok(MergeReport {
+        policy,
+        ours: ours_counts,
+        theirs: theirs_counts,
+        merged: counts(&db)?,
+        sync,
+    })
+}
+
+#[test]
+fn test_merge_files() {
+    use crate::db::{temp_db, temp_object, SourceCate};
+    use crate::service::*;
+
+    let a = temp_db();
+    let b = temp_db();
+    let both = temp_object(&a);
+    let mut other = new_object("211", "清华大学", "self", "张三").unwrap();
+    other.info = Some("乙的信息".to_string());
+    other.date = "2022-01-01".to_string();
+    b.add_object(&other).unwrap();
+    post_comment(&a, &both.object_id, "甲的评价", SourceCate::Web, "otp").unwrap();
+    let c = post_comment(&b, &both.object_id, "乙的评价", SourceCate::Web, "otp").unwrap();
+    create_object(&b, "985", "北京大学", "self", "李四").unwrap();
+    // 不经本程序写入的数据同样会被合并，但 id 须与内容相符
+    let script_id = crate::sec::hash_comment_id(
+        &both.object_id,
+        &"脚本写入".to_string(),
+        &"2023-09-01".to_string(),
+    );
+    for id in [script_id.as_str(), "forged"] {
+        b.pool
+            .get()
+            .unwrap()
+            .execute(
+                "INSERT INTO comments (object, description, date, source_cate, type, id) \
+                VALUES (?, '脚本写入', '2023-09-01', 'admin', 'teacher', ?)",
+                [&both.object_id, id],
+            )
+            .unwrap();
+    }
+
+    let dir = std::env::temp_dir().join(format!("safc-test-merge-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("out.sqlite");
+    std::fs::remove_file(&out).ok();
+    let (pa, pb) = (Path::new(&a.db_path), Path::new(&b.db_path));
+    let report = merge_files(pa, pb, &out, MergePolicy::Converge).unwrap();
+    assert_eq!((1, 1), report.ours);
+    assert_eq!((2, 3), report.theirs);
+    assert_eq!((2, 3), report.merged);
+    assert_eq!(
+        vec!["评价 forged 的 id 与内容不符".to_string()],
+        report.sync.problems
+    );
+    assert_eq!(
+        vec!["school_cate", "date", "info"],
+        report
+            .sync
+            .conflicts
+            .iter()
+            .map(|c| c.field)
+            .collect::<Vec<_>>()
+    );
+    let merged = SAFCdb::new_with_path(out.to_string_lossy().into_owned());
+    let t = merged
+        .find_objteacher_with_id(&both.object_id)
+        .unwrap()
+        .unwrap();
+    // 学校类别只报告冲突，不修改
+    assert_eq!(
+        ("985", "2022-01-01", Some("乙的信息")),
+        (t.school_cate.as_str(), t.date.as_str(), t.info.as_deref())
+    );
+    assert!(report.sync.conflicts[0]
+        .to_string()
+        .ends_with("本地 985，对端 211，取 985"));
+    assert!(merged.find_comment_with_id(&c.id).unwrap().is_some());
+    assert_eq!(
+        Path::new(&b.db_path).to_string_lossy(),
+        merged.find_provenance(&c.id).unwrap()[0].peer
+    );
+    assert!(merged.verify_chain().unwrap().is_valid());
+    assert!(report.to_string().contains("客体 3 处字段"));
+
+    // 输出文件已存在时拒绝覆盖；保留本地的策略不修改客体
+    assert!(merge_files(pa, pb, &out, MergePolicy::Ours).is_err());
+    std::fs::remove_file(&out).unwrap();
+    let report = merge_files(pa, pb, &out, MergePolicy::Ours).unwrap();
+    assert_eq!(0, report.sync.objects_updated);
+    assert_eq!(3, report.sync.conflicts.len());
+    let merged = SAFCdb::new_with_path(out.to_string_lossy().into_owned());
+    assert_eq!(
+        "985",
+        merged
+            .find_objteacher_with_id(&both.object_id)
+            .unwrap()
+            .unwrap()
+            .school_cate
+    );
+    // 采用对端的策略修改学校类别：清华大学整体移到 211 下，985 下不再有清华大学
+    std::fs::remove_file(&out).unwrap();
+    merge_files(pa, pb, &out, MergePolicy::Theirs).unwrap();
+    let merged = SAFCdb::new_with_path(out.to_string_lossy().into_owned());
+    assert_eq!(
+        vec!["北京大学".to_string()],
+        merged.find_university(&"985".to_string()).unwrap()
+    );
+    assert_eq!(
+        vec!["清华大学".to_string()],
+        merged.find_university(&"211".to_string()).unwrap()
+    );
+    // 输入文件未被修改
+    assert_eq!((1, 1), counts(&a).unwrap());
+    std::fs::remove_dir_all(&dir).ok();
+}
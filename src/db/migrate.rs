@@ -0,0 +1,631 @@
+//! # migrate
+//!
+//! 数据库结构的版本管理与迁移
+//!
+//! 版本号记录在 sqlite 的 `PRAGMA user_version` 中：
+//! - `0` 表示空数据库，或者是引入迁移机制之前由脚本（`script/data.py` 等）建立的数据库
+//! - 之后每一步迁移完成都会把版本号加一
+//!
+//! 迁移步骤只能追加，不能修改已发布的步骤，否则已有的各个中心的数据库将无法正确升级。
+//! 每一步都在一个事务中执行，失败则整体回滚。
+
+use rusqlite::{Connection, Transaction, TransactionBehavior};
+
+use crate::{Error, Result};
+
+/// 一步迁移
+pub struct Migration {
+    /// 迁移后的版本号
+    pub version: u32,
+    /// 简短说明，用于日志
+    pub description: &'static str,
+    /// 迁移的实际操作
+    pub up: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+/// 按顺序排列的所有迁移步骤
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "初始结构：objects 与 comments",
+        up: v1_initial,
+    },
+    Migration {
+        version: 2,
+        description: "客体表规范化：school_categories < universities < departments < subjects",
+        up: v2_normalize_objects,
+    },
+    Migration {
+        version: 3,
+        description: "全文搜索：objects_fts 与 comments_fts（FTS5 trigram）",
+        up: v3_full_text_search,
+    },
+    Migration {
+        version: 4,
+        description: "评价修订历史：comment_revisions",
+        up: v4_comment_revisions,
+    },
+    Migration {
+        version: 5,
+        description: "评价墓碑：comments.tombstone",
+        up: v5_comment_tombstones,
+    },
+    Migration {
+        version: 6,
+        description: "bot 会话状态：dialogues",
+        up: v6_dialogues,
+    },
+    Migration {
+        version: 7,
+        description: "关注客体：subscriptions 与新评价队列 comment_events",
+        up: v7_subscriptions,
+    },
+    Migration {
+        version: 8,
+        description: "管理员：audit_log 与 banned_users",
+        up: v8_admin,
+    },
+    Migration {
+        version: 9,
+        description: "按用户限流：rate_limits",
+        up: v9_rate_limits,
+    },
+    Migration {
+        version: 10,
+        description: "评价的评星：comments.rating_*，comment_revisions.rating_*",
+        up: v10_comment_ratings,
+    },
+    Migration {
+        version: 11,
+        description: "防篡改哈希链：chain_log",
+        up: v11_chain_log,
+    },
+    Migration {
+        version: 12,
+        description: "节点间同步：sync_peers、sync_provenance 与 comments.edited",
+        up: v12_sync,
+    },
+    Migration {
+        version: 13,
+        description: "objects 视图支持修改学校类别",
+        up: v13_update_school_cate,
+    },
+];
+
+/// 当前程序所期望的数据库结构版本
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// 读取数据库的结构版本
+pub fn schema_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// 将数据库升级到最新版本，返回升级前的版本号
+///
+/// 使用 `BEGIN IMMEDIATE` 拿到写锁后再读取版本号，
+/// 这样多个进程（bot 与 web）同时启动时也只有一个会执行迁移。
+///
+/// 数据库的版本高于程序支持的版本（已被更新的程序迁移过）时返回 [`Error::SchemaVersion`]，
+/// 旧程序不应读写它不认识的结构
+pub fn migrate(conn: &mut Connection) -> Result<u32> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let from = schema_version(&tx)?;
+    if from > latest_version() {
+        return Err(Error::SchemaVersion {
+            found: from,
+            expected: latest_version(),
+        });
+    }
+    for m in MIGRATIONS.iter().filter(|m| m.version > from) {
+        log::info!("数据库迁移 v{}：{}", m.version, m.description);
+        (m.up)(&tx)?;
+        // PRAGMA 不支持参数绑定
+        tx.pragma_update(None, "user_version", m.version)?;
+    }
+    tx.commit()?;
+    Ok(from)
+}
+
+/// v1：与 `script/data.py` 建立的结构一致
+///
+/// 使用 `IF NOT EXISTS`，旧的（版本号为 0 的）数据库文件可以直接标记为 v1
+fn v1_initial(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS objects (
+            school_cate TEXT NOT NULL,
+            university TEXT NOT NULL,
+            department TEXT NOT NULL,
+            supervisor TEXT NOT NULL,
+            date TEXT NOT NULL,
+            info TEXT,
+            object TEXT NOT NULL,
+            PRIMARY KEY (object)
+        );
+        CREATE TABLE IF NOT EXISTS comments (
+            object TEXT NOT NULL,
+            description TEXT NOT NULL,
+            date TEXT NOT NULL,
+            source_cate TEXT NOT NULL,
+            type TEXT NOT NULL,
+            author_sign TEXT,
+            id TEXT NOT NULL,
+            PRIMARY KEY (id)
+        );",
+    )
+}
+
+/// v2：将 objects 拆分为规范化的关系表
+///
+/// 原来的 objects 表变为同名的视图，列与 v1 完全一致，
+/// 并通过 `INSTEAD OF` 触发器支持插入与更新，以兼容 `script/crawlers` 中直接写库的脚本
+fn v2_normalize_objects(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE school_categories (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE
+        );
+        CREATE TABLE universities (
+            id INTEGER PRIMARY KEY,
+            school_cate_id INTEGER NOT NULL REFERENCES school_categories (id),
+            name TEXT NOT NULL,
+            UNIQUE (school_cate_id, name)
+        );
+        CREATE TABLE departments (
+            id INTEGER PRIMARY KEY,
+            university_id INTEGER NOT NULL REFERENCES universities (id),
+            name TEXT NOT NULL,
+            UNIQUE (university_id, name)
+        );
+        CREATE TABLE subjects (
+            object TEXT NOT NULL PRIMARY KEY,
+            department_id INTEGER NOT NULL REFERENCES departments (id),
+            supervisor TEXT NOT NULL,
+            date TEXT NOT NULL,
+            info TEXT
+        );
+        CREATE INDEX subjects_department ON subjects (department_id, supervisor);
+
+        INSERT OR IGNORE INTO school_categories (name)
+            SELECT DISTINCT school_cate FROM objects ORDER BY rowid;
+        INSERT OR IGNORE INTO universities (school_cate_id, name)
+            SELECT c.id, o.university FROM objects o
+            JOIN school_categories c ON c.name = o.school_cate
+            ORDER BY o.rowid;
+        INSERT OR IGNORE INTO departments (university_id, name)
+            SELECT u.id, o.department FROM objects o
+            JOIN school_categories c ON c.name = o.school_cate
+            JOIN universities u ON u.school_cate_id = c.id AND u.name = o.university
+            ORDER BY o.rowid;
+        INSERT INTO subjects (object, department_id, supervisor, date, info)
+            SELECT o.object, d.id, o.supervisor, o.date, o.info FROM objects o
+            JOIN school_categories c ON c.name = o.school_cate
+            JOIN universities u ON u.school_cate_id = c.id AND u.name = o.university
+            JOIN departments d ON d.university_id = u.id AND d.name = o.department
+            ORDER BY o.rowid;
+        DROP TABLE objects;
+
+        CREATE VIEW objects AS
+            SELECT c.name AS school_cate, u.name AS university, d.name AS department,
+                s.supervisor AS supervisor, s.date AS date, s.info AS info, s.object AS object
+            FROM subjects s
+            JOIN departments d ON d.id = s.department_id
+            JOIN universities u ON u.id = d.university_id
+            JOIN school_categories c ON c.id = u.school_cate_id;
+
+        CREATE TRIGGER objects_insert INSTEAD OF INSERT ON objects
+        BEGIN
+            SELECT RAISE(ABORT, 'NOT NULL constraint failed: objects')
+            WHERE NEW.school_cate IS NULL OR NEW.university IS NULL OR NEW.department IS NULL
+                OR NEW.supervisor IS NULL OR NEW.date IS NULL OR NEW.object IS NULL;
+            INSERT OR IGNORE INTO school_categories (name) VALUES (NEW.school_cate);
+            INSERT OR IGNORE INTO universities (school_cate_id, name)
+                SELECT id, NEW.university FROM school_categories WHERE name = NEW.school_cate;
+            INSERT OR IGNORE INTO departments (university_id, name)
+                SELECT u.id, NEW.department FROM universities u
+                JOIN school_categories c ON c.id = u.school_cate_id
+                WHERE c.name = NEW.school_cate AND u.name = NEW.university;
+            INSERT INTO subjects (object, department_id, supervisor, date, info)
+                SELECT NEW.object, d.id, NEW.supervisor, NEW.date, NEW.info FROM departments d
+                JOIN universities u ON u.id = d.university_id
+                JOIN school_categories c ON c.id = u.school_cate_id
+                WHERE c.name = NEW.school_cate AND u.name = NEW.university
+                    AND d.name = NEW.department;
+        END;
+
+        CREATE TRIGGER objects_update INSTEAD OF UPDATE OF date, info ON objects
+        BEGIN
+            UPDATE subjects SET date = NEW.date, info = NEW.info WHERE object = OLD.object;
+        END;",
+    )
+}
+
+/// v3：FTS5 全文索引，由触发器与 subjects、comments 保持同步
+///
+/// 使用 trigram 分词器，对中文等没有空格分词的文字也有效。
+/// 不使用 rowid 关联原表，因为 `VACUUM` 可能改变没有 INTEGER PRIMARY KEY 的表的 rowid
+fn v3_full_text_search(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE VIRTUAL TABLE objects_fts USING fts5 (
+            object UNINDEXED,
+            text,
+            tokenize = 'trigram'
+        );
+        CREATE VIRTUAL TABLE comments_fts USING fts5 (
+            id UNINDEXED,
+            object UNINDEXED,
+            text,
+            tokenize = 'trigram'
+        );
+
+        INSERT INTO objects_fts (object, text)
+            SELECT s.object, u.name || ' ' || d.name || ' ' || s.supervisor FROM subjects s
+            JOIN departments d ON d.id = s.department_id
+            JOIN universities u ON u.id = d.university_id;
+        INSERT INTO comments_fts (id, object, text)
+            SELECT id, object, description FROM comments;
+
+        CREATE TRIGGER subjects_fts_insert AFTER INSERT ON subjects
+        BEGIN
+            INSERT INTO objects_fts (object, text)
+                SELECT NEW.object, u.name || ' ' || d.name || ' ' || NEW.supervisor
+                FROM departments d JOIN universities u ON u.id = d.university_id
+                WHERE d.id = NEW.department_id;
+        END;
+        CREATE TRIGGER subjects_fts_update AFTER UPDATE OF supervisor, department_id ON subjects
+        BEGIN
+            DELETE FROM objects_fts WHERE object = OLD.object;
+            INSERT INTO objects_fts (object, text)
+                SELECT NEW.object, u.name || ' ' || d.name || ' ' || NEW.supervisor
+                FROM departments d JOIN universities u ON u.id = d.university_id
+                WHERE d.id = NEW.department_id;
+        END;
+        CREATE TRIGGER subjects_fts_delete AFTER DELETE ON subjects
+        BEGIN
+            DELETE FROM objects_fts WHERE object = OLD.object;
+        END;
+
+        CREATE TRIGGER comments_fts_insert AFTER INSERT ON comments
+        BEGIN
+            INSERT INTO comments_fts (id, object, text)
+                VALUES (NEW.id, NEW.object, NEW.description);
+        END;
+        CREATE TRIGGER comments_fts_update AFTER UPDATE OF object, description ON comments
+        BEGIN
+            DELETE FROM comments_fts WHERE id = OLD.id;
+            INSERT INTO comments_fts (id, object, text)
+                VALUES (NEW.id, NEW.object, NEW.description);
+        END;
+        CREATE TRIGGER comments_fts_delete AFTER DELETE ON comments
+        BEGIN
+            DELETE FROM comments_fts WHERE id = OLD.id;
+        END;",
+    )
+}
+
+/// v4：评价的修订历史，保存每次修改或撤回之前的内容
+///
+/// action：edit（修改）, retract（发布人撤回）
+fn v4_comment_revisions(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE comment_revisions (
+            comment_id TEXT NOT NULL,
+            revision INTEGER NOT NULL,
+            description TEXT NOT NULL,
+            date TEXT NOT NULL,
+            action TEXT NOT NULL,
+            PRIMARY KEY (comment_id, revision)
+        );",
+    )
+}
+
+/// v5：评价的墓碑，被撤回或隐藏的评价保留其 id 与位置，以免嵌套评价失去上下文
+///
+/// tombstone：NULL（正常）, retracted（发布人撤回）, moderated（管理员隐藏）
+/// 墓碑化时原内容存入 comment_revisions，description 置空，并从全文索引中移除
+fn v5_comment_tombstones(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE comments ADD COLUMN tombstone TEXT;
+        ALTER TABLE comments ADD COLUMN tombstone_reason TEXT;
+        ALTER TABLE comments ADD COLUMN tombstone_date TEXT;
+
+        DROP TRIGGER comments_fts_update;
+        CREATE TRIGGER comments_fts_update
+        AFTER UPDATE OF object, description, tombstone ON comments
+        BEGIN
+            DELETE FROM comments_fts WHERE id = OLD.id;
+            INSERT INTO comments_fts (id, object, text)
+                SELECT NEW.id, NEW.object, NEW.description WHERE NEW.tombstone IS NULL;
+        END;",
+    )
+}
+
+/// v6：telegram bot 的会话状态，bot 重启后用户不会丢失进行中的对话
+///
+/// state 为 json 序列化的会话状态，updated 为最后更新的 unix 时间戳（秒）
+fn v6_dialogues(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE dialogues (
+            chat_id INTEGER NOT NULL PRIMARY KEY,
+            state TEXT NOT NULL,
+            updated INTEGER NOT NULL
+        );
+        CREATE INDEX dialogues_updated ON dialogues (updated);",
+    )
+}
+
+/// v7：关注客体
+///
+/// subscriptions 记录 telegram 会话关注的客体；
+/// bot 与 web 发布的新评价由 [`crate::service`] 写入 comment_events 队列（见 [`super::subscription`]），
+/// 导入、同步与合并写入的历史评价不入队；bot 的通知任务读取队列、汇总后通知关注者，再删除已处理的事件
+///
+/// created 为入队的 unix 时间戳，用于清理长期无人处理的事件；
+/// author_chat 为在 bot 中发布评价的会话，不通知发布人自己
+fn v7_subscriptions(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE subscriptions (
+            chat_id INTEGER NOT NULL,
+            object TEXT NOT NULL REFERENCES subjects (object) ON DELETE CASCADE,
+            date TEXT NOT NULL,
+            PRIMARY KEY (chat_id, object)
+        );
+        CREATE INDEX subscriptions_object ON subscriptions (object);
+
+        CREATE TABLE comment_events (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            comment_id TEXT NOT NULL,
+            created INTEGER NOT NULL DEFAULT 0,
+            author_chat INTEGER
+        );",
+    )
+}
+
+/// v8：管理员操作的审计日志与被封禁的 telegram 用户
+fn v8_admin(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            date TEXT NOT NULL,
+            admin_id INTEGER NOT NULL,
+            action TEXT NOT NULL,
+            target TEXT NOT NULL,
+            detail TEXT
+        );
+        CREATE TABLE banned_users (
+            user_id INTEGER NOT NULL PRIMARY KEY,
+            date TEXT NOT NULL,
+            reason TEXT
+        );",
+    )
+}
+
+/// v9：按用户、按操作的令牌桶，保存在数据库中以免重启后重置
+///
+/// updated 为令牌数最后更新的 unix 时间戳（秒，带小数）
+fn v9_rate_limits(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE rate_limits (
+            user_id INTEGER NOT NULL,
+            action TEXT NOT NULL,
+            tokens REAL NOT NULL,
+            updated REAL NOT NULL,
+            PRIMARY KEY (user_id, action)
+        );",
+    )
+}
+
+/// v10：评价的分项评星，1 到 5 星，NULL 表示未评，见 [`super::rating`]
+///
+/// 评星随正文一起存入修订历史，撤回或隐藏评价时清空评星，恢复时从修订历史取回
+fn v10_comment_ratings(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE comments ADD COLUMN rating_academic INTEGER
+            CHECK (rating_academic BETWEEN 1 AND 5);
+        ALTER TABLE comments ADD COLUMN rating_funding INTEGER
+            CHECK (rating_funding BETWEEN 1 AND 5);
+        ALTER TABLE comments ADD COLUMN rating_relationship INTEGER
+            CHECK (rating_relationship BETWEEN 1 AND 5);
+        ALTER TABLE comments ADD COLUMN rating_prospects INTEGER
+            CHECK (rating_prospects BETWEEN 1 AND 5);
+        ALTER TABLE comments ADD COLUMN rating_stipend INTEGER
+            CHECK (rating_stipend BETWEEN 1 AND 5);
+
+        ALTER TABLE comment_revisions ADD COLUMN rating_academic INTEGER;
+        ALTER TABLE comment_revisions ADD COLUMN rating_funding INTEGER;
+        ALTER TABLE comment_revisions ADD COLUMN rating_relationship INTEGER;
+        ALTER TABLE comment_revisions ADD COLUMN rating_prospects INTEGER;
+        ALTER TABLE comment_revisions ADD COLUMN rating_stipend INTEGER;",
+    )
+}
+
+/// v11：客体与评价的哈希链日志，只能追加，见 [`super::chain`]
+///
+/// 已有的客体与评价补录为链的开头
+fn v11_chain_log(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE chain_log (
+            seq INTEGER PRIMARY KEY,
+            op TEXT NOT NULL,
+            target TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            prev_hash TEXT NOT NULL UNIQUE,
+            hash TEXT NOT NULL UNIQUE
+        );
+        CREATE TRIGGER chain_log_no_update BEFORE UPDATE ON chain_log BEGIN
+            SELECT RAISE(ABORT, 'chain_log 只能追加');
+        END;
+        CREATE TRIGGER chain_log_no_delete BEFORE DELETE ON chain_log BEGIN
+            SELECT RAISE(ABORT, 'chain_log 只能追加');
+        END;",
+    )?;
+    let n = super::chain::backfill(tx)?;
+    log::info!("已将 {n} 个客体与评价补录到哈希链");
+    Ok(())
+}
+
+/// v12：节点间同步，每个对端的游标与同步来的数据的来源，见 [`super::sync`]
+///
+/// comments.edited 为评价最后一次修改、撤回、隐藏或恢复的日期，同步时据此取舍；
+/// 链上已有的记录中没有此日期，已有的评价不补录，视为未修改过
+fn v12_sync(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE sync_peers (
+            peer TEXT PRIMARY KEY,
+            cursor INTEGER NOT NULL,
+            updated TEXT NOT NULL
+        );
+        CREATE TABLE sync_provenance (
+            id INTEGER PRIMARY KEY,
+            target TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            action TEXT NOT NULL,
+            peer TEXT NOT NULL,
+            date TEXT NOT NULL
+        );
+        CREATE INDEX sync_provenance_target ON sync_provenance (target);
+        ALTER TABLE comments ADD COLUMN edited TEXT;",
+    )
+}
+
+/// v13：通过 objects 视图修改客体的学校类别，合并数据库时使用，见 [`super::merge`]
+///
+/// 客体移到新类别下的同名学校、学院中（缺少时建立），原来的学院、学校、类别空了则删除；
+/// 只删除客体原来所在的学院与学校，其他学校下的同名学院、其他类别下的同名学校不受影响
+fn v13_update_school_cate(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TRIGGER objects_update_school_cate INSTEAD OF UPDATE OF school_cate ON objects
+        WHEN NEW.school_cate IS NOT OLD.school_cate
+        BEGIN
+            INSERT OR IGNORE INTO school_categories (name) VALUES (NEW.school_cate);
+            INSERT OR IGNORE INTO universities (school_cate_id, name)
+                SELECT id, OLD.university FROM school_categories WHERE name = NEW.school_cate;
+            INSERT OR IGNORE INTO departments (university_id, name)
+                SELECT u.id, OLD.department FROM universities u
+                JOIN school_categories c ON c.id = u.school_cate_id
+                WHERE c.name = NEW.school_cate AND u.name = OLD.university;
+            UPDATE subjects SET department_id = (
+                SELECT d.id FROM departments d
+                JOIN universities u ON u.id = d.university_id
+                JOIN school_categories c ON c.id = u.school_cate_id
+                WHERE c.name = NEW.school_cate AND u.name = OLD.university
+                    AND d.name = OLD.department
+            ) WHERE object = OLD.object;
+            DELETE FROM departments WHERE id NOT IN (SELECT department_id FROM subjects)
+                AND id IN (
+                    SELECT d.id FROM departments d
+                    JOIN universities u ON u.id = d.university_id
+                    JOIN school_categories c ON c.id = u.school_cate_id
+                    WHERE c.name = OLD.school_cate AND u.name = OLD.university
+                        AND d.name = OLD.department
+                );
+            DELETE FROM universities WHERE id NOT IN (SELECT university_id FROM departments)
+                AND id IN (
+                    SELECT u.id FROM universities u
+                    JOIN school_categories c ON c.id = u.school_cate_id
+                    WHERE c.name = OLD.school_cate AND u.name = OLD.university
+                );
+            DELETE FROM school_categories
+                WHERE id NOT IN (SELECT school_cate_id FROM universities)
+                AND name = OLD.school_cate;
+        END;",
+    )
+}
+
+#[test]
+fn test_migrate_fresh() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    assert_eq!(0, migrate(&mut conn).unwrap());
+    assert_eq!(latest_version(), schema_version(&conn).unwrap());
+    // 再次迁移不做任何事
+    assert_eq!(latest_version(), migrate(&mut conn).unwrap());
+}
+
+#[test]
+fn test_migrate_newer() {
+    // 被更新的程序迁移过的数据库，拒绝打开
+    let mut conn = Connection::open_in_memory().unwrap();
+    migrate(&mut conn).unwrap();
+    conn.pragma_update(None, "user_version", latest_version() + 1)
+        .unwrap();
+    assert!(matches!(
+        migrate(&mut conn),
+        Err(Error::SchemaVersion { found, .. }) if found == latest_version() + 1
+    ));
+    assert_eq!(latest_version() + 1, schema_version(&conn).unwrap());
+}
+
+#[test]
+fn test_migrate_update_school_cate() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    migrate(&mut conn).unwrap();
+    conn.execute_batch(
+        "INSERT INTO objects (school_cate, university, department, supervisor, date, object)
+            VALUES ('985', '清华大学', 'self', '张三', '2023-09-18', 'a'),
+                ('985', '北京大学', '计算机系', '李四', '2023-09-18', 'b');
+        -- 其他学校下没有客体的同名学院、其他类别下没有学院的同名学校
+        INSERT INTO departments (university_id, name)
+            SELECT id, 'self' FROM universities WHERE name = '北京大学';
+        INSERT INTO school_categories (name) VALUES ('双一流');
+        INSERT INTO universities (school_cate_id, name)
+            SELECT id, '清华大学' FROM school_categories WHERE name = '双一流';
+        UPDATE objects SET school_cate = '211' WHERE object = 'a';",
+    )
+    .unwrap();
+    let names = |sql: &str| -> Vec<String> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+        rows.collect::<rusqlite::Result<_>>().unwrap()
+    };
+    assert_eq!(
+        vec!["211/清华大学", "985/北京大学", "双一流/清华大学"],
+        names(
+            "SELECT c.name || '/' || u.name FROM universities u
+            JOIN school_categories c ON c.id = u.school_cate_id ORDER BY 1"
+        )
+    );
+    assert_eq!(
+        vec!["北京大学/self", "北京大学/计算机系", "清华大学/self"],
+        names(
+            "SELECT u.name || '/' || d.name FROM departments d
+            JOIN universities u ON u.id = d.university_id ORDER BY 1"
+        )
+    );
+}
+
+#[test]
+fn test_migrate_legacy() {
+    // 迁移机制之前建立的数据库：有表，但 user_version 为 0
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        "CREATE TABLE objects (school_cate TEXT NOT NULL, university TEXT NOT NULL,
+            department TEXT NOT NULL, supervisor TEXT NOT NULL, date TEXT NOT NULL,
+            info TEXT, object TEXT NOT NULL, PRIMARY KEY (object));
+        CREATE TABLE comments (object TEXT NOT NULL, description TEXT NOT NULL,
+            date TEXT NOT NULL, source_cate TEXT NOT NULL, type TEXT NOT NULL,
+            author_sign TEXT, id TEXT NOT NULL, PRIMARY KEY (id));
+        INSERT INTO objects VALUES ('985', '清华大学', 'self', '张三', '2022-05', NULL, 'o1');
+        INSERT INTO objects VALUES ('985', '清华大学', 'self', '李四', '2022-05', 'x', 'o2');
+        INSERT INTO objects VALUES ('985', '清华大学', '计算机系', '王五', '2022-05', NULL, 'o3');",
+    )
+    .unwrap();
+    assert_eq!(0, migrate(&mut conn).unwrap());
+    let count = |sql: &str| -> i32 { conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+    assert_eq!(3, count("SELECT COUNT(*) FROM objects"));
+    assert_eq!(1, count("SELECT COUNT(*) FROM universities"));
+    assert_eq!(2, count("SELECT COUNT(*) FROM departments"));
+
+    // 旧的写法（script/crawlers）通过视图的触发器依然可用
+    conn.execute_batch(
+        "INSERT INTO objects (school_cate, university, department, supervisor, date, info, object)
+            VALUES ('985', '清华大学', '计算机系', '赵六', '2023-09-26', NULL, 'o4');
+        UPDATE objects SET info = 'y' WHERE object = 'o4';",
+    )
+    .unwrap();
+    assert_eq!(4, count("SELECT COUNT(*) FROM subjects"));
+    assert_eq!(2, count("SELECT COUNT(*) FROM departments"));
+    assert_eq!(1, count("SELECT COUNT(*) FROM objects WHERE info = 'y'"));
+    // 已有的客体补录到哈希链
+    assert_eq!(3, count("SELECT COUNT(*) FROM chain_log"));
+}
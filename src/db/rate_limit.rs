@@ -0,0 +1,212 @@
+//! # rate_limit
+//!
+//! 按用户、按操作的令牌桶限流
+//!
+//! 每个用户的每种操作有一个桶，桶中最多 [`Bucket::capacity`] 个令牌，
+//! 每秒恢复 [`Bucket::refill_per_sec`] 个；每次操作消耗一个令牌，没有令牌时返回
+//! [`Error::RateLimited`]。桶的状态保存在 rate_limits 表中（见 [`super::migrate`] v9）。
+//!
+//! 错误的发布人 OTP 按评价计数（见 [`comment_key`]），而不是按用户：web 端没有用户身份，
+//! 分散在多个账号上对同一条评价的尝试也应一起限制。
+
+use std::time::Duration;
+
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use sha2::{Digest, Sha256};
+use strum_macros::{Display, EnumString};
+
+use super::SAFCdb;
+use crate::{Error, Result};
+
+/// 受限流的操作
+#[derive(Debug, EnumString, Display, PartialEq, Clone, Copy)]
+#[strum(serialize_all = "snake_case")]
+pub enum RateAction {
+    /// 建立客体
+    CreateObject,
+    /// 发布评价
+    PublishComment,
+    /// 搜索
+    Search,
+    /// inline 模式的搜索，每输入一个字就是一次查询，因此与 [`RateAction::Search`] 分开计数
+    InlineSearch,
+    /// 导出数据库
+    Export,
+    /// 输入错误的发布人 OTP，按评价计数
+    OtpFailure,
+}
+
+/// 令牌桶的参数
+#[derive(Debug, Clone, Copy)]
+pub struct Bucket {
+    /// 最多积攒的令牌数，即允许的突发次数
+    pub capacity: f64,
+    /// 每秒恢复的令牌数
+    pub refill_per_sec: f64,
+}
+
+const HOUR: f64 = 60.0 * 60.0;
+
+impl RateAction {
+    pub fn bucket(&self) -> Bucket {
+        match self {
+            // 每小时 5 个，最多连续 10 个
+            Self::CreateObject => Bucket {
+                capacity: 10.0,
+                refill_per_sec: 5.0 / HOUR,
+            },
+            // 每小时 10 条，最多连续 10 条
+            Self::PublishComment => Bucket {
+                capacity: 10.0,
+                refill_per_sec: 10.0 / HOUR,
+            },
+            // 每 2 秒 1 次，最多连续 30 次
+            Self::Search => Bucket {
+                capacity: 30.0,
+                refill_per_sec: 0.5,
+            },
+            // 每秒 2 次，最多连续 60 次
+            Self::InlineSearch => Bucket {
+                capacity: 60.0,
+                refill_per_sec: 2.0,
+            },
+            // 每小时 3 次
+            Self::Export => Bucket {
+                capacity: 3.0,
+                refill_per_sec: 3.0 / HOUR,
+            },
+            // 每小时 5 次，最多连续 5 次
+            Self::OtpFailure => Bucket {
+                capacity: 5.0,
+                refill_per_sec: 5.0 / HOUR,
+            },
+        }
+    }
+}
+
+/// 评价在 rate_limits 表中代替用户 id 的键，用于 [`RateAction::OtpFailure`]
+pub fn comment_key(comment_id: &str) -> i64 {
+    let digest = Sha256::digest(comment_id.as_bytes());
+    i64::from_be_bytes(digest[..8].try_into().expect("sha256 不短于 8 字节"))
+}
+
+/// 当前时间的 unix 时间戳（秒）
+fn now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// 桶在 `now` 时的令牌数
+fn tokens_at(conn: &Connection, user_id: i64, action: RateAction, now: f64) -> Result<f64> {
+    let Bucket {
+        capacity,
+        refill_per_sec,
+    } = action.bucket();
+    Ok(
+        match conn
+            .query_row(
+                "SELECT tokens, updated FROM rate_limits WHERE user_id = ?1 AND action = ?2",
+                params![user_id, action.to_string()],
+                |row| Ok((row.get::<_, f64>(0)?, row.get::<_, f64>(1)?)),
+            )
+            .optional()?
+        {
+            Some((tokens, updated)) => {
+                (tokens + (now - updated).max(0.0) * refill_per_sec).min(capacity)
+            }
+            None => capacity,
+        },
+    )
+}
+
+/// 令牌不足一个时需要等待的时间
+fn limited(action: RateAction, tokens: f64) -> Error {
+    let wait = (1.0 - tokens) / action.bucket().refill_per_sec;
+    Error::RateLimited(Duration::from_secs_f64(wait))
+}
+
+impl SAFCdb {
+    /// 为用户的一次操作消耗一个令牌，没有令牌时返回 [`Error::RateLimited`]
+    pub fn take_token(&self, user_id: i64, action: RateAction) -> Result<()> {
+        self.take_token_at(user_id, action, now())
+    }
+
+    /// 用户的操作是否还有令牌，不消耗令牌；没有令牌时返回 [`Error::RateLimited`]
+    pub fn check_token(&self, user_id: i64, action: RateAction) -> Result<()> {
+        self.check_token_at(user_id, action, now())
+    }
+
+    fn check_token_at(&self, user_id: i64, action: RateAction, now: f64) -> Result<()> {
+        let conn = self.pool.clone().get()?;
+        let tokens = tokens_at(&conn, user_id, action, now)?;
+        if tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(limited(action, tokens))
+        }
+    }
+
+    fn take_token_at(&self, user_id: i64, action: RateAction, now: f64) -> Result<()> {
+        let mut conn = self.pool.clone().get()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let tokens = tokens_at(&tx, user_id, action, now)?;
+        let allowed = tokens >= 1.0;
+        let left = if allowed { tokens - 1.0 } else { tokens };
+        tx.execute(
+            "INSERT INTO rate_limits (user_id, action, tokens, updated) VALUES (?1, ?2, ?3, ?4) \
+            ON CONFLICT (user_id, action) DO UPDATE SET tokens = ?3, updated = ?4",
+            params![user_id, action.to_string(), left, now],
+        )?;
+        tx.commit()?;
+        if allowed {
+            Ok(())
+        } else {
+            Err(limited(action, tokens))
+        }
+    }
+
+    /// 清空用户的所有令牌桶，返回清除的桶数
+    pub fn reset_rate_limits(&self, user_id: i64) -> Result<usize> {
+        let conn = self.pool.clone().get()?;
+        Ok(conn.execute("DELETE FROM rate_limits WHERE user_id = ?", [user_id])?)
+    }
+}
+
+#[test]
+fn test_rate_limit() {
+    use super::temp_db;
+    let db = temp_db();
+    let Bucket {
+        capacity,
+        refill_per_sec,
+    } = RateAction::Search.bucket();
+    let now = 1_000_000.0;
+    for _ in 0..capacity as usize {
+        db.take_token_at(1, RateAction::Search, now).unwrap();
+    }
+    let e = db.take_token_at(1, RateAction::Search, now).unwrap_err();
+    assert!(matches!(e, Error::RateLimited(d) if d.as_secs_f64() > 0.0));
+    // 其他用户、其他操作不受影响
+    db.take_token_at(2, RateAction::Search, now).unwrap();
+    db.take_token_at(1, RateAction::PublishComment, now)
+        .unwrap();
+    // 等待一个令牌恢复后可以继续
+    db.take_token_at(1, RateAction::Search, now + 1.0 / refill_per_sec)
+        .unwrap();
+    assert!(db
+        .take_token_at(1, RateAction::Search, now + 1.0 / refill_per_sec)
+        .is_err());
+    // 只检查不消耗令牌
+    assert!(db
+        .check_token_at(1, RateAction::Search, now + 1.0 / refill_per_sec)
+        .is_err());
+    db.check_token_at(1, RateAction::Search, now + 2.0 / refill_per_sec)
+        .unwrap();
+    db.check_token_at(1, RateAction::Search, now + 2.0 / refill_per_sec)
+        .unwrap();
+    // 管理员重置后恢复满桶
+    assert_eq!(2, db.reset_rate_limits(1).unwrap());
+    db.take_token_at(1, RateAction::Search, now).unwrap();
+}
@@ -0,0 +1,286 @@
+//! # rating
+//!
+//! 评价的分项评星
+//!
+//! 评价除正文外可附带固定几项的评星（见 [`RatingDimension`]），每项 1 到 [`MAX_STARS`] 星，
+//! 均为可选。评星保存在 comments 表的 `rating_*` 列中（见 [`super::migrate`] v10），
+//! 不参与评价 id 的计算。客体的评星汇总见 [`SAFCdb::rating_summary`]，
+//! 只统计未被撤回或隐藏的、直接针对客体的评价。
+
+use rusqlite::Row;
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+use super::SAFCdb;
+use crate::{Error, Result};
+
+/// 满分
+pub const MAX_STARS: u8 = 5;
+
+/// 评星的项目
+#[derive(Debug, EnumString, Display, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum RatingDimension {
+    /// 学术水平
+    Academic,
+    /// 科研经费
+    Funding,
+    /// 师生关系
+    Relationship,
+    /// 学生前途
+    Prospects,
+    /// 学生补助
+    Stipend,
+}
+
+impl RatingDimension {
+    /// 所有项目，按显示顺序
+    pub const ALL: [Self; 5] = [
+        Self::Academic,
+        Self::Funding,
+        Self::Relationship,
+        Self::Prospects,
+        Self::Stipend,
+    ];
+
+    /// 显示的名称
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Academic => "学术水平",
+            Self::Funding => "科研经费",
+            Self::Relationship => "师生关系",
+            Self::Prospects => "学生前途",
+            Self::Stipend => "学生补助",
+        }
+    }
+
+    /// comments 表中的列名
+    fn column(&self) -> &'static str {
+        match self {
+            Self::Academic => "rating_academic",
+            Self::Funding => "rating_funding",
+            Self::Relationship => "rating_relationship",
+            Self::Prospects => "rating_prospects",
+            Self::Stipend => "rating_stipend",
+        }
+    }
+}
+
+/// 一条评价的评星，`None` 表示此项未评
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Ratings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub academic: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub funding: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relationship: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prospects: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stipend: Option<u8>,
+}
+
+impl Ratings {
+    pub fn get(&self, dimension: RatingDimension) -> Option<u8> {
+        *self.slot(dimension)
+    }
+
+    pub fn set(&mut self, dimension: RatingDimension, stars: Option<u8>) {
+        *self.slot_mut(dimension) = stars;
+    }
+
+    fn slot(&self, dimension: RatingDimension) -> &Option<u8> {
+        match dimension {
+            RatingDimension::Academic => &self.academic,
+            RatingDimension::Funding => &self.funding,
+            RatingDimension::Relationship => &self.relationship,
+            RatingDimension::Prospects => &self.prospects,
+            RatingDimension::Stipend => &self.stipend,
+        }
+    }
+
+    fn slot_mut(&mut self, dimension: RatingDimension) -> &mut Option<u8> {
+        match dimension {
+            RatingDimension::Academic => &mut self.academic,
+            RatingDimension::Funding => &mut self.funding,
+            RatingDimension::Relationship => &mut self.relationship,
+            RatingDimension::Prospects => &mut self.prospects,
+            RatingDimension::Stipend => &mut self.stipend,
+        }
+    }
+
+    /// 已评的项目及星数，按 [`RatingDimension::ALL`] 的顺序
+    pub fn iter(&self) -> impl Iterator<Item = (RatingDimension, u8)> + '_ {
+        RatingDimension::ALL
+            .into_iter()
+            .filter_map(|d| self.get(d).map(|s| (d, s)))
+    }
+
+    /// 一项都未评
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// 每项须为 1 到 [`MAX_STARS`] 星，否则返回 [`Error::Validation`]
+    pub fn validate(&self) -> Result<()> {
+        match self.iter().find(|(_, s)| !(1..=MAX_STARS).contains(s)) {
+            Some((d, s)) => Err(Error::Validation(format!(
+                "{}的评星应为 1 到 {MAX_STARS} 星，而不是 {s}",
+                d.label()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// 从含 `rating_*` 列的行中读取，一项都未评时为 `None`
+    pub(super) fn from_row(row: &Row) -> rusqlite::Result<Option<Self>> {
+        let mut ratings = Self::default();
+        for d in RatingDimension::ALL {
+            ratings.set(d, row.get(d.column())?);
+        }
+        Ok((!ratings.is_empty()).then_some(ratings))
+    }
+}
+
+/// 一项评星的汇总
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DimensionSummary {
+    pub dimension: RatingDimension,
+    /// 评了此项的评价数
+    pub count: usize,
+    /// 平均星数，无人评时为 `None`
+    pub mean: Option<f64>,
+    /// 各星数的评价数，`distribution[0]` 为 1 星
+    pub distribution: [usize; MAX_STARS as usize],
+}
+
+/// 客体的评星汇总，包含所有项目，按 [`RatingDimension::ALL`] 的顺序
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RatingSummary {
+    pub dimensions: Vec<DimensionSummary>,
+}
+
+impl RatingSummary {
+    /// 没有任何评星
+    pub fn is_empty(&self) -> bool {
+        self.dimensions.iter().all(|d| d.count == 0)
+    }
+}
+
+impl SAFCdb {
+    /// 客体的评星汇总，只统计未被撤回或隐藏的、直接针对此客体的评价
+    pub fn rating_summary(&self, object_id: &str) -> Result<RatingSummary> {
+        let conn = self.pool.clone().get()?;
+        let mut dimensions = vec![];
+        for dimension in RatingDimension::ALL {
+            let column = dimension.column();
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {column}, COUNT(*) FROM comments \
+                WHERE object = ? AND tombstone IS NULL AND {column} IS NOT NULL \
+                GROUP BY {column}"
+            ))?;
+            let mut distribution = [0; MAX_STARS as usize];
+            for row in stmt.query_map([object_id], |row| {
+                Ok((row.get::<_, u8>(0)?, row.get::<_, usize>(1)?))
+            })? {
+                let (stars, n) = row?;
+                if let Some(slot) = distribution.get_mut(usize::from(stars).wrapping_sub(1)) {
+                    *slot = n;
+                }
+            }
+            let count: usize = distribution.iter().sum();
+            let total: usize = distribution
+                .iter()
+                .enumerate()
+                .map(|(i, n)| (i + 1) * n)
+                .sum();
+            dimensions.push(DimensionSummary {
+                dimension,
+                count,
+                mean: (count > 0).then(|| total as f64 / count as f64),
+                distribution,
+            });
+        }
+        Ok(RatingSummary { dimensions })
+    }
+}
+
+#[test]
+fn test_rating_summary() {
+    use crate::db::{temp_db, temp_object, CommentType, ObjComment, SourceCate};
+
+    let db = temp_db();
+    let t = temp_object(&db);
+    let rate = |text: &str, ratings: Option<Ratings>| {
+        let mut c = ObjComment::new_with_otp(
+            t.object_id.clone(),
+            text.to_string(),
+            SourceCate::Web,
+            CommentType::Teacher,
+            "otp".to_string(),
+        );
+        c.ratings = ratings;
+        db.add_comment(&c).unwrap();
+        c
+    };
+    let a = rate(
+        "好",
+        Some(Ratings {
+            academic: Some(5),
+            funding: Some(4),
+            ..Default::default()
+        }),
+    );
+    rate(
+        "一般",
+        Some(Ratings {
+            academic: Some(2),
+            ..Default::default()
+        }),
+    );
+    rate("没有评星", None);
+    assert_eq!(
+        a.ratings,
+        db.find_comment_with_id(&a.id).unwrap().unwrap().ratings
+    );
+
+    let s = db.rating_summary(&t.object_id).unwrap();
+    assert_eq!(RatingDimension::ALL.len(), s.dimensions.len());
+    let academic = &s.dimensions[0];
+    assert_eq!(RatingDimension::Academic, academic.dimension);
+    assert_eq!(2, academic.count);
+    assert_eq!(Some(3.5), academic.mean);
+    assert_eq!([0, 1, 0, 0, 1], academic.distribution);
+    assert_eq!(1, s.dimensions[1].count);
+    assert_eq!(None, s.dimensions[4].mean);
+
+    // 被隐藏的评价不计入
+    db.tombstone_comment(&a.id, crate::db::TombstoneKind::Moderated, None)
+        .unwrap();
+    let s = db.rating_summary(&t.object_id).unwrap();
+    assert_eq!(1, s.dimensions[0].count);
+    assert_eq!(0, s.dimensions[1].count);
+    assert!(db.rating_summary("nonexistent").unwrap().is_empty());
+
+    // 超出范围的评星不能写入
+    assert!(Ratings {
+        stipend: Some(6),
+        ..Default::default()
+    }
+    .validate()
+    .is_err());
+    let mut bad = ObjComment::new_with_otp(
+        t.object_id.clone(),
+        "坏".to_string(),
+        SourceCate::Web,
+        CommentType::Teacher,
+        "otp".to_string(),
+    );
+    bad.ratings = Some(Ratings {
+        funding: Some(0),
+        ..Default::default()
+    });
+    assert!(db.add_comment(&bad).is_err());
+}
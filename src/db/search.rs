@@ -0,0 +1,199 @@
+//! # search
+//!
+//! 基于 sqlite FTS5 的全文搜索
+//!
+//! 索引表 objects_fts、comments_fts 由触发器维护（见 [`super::migrate`] v3），
+//! 分词器为 trigram，所以：
+//! - 关键字之间不分顺序，「前途 无量」与「无量 前途」的结果相同
+//! - 不少于 3 个字的关键字走索引（MATCH），按 bm25 排序
+//! - 少于 3 个字的关键字（中文常见）退化为对索引表的 LIKE 子串匹配
+
+use super::SAFCdb;
+use crate::Result;
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+/// 搜索的对象种类
+#[derive(Debug, EnumString, Display, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum SearchKind {
+    /// 客体（导师等），索引内容为 学校 学院 导师
+    Object,
+    /// 评价，索引内容为评价正文
+    Comment,
+}
+
+/// 一条搜索结果
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SearchHit {
+    pub kind: SearchKind,
+    /// 客体 id 或评价 id
+    pub id: String,
+    /// 所属的客体 id；客体自身即为 `id`，嵌套评价则为上级评价的 id
+    pub object: String,
+    /// 命中处附近的片段，纯文本
+    pub snippet: String,
+    /// bm25 相关度，越小越相关；没有可用索引的关键字时为 0
+    pub rank: f64,
+}
+
+/// 片段中命中处两侧保留的字数
+const SNIPPET_CONTEXT: usize = 24;
+
+impl SAFCdb {
+    /// 全文搜索，`query` 为空格分隔的关键字，结果按相关度排序，最多 `limit` 条
+    pub fn search(&self, query: &str, kind: SearchKind, limit: usize) -> Result<Vec<SearchHit>> {
+        let keys: Vec<&str> = query.split_whitespace().collect();
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let (long, short): (Vec<&str>, Vec<&str>) =
+            keys.iter().partition(|k| k.chars().count() >= 3);
+
+        let mut conds = vec![];
+        let mut params: Vec<Value> = vec![];
+        if !long.is_empty() {
+            conds.push("text MATCH ?".to_string());
+            params.push(Value::Text(fts_match_expr(&long)));
+        }
+        for k in &short {
+            conds.push("text LIKE ? ESCAPE '\\'".to_string());
+            params.push(Value::Text(format!("%{}%", escape_like(k))));
+        }
+        params.push(Value::Integer(limit as i64));
+
+        let (table, id_col, object_col) = match kind {
+            SearchKind::Object => ("objects_fts", "object", "object"),
+            SearchKind::Comment => ("comments_fts", "id", "object"),
+        };
+        let (rank, order) = if long.is_empty() {
+            ("0.0", "rowid")
+        } else {
+            ("rank", "rank")
+        };
+        let sql = format!(
+            "SELECT {id_col}, {object_col}, text, {rank} FROM {table} \
+            WHERE {} ORDER BY {order} LIMIT ?",
+            conds.join(" AND ")
+        );
+
+        let conn = self.pool.clone().get()?;
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+            Ok(SearchHit {
+                kind,
+                id: row.get(0)?,
+                object: row.get(1)?,
+                snippet: make_snippet(&row.get::<_, String>(2)?, &keys),
+                rank: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+}
+
+/// 构造 FTS5 的 MATCH 表达式：每个关键字作为一个短语，之间为 AND
+fn fts_match_expr(keys: &[&str]) -> String {
+    keys.iter()
+        .map(|k| format!("\"{}\"", k.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
+
+/// 转义 LIKE 中的通配符，配合 `ESCAPE '\'` 使用
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// 截取第一个命中的关键字附近的文字
+fn make_snippet(text: &str, keys: &[&str]) -> String {
+    let lower = text.to_lowercase();
+    let hit = keys
+        .iter()
+        .filter_map(|k| lower.find(&k.to_lowercase()))
+        .min()
+        .unwrap_or(0);
+    // to_lowercase 可能改变字节长度，所以这里用字符数定位
+    let hit = lower[..hit].chars().count();
+
+    let chars: Vec<char> = text.chars().collect();
+    let start = hit.saturating_sub(SNIPPET_CONTEXT);
+    let end = (hit + SNIPPET_CONTEXT * 2).min(chars.len());
+    format!(
+        "{}{}{}",
+        if start > 0 { "…" } else { "" },
+        chars[start..end].iter().collect::<String>(),
+        if end < chars.len() { "…" } else { "" }
+    )
+}
+
+#[test]
+fn test_search() {
+    use super::*;
+    let db = temp_db();
+    let u = "清华大学".to_string();
+    let d = "计算机系".to_string();
+    let s = "张三丰".to_string();
+    let object_id = hash_object_id(&u, &d, &s);
+    db.add_object(&ObjTeacher {
+        school_cate: "985".to_string(),
+        university: u,
+        department: d,
+        supervisor: s,
+        date: get_current_date(),
+        info: None,
+        object_id: object_id.clone(),
+    })
+    .unwrap();
+    for text in [
+        "学生前途无量，导师人很好",
+        "经费充足，但是前途一般",
+        "无关的评价",
+    ] {
+        db.add_comment(&ObjComment::new_with_otp(
+            object_id.clone(),
+            text.to_string(),
+            SourceCate::Admin,
+            CommentType::Teacher,
+            "otp".to_string(),
+        ))
+        .unwrap();
+    }
+
+    let hits = db.search("张三丰 清华", SearchKind::Object, 10).unwrap();
+    assert_eq!(1, hits.len());
+    assert_eq!(object_id, hits[0].id);
+
+    // 关键字不分顺序
+    let a = db.search("前途 无量", SearchKind::Comment, 10).unwrap();
+    let b = db.search("无量 前途", SearchKind::Comment, 10).unwrap();
+    assert_eq!(1, a.len());
+    assert_eq!(a[0].id, b[0].id);
+    assert_eq!(object_id, a[0].object);
+
+    // 长关键字走索引
+    let hits = db.search("前途无量", SearchKind::Comment, 10).unwrap();
+    assert_eq!(1, hits.len());
+    assert!(hits[0].rank < 0.0);
+
+    assert_eq!(2, db.search("前途", SearchKind::Comment, 10).unwrap().len());
+    assert!(db
+        .search("100%", SearchKind::Comment, 10)
+        .unwrap()
+        .is_empty());
+    assert!(db.search("  ", SearchKind::Comment, 10).unwrap().is_empty());
+}
+
+#[test]
+fn test_make_snippet() {
+    let text = "一".repeat(50) + "关键字" + &"二".repeat(80);
+    let s = make_snippet(&text, &["关键字"]);
+    assert!(s.starts_with('…') && s.ends_with('…'));
+    assert!(s.contains("关键字"));
+    assert_eq!("短文本", make_snippet("短文本", &["不存在"]));
+}
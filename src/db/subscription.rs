@@ -0,0 +1,257 @@
+//! # subscription
+//!
+//! telegram 会话关注客体，客体（或其嵌套评价）有新评价时汇总通知
+//!
+//! bot 与 web 发布的新评价由 [`crate::service`] 调用 [`SAFCdb::enqueue_comment_event`] 写入 comment_events 队列
+//! （见 [`super::migrate`] v7），导入、同步与合并写入的评价不入队。
+//! 通知方调用 [`SAFCdb::pending_digests`] 汇总，发送后再调用 [`SAFCdb::ack_comment_events`]。
+//! 发布人自己的会话不会因自己的评价收到通知。
+
+use super::{get_current_date, ObjTeacher, SAFCdb, OBJECT_COLUMNS};
+use crate::{Error, Result};
+
+/// 队列中的事件超过这么多天未被处理时清理，如只运行 web 的节点上没有通知任务
+pub const COMMENT_EVENT_TTL_DAYS: i64 = 7;
+
+/// 一个会话待发送的通知摘要
+#[derive(Debug, Clone)]
+pub struct Digest {
+    pub chat_id: i64,
+    /// 有新评价的客体及其新评价数（含嵌套评价）
+    pub objects: Vec<(ObjTeacher, usize)>,
+}
+
+impl SAFCdb {
+    /// 关注客体，客体不存在时返回 [`Error::NotFound`]；已关注时返回 `false`
+    pub fn subscribe(&self, chat_id: i64, object_id: &str) -> Result<bool> {
+        if self.find_objteacher_with_id(object_id)?.is_none() {
+            return Err(Error::NotFound(format!("客体 {object_id}")));
+        }
+        let conn = self.pool.clone().get()?;
+        let n = conn.execute(
+            "INSERT OR IGNORE INTO subscriptions (chat_id, object, date) VALUES (?1, ?2, ?3)",
+            rusqlite::params![chat_id, object_id, get_current_date()],
+        )?;
+        Ok(n > 0)
+    }
+
+    /// 取消关注，未关注时返回 `false`
+    pub fn unsubscribe(&self, chat_id: i64, object_id: &str) -> Result<bool> {
+        let conn = self.pool.clone().get()?;
+        let n = conn.execute(
+            "DELETE FROM subscriptions WHERE chat_id = ?1 AND object = ?2",
+            rusqlite::params![chat_id, object_id],
+        )?;
+        Ok(n > 0)
+    }
+
+    /// 会话关注的客体
+    pub fn find_subscriptions(&self, chat_id: i64) -> Result<Vec<ObjTeacher>> {
+        let conn = self.pool.clone().get()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {OBJECT_COLUMNS} FROM objects \
+            WHERE object IN (SELECT object FROM subscriptions WHERE chat_id = ?) \
+            ORDER BY university, department, supervisor"
+        ))?;
+        let rows = stmt.query_map([chat_id], ObjTeacher::from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// 关注客体的会话
+    pub fn find_subscribers(&self, object_id: &str) -> Result<Vec<i64>> {
+        let conn = self.pool.clone().get()?;
+        let mut stmt =
+            conn.prepare("SELECT chat_id FROM subscriptions WHERE object = ? ORDER BY chat_id")?;
+        let rows = stmt.query_map([object_id], |row| row.get(0))?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// 新评价入队，没有任何关注时（如不运行 bot 的节点）不入队
+    ///
+    /// `author_chat` 为在 bot 中发布评价的会话，汇总时不通知该会话；web 发布的评价为 `None`。
+    /// 同时清理超过 [`COMMENT_EVENT_TTL_DAYS`] 天未处理的事件，以免没有通知任务的节点上队列无限增长
+    pub fn enqueue_comment_event(&self, comment_id: &str, author_chat: Option<i64>) -> Result<()> {
+        let conn = self.pool.clone().get()?;
+        let now = chrono::Utc::now().timestamp();
+        conn.execute(
+            "DELETE FROM comment_events WHERE created < ?",
+            [now - COMMENT_EVENT_TTL_DAYS * 24 * 3600],
+        )?;
+        conn.execute(
+            "INSERT INTO comment_events (comment_id, created, author_chat) \
+            SELECT ?1, ?2, ?3 WHERE EXISTS (SELECT 1 FROM subscriptions)",
+            rusqlite::params![comment_id, now, author_chat],
+        )?;
+        Ok(())
+    }
+
+    /// 汇总最多 `limit` 条未处理的新评价，按会话生成通知摘要
+    ///
+    /// 同时返回已汇总的最后一个事件序号，通知发送后应以此调用 [`SAFCdb::ack_comment_events`]；
+    /// 没有新评价时为 `None`。找不到所属客体的评价会被忽略。
+    pub fn pending_digests(&self, limit: usize) -> Result<(Vec<Digest>, Option<i64>)> {
+        let conn = self.pool.clone().get()?;
+        let last_seq: Option<i64> = conn.query_row(
+            "SELECT MAX(seq) FROM (SELECT seq FROM comment_events ORDER BY seq LIMIT ?)",
+            [limit as i64],
+            |row| row.get(0),
+        )?;
+        let Some(last_seq) = last_seq else {
+            return Ok((vec![], None));
+        };
+
+        // 沿嵌套评价向上找到每个事件所属的客体，再按关注的会话与客体计数；depth 用于防止环形引用
+        let mut stmt = conn.prepare(&format!(
+            "WITH RECURSIVE up(seq, object, depth) AS ( \
+                SELECT e.seq, c.object, 0 FROM comment_events e \
+                JOIN comments c ON c.id = e.comment_id WHERE e.seq <= ?1 \
+                UNION ALL \
+                SELECT up.seq, c.object, up.depth + 1 FROM comments c \
+                JOIN up ON c.id = up.object WHERE up.depth < 64 \
+            ), \
+            roots AS ( \
+                SELECT s.chat_id, up.object AS root, COUNT(*) AS n, MIN(up.seq) AS first FROM up \
+                JOIN comment_events e ON e.seq = up.seq \
+                JOIN subscriptions s ON s.object = up.object \
+                WHERE e.author_chat IS NULL OR e.author_chat != s.chat_id \
+                GROUP BY s.chat_id, up.object \
+            ) \
+            SELECT roots.chat_id, roots.n, {OBJECT_COLUMNS} FROM roots \
+            JOIN objects ON objects.object = roots.root \
+            ORDER BY roots.chat_id, roots.first"
+        ))?;
+        let rows = stmt.query_map([last_seq], |row| {
+            Ok((
+                row.get::<_, i64>("chat_id")?,
+                row.get::<_, i64>("n")? as usize,
+                ObjTeacher::from_row(row)?,
+            ))
+        })?;
+
+        let mut digests: Vec<Digest> = vec![];
+        for row in rows {
+            let (chat_id, n, obj) = row?;
+            match digests.last_mut() {
+                Some(d) if d.chat_id == chat_id => d.objects.push((obj, n)),
+                _ => digests.push(Digest {
+                    chat_id,
+                    objects: vec![(obj, n)],
+                }),
+            }
+        }
+        Ok((digests, Some(last_seq)))
+    }
+
+    /// 删除序号不大于 `seq` 的已处理事件
+    pub fn ack_comment_events(&self, seq: i64) -> Result<usize> {
+        let conn = self.pool.clone().get()?;
+        Ok(conn.execute("DELETE FROM comment_events WHERE seq <= ?", [seq])?)
+    }
+}
+
+#[test]
+fn test_subscriptions() {
+    use super::*;
+    let db = temp_db();
+    let t = temp_object(&db);
+    assert!(matches!(
+        db.subscribe(1, "nonexistent"),
+        Err(Error::NotFound(_))
+    ));
+    assert!(db.subscribe(1, &t.object_id).unwrap());
+    assert!(!db.subscribe(1, &t.object_id).unwrap());
+    assert!(db.subscribe(2, &t.object_id).unwrap());
+    assert_eq!(vec![1, 2], db.find_subscribers(&t.object_id).unwrap());
+    assert_eq!("张三", db.find_subscriptions(1).unwrap()[0].supervisor);
+
+    assert_eq!(None, db.pending_digests(100).unwrap().1);
+    let new_comment = |object: &str, text: &str, ty| {
+        let c = ObjComment::new_with_otp(
+            object.to_string(),
+            text.to_string(),
+            SourceCate::Web,
+            ty,
+            "otp".to_string(),
+        );
+        db.add_comment(&c).unwrap();
+        db.enqueue_comment_event(&c.id, None).unwrap();
+        c.id
+    };
+    let c = new_comment(&t.object_id, "评价", CommentType::Teacher);
+    new_comment(&c, "回复", CommentType::Nest);
+    new_comment("orphan", "找不到客体", CommentType::Teacher);
+
+    // 一连串的新评价汇总为每个会话一条摘要
+    let (digests, last_seq) = db.pending_digests(100).unwrap();
+    assert_eq!(2, digests.len());
+    assert_eq!(1, digests[0].chat_id);
+    assert_eq!(1, digests[0].objects.len());
+    assert_eq!(2, digests[0].objects[0].1);
+    assert_eq!(3, db.ack_comment_events(last_seq.unwrap()).unwrap());
+    assert!(db.pending_digests(100).unwrap().0.is_empty());
+
+    // 发布人自己的会话不会收到通知
+    let c = crate::service::post_rated_comment(
+        &db,
+        &t.object_id,
+        "会话 1 发布的评价",
+        None,
+        SourceCate::Telegram,
+        "otp",
+        Some(1),
+    )
+    .unwrap();
+    crate::service::reply_to_comment(
+        &db,
+        &c.id,
+        "会话 1 的回复",
+        SourceCate::Telegram,
+        "otp",
+        Some(1),
+    )
+    .unwrap();
+    let (digests, last_seq) = db.pending_digests(100).unwrap();
+    assert_eq!(1, digests.len());
+    assert_eq!((2, 2), (digests[0].chat_id, digests[0].objects[0].1));
+    db.ack_comment_events(last_seq.unwrap()).unwrap();
+
+    // 导入、同步写入的评价不入队
+    let imported = ObjComment::new_with_otp(
+        t.object_id.clone(),
+        "导入的评价".to_string(),
+        SourceCate::Admin,
+        CommentType::Teacher,
+        String::new(),
+    );
+    db.add_comment(&imported).unwrap();
+    assert_eq!(None, db.pending_digests(100).unwrap().1);
+
+    // 长期无人处理的事件在下次入队时清理
+    let count = || -> i64 {
+        db.pool
+            .get()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM comment_events", [], |row| row.get(0))
+            .unwrap()
+    };
+    db.pool
+        .get()
+        .unwrap()
+        .execute(
+            "INSERT INTO comment_events (comment_id, created) VALUES ('stale', 0)",
+            [],
+        )
+        .unwrap();
+    new_comment(&t.object_id, "新评价", CommentType::Teacher);
+    assert_eq!(1, count());
+
+    assert!(db.unsubscribe(1, &t.object_id).unwrap());
+    assert!(!db.unsubscribe(1, &t.object_id).unwrap());
+    assert!(db.find_subscriptions(1).unwrap().is_empty());
+
+    // 没有任何关注时不入队
+    db.ack_comment_events(i64::MAX).unwrap();
+    assert!(db.unsubscribe(2, &t.object_id).unwrap());
+    new_comment(&t.object_id, "无人关注", CommentType::Teacher);
+    assert_eq!(0, count());
+}
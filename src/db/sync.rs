@@ -0,0 +1,984 @@
+//! # sync
+//!
+//! 弱中心节点之间的数据库同步
+//!
+//! 每个节点的哈希链（见 [`super::chain`]）按序号记录了所有写入，序号即同步的游标：
+//! [`SAFCdb::sync_changes`] 返回游标之后被写入的客体、评价的当前状态与客体的合并，
+//! 由 web 端以 JSONL 的形式提供（`GET /api/sync/changes?since=<游标>`，见 [`to_jsonl`]），
+//! `safc_sync` 定期拉取各个对端，用 [`SAFCdb::apply_sync`] 合并，并记下每个对端的游标。
+//!
+//! 客体与评价按内容计算的 id（[`crate::sec::hash_object_id`]、[`crate::sec::hash_comment_id`]）合并，
+//! 本地没有的直接写入；同一 id 的冲突按以下确定的规则处理，各节点来回同步后最终一致：
+//!
+//! - 客体：日期与信息按 [`MergePolicy::Converge`] 合并，学校类别不同时只记为冲突，不修改
+//! - 评价：修改时间（最后一次修改、撤回、隐藏或恢复的时间，随评价记在链中）较晚的优先；
+//!   相同时被撤回或隐藏的优先；再相同时取正文、墓碑与评星的 json 字典序较大的
+//! - 合并：两个客体在本地都存在时同样合并
+//!
+//! 对端不受信任：本地没有的评价只在 id 与内容相符时写入（被撤回或隐藏的评价没有正文，不做校验，正文一律置空）；
+//! 已有的评价只采用对端的正文、墓碑与评星，所属客体与签名以本地为准；本地隐藏的评价不会因同步而恢复。
+//! 任何对端都可以把修改时间填成现在，所以默认只接受对端的撤回与隐藏（本地的正文存入修订历史，管理员可以恢复），
+//! 修改与恢复只接受运行者指定的可信对端的（见 [`PeerTrust`]）。
+//!
+//! 离线合并两个数据库文件时使用其他策略，见 [`super::merge`]。
+//!
+//! 从对端写入或更新的数据记在 sync_provenance 表中（见 [`super::migrate`] v12），见 [`SAFCdb::find_provenance`]。
+
+use std::collections::HashSet;
+use std::fmt;
+
+use rusqlite::{params, OptionalExtension, Transaction, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+
+use super::chain::{self, ChainComment, ChainObject, ChainOp, CHAIN_COMMENT_SQL, CHAIN_OBJECT_SQL};
+use super::merge::{FieldConflict, MergePolicy};
+use super::{
+    admin, archive_comment, get_current_date, insert_comment, insert_object, CommentType,
+    ObjComment, ObjTeacher, RevisionAction, SAFCdb, SourceCate, Tombstone, TombstoneKind,
+};
+use crate::sec::{hash_comment_id, hash_object_id};
+use crate::{Error, Result};
+
+/// 单次请求最多包含的链上记录数
+pub const SYNC_PAGE_SIZE: usize = 1000;
+
+/// 允许对端的时钟比本地快的时间（秒），修改时间更晚的评价被跳过
+const MAX_CLOCK_SKEW_SECS: i64 = 10 * 60;
+
+/// 对端能否改变本地已有的评价
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerTrust {
+    /// 只接受撤回与隐藏
+    #[default]
+    TombstoneOnly,
+    /// 还接受修改与恢复，用于运行者指定的对端（`safc_sync --trusted`）与离线合并
+    Trusted,
+}
+
+/// 同步的一条记录，即 JSONL 中的一行
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SyncRecord {
+    Object(ChainObject),
+    Comment(SyncComment),
+    /// 客体 `from` 被合并到 `into`
+    Merge {
+        from: String,
+        into: String,
+    },
+    /// 最后一行：`seq` 为下次请求的游标，`head` 为对端的链头序号，`seq < head` 时还有更多记录
+    Cursor {
+        seq: i64,
+        head: i64,
+    },
+}
+
+/// 同步的评价：评价的当前状态
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncComment {
+    #[serde(flatten)]
+    pub comment: ChainComment,
+    /// 直接或间接合并到评价所属客体的客体，评价最初属于其中之一时，对端据此校验评价的 id
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged_from: Vec<String>,
+}
+
+impl SyncComment {
+    /// 冲突时比较的优先级，见模块说明
+    pub(super) fn precedence(&self) -> (Option<&str>, bool, String) {
+        let c = &self.comment;
+        let content = (
+            &c.description,
+            &c.tombstone,
+            &c.tombstone_reason,
+            &c.tombstone_date,
+            &c.ratings,
+        );
+        (
+            c.edited.as_deref(),
+            c.tombstone.is_some(),
+            serde_json::to_string(&content).expect("评价可以序列化"),
+        )
+    }
+}
+
+/// 将记录编码为 JSONL
+pub fn to_jsonl(records: &[SyncRecord]) -> String {
+    let mut s = String::new();
+    for r in records {
+        s.push_str(&serde_json::to_string(r).expect("同步记录可以序列化"));
+        s.push('\n');
+    }
+    s
+}
+
+/// 解析 JSONL，任一行无法解析时返回 [`Error::Validation`]
+pub fn parse_jsonl(input: &str) -> Result<Vec<SyncRecord>> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| {
+            serde_json::from_str(l).map_err(|e| Error::Validation(format!("第 {} 行：{e}", i + 1)))
+        })
+        .collect()
+}
+
+/// 从对端写入或更新数据的记录
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Provenance {
+    pub target: String,
+    /// object 或 comment
+    pub kind: String,
+    /// insert、update 或 merge
+    pub action: String,
+    pub peer: String,
+    pub date: String,
+}
+
+/// [`SAFCdb::apply_sync`] 的结果
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    pub objects_inserted: usize,
+    pub objects_updated: usize,
+    pub merges: usize,
+    pub comments_inserted: usize,
+    pub comments_updated: usize,
+    /// 与对端冲突、按规则保留本地版本的评价数
+    pub comments_kept: usize,
+    pub unchanged: usize,
+    /// 同一客体两边不同的字段
+    pub conflicts: Vec<FieldConflict>,
+    /// 被跳过的记录及原因
+    pub problems: Vec<String>,
+    /// 保存的新游标
+    pub cursor: Option<i64>,
+}
+
+impl SyncReport {
+    /// 写入或更新了数据
+    pub fn changed(&self) -> usize {
+        self.objects_inserted
+            + self.objects_updated
+            + self.merges
+            + self.comments_inserted
+            + self.comments_updated
+    }
+
+    /// 累加另一批记录的结果，游标取后者
+    pub fn extend(&mut self, other: SyncReport) {
+        self.objects_inserted += other.objects_inserted;
+        self.objects_updated += other.objects_updated;
+        self.merges += other.merges;
+        self.comments_inserted += other.comments_inserted;
+        self.comments_updated += other.comments_updated;
+        self.comments_kept += other.comments_kept;
+        self.unchanged += other.unchanged;
+        self.conflicts.extend(other.conflicts);
+        self.problems.extend(other.problems);
+        self.cursor = other.cursor.or(self.cursor);
+    }
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "客体：新增 {}，更新 {}，合并 {}；评价：新增 {}，更新 {}，保留本地 {}；未变 {}",
+            self.objects_inserted,
+            self.objects_updated,
+            self.merges,
+            self.comments_inserted,
+            self.comments_updated,
+            self.comments_kept,
+            self.unchanged
+        )?;
+        for c in &self.conflicts {
+            writeln!(f, "- {c}")?;
+        }
+        for p in &self.problems {
+            writeln!(f, "- 跳过：{p}")?;
+        }
+        Ok(())
+    }
+}
+
+/// 链上的全部客体合并，按先后顺序
+fn chain_merges(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<chain::Merge>> {
+    let mut stmt = conn.prepare("SELECT payload FROM chain_log WHERE op = ? ORDER BY seq")?;
+    let payloads = stmt
+        .query_map([ChainOp::MergeObjects.to_string()], |row| {
+            row.get::<_, String>(0)
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(payloads
+        .iter()
+        .filter_map(|p| match serde_json::from_str::<chain::Merge>(p) {
+            Ok(m) => Some(m),
+            Err(e) => {
+                log::warn!("链上的合并记录无法解析：{e}");
+                None
+            }
+        })
+        .collect())
+}
+
+/// 直接或间接合并到 `object` 的客体
+fn merged_from(merges: &[chain::Merge], object: &str) -> Vec<String> {
+    let mut found = vec![object.to_string()];
+    let mut i = 0;
+    while i < found.len() {
+        let into = found[i].clone();
+        for m in merges.iter().filter(|m| m.into == into) {
+            if !found.contains(&m.from) {
+                found.push(m.from.clone());
+            }
+        }
+        i += 1;
+    }
+    found.split_off(1)
+}
+
+fn record_provenance(
+    tx: &Transaction,
+    target: &str,
+    kind: &str,
+    action: &str,
+    peer: &str,
+) -> Result<()> {
+    tx.execute(
+        "INSERT INTO sync_provenance (target, kind, action, peer, date) VALUES (?, ?, ?, ?, ?)",
+        params![target, kind, action, peer, get_current_date()],
+    )?;
+    Ok(())
+}
+
+fn object_exists(tx: &Transaction, id: &str) -> rusqlite::Result<bool> {
+    tx.query_row(
+        "SELECT COUNT(*) FROM subjects WHERE object = ?",
+        [id],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|n| n > 0)
+}
+
+/// 将对端的评价转换为 [`ObjComment`]，枚举无法识别时返回说明
+fn to_obj_comment(c: &ChainComment) -> std::result::Result<ObjComment, String> {
+    let unknown = |field: &str, v: &str| format!("评价 {} 的{field} {v} 无法识别", c.id);
+    let tombstone = match (&c.tombstone, &c.tombstone_date) {
+        (Some(kind), Some(date)) => Some(Tombstone {
+            kind: kind
+                .parse::<TombstoneKind>()
+                .map_err(|_| unknown("墓碑", kind))?,
+            reason: c.tombstone_reason.clone(),
+            date: date.clone(),
+        }),
+        (None, _) => None,
+        (Some(_), None) => return Err(format!("评价 {} 的墓碑缺少日期", c.id)),
+    };
+    if let Some(r) = &c.ratings {
+        r.validate().map_err(|e| format!("评价 {}：{e}", c.id))?;
+    }
+    // 修改时间按字典序决定同步时的取舍，只接受与 [`super::get_current_time`] 格式相同、不晚于现在的时间
+    if let Some(edited) = &c.edited {
+        let latest = chrono::Utc::now() + chrono::Duration::seconds(MAX_CLOCK_SKEW_SECS);
+        match chrono::DateTime::parse_from_rfc3339(edited) {
+            Ok(t)
+                if t <= latest
+                    && t.with_timezone(&chrono::Utc)
+                        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                        == *edited => {}
+            _ => return Err(format!("评价 {} 的修改时间 {edited} 无效", c.id)),
+        }
+    }
+    Ok(ObjComment {
+        object: c.object.clone(),
+        description: c.description.clone(),
+        date: c.date.clone(),
+        source_cate: c
+            .source_cate
+            .parse::<SourceCate>()
+            .map_err(|_| unknown("来源分类", &c.source_cate))?,
+        comment_type: c
+            .comment_type
+            .parse::<CommentType>()
+            .map_err(|_| unknown("评价类型", &c.comment_type))?,
+        author_sign: c.author_sign.clone(),
+        id: c.id.clone(),
+        tombstone,
+        ratings: c.ratings,
+        edited: c.edited.clone(),
+    })
+}
+
+impl SAFCdb {
+    /// 链上序号大于 `since` 的至多 `limit` 条记录所涉及的客体、评价的当前状态与客体的合并，
+    /// 按首次出现的顺序，最后一条为 [`SyncRecord::Cursor`]
+    ///
+    /// 已被合并或删除的客体、评价不再返回
+    pub fn sync_changes(&self, since: i64, limit: usize) -> Result<Vec<SyncRecord>> {
+        let conn = self.pool.clone().get()?;
+        let head = chain::head(&conn)?.map_or(0, |h| h.seq);
+        let mut stmt = conn.prepare(
+            "SELECT seq, op, target, payload FROM chain_log WHERE seq > ? ORDER BY seq LIMIT ?",
+        )?;
+        let entries = stmt
+            .query_map(params![since, limit as i64], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let merges = chain_merges(&conn)?;
+        let mut cursor = since;
+        let mut objects = HashSet::new();
+        let mut comments = HashSet::new();
+        let mut records = vec![];
+        for (seq, op, target, payload) in entries {
+            cursor = seq;
+            match op.parse::<ChainOp>() {
+                Ok(ChainOp::MergeObjects) => match serde_json::from_str::<chain::Merge>(&payload) {
+                    Ok(m) => records.push(SyncRecord::Merge {
+                        from: m.from,
+                        into: m.into,
+                    }),
+                    Err(e) => log::warn!("链上第 {seq} 条记录无法解析：{e}"),
+                },
+                Ok(ChainOp::AddObject | ChainOp::UpdateObject) => {
+                    if !objects.insert(target.clone()) {
+                        continue;
+                    }
+                    let o = conn
+                        .query_row(
+                            &format!("{CHAIN_OBJECT_SQL} WHERE object = ?"),
+                            [&target],
+                            ChainObject::from_row,
+                        )
+                        .optional()?;
+                    records.extend(o.map(SyncRecord::Object));
+                }
+                Ok(_) => {
+                    if !comments.insert(target.clone()) {
+                        continue;
+                    }
+                    let c = conn
+                        .query_row(
+                            &format!("{CHAIN_COMMENT_SQL} WHERE id = ?"),
+                            [&target],
+                            ChainComment::from_row,
+                        )
+                        .optional()?;
+                    if let Some(comment) = c {
+                        records.push(SyncRecord::Comment(SyncComment {
+                            merged_from: merged_from(&merges, &comment.object),
+                            comment,
+                        }));
+                    }
+                }
+                Err(_) => log::warn!("链上第 {seq} 条记录的操作 {op} 无法识别"),
+            }
+        }
+        records.push(SyncRecord::Cursor { seq: cursor, head });
+        Ok(records)
+    }
+
+    /// 全部客体、客体的合并与全部评价，包括不在哈希链中的数据，不含游标，供离线合并使用
+    pub fn sync_all(&self) -> Result<Vec<SyncRecord>> {
+        let conn = self.pool.clone().get()?;
+        let mut records = vec![];
+        let mut stmt = conn.prepare(&format!("{CHAIN_OBJECT_SQL} ORDER BY object"))?;
+        for o in stmt.query_map([], ChainObject::from_row)? {
+            records.push(SyncRecord::Object(o?));
+        }
+        let merges = chain_merges(&conn)?;
+        for m in &merges {
+            records.push(SyncRecord::Merge {
+                from: m.from.clone(),
+                into: m.into.clone(),
+            });
+        }
+        let mut stmt = conn.prepare(&format!("{CHAIN_COMMENT_SQL} ORDER BY rowid"))?;
+        let comments = stmt
+            .query_map([], ChainComment::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for comment in comments {
+            records.push(SyncRecord::Comment(SyncComment {
+                merged_from: merged_from(&merges, &comment.object),
+                comment,
+            }));
+        }
+        Ok(records)
+    }
+
+    /// 上次从对端 `peer` 同步到的游标，从未同步时为 0
+    pub fn sync_cursor(&self, peer: &str) -> Result<i64> {
+        let conn = self.pool.clone().get()?;
+        Ok(conn
+            .query_row(
+                "SELECT cursor FROM sync_peers WHERE peer = ?",
+                [peer],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0))
+    }
+
+    /// 在一个事务中合并从对端 `peer` 拉取的记录，并保存其中的游标
+    ///
+    /// 先写入客体，再合并客体，最后写入评价；无法合并的记录跳过并记在 [`SyncReport::problems`] 中
+    pub fn apply_sync(
+        &self,
+        peer: &str,
+        trust: PeerTrust,
+        records: &[SyncRecord],
+    ) -> Result<SyncReport> {
+        self.apply_sync_with(peer, trust, records, MergePolicy::Converge)
+    }
+
+    /// 同 [`SAFCdb::apply_sync`]，同一 id 在两边不同时按 `policy` 取舍
+    pub fn apply_sync_with(
+        &self,
+        peer: &str,
+        trust: PeerTrust,
+        records: &[SyncRecord],
+        policy: MergePolicy,
+    ) -> Result<SyncReport> {
+        let mut conn = self.pool.clone().get()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut report = SyncReport::default();
+        for r in records {
+            if let SyncRecord::Object(o) = r {
+                apply_object(&tx, peer, o, policy, &mut report)?;
+            }
+        }
+        for r in records {
+            if let SyncRecord::Merge { from, into } = r {
+                apply_merge(&tx, peer, from, into, &mut report)?;
+            }
+        }
+        for r in records {
+            if let SyncRecord::Comment(c) = r {
+                apply_comment(&tx, peer, trust, c, policy, &mut report)?;
+            }
+        }
+        if let Some(seq) = records.iter().rev().find_map(|r| match r {
+            SyncRecord::Cursor { seq, .. } => Some(*seq),
+            _ => None,
+        }) {
+            tx.execute(
+                "INSERT INTO sync_peers (peer, cursor, updated) VALUES (?1, ?2, ?3) \
+                ON CONFLICT (peer) DO UPDATE SET cursor = ?2, updated = ?3",
+                params![peer, seq, get_current_date()],
+            )?;
+            report.cursor = Some(seq);
+        }
+        tx.commit()?;
+        Ok(report)
+    }
+
+    /// 客体或评价从对端写入、更新或合并的记录，按时间顺序
+    pub fn find_provenance(&self, target: &str) -> Result<Vec<Provenance>> {
+        let conn = self.pool.clone().get()?;
+        let mut stmt = conn.prepare(
+            "SELECT target, kind, action, peer, date FROM sync_provenance \
+            WHERE target = ? ORDER BY id",
+        )?;
+        let rows = stmt.query_map([target], |row| {
+            Ok(Provenance {
+                target: row.get(0)?,
+                kind: row.get(1)?,
+                action: row.get(2)?,
+                peer: row.get(3)?,
+                date: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+}
+
+fn apply_object(
+    tx: &Transaction,
+    peer: &str,
+    o: &ChainObject,
+    policy: MergePolicy,
+    report: &mut SyncReport,
+) -> Result<()> {
+    if hash_object_id(&o.university, &o.department, &o.supervisor) != o.object {
+        report
+            .problems
+            .push(format!("客体 {} 的 id 与内容不符", o.object));
+        return Ok(());
+    }
+    let local = tx
+        .query_row(
+            &format!("{CHAIN_OBJECT_SQL} WHERE object = ?"),
+            [&o.object],
+            ChainObject::from_row,
+        )
+        .optional()?;
+    let Some(local) = local else {
+        insert_object(
+            tx,
+            &ObjTeacher {
+                school_cate: o.school_cate.clone(),
+                university: o.university.clone(),
+                department: o.department.clone(),
+                supervisor: o.supervisor.clone(),
+                date: o.date.clone(),
+                info: o.info.clone(),
+                object_id: o.object.clone(),
+            },
+        )?;
+        record_provenance(tx, &o.object, "object", "insert", peer)?;
+        report.objects_inserted += 1;
+        return Ok(());
+    };
+    if (&local.university, &local.department, &local.supervisor)
+        != (&o.university, &o.department, &o.supervisor)
+    {
+        report
+            .problems
+            .push(format!("客体 {} 的层级与本地不同", o.object));
+        return Ok(());
+    }
+    let (merged, conflicts) = policy.resolve_object(&local, o);
+    report.conflicts.extend(conflicts);
+    if merged == local {
+        report.unchanged += 1;
+        return Ok(());
+    }
+    tx.execute(
+        "UPDATE objects SET school_cate = ?, date = ?, info = ? WHERE object = ?",
+        params![merged.school_cate, merged.date, merged.info, o.object],
+    )?;
+    chain::append_update_object(tx, &merged)?;
+    record_provenance(tx, &o.object, "object", "update", peer)?;
+    report.objects_updated += 1;
+    Ok(())
+}
+
+fn apply_merge(
+    tx: &Transaction,
+    peer: &str,
+    from: &str,
+    into: &str,
+    report: &mut SyncReport,
+) -> Result<()> {
+    if from == into || !object_exists(tx, from)? {
+        report.unchanged += 1;
+        return Ok(());
+    }
+    if !object_exists(tx, into)? {
+        report
+            .problems
+            .push(format!("合并的目标客体 {into} 在本地不存在"));
+        return Ok(());
+    }
+    admin::merge_in(tx, from, into)?;
+    record_provenance(tx, from, "object", "merge", peer)?;
+    report.merges += 1;
+    Ok(())
+}
+
+fn apply_comment(
+    tx: &Transaction,
+    peer: &str,
+    trust: PeerTrust,
+    remote: &SyncComment,
+    policy: MergePolicy,
+    report: &mut SyncReport,
+) -> Result<()> {
+    let mut remote = remote.clone();
+    // 被撤回或隐藏的评价不公开正文与评星，见 [`SAFCdb::tombstone_comment`]
+    if remote.comment.tombstone.is_some() {
+        remote.comment.description.clear();
+    }
+    remote.comment.ratings = remote
+        .comment
+        .ratings
+        .filter(|r| !r.is_empty() && remote.comment.tombstone.is_none());
+    let c = &remote.comment;
+    let obj_comment = match to_obj_comment(c) {
+        Ok(o) => o,
+        Err(e) => {
+            report.problems.push(e);
+            return Ok(());
+        }
+    };
+    let local = tx
+        .query_row(
+            &format!("{CHAIN_COMMENT_SQL} WHERE id = ?"),
+            [&c.id],
+            ChainComment::from_row,
+        )
+        .optional()?;
+    let Some(local) = local else {
+        return insert_remote_comment(tx, peer, &remote, &obj_comment, report);
+    };
+    // 对端只能改变正文、墓碑、评星与修改日期，所属客体、签名等以本地为准
+    let theirs = SyncComment {
+        comment: ChainComment {
+            description: c.description.clone(),
+            tombstone: c.tombstone.clone(),
+            tombstone_reason: c.tombstone_reason.clone(),
+            tombstone_date: c.tombstone_date.clone(),
+            ratings: c.ratings,
+            edited: c.edited.clone(),
+            ..local.clone()
+        },
+        ..remote.clone()
+    };
+    let c = &theirs.comment;
+    if local == *c {
+        report.unchanged += 1;
+        return Ok(());
+    }
+    // 本地的隐藏不会被对端撤销
+    let moderated = Some(TombstoneKind::Moderated.to_string());
+    if local.tombstone == moderated && c.tombstone != moderated {
+        report.comments_kept += 1;
+        return Ok(());
+    }
+    let local = SyncComment {
+        comment: local,
+        merged_from: vec![],
+    };
+    if !policy.prefer_theirs(&local, &theirs) {
+        report.comments_kept += 1;
+        return Ok(());
+    }
+    if trust == PeerTrust::TombstoneOnly
+        && (local.comment.tombstone.is_some() || c.tombstone.is_none())
+    {
+        report.problems.push(format!(
+            "对端 {peer} 不是可信对端，只接受其撤回与隐藏，未采用评价 {} 的修改",
+            c.id
+        ));
+        return Ok(());
+    }
+    // 本地的正文存入修订历史，以便日后恢复
+    if local.comment.tombstone.is_none() && local.comment.description != c.description {
+        let action = match obj_comment.tombstone.as_ref().map(|t| t.kind) {
+            None => RevisionAction::Edit,
+            Some(TombstoneKind::Retracted) => RevisionAction::Retract,
+            Some(TombstoneKind::Moderated) => RevisionAction::Moderate,
+        };
+        archive_comment(tx, &c.id, action)?;
+    }
+    let ratings = c.ratings.unwrap_or_default();
+    tx.execute(
+        "UPDATE comments SET description = ?, \
+        tombstone = ?, tombstone_reason = ?, tombstone_date = ?, \
+        rating_academic = ?, rating_funding = ?, rating_relationship = ?, \
+        rating_prospects = ?, rating_stipend = ?, edited = ? WHERE id = ?",
+        params![
+            c.description,
+            c.tombstone,
+            c.tombstone_reason,
+            c.tombstone_date,
+            ratings.academic,
+            ratings.funding,
+            ratings.relationship,
+            ratings.prospects,
+            ratings.stipend,
+            c.edited,
+            c.id
+        ],
+    )?;
+    chain::append_comment(tx, ChainOp::SyncComment, &c.id)?;
+    record_provenance(tx, &c.id, "comment", "update", peer)?;
+    report.comments_updated += 1;
+    Ok(())
+}
+
+/// 写入本地没有的评价
+///
+/// 评价的 id 须与其内容相符（所属客体可以是合并前的客体），被撤回或隐藏的评价没有正文，不做校验。
+/// 评价最初所属的客体在本地不存在时，将这次合并补录到链中，以便转发给其他节点时同样可以校验
+fn insert_remote_comment(
+    tx: &Transaction,
+    peer: &str,
+    remote: &SyncComment,
+    obj_comment: &ObjComment,
+    report: &mut SyncReport,
+) -> Result<()> {
+    let c = &remote.comment;
+    let origin = std::iter::once(&c.object)
+        .chain(&remote.merged_from)
+        .find(|o| hash_comment_id(o, &c.description, &c.date) == c.id);
+    match origin {
+        None if c.tombstone.is_none() => {
+            report
+                .problems
+                .push(format!("评价 {} 的 id 与内容不符", c.id));
+            return Ok(());
+        }
+        Some(o) if *o != c.object && !object_exists(tx, o)? => {
+            chain::append_merge(tx, o, &c.object)?;
+        }
+        _ => {}
+    }
+    insert_comment(tx, obj_comment)?;
+    record_provenance(tx, &c.id, "comment", "insert", peer)?;
+    report.comments_inserted += 1;
+    Ok(())
+}
+
+/// 将 `from` 的全部记录分页拉取到 `to`，返回各页的结果
+#[cfg(test)]
+fn pull(to: &SAFCdb, from: &SAFCdb, peer: &str, page: usize) -> Vec<SyncReport> {
+    let mut reports = vec![];
+    loop {
+        let since = to.sync_cursor(peer).unwrap();
+        let jsonl = to_jsonl(&from.sync_changes(since, page).unwrap());
+        let records = parse_jsonl(&jsonl).unwrap();
+        reports.push(to.apply_sync(peer, PeerTrust::Trusted, &records).unwrap());
+        match records.last() {
+            Some(SyncRecord::Cursor { seq, head }) if seq < head => {}
+            _ => return reports,
+        }
+    }
+}
+
+#[test]
+fn test_sync() {
+    use crate::db::{temp_db, temp_object};
+    use crate::service::*;
+
+    let a = temp_db();
+    let b = temp_db();
+    let t = temp_object(&a);
+    let t2 = create_object(&a, "985", "清华大学", "self", "张三三").unwrap();
+    let moved = post_comment(&a, &t2.object_id, "合并前的评价", SourceCate::Web, "otp").unwrap();
+    a.merge_objects(&t2.object_id, &t.object_id).unwrap();
+    let hidden = post_comment(&a, &t.object_id, "广告", SourceCate::Web, "otp").unwrap();
+    moderate_comment(&a, &hidden.id, Some("广告")).unwrap();
+    let reply = reply_to_comment(&a, &moved.id, "回复", SourceCate::Telegram, "otp", None).unwrap();
+    // 两个节点上同一条评价（同一天的同样内容）被分别编辑
+    let same_a = post_comment(&a, &t.object_id, "同样的评价", SourceCate::Web, "otp").unwrap();
+    let b_t = temp_object(&b);
+    assert_eq!(t.object_id, b_t.object_id);
+    let same_b = post_comment(&b, &t.object_id, "同样的评价", SourceCate::Web, "otp").unwrap();
+    assert_eq!(same_a.id, same_b.id);
+    edit_comment(&a, &same_a.id, "甲节点的修改", "otp").unwrap();
+    edit_comment(&b, &same_b.id, "乙节点的修改", "otp").unwrap();
+    let mut li = new_object("985", "北京大学", "self", "李四").unwrap();
+    li.info = Some("乙节点的信息".to_string());
+    b.add_object(&li).unwrap();
+
+    // 分页拉取
+    let reports = pull(&b, &a, "http://a", 3);
+    assert!(reports.len() > 1);
+    assert!(reports.iter().all(|r| r.problems.is_empty()));
+    let merged: usize = reports.iter().map(|r| r.merges).sum();
+    assert_eq!(0, merged, "张三三 未曾同步到乙节点，无需合并");
+    pull(&a, &b, "http://b", SYNC_PAGE_SIZE);
+    // 来回拉取直到不再变化
+    for _ in 0..3 {
+        pull(&b, &a, "http://a", SYNC_PAGE_SIZE);
+        pull(&a, &b, "http://b", SYNC_PAGE_SIZE);
+    }
+    let again = pull(&b, &a, "http://a", SYNC_PAGE_SIZE);
+    assert_eq!(0, again.iter().map(SyncReport::changed).sum::<usize>());
+
+    // 两个节点一致
+    let state = |db: &SAFCdb| {
+        let mut objects: Vec<_> = db
+            .find_all_objects()
+            .unwrap()
+            .iter()
+            .map(ChainObject::from)
+            .collect();
+        objects.sort_by(|x, y| x.object.cmp(&y.object));
+        let mut comments: Vec<_> = db
+            .find_all_comments()
+            .unwrap()
+            .iter()
+            .map(ChainComment::from)
+            .collect();
+        comments.sort_by(|x, y| x.id.cmp(&y.id));
+        (objects, comments)
+    };
+    assert_eq!(state(&a), state(&b));
+    let (objects, comments) = state(&a);
+    assert_eq!(2, objects.len());
+    assert_eq!(4, comments.len());
+    // 修改日期相同，取 json 字典序较大的修改
+    let same = b.find_comment_with_id(&same_b.id).unwrap().unwrap();
+    assert_eq!("甲节点的修改", same.description);
+    assert!(objects
+        .iter()
+        .any(|o| o.info.as_deref() == Some("乙节点的信息")));
+    assert_eq!(
+        TombstoneKind::Moderated,
+        b.find_comment_with_id(&hidden.id)
+            .unwrap()
+            .unwrap()
+            .tombstone
+            .unwrap()
+            .kind
+    );
+    assert_eq!(
+        t.object_id,
+        b.find_comment_with_id(&moved.id).unwrap().unwrap().object
+    );
+    // 同步的写入同样入链
+    assert!(a.verify_chain().unwrap().is_valid());
+    assert!(b.verify_chain().unwrap().is_valid());
+
+    // 来源
+    let p = b.find_provenance(&hidden.id).unwrap();
+    assert_eq!(1, p.len());
+    assert_eq!(
+        ("insert", "http://a"),
+        (p[0].action.as_str(), p[0].peer.as_str())
+    );
+    let p = b.find_provenance(&same_b.id).unwrap();
+    assert_eq!(
+        vec!["update"],
+        p.iter().map(|p| p.action.as_str()).collect::<Vec<_>>()
+    );
+
+    // 之后的合并也会同步
+    let t3 = create_object(&a, "985", "清华大学", "self", "张三丰").unwrap();
+    pull(&b, &a, "http://a", SYNC_PAGE_SIZE);
+    a.merge_objects(&t3.object_id, &t.object_id).unwrap();
+    let r = pull(&b, &a, "http://a", SYNC_PAGE_SIZE);
+    assert_eq!(1, r.iter().map(|r| r.merges).sum::<usize>());
+    assert!(b.find_objteacher_with_id(&t3.object_id).unwrap().is_none());
+
+    // id 与内容不符的客体被跳过
+    let mut forged: ChainObject = (&t).into();
+    forged.supervisor = "伪造".to_string();
+    let r = b
+        .apply_sync(
+            "http://evil",
+            PeerTrust::TombstoneOnly,
+            &[SyncRecord::Object(forged)],
+        )
+        .unwrap();
+    assert_eq!(1, r.problems.len());
+    assert!(parse_jsonl("{\"kind\":\"object\"}").is_err());
+
+    // 对端不能写入 id 与内容不符的评价，不能改变评价的客体与签名，也不能撤销本地的隐藏；
+    // 修改时间较晚的优先，但不能是将来的时间；不可信的对端只能撤回或隐藏评价
+    let now = crate::db::get_current_time();
+    let soon = (chrono::Utc::now() + chrono::Duration::minutes(1))
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    let remote = |id: &str| SyncComment {
+        comment: ChainComment::from(&b.find_comment_with_id(id).unwrap().unwrap()),
+        merged_from: vec![],
+    };
+    let mut forged = remote(&moved.id);
+    forged.comment.id = "forged".to_string();
+    let mut edited = remote(&moved.id);
+    edited.comment.description = "对端的修改".to_string();
+    edited.comment.object = li.object_id.clone();
+    edited.comment.author_sign = Some("伪造".to_string());
+    edited.comment.edited = Some(soon.clone());
+    let mut unhidden = remote(&hidden.id);
+    unhidden.comment.description = "广告".to_string();
+    unhidden.comment.tombstone = None;
+    unhidden.comment.tombstone_reason = None;
+    unhidden.comment.tombstone_date = None;
+    unhidden.comment.edited = Some(soon.clone());
+    let mut older = remote(&same_b.id);
+    older.comment.description = "较早的修改".to_string();
+    older.comment.edited = None;
+    let mut future = remote(&same_b.id);
+    future.comment.description = "将来的修改".to_string();
+    future.comment.edited = Some("2999-01-01T00:00:00Z".to_string());
+    let mut day_only = remote(&same_b.id);
+    day_only.comment.edited = Some(crate::db::get_current_date());
+    let mut retracted = remote(&reply.id);
+    retracted.comment.description = "撤回后不应有正文".to_string();
+    retracted.comment.tombstone = Some(TombstoneKind::Retracted.to_string());
+    retracted.comment.tombstone_date = Some(crate::db::get_current_date());
+    retracted.comment.edited = Some(now);
+    let r = b
+        .apply_sync(
+            "http://evil",
+            PeerTrust::TombstoneOnly,
+            &[
+                forged,
+                edited.clone(),
+                unhidden,
+                older,
+                future,
+                day_only.clone(),
+                retracted,
+            ]
+            .map(SyncRecord::Comment),
+        )
+        .unwrap();
+    assert_eq!(
+        vec![
+            "评价 forged 的 id 与内容不符".to_string(),
+            format!(
+                "对端 http://evil 不是可信对端，只接受其撤回与隐藏，未采用评价 {} 的修改",
+                moved.id
+            ),
+            format!("评价 {} 的修改时间 2999-01-01T00:00:00Z 无效", same_b.id),
+            format!(
+                "评价 {} 的修改时间 {} 无效",
+                same_b.id,
+                day_only.comment.edited.unwrap()
+            ),
+        ],
+        r.problems
+    );
+    assert_eq!((1, 2), (r.comments_updated, r.comments_kept));
+    assert_eq!(
+        "合并前的评价",
+        b.find_comment_with_id(&moved.id)
+            .unwrap()
+            .unwrap()
+            .description
+    );
+    // 撤回的正文存入修订历史，管理员可以恢复
+    let c = b.find_comment_with_id(&reply.id).unwrap().unwrap();
+    assert_eq!(
+        ("", TombstoneKind::Retracted),
+        (c.description.as_str(), c.tombstone.unwrap().kind)
+    );
+    restore_comment(&b, &reply.id).unwrap();
+    assert_eq!(
+        "回复",
+        b.find_comment_with_id(&reply.id)
+            .unwrap()
+            .unwrap()
+            .description
+    );
+
+    // 可信的对端可以修改评价
+    let r = b
+        .apply_sync(
+            "http://trusted",
+            PeerTrust::Trusted,
+            &[SyncRecord::Comment(edited)],
+        )
+        .unwrap();
+    assert_eq!(1, r.comments_updated);
+    assert_eq!(
+        "甲节点的修改",
+        b.find_comment_with_id(&same_b.id)
+            .unwrap()
+            .unwrap()
+            .description
+    );
+    let c = b.find_comment_with_id(&moved.id).unwrap().unwrap();
+    assert_eq!("对端的修改", c.description);
+    assert_eq!(t.object_id, c.object);
+    assert_eq!(moved.author_sign, c.author_sign);
+    assert!(b
+        .find_comment_with_id(&hidden.id)
+        .unwrap()
+        .unwrap()
+        .tombstone
+        .is_some());
+    assert!(b.verify_chain().unwrap().is_valid());
+}
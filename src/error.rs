@@ -0,0 +1,117 @@
+//! # error
+//!
+//! safc 库统一的错误类型
+//!
+//! 参考：https://course.rs/advance/errors.html - 归一化不同的错误类型
+
+use std::fmt;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// 找不到客体或评价等，内容为其 id 或描述
+    NotFound(String),
+    /// id 已存在，如重复添加的客体或评价
+    DuplicateId(String),
+    /// 数据库中无法识别的枚举值，如未知的 source_cate
+    InvalidEnum { name: &'static str, value: String },
+    /// 连接池中没有可用的连接
+    PoolExhausted(r2d2::Error),
+    /// sqlite 错误
+    Sqlite(rusqlite::Error),
+    /// 文件读写错误，如备份与导出
+    Io(std::io::Error),
+    /// 输入不合法，内容为给用户看的说明
+    Validation(String),
+    /// 没有权限，如发布人 OTP 与签名不符
+    Forbidden(String),
+    /// 操作过于频繁，内容为需要等待的时间
+    RateLimited(std::time::Duration),
+    /// 数据库的结构版本与程序不符，如数据库已被更新的程序迁移过
+    SchemaVersion { found: u32, expected: u32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(s) => write!(f, "未找到：{}", s),
+            Self::DuplicateId(id) => write!(f, "id 已存在：{}", id),
+            Self::InvalidEnum { name, value } => write!(f, "无效的 {} 值：{}", name, value),
+            Self::PoolExhausted(e) => write!(f, "数据库连接池错误：{}", e),
+            Self::Sqlite(e) => write!(f, "数据库错误：{}", e),
+            Self::Io(e) => write!(f, "文件读写错误：{}", e),
+            Self::Validation(s) => write!(f, "输入不合法：{}", s),
+            Self::Forbidden(s) => write!(f, "没有权限：{}", s),
+            Self::RateLimited(d) => write!(f, "操作过于频繁，请在 {} 秒后重试", d.as_secs() + 1),
+            Self::SchemaVersion { found, expected } if found > expected => write!(
+                f,
+                "数据库结构版本 v{} 高于程序支持的 v{}，请升级程序",
+                found, expected
+            ),
+            Self::SchemaVersion { found, expected } => write!(
+                f,
+                "数据库结构版本 v{} 低于程序所需的 v{}，请先迁移数据库",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::PoolExhausted(e) => Some(e),
+            Self::Sqlite(e) => Some(e),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<r2d2::Error> for Error {
+    fn from(e: r2d2::Error) -> Self {
+        Self::PoolExhausted(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    /// 读取行时 `FromSql` 产生的 [`Error`] 会被 rusqlite 包装，这里将其还原
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            rusqlite::Error::FromSqlConversionFailure(i, t, inner) => {
+                match inner.downcast::<Error>() {
+                    Ok(inner) => *inner,
+                    Err(inner) => {
+                        Self::Sqlite(rusqlite::Error::FromSqlConversionFailure(i, t, inner))
+                    }
+                }
+            }
+            e => Self::Sqlite(e),
+        }
+    }
+}
+
+impl Error {
+    /// 插入时的主键冲突视为 [`Error::DuplicateId`]
+    pub(crate) fn from_insert(e: rusqlite::Error, id: &str) -> Self {
+        match e.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::ConstraintViolation)
+                if matches!(
+                    e.sqlite_error().map(|x| x.extended_code),
+                    Some(rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY)
+                        | Some(rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE)
+                ) =>
+            {
+                Self::DuplicateId(id.to_string())
+            }
+            _ => e.into(),
+        }
+    }
+}
@@ -0,0 +1,565 @@
+//! # export
+//!
+//! 数据库的多格式导出：JSON、JSONL、CSV 与 Markdown
+//!
+//! 评价按所属关系组织为树：客体 < 评价 < 嵌套评价。找不到所属客体的评价单独列出。
+//! 导出的内容只取决于数据库内容，客体按学校、学院、导师、id 排序，评价按日期、id 排序，
+//! 所以两次导出的 diff 就是数据库的变化。
+//!
+//! - JSON：一个 [`Dump`]，嵌套评价在 `replies` 中
+//! - JSONL：每行一条 [`Record`]，先客体后其评价，嵌套评价紧随其上级评价
+//! - CSV：与 JSONL 相同的顺序，每行一条记录，`kind` 列区分客体与评价，文件以 UTF-8 BOM 开头以便表格软件识别
+//! - Markdown：按学校、学院分组的可读文本
+//!
+//! 公开的导出接口使用 [`ExportCache`]，公开内容未变化时不重新生成。
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+use crate::db::rating::MAX_STARS;
+use crate::db::{ObjComment, ObjTeacher, RatingDimension, SAFCdb, TombstoneKind};
+use crate::Result;
+use sha2::{Digest, Sha256};
+
+/// 导出格式的版本，格式不兼容地变化时递增
+pub const FORMAT_VERSION: u32 = 1;
+
+/// 导出格式
+#[derive(Debug, EnumString, Display, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Jsonl,
+    Csv,
+    #[strum(to_string = "md", serialize = "markdown")]
+    Md,
+}
+
+impl ExportFormat {
+    /// 文件扩展名
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Jsonl => "jsonl",
+            Self::Csv => "csv",
+            Self::Md => "md",
+        }
+    }
+
+    /// HTTP Content-Type
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json; charset=utf-8",
+            Self::Jsonl => "application/x-ndjson; charset=utf-8",
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Md => "text/markdown; charset=utf-8",
+        }
+    }
+
+    /// 导出文件名，如 `safc.json`
+    pub fn file_name(&self) -> String {
+        format!("safc.{}", self.extension())
+    }
+}
+
+/// 完整的导出内容
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dump {
+    pub format_version: u32,
+    pub objects: Vec<ExportObject>,
+    /// 找不到所属客体的评价
+    pub orphan_comments: Vec<ExportComment>,
+}
+
+/// 客体及其评价
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportObject {
+    #[serde(flatten)]
+    pub object: ObjTeacher,
+    pub comments: Vec<ExportComment>,
+}
+
+/// 评价及其嵌套评价
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportComment {
+    #[serde(flatten)]
+    pub comment: ObjComment,
+    pub replies: Vec<ExportComment>,
+}
+
+/// JSONL 中的一行
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Record {
+    Object(ObjTeacher),
+    Comment(ObjComment),
+}
+
+/// CSV 的列，客体没有的列留空
+const CSV_HEADER: [&str; 19] = [
+    "kind",
+    "id",
+    "parent",
+    "school_cate",
+    "university",
+    "department",
+    "supervisor",
+    "info",
+    "comment_type",
+    "source_cate",
+    "date",
+    "description",
+    "author_sign",
+    "tombstone",
+    "rating_academic",
+    "rating_funding",
+    "rating_relationship",
+    "rating_prospects",
+    "rating_stipend",
+];
+
+/// 以 `format` 导出整个数据库到 `w`
+pub fn export_to<W: Write>(db: &SAFCdb, format: ExportFormat, w: W) -> Result<()> {
+    Dump::load(db)?.write(format, w)
+}
+
+/// 生成好的导出内容
+#[derive(Debug, Clone)]
+pub struct Rendered {
+    pub body: Bytes,
+    /// 内容的 sha256（hex），可用作 ETag
+    pub sha256: String,
+}
+
+/// 按格式缓存的导出内容
+///
+/// 公开内容不变（见 [`SAFCdb::content_fingerprint`]）时复用已生成的内容（与 [`crate::db::SnapshotCache`] 相同），
+/// 反复请求导出不会反复读取整个数据库。生成期间持有锁，并发的请求等待同一份内容
+#[derive(Clone)]
+pub struct ExportCache {
+    db: SAFCdb,
+    current: Arc<Mutex<HashMap<ExportFormat, (String, Rendered)>>>,
+}
+
+impl ExportCache {
+    pub fn new(db: SAFCdb) -> Self {
+        Self {
+            db,
+            current: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// 与当前数据库一致的导出内容，生成是阻塞的操作
+    pub fn get(&self, format: ExportFormat) -> Result<Rendered> {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        let fingerprint = self.db.content_fingerprint()?;
+        if let Some((f, r)) = current.get(&format) {
+            if *f == fingerprint {
+                return Ok(r.clone());
+            }
+        }
+        let mut buf = vec![];
+        export_to(&self.db, format, &mut buf)?;
+        let rendered = Rendered {
+            sha256: hex::encode(Sha256::digest(&buf)),
+            body: Bytes::from(buf),
+        };
+        current.insert(format, (fingerprint, rendered.clone()));
+        Ok(rendered)
+    }
+}
+
+impl Dump {
+    /// 从数据库读取并组织评价树
+    pub fn load(db: &SAFCdb) -> Result<Self> {
+        let objects = db.find_all_objects()?;
+        let comments = db.find_all_comments()?;
+
+        let comment_ids: HashSet<String> = comments.iter().map(|c| c.id.clone()).collect();
+        // 上级（客体或评价）id -> 评价，保持按日期、id 的顺序
+        let mut children: HashMap<String, Vec<ObjComment>> = HashMap::new();
+        for c in comments {
+            children.entry(c.object.clone()).or_default().push(c);
+        }
+
+        let objects = objects
+            .into_iter()
+            .map(|object| ExportObject {
+                comments: take_replies(&mut children, &object.object_id),
+                object,
+            })
+            .collect();
+
+        // 剩下的是找不到所属客体的评价：先从上级不存在的评价开始，再处理成环的评价
+        let mut parents: Vec<String> = children.keys().cloned().collect();
+        parents.sort_by_key(|p| (comment_ids.contains(p), p.clone()));
+        let mut orphan_comments = vec![];
+        for parent in parents {
+            orphan_comments.extend(take_replies(&mut children, &parent));
+        }
+
+        Ok(Self {
+            format_version: FORMAT_VERSION,
+            objects,
+            orphan_comments,
+        })
+    }
+
+    /// 客体数与评价数（含嵌套评价）
+    pub fn counts(&self) -> (usize, usize) {
+        let comments = self
+            .objects
+            .iter()
+            .flat_map(|o| &o.comments)
+            .chain(&self.orphan_comments)
+            .map(ExportComment::count)
+            .sum();
+        (self.objects.len(), comments)
+    }
+
+    /// JSONL 与 CSV 使用的扁平顺序
+    pub fn records(&self) -> Vec<Record> {
+        let mut records = vec![];
+        for o in &self.objects {
+            records.push(Record::Object(o.object.clone()));
+            for c in &o.comments {
+                c.flatten_into(&mut records);
+            }
+        }
+        for c in &self.orphan_comments {
+            c.flatten_into(&mut records);
+        }
+        records
+    }
+
+    pub fn write<W: Write>(&self, format: ExportFormat, mut w: W) -> Result<()> {
+        match format {
+            ExportFormat::Json => {
+                serde_json::to_writer_pretty(&mut w, self).map_err(std::io::Error::from)?;
+                writeln!(w)?;
+            }
+            ExportFormat::Jsonl => {
+                for r in self.records() {
+                    serde_json::to_writer(&mut w, &r).map_err(std::io::Error::from)?;
+                    writeln!(w)?;
+                }
+            }
+            ExportFormat::Csv => self.write_csv(&mut w)?,
+            ExportFormat::Md => self.write_md(&mut w)?,
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    fn write_csv<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_all("\u{feff}".as_bytes())?;
+        write_csv_row(w, &CSV_HEADER)?;
+        for r in self.records() {
+            match r {
+                Record::Object(o) => write_csv_row(
+                    w,
+                    &[
+                        "object",
+                        &o.object_id,
+                        "",
+                        &o.school_cate,
+                        &o.university,
+                        &o.department,
+                        &o.supervisor,
+                        o.info.as_deref().unwrap_or_default(),
+                        "",
+                        "",
+                        &o.date,
+                        "",
+                        "",
+                        "",
+                        "",
+                        "",
+                        "",
+                        "",
+                        "",
+                    ],
+                )?,
+                Record::Comment(c) => {
+                    let ratings = c.ratings.unwrap_or_default();
+                    let stars: Vec<String> = RatingDimension::ALL
+                        .into_iter()
+                        .map(|d| ratings.get(d).map(|s| s.to_string()).unwrap_or_default())
+                        .collect();
+                    write_csv_row(
+                        w,
+                        &[
+                            "comment",
+                            &c.id,
+                            &c.object,
+                            "",
+                            "",
+                            "",
+                            "",
+                            "",
+                            &c.comment_type.to_string(),
+                            &c.source_cate.to_string(),
+                            &c.date,
+                            &c.description,
+                            c.author_sign.as_deref().unwrap_or_default(),
+                            &c.tombstone
+                                .as_ref()
+                                .map(|t| t.kind.to_string())
+                                .unwrap_or_default(),
+                            &stars[0],
+                            &stars[1],
+                            &stars[2],
+                            &stars[3],
+                            &stars[4],
+                        ],
+                    )?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_md<W: Write>(&self, w: &mut W) -> Result<()> {
+        let (n_objects, n_comments) = self.counts();
+        writeln!(w, "# SAFC 数据库导出\n")?;
+        writeln!(w, "客体 {n_objects} 个，评价 {n_comments} 条（含嵌套评价）")?;
+
+        let mut university = None;
+        let mut department = None;
+        for o in &self.objects {
+            let t = &o.object;
+            if university != Some(&t.university) {
+                writeln!(w, "\n## 🏫 {}", t.university)?;
+                university = Some(&t.university);
+                department = None;
+            }
+            if department != Some(&t.department) {
+                writeln!(w, "\n### 🏢 {}", t.department)?;
+                department = Some(&t.department);
+            }
+            writeln!(w, "\n#### 👔 {} `{}`\n", t.supervisor, t.object_id)?;
+            write!(w, "🧭 {} · 📅 {}", t.school_cate, t.date)?;
+            if let Some(info) = t.info.as_deref().filter(|s| !s.is_empty()) {
+                write!(w, " · {}", info.replace('\n', " "))?;
+            }
+            writeln!(w)?;
+            if !o.comments.is_empty() {
+                writeln!(w)?;
+            }
+            for c in &o.comments {
+                c.write_md(w, 0)?;
+            }
+        }
+
+        if !self.orphan_comments.is_empty() {
+            writeln!(w, "\n## 找不到所属客体的评价\n")?;
+            for c in &self.orphan_comments {
+                c.write_md(w, 0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ExportComment {
+    /// 此评价及其所有嵌套评价的数量
+    fn count(&self) -> usize {
+        1 + self.replies.iter().map(Self::count).sum::<usize>()
+    }
+
+    fn flatten_into(&self, records: &mut Vec<Record>) {
+        records.push(Record::Comment(self.comment.clone()));
+        for r in &self.replies {
+            r.flatten_into(records);
+        }
+    }
+
+    /// 列表项，嵌套评价缩进一级
+    fn write_md<W: Write>(&self, w: &mut W, depth: usize) -> Result<()> {
+        let c = &self.comment;
+        let indent = "  ".repeat(depth);
+        writeln!(
+            w,
+            "{indent}- 💬 {} · {} · {} · `{}`",
+            c.date, c.source_cate, c.comment_type, c.id
+        )?;
+        if let Some(ratings) = c.ratings.filter(|_| c.tombstone.is_none()) {
+            let stars: Vec<String> = ratings
+                .iter()
+                .map(|(d, s)| format!("{} {s}/{MAX_STARS}", d.label()))
+                .collect();
+            writeln!(w, "{indent}  ⭐ {}", stars.join(" · "))?;
+        }
+        let body = match c.tombstone.as_ref().map(|t| t.kind) {
+            Some(TombstoneKind::Retracted) => "*（已被发布人撤回）*",
+            Some(TombstoneKind::Moderated) => "*（已被管理员隐藏）*",
+            None => c.description.as_str(),
+        };
+        for line in body.lines() {
+            if line.trim().is_empty() {
+                writeln!(w)?;
+            } else {
+                writeln!(w, "{indent}  {line}")?;
+            }
+        }
+        for r in &self.replies {
+            r.write_md(w, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// 取出 `parent` 下的评价及其嵌套评价；取出后即从 `children` 中删除，成环的评价也不会重复
+fn take_replies(
+    children: &mut HashMap<String, Vec<ObjComment>>,
+    parent: &str,
+) -> Vec<ExportComment> {
+    children
+        .remove(parent)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|comment| ExportComment {
+            replies: take_replies(children, &comment.id),
+            comment,
+        })
+        .collect()
+}
+
+fn write_csv_row<W: Write>(w: &mut W, fields: &[&str]) -> Result<()> {
+    let row: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+    writeln!(w, "{}", row.join(","))?;
+    Ok(())
+}
+
+/// 含逗号、引号或换行的字段加引号，引号转义为两个引号
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[test]
+fn test_export() {
+    use crate::db::{temp_db, CommentType, SourceCate};
+    use std::str::FromStr;
+    let db = temp_db();
+    let new_object = |university: &str, supervisor: &str| {
+        let t = ObjTeacher {
+            school_cate: "985".to_string(),
+            university: university.to_string(),
+            department: "计算机系".to_string(),
+            supervisor: supervisor.to_string(),
+            date: "2023-09-01".to_string(),
+            info: None,
+            object_id: supervisor.to_string(),
+        };
+        db.add_object(&t).unwrap();
+    };
+    new_object("清华大学", "张三");
+    new_object("北京大学", "李四");
+    let new_comment = |object: &str, text: &str, ty| {
+        let c = ObjComment::new_with_otp(
+            object.to_string(),
+            text.to_string(),
+            SourceCate::Web,
+            ty,
+            "otp".to_string(),
+        );
+        db.add_comment(&c).unwrap();
+        c.id
+    };
+    let c = new_comment("张三", "第一行\n第二行, \"引号\"", CommentType::Teacher);
+    new_comment(&c, "回复", CommentType::Nest);
+    new_comment("orphan", "找不到客体", CommentType::Teacher);
+    let mut rated = ObjComment::new_with_otp(
+        "李四".to_string(),
+        "有评星".to_string(),
+        SourceCate::Web,
+        CommentType::Teacher,
+        "otp".to_string(),
+    );
+    rated.ratings = Some(crate::db::Ratings {
+        academic: Some(4),
+        stipend: Some(2),
+        ..Default::default()
+    });
+    db.add_comment(&rated).unwrap();
+
+    let dump = Dump::load(&db).unwrap();
+    assert_eq!((2, 4), dump.counts());
+    // 北京大学排在清华大学之前
+    assert_eq!("李四", dump.objects[0].object.supervisor);
+    assert_eq!(1, dump.objects[1].comments[0].replies.len());
+    assert_eq!(1, dump.orphan_comments.len());
+
+    let export = |format| {
+        let mut buf = vec![];
+        export_to(&db, format, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    };
+    for format in [
+        ExportFormat::Json,
+        ExportFormat::Jsonl,
+        ExportFormat::Csv,
+        ExportFormat::Md,
+    ] {
+        // 相同的数据库导出相同的内容
+        assert_eq!(export(format), export(format));
+        assert_eq!(Ok(format), ExportFormat::from_str(&format.to_string()));
+    }
+    assert_eq!(Ok(ExportFormat::Md), ExportFormat::from_str("markdown"));
+
+    let json: Dump = serde_json::from_str(&export(ExportFormat::Json)).unwrap();
+    assert_eq!(dump.counts(), json.counts());
+
+    let jsonl = export(ExportFormat::Jsonl);
+    let records: Vec<Record> = jsonl
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(6, records.len());
+    assert!(matches!(&records[1], Record::Comment(x) if x.ratings == rated.ratings));
+    assert!(matches!(&records[3], Record::Comment(x) if x.id == c));
+
+    let csv = export(ExportFormat::Csv);
+    assert!(csv.starts_with("\u{feff}kind,id,parent,"));
+    assert!(csv.contains("\"第一行\n第二行, \"\"引号\"\"\""));
+    assert!(csv.contains(",4,,,,2\n"));
+
+    let md = export(ExportFormat::Md);
+    assert!(md.find("## 🏫 北京大学").unwrap() < md.find("## 🏫 清华大学").unwrap());
+    assert!(md.contains("    回复"));
+    assert!(md.contains("⭐ 学术水平 4/5 · 学生补助 2/5"));
+}
+
+#[test]
+fn test_export_cache() {
+    use crate::db::{temp_db, temp_object};
+    let db = temp_db();
+    let cache = ExportCache::new(db.clone());
+    let first = cache.get(ExportFormat::Json).unwrap();
+    // 数据库未变化时复用同一份内容
+    let again = cache.get(ExportFormat::Json).unwrap();
+    assert_eq!(first.body.as_ptr(), again.body.as_ptr());
+    assert_ne!(first.sha256, cache.get(ExportFormat::Csv).unwrap().sha256);
+
+    let t = temp_object(&db);
+    let second = cache.get(ExportFormat::Json).unwrap();
+    assert_ne!(first.sha256, second.sha256);
+    let mut buf = vec![];
+    export_to(&db, ExportFormat::Json, &mut buf).unwrap();
+    assert_eq!(buf, second.body);
+
+    // 私有数据的写入不使缓存失效
+    db.subscribe(1, &t.object_id).unwrap();
+    let again = cache.get(ExportFormat::Json).unwrap();
+    assert_eq!(second.body.as_ptr(), again.body.as_ptr());
+}
@@ -0,0 +1,742 @@
+//! # import
+//!
+//! 从外部来源导入客体与评价
+//!
+//! 导入分三步：
+//! 1. [`read_records`] 将输入解析为规范化的 [`ImportRecord`]，外部格式由各自的适配器转换；
+//! 2. [`plan`] 计算 id（见 [`crate::sec`]）、标注来源、与数据库及本批次内去重，得到 [`ImportPlan`]，
+//!    其 `Display` 即试运行的报告；
+//! 3. [`apply`] 在一个事务中写入。
+//!
+//! 支持的输入格式见 [`ImportFormat`]。id 与原先的 Python 脚本算法相同，
+//! 所以重复导入同一份数据，或导入已由脚本写入的数据，都只会被计为重复。
+
+use std::collections::HashSet;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+use crate::db::{
+    get_current_date, CommentType, ObjComment, ObjTeacher, RatingDimension, Ratings, SAFCdb,
+    SourceCate,
+};
+use crate::sec::{hash_comment_id, hash_object_id};
+use crate::service::validate_name;
+use crate::{Error, Result};
+
+pub mod tg;
+
+/// 报告中最多列出的问题数
+const MAX_REPORTED_PROBLEMS: usize = 50;
+
+/// 输入格式
+#[derive(Debug, EnumString, Display, PartialEq, Clone, Copy)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ImportFormat {
+    /// [`ImportRecord`] 的 JSON 数组
+    Json,
+    /// 每行一条 [`ImportRecord`]
+    Jsonl,
+    /// 带表头的 CSV，列名同 [`ImportRecord`] 的字段，评星为 `rating_academic` 等列，不支持嵌套评价
+    Csv,
+    /// RateMySupervisor（urfire）的 comments_data.json，见 [`UrfireRecord`]
+    Urfire,
+    /// pi-review.com 爬虫的导出，见 [`PiReviewRecord`]
+    PiReview,
+    /// telegram 群中收集的评价 `tmp_from_tg.txt`，见 [`tg`]
+    TgText,
+}
+
+impl ImportFormat {
+    /// 按文件扩展名推断规范化格式
+    pub fn from_extension(path: &str) -> Option<Self> {
+        match path.rsplit_once('.')?.1.to_ascii_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "jsonl" | "ndjson" => Some(Self::Jsonl),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+
+    /// 未指定来源时使用的 [`SourceCate`]
+    pub fn default_source(&self) -> SourceCate {
+        match self {
+            Self::Urfire => SourceCate::Urfire,
+            Self::PiReview => SourceCate::PiReview,
+            Self::TgText => SourceCate::Telegram,
+            Self::Json | Self::Jsonl | Self::Csv => SourceCate::Admin,
+        }
+    }
+}
+
+/// 规范化的导入记录：一个客体及其评价
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ImportRecord {
+    pub school_cate: String,
+    pub university: String,
+    pub department: String,
+    pub supervisor: String,
+    #[serde(default)]
+    pub info: Option<String>,
+    /// 客体的日期，默认为今天
+    #[serde(default)]
+    pub object_date: Option<String>,
+    /// 评价，为空时只导入客体
+    #[serde(default)]
+    pub description: Option<String>,
+    /// 评价的日期，默认为今天
+    #[serde(default)]
+    pub date: Option<String>,
+    /// 评价的分项评星
+    #[serde(default)]
+    pub ratings: Option<Ratings>,
+    /// 对此评价的嵌套评价
+    #[serde(default)]
+    pub replies: Vec<ImportReply>,
+}
+
+/// 导入的嵌套评价
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ImportReply {
+    pub description: String,
+    #[serde(default)]
+    pub date: Option<String>,
+    #[serde(default)]
+    pub replies: Vec<ImportReply>,
+}
+
+/// RateMySupervisor（urfire）数据的一条记录，`rate` 与 `counts` 不导入
+#[derive(Debug, Clone, Deserialize)]
+pub struct UrfireRecord {
+    pub school_cate: String,
+    pub university: String,
+    pub department: String,
+    pub supervisor: String,
+    pub description: String,
+    pub date: String,
+}
+
+/// urfire 数据最后的评价日期，用作客体的日期
+const URFIRE_DATE: &str = "2022-05";
+
+impl From<UrfireRecord> for ImportRecord {
+    fn from(r: UrfireRecord) -> Self {
+        ImportRecord {
+            school_cate: r.school_cate,
+            university: r.university,
+            department: r.department,
+            supervisor: r.supervisor,
+            info: None,
+            object_date: Some(URFIRE_DATE.to_string()),
+            description: Some(r.description),
+            date: Some(r.date),
+            ratings: None,
+            replies: vec![],
+        }
+    }
+}
+
+/// pi-review.com 的一位导师页面，由 `script/crawlers/pi-review.py` 爬取
+#[derive(Debug, Clone, Deserialize)]
+pub struct PiReviewRecord {
+    /// 页面地址，如 `https://pi-review.com/pis/8987`
+    pub url: String,
+    /// 爬取日期
+    pub updated: String,
+    pub university: String,
+    pub department: String,
+    pub supervisor: String,
+    #[serde(default)]
+    pub website: Option<String>,
+    /// 未指定时沿用数据库中同一学校的类别，见 [`pi_review_school_cate`]
+    #[serde(default)]
+    pub school_cate: Option<String>,
+    #[serde(default)]
+    pub reviews: Vec<PiReviewReview>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PiReviewReview {
+    /// `2023-09-26` 或 `2023-09-26T08:00:00Z`
+    pub date: String,
+    pub content: String,
+    #[serde(default)]
+    pub comments: Vec<PiReviewReview>,
+}
+
+/// 无法确定学校类别时使用
+const UNCATEGORIZED: &str = "未归类";
+
+/// pi-review 导师的学校类别：优先沿用数据库中同一学校的类别，再按学校名归类
+pub fn pi_review_school_cate(db: &SAFCdb, university: &str) -> Result<String> {
+    if let Some(cate) = db.find_school_cate_of_university(university)? {
+        return Ok(cate);
+    }
+    let cate = if [
+        "University of California",
+        "University of Illinois at Urbana",
+        "Stony Brook University",
+    ]
+    .iter()
+    .any(|u| university.contains(u))
+    {
+        "U.S."
+    } else if university.contains("中山大学") {
+        "985"
+    } else if university.contains("中国石油大学") || university.contains("中国地质大学")
+    {
+        "211"
+    } else {
+        UNCATEGORIZED
+    };
+    Ok(cate.to_string())
+}
+
+impl PiReviewRecord {
+    /// 转换为规范化记录；没有评价且无法归类的导师不导入，返回 `Ok(None)`
+    ///
+    /// 评价正文附带来源页面，与原先的 Python 脚本一致
+    fn into_records(self, db: &SAFCdb) -> Result<Option<Vec<ImportRecord>>> {
+        let school_cate = match self.school_cate {
+            Some(cate) => cate,
+            None => pi_review_school_cate(db, &self.university)?,
+        };
+        if school_cate == UNCATEGORIZED && self.reviews.is_empty() {
+            return Ok(None);
+        }
+        let footer = format!("\n\nfrom {} updated at {}", self.url, self.updated);
+        fn reply(r: PiReviewReview, footer: &str) -> ImportReply {
+            ImportReply {
+                description: format!("{}{footer}", r.content),
+                date: Some(normalize_date(&r.date)),
+                replies: r.comments.into_iter().map(|c| reply(c, footer)).collect(),
+            }
+        }
+        let object = ImportRecord {
+            school_cate,
+            university: self.university,
+            department: self.department,
+            supervisor: self.supervisor,
+            info: self
+                .website
+                .map(|w| format!("Personal Website: {}\n", w.trim())),
+            object_date: Some(normalize_date(&self.updated)),
+            description: None,
+            date: None,
+            ratings: None,
+            replies: vec![],
+        };
+        if self.reviews.is_empty() {
+            return Ok(Some(vec![object]));
+        }
+        Ok(Some(
+            self.reviews
+                .into_iter()
+                .map(|r| {
+                    let r = reply(r, &footer);
+                    ImportRecord {
+                        description: Some(r.description),
+                        date: r.date,
+                        replies: r.replies,
+                        ..object.clone()
+                    }
+                })
+                .collect(),
+        ))
+    }
+}
+
+/// `2023-09-26T08:00:00Z` 之类的时间只取日期部分
+fn normalize_date(s: &str) -> String {
+    let s = s.trim();
+    match s.get(..10) {
+        Some(d) if chrono::NaiveDate::parse_from_str(d, "%Y-%m-%d").is_ok() => d.to_string(),
+        _ => s.to_string(),
+    }
+}
+
+/// 解析输入，每条记录单独成败：格式错误的记录以说明代替，不影响其他记录
+///
+/// 整个输入无法解析时（如不是 JSON 数组）返回 [`Error::Validation`]
+pub fn read_records(
+    db: &SAFCdb,
+    format: ImportFormat,
+    input: &str,
+) -> Result<Vec<std::result::Result<ImportRecord, String>>> {
+    let input = input.trim_start_matches('\u{feff}');
+    Ok(match format {
+        ImportFormat::Json => json_array(input)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| {
+                serde_json::from_value(v).map_err(|e| format!("第 {} 条记录：{e}", i + 1))
+            })
+            .collect(),
+        ImportFormat::Jsonl => input
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.trim().is_empty())
+            .map(|(i, l)| serde_json::from_str(l).map_err(|e| format!("第 {} 行：{e}", i + 1)))
+            .collect(),
+        ImportFormat::Csv => csv_records(input)?,
+        ImportFormat::Urfire => json_array(input)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| {
+                serde_json::from_value::<UrfireRecord>(v)
+                    .map(ImportRecord::from)
+                    .map_err(|e| format!("第 {} 条记录：{e}", i + 1))
+            })
+            .collect(),
+        ImportFormat::PiReview => {
+            let mut records = vec![];
+            for (i, v) in json_array(input)?.into_iter().enumerate() {
+                match serde_json::from_value::<PiReviewRecord>(v) {
+                    Ok(r) => {
+                        let name = format!("{} {}", r.university, r.supervisor);
+                        match r.into_records(db)? {
+                            Some(rs) => records.extend(rs.into_iter().map(Ok)),
+                            None => records.push(Err(format!(
+                                "第 {} 条记录：{name} 没有评价且无法归类，跳过",
+                                i + 1
+                            ))),
+                        }
+                    }
+                    Err(e) => records.push(Err(format!("第 {} 条记录：{e}", i + 1))),
+                }
+            }
+            records
+        }
+        ImportFormat::TgText => {
+            let dump = tg::parse(input);
+            let school_cate = db
+                .find_school_cate_of_university(tg::TG_UNIVERSITY)?
+                .unwrap_or_else(|| tg::TG_SCHOOL_CATE.to_string());
+            dump.problems
+                .into_iter()
+                .map(|p| Err(format!("第 {} 行：{}：{}", p.line, p.reason, p.text)))
+                .chain(
+                    dump.reviews
+                        .into_iter()
+                        .map(|r| Ok(r.into_record(&school_cate, tg::TG_UNIVERSITY))),
+                )
+                .collect()
+        }
+    })
+}
+
+fn json_array(input: &str) -> Result<Vec<serde_json::Value>> {
+    serde_json::from_str(input).map_err(|e| Error::Validation(format!("不是 JSON 数组：{e}")))
+}
+
+fn csv_records(input: &str) -> Result<Vec<std::result::Result<ImportRecord, String>>> {
+    let mut rows = parse_csv(input).into_iter();
+    let header = rows
+        .next()
+        .ok_or_else(|| Error::Validation("CSV 为空".to_string()))?;
+    let col = |name: &str| header.iter().position(|h| h.trim() == name);
+    let required = ["school_cate", "university", "department", "supervisor"];
+    if let Some(missing) = required.iter().find(|c| col(c).is_none()) {
+        return Err(Error::Validation(format!("CSV 缺少列 {missing}")));
+    }
+    let get = |row: &[String], name: &str| -> Option<String> {
+        col(name)
+            .and_then(|i| row.get(i))
+            .filter(|s| !s.is_empty())
+            .cloned()
+    };
+    Ok(rows
+        .enumerate()
+        .map(|(i, row)| {
+            if row.len() != header.len() {
+                return Err(format!(
+                    "第 {} 行：应有 {} 列，实有 {} 列",
+                    i + 2,
+                    header.len(),
+                    row.len()
+                ));
+            }
+            let mut ratings = Ratings::default();
+            for d in RatingDimension::ALL {
+                if let Some(v) = get(&row, &format!("rating_{d}")) {
+                    let stars = v.trim().parse().map_err(|_| {
+                        format!("第 {} 行：{}的评星 {v} 不是数字", i + 2, d.label())
+                    })?;
+                    ratings.set(d, Some(stars));
+                }
+            }
+            Ok(ImportRecord {
+                school_cate: get(&row, "school_cate").unwrap_or_default(),
+                university: get(&row, "university").unwrap_or_default(),
+                department: get(&row, "department").unwrap_or_default(),
+                supervisor: get(&row, "supervisor").unwrap_or_default(),
+                info: get(&row, "info"),
+                object_date: get(&row, "object_date"),
+                description: get(&row, "description"),
+                date: get(&row, "date"),
+                ratings: Some(ratings).filter(|r| !r.is_empty()),
+                replies: vec![],
+            })
+        })
+        .collect())
+}
+
+/// 按 RFC 4180 解析 CSV：引号内可含逗号、换行，两个引号表示一个引号
+fn parse_csv(input: &str) -> Vec<Vec<String>> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => row.push(std::mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    // 忽略空行
+    rows.retain(|r| !(r.len() == 1 && r[0].trim().is_empty()));
+    rows
+}
+
+/// 导入计划，即试运行的结果
+#[derive(Debug, Clone)]
+pub struct ImportPlan {
+    pub source: SourceCate,
+    /// 将要增加的客体
+    pub new_objects: Vec<ObjTeacher>,
+    /// 将要增加的评价，嵌套评价排在其上级评价之后
+    pub new_comments: Vec<ObjComment>,
+    /// 已存在（或在本批次中重复）的客体数
+    pub existing_objects: usize,
+    /// 已存在（或在本批次中重复）的评价数
+    pub duplicate_comments: usize,
+    /// 已存在但学校类别不同的客体：(object id, 数据库中的类别, 导入的类别)，以数据库为准
+    pub conflicts: Vec<(String, String, String)>,
+    /// 无法导入的记录及原因
+    pub problems: Vec<String>,
+}
+
+/// 计算 id 并去重，生成导入计划，不写入数据库
+pub fn plan(
+    db: &SAFCdb,
+    records: Vec<std::result::Result<ImportRecord, String>>,
+    source: SourceCate,
+) -> Result<ImportPlan> {
+    let mut p = ImportPlan {
+        source,
+        new_objects: vec![],
+        new_comments: vec![],
+        existing_objects: 0,
+        duplicate_comments: 0,
+        conflicts: vec![],
+        problems: vec![],
+    };
+    let mut seen_objects = HashSet::new();
+    let mut seen_comments = HashSet::new();
+    let today = get_current_date();
+
+    for (i, r) in records.into_iter().enumerate() {
+        let r = match r {
+            Ok(r) => r,
+            Err(e) => {
+                p.problems.push(e);
+                continue;
+            }
+        };
+        let names = (|| -> Result<_> {
+            Ok((
+                validate_name("学校类别", &r.school_cate)?,
+                validate_name("学校", &r.university)?,
+                validate_name("学院", &r.department)?,
+                validate_name("客体", &r.supervisor)?,
+            ))
+        })();
+        let (school_cate, university, department, supervisor) = match names {
+            Ok(names) => names,
+            Err(e) => {
+                p.problems.push(format!("第 {} 条记录：{e}", i + 1));
+                continue;
+            }
+        };
+        let object_id = hash_object_id(&university, &department, &supervisor);
+        if seen_objects.insert(object_id.clone()) {
+            match db.find_objteacher_with_id(&object_id)? {
+                Some(t) => {
+                    p.existing_objects += 1;
+                    if t.school_cate != school_cate {
+                        p.conflicts
+                            .push((object_id.clone(), t.school_cate, school_cate.clone()));
+                    }
+                }
+                None => p.new_objects.push(ObjTeacher {
+                    school_cate,
+                    university,
+                    department,
+                    supervisor,
+                    date: r.object_date.clone().unwrap_or_else(|| today.clone()),
+                    info: r.info.clone().filter(|s| !s.is_empty()),
+                    object_id: object_id.clone(),
+                }),
+            }
+        } else {
+            p.existing_objects += 1;
+        }
+
+        if let Some(description) = r.description {
+            let ratings = r.ratings.filter(|r| !r.is_empty());
+            if let Some(Err(e)) = ratings.map(|r| r.validate()) {
+                p.problems.push(format!("第 {} 条记录：{e}", i + 1));
+                continue;
+            }
+            let reply = ImportReply {
+                description,
+                date: r.date,
+                replies: r.replies,
+            };
+            p.add_comment(
+                db,
+                &mut seen_comments,
+                &object_id,
+                CommentType::Teacher,
+                reply,
+                ratings,
+                &today,
+                i,
+            )?;
+        } else if !r.replies.is_empty() {
+            p.problems
+                .push(format!("第 {} 条记录：没有评价，其嵌套评价被忽略", i + 1));
+        }
+    }
+    Ok(p)
+}
+
+impl ImportPlan {
+    /// 加入评价及其嵌套评价。评价 id 不修剪正文，以便与已导入的数据去重
+    #[allow(clippy::too_many_arguments)]
+    fn add_comment(
+        &mut self,
+        db: &SAFCdb,
+        seen: &mut HashSet<String>,
+        object: &str,
+        comment_type: CommentType,
+        r: ImportReply,
+        ratings: Option<Ratings>,
+        today: &str,
+        i: usize,
+    ) -> Result<()> {
+        if r.description.trim().is_empty() {
+            self.problems.push(format!("第 {} 条记录：评价为空", i + 1));
+            return Ok(());
+        }
+        let date = r
+            .date
+            .map(|d| normalize_date(&d))
+            .unwrap_or_else(|| today.to_string());
+        let id = hash_comment_id(&object.to_string(), &r.description, &date);
+        if !seen.insert(id.clone()) || db.find_comment_with_id(&id)?.is_some() {
+            self.duplicate_comments += 1;
+        } else {
+            self.new_comments.push(ObjComment {
+                object: object.to_string(),
+                description: r.description,
+                date,
+                source_cate: self.source.clone(),
+                comment_type,
+                author_sign: None,
+                id: id.clone(),
+                tombstone: None,
+                ratings,
+                edited: None,
+            });
+        }
+        // 即使上级评价已存在，其嵌套评价也可能是新的
+        for reply in r.replies {
+            self.add_comment(db, seen, &id, CommentType::Nest, reply, None, today, i)?;
+        }
+        Ok(())
+    }
+
+    /// 没有需要写入的内容
+    pub fn is_empty(&self) -> bool {
+        self.new_objects.is_empty() && self.new_comments.is_empty()
+    }
+}
+
+impl fmt::Display for ImportPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "来源：{}", self.source)?;
+        writeln!(
+            f,
+            "新客体：{}，已存在的客体：{}",
+            self.new_objects.len(),
+            self.existing_objects
+        )?;
+        writeln!(
+            f,
+            "新评价：{}（其中嵌套评价 {}），重复的评价：{}",
+            self.new_comments.len(),
+            self.new_comments
+                .iter()
+                .filter(|c| c.comment_type == CommentType::Nest)
+                .count(),
+            self.duplicate_comments
+        )?;
+        if !self.conflicts.is_empty() {
+            writeln!(f, "学校类别与数据库不同的客体（以数据库为准）：")?;
+            for (id, db_cate, cate) in &self.conflicts {
+                writeln!(f, "  {id}: {db_cate} <- {cate}")?;
+            }
+        }
+        if !self.problems.is_empty() {
+            writeln!(f, "无法导入的记录：{}", self.problems.len())?;
+            for p in self.problems.iter().take(MAX_REPORTED_PROBLEMS) {
+                writeln!(f, "  {p}")?;
+            }
+            if self.problems.len() > MAX_REPORTED_PROBLEMS {
+                writeln!(f, "  ……")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 在一个事务中写入导入计划
+pub fn apply(db: &SAFCdb, plan: &ImportPlan) -> Result<()> {
+    db.add_batch(&plan.new_objects, &plan.new_comments)
+}
+
+#[test]
+fn test_import_normalized() {
+    use crate::db::temp_db;
+    let db = temp_db();
+    let json = r#"[
+        {"school_cate": "985", "university": "清华大学", "department": "计算机系", "supervisor": "张三",
+         "description": "评价", "date": "2023-09-01",
+         "replies": [{"description": "回复", "date": "2023-09-02"}]},
+        {"school_cate": "985", "university": "清华大学", "department": "计算机系", "supervisor": "张三",
+         "description": "评价", "date": "2023-09-01"},
+        {"school_cate": "", "university": "清华大学", "department": "计算机系", "supervisor": "李四"},
+        {"university": "缺少字段"}
+    ]"#;
+    let records = read_records(&db, ImportFormat::Json, json).unwrap();
+    let p = plan(&db, records, SourceCate::Admin).unwrap();
+    assert_eq!(1, p.new_objects.len());
+    assert_eq!(1, p.existing_objects);
+    assert_eq!(2, p.new_comments.len());
+    assert_eq!(1, p.duplicate_comments);
+    assert_eq!(2, p.problems.len());
+    let t = &p.new_objects[0];
+    assert_eq!(
+        hash_object_id(&t.university, &t.department, &t.supervisor),
+        t.object_id
+    );
+    assert_eq!(p.new_comments[0].id, p.new_comments[1].object);
+    assert_eq!(CommentType::Nest, p.new_comments[1].comment_type);
+    assert!(p.to_string().contains("新评价：2（其中嵌套评价 1）"));
+
+    apply(&db, &p).unwrap();
+    assert_eq!(1, db.find_comment(&t.object_id).unwrap().len());
+
+    // 再次导入全部为重复
+    let records = read_records(&db, ImportFormat::Json, json).unwrap();
+    let p = plan(&db, records, SourceCate::Admin).unwrap();
+    assert!(p.is_empty());
+    assert_eq!(2, p.existing_objects);
+    assert_eq!(3, p.duplicate_comments);
+
+    // CSV 与 JSON 得到相同的 id
+    let csv = "\u{feff}school_cate,university,department,supervisor,description,date\n\
+        211,清华大学,计算机系,张三,\"评价\",2023-09-01\n\
+        985,北京大学,\"数学, 科学\",王五,\"多行\n\"\"评价\"\"\",2023-09-03\n\
+        985,北京大学\n";
+    let records = read_records(&db, ImportFormat::Csv, csv).unwrap();
+    let p = plan(&db, records, SourceCate::Admin).unwrap();
+    assert_eq!(1, p.duplicate_comments);
+    assert_eq!("数学, 科学", p.new_objects[0].department);
+    assert_eq!("多行\n\"评价\"", p.new_comments[0].description);
+    assert_eq!(1, p.conflicts.len());
+    assert_eq!(1, p.problems.len());
+
+    // 评星列可选，超出范围的评星作为问题报告
+    let csv = "school_cate,university,department,supervisor,description,rating_academic\n\
+        985,北京大学,self,赵六,有评星,3\n\
+        985,北京大学,self,赵六,超出范围,7\n\
+        985,北京大学,self,赵六,不是数字,x\n\
+        985,北京大学,self,赵六,没有评星,\n";
+    let records = read_records(&db, ImportFormat::Csv, csv).unwrap();
+    let p = plan(&db, records, SourceCate::Admin).unwrap();
+    assert_eq!(2, p.new_comments.len());
+    assert_eq!(Some(3), p.new_comments[0].ratings.and_then(|r| r.academic));
+    assert_eq!(None, p.new_comments[1].ratings);
+    assert_eq!(2, p.problems.len());
+}
+
+#[test]
+fn test_import_adapters() {
+    use crate::db::temp_db;
+    let db = temp_db();
+    let urfire = r#"[{"school_cate": "985", "university": "清华大学", "department": "计算机系",
+        "supervisor": "张三", "rate": 5, "description": "好", "date": "2021-01-01", "counts": 1}]"#;
+    let records = read_records(&db, ImportFormat::Urfire, urfire).unwrap();
+    let p = plan(&db, records, ImportFormat::Urfire.default_source()).unwrap();
+    assert_eq!(URFIRE_DATE, p.new_objects[0].date);
+    assert_eq!(SourceCate::Urfire, p.new_comments[0].source_cate);
+    // 与原先的 Python 脚本相同的 id
+    assert_eq!(
+        hash_comment_id(
+            &p.new_objects[0].object_id,
+            &"好".to_string(),
+            &"2021-01-01".to_string()
+        ),
+        p.new_comments[0].id
+    );
+    apply(&db, &p).unwrap();
+
+    let pi_review = r#"[
+        {"url": "https://pi-review.com/pis/1", "updated": "2023-09-26", "university": "清华大学",
+         "department": "电子系", "supervisor": "李四", "website": "https://example.com",
+         "reviews": [{"date": "2023-01-02T03:04:05Z", "content": "评价",
+                      "comments": [{"date": "2023-01-03T00:00:00Z", "content": "回复"}]}]},
+        {"url": "https://pi-review.com/pis/2", "updated": "2023-09-26", "university": "某大学",
+         "department": "某系", "supervisor": "王五"}
+    ]"#;
+    let records = read_records(&db, ImportFormat::PiReview, pi_review).unwrap();
+    let p = plan(&db, records, ImportFormat::PiReview.default_source()).unwrap();
+    // 沿用数据库中清华大学的类别
+    assert_eq!("985", p.new_objects[0].school_cate);
+    assert_eq!(
+        Some("Personal Website: https://example.com\n".to_string()),
+        p.new_objects[0].info
+    );
+    assert_eq!("2023-01-02", p.new_comments[0].date);
+    assert_eq!(
+        "评价\n\nfrom https://pi-review.com/pis/1 updated at 2023-09-26",
+        p.new_comments[0].description
+    );
+    assert_eq!(2, p.new_comments.len());
+    assert_eq!(1, p.problems.len());
+
+    assert!(matches!(
+        read_records(&db, ImportFormat::Json, "{}"),
+        Err(Error::Validation(_))
+    ));
+}
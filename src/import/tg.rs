@@ -0,0 +1,351 @@
+//! # tg
+//!
+//! `script/crawlers/tmp_from_tg.txt` 的解析：早期在 telegram 群中收集的上海交通大学导师评价
+//!
+//! 文件为半结构化的文本：
+//!
+//! ```text
+//! 电子信息与电气工程学院
+//!
+//! ********************************************************************************
+//!
+//! Name:冯冬涵
+//! ------------------
+//! 评星(满分5星) 学术水平:4 科研经费:5 师生关系:2 学生前途:3
+//!
+//! 导师辨识特征：……
+//!
+//! 学术水平：……
+//!
+//! 2022-10
+//! ```
+//!
+//! 条目之外的单行文本为学院，对其后的条目生效；`*` 组成的行分隔条目；条目以 `Name:` 开始，
+//! 以 `YYYY-MM` 的日期行结束，评星行之后到日期行之前的文本（含各个带标签的段落）即评价正文。
+//!
+//! 无法解析的行记为 [`TgProblem`]，管理员可据此修改文件后再导入。
+
+use super::ImportRecord;
+use crate::db::rating::MAX_STARS;
+use crate::db::{RatingDimension, Ratings};
+
+/// 文件中的评价均来自上海交通大学
+pub const TG_UNIVERSITY: &str = "上海交通大学";
+/// 数据库中没有 [`TG_UNIVERSITY`] 时使用的学校类别
+pub const TG_SCHOOL_CATE: &str = "985";
+
+/// 解析 `评星(满分5星) 学术水平:4 科研经费:5 师生关系:2 学生前途:3`，返回无法识别的项
+///
+/// `未填` 为未评
+fn parse_ratings(line: &str) -> (Ratings, Vec<String>) {
+    let mut ratings = Ratings::default();
+    let mut unknown = vec![];
+    for item in line.split_whitespace().skip(1) {
+        let dimension = item.split_once([':', '：']).and_then(|(label, value)| {
+            RatingDimension::ALL
+                .into_iter()
+                .find(|d| d.label() == label)
+                .map(|d| (d, value))
+        });
+        match dimension {
+            Some((_, "未填")) => {}
+            Some((d, v)) => match v.parse::<u8>() {
+                Ok(n) if (1..=MAX_STARS).contains(&n) => ratings.set(d, Some(n)),
+                _ => unknown.push(item.to_string()),
+            },
+            None => unknown.push(item.to_string()),
+        }
+    }
+    (ratings, unknown)
+}
+
+/// 一条评价
+#[derive(Debug, Clone, PartialEq)]
+pub struct TgReview {
+    /// `Name:` 所在的行号，从 1 开始
+    pub line: usize,
+    pub department: String,
+    pub supervisor: String,
+    pub ratings: Ratings,
+    /// 评价正文，保留各段落的标签
+    pub description: String,
+    /// `YYYY-MM`
+    pub date: String,
+}
+
+impl TgReview {
+    /// 转换为规范化的导入记录，客体的日期取评价的日期
+    pub fn into_record(self, school_cate: &str, university: &str) -> ImportRecord {
+        ImportRecord {
+            school_cate: school_cate.to_string(),
+            university: university.to_string(),
+            department: self.department,
+            supervisor: self.supervisor,
+            info: None,
+            object_date: Some(self.date.clone()),
+            description: Some(self.description),
+            date: Some(self.date),
+            ratings: Some(self.ratings).filter(|r| !r.is_empty()),
+            replies: vec![],
+        }
+    }
+}
+
+/// 无法解析的行
+#[derive(Debug, Clone, PartialEq)]
+pub struct TgProblem {
+    /// 行号，从 1 开始
+    pub line: usize,
+    pub reason: String,
+    /// 该行的原文
+    pub text: String,
+}
+
+/// 解析结果
+#[derive(Debug, Clone, Default)]
+pub struct TgDump {
+    pub reviews: Vec<TgReview>,
+    pub problems: Vec<TgProblem>,
+}
+
+/// 正在解析的条目
+struct Entry {
+    name: (usize, String),
+    ratings: Option<Ratings>,
+    body: Vec<String>,
+    date: Option<String>,
+}
+
+/// 解析的状态
+enum State {
+    /// 条目之外，`bool` 为是否已读到学院行
+    Outside(bool),
+    /// 分隔行之后，等待 `Name:`
+    Start,
+    Entry(Entry),
+    /// 无法解析的条目，跳过到下一个分隔行
+    Skip,
+}
+
+fn is_separator(line: &str) -> bool {
+    line.len() >= 10 && line.chars().all(|c| c == '*')
+}
+
+fn is_date(line: &str) -> bool {
+    line.len() == 7 && chrono::NaiveDate::parse_from_str(&format!("{line}-01"), "%Y-%m-%d").is_ok()
+}
+
+/// 解析整个文件，无法解析的条目不会出现在 [`TgDump::reviews`] 中
+pub fn parse(input: &str) -> TgDump {
+    let mut dump = TgDump::default();
+    let mut department: Option<String> = None;
+    let mut state = State::Outside(false);
+
+    for (i, raw) in input.trim_start_matches('\u{feff}').lines().enumerate() {
+        let n = i + 1;
+        let line = raw.trim();
+        if is_separator(line) {
+            if let State::Entry(e) = std::mem::replace(&mut state, State::Start) {
+                dump.finish(e, department.as_deref());
+            }
+            continue;
+        }
+        match &mut state {
+            State::Skip => {}
+            State::Outside(_) | State::Start if line.is_empty() => {}
+            State::Outside(seen) => {
+                if *seen || line.contains([':', '：']) {
+                    dump.problem(n, "条目之外的多余文本", line);
+                } else {
+                    *seen = true;
+                    department = Some(line.to_string());
+                }
+            }
+            State::Start => match line.strip_prefix("Name:").or(line.strip_prefix("Name：")) {
+                Some(name) => {
+                    state = State::Entry(Entry {
+                        name: (n, name.trim().to_string()),
+                        ratings: None,
+                        body: vec![],
+                        date: None,
+                    })
+                }
+                None => {
+                    dump.problem(n, "条目应以 Name: 开始", line);
+                    state = State::Skip;
+                }
+            },
+            State::Entry(e) if e.ratings.is_none() => {
+                if line.is_empty() || line.chars().all(|c| c == '-') {
+                    continue;
+                }
+                if line.starts_with("评星") {
+                    let (ratings, unknown) = parse_ratings(line);
+                    if !unknown.is_empty() {
+                        let reason = format!("无法识别的评星：{}", unknown.join(" "));
+                        dump.problem(n, &reason, line);
+                    }
+                    e.ratings = Some(ratings);
+                } else {
+                    dump.problem(n, "缺少评星行", line);
+                    e.ratings = Some(Ratings::default());
+                    e.body.push(raw.trim_end().to_string());
+                }
+            }
+            State::Entry(e) => {
+                if is_date(line) {
+                    e.date = Some(line.to_string());
+                    // 日期行结束条目，之后到下一个分隔行之间可以是新的学院
+                    let e = match std::mem::replace(&mut state, State::Outside(false)) {
+                        State::Entry(e) => e,
+                        _ => unreachable!(),
+                    };
+                    dump.finish(e, department.as_deref());
+                } else {
+                    e.body.push(raw.trim_end().to_string());
+                }
+            }
+        }
+    }
+    if let State::Entry(e) = state {
+        dump.finish(e, department.as_deref());
+    }
+    dump.problems.sort_by_key(|p| p.line);
+    dump
+}
+
+impl TgDump {
+    fn problem(&mut self, line: usize, reason: &str, text: &str) {
+        self.problems.push(TgProblem {
+            line,
+            reason: reason.to_string(),
+            text: text.to_string(),
+        })
+    }
+
+    /// 检查条目是否完整，完整的加入 [`TgDump::reviews`]
+    fn finish(&mut self, e: Entry, department: Option<&str>) {
+        let (line, supervisor) = e.name;
+        let text = format!("Name:{supervisor}");
+        let Some(department) = department else {
+            return self.problem(line, "条目之前没有学院", &text);
+        };
+        let Some(date) = e.date else {
+            return self.problem(line, "条目缺少 YYYY-MM 格式的日期行", &text);
+        };
+        // 合并连续的空行
+        let mut description = String::new();
+        for l in &e.body {
+            if l.is_empty() && (description.is_empty() || description.ends_with("\n\n")) {
+                continue;
+            }
+            description.push_str(l);
+            description.push('\n');
+        }
+        let description = description.trim().to_string();
+        if description.is_empty() {
+            return self.problem(line, "条目没有评价正文", &text);
+        }
+        self.reviews.push(TgReview {
+            line,
+            department: department.to_string(),
+            supervisor,
+            ratings: e.ratings.unwrap_or_default(),
+            description,
+            date,
+        });
+    }
+}
+
+#[test]
+fn test_tg_parse() {
+    let input = "电子信息与电气工程学院
+
+********************************************************************************
+
+Name:冯冬涵
+------------------
+评星(满分5星) 学术水平:4 科研经费:5 师生关系:未填 学生前途:3
+
+导师辨识特征：办公室在电院
+
+师生关系：1、管理严格
+2、经常开会
+
+
+2022-10
+
+
+********************************************************************************
+
+Name:张三
+------------------
+评星(满分5星) 学术水平:9 科研经费:5 师生关系:2 学生前途:3
+
+学术水平：一般
+
+2022-11
+
+机械与动力工程学院
+
+********************************************************************************
+
+Name:李四
+------------------
+评星(满分5星) 学术水平:1 科研经费:1 师生关系:1 学生前途:1
+
+学生前途：好
+
+********************************************************************************
+
+李四
+------------------
+";
+    let dump = parse(input);
+    assert_eq!(dump.reviews.len(), 2);
+    let r = &dump.reviews[0];
+    assert_eq!(r.line, 5);
+    assert_eq!(r.department, "电子信息与电气工程学院");
+    assert_eq!(r.supervisor, "冯冬涵");
+    assert_eq!(
+        r.ratings,
+        Ratings {
+            academic: Some(4),
+            funding: Some(5),
+            relationship: None,
+            prospects: Some(3),
+            stipend: None,
+        }
+    );
+    assert_eq!(
+        r.description,
+        "导师辨识特征：办公室在电院\n\n师生关系：1、管理严格\n2、经常开会"
+    );
+    assert_eq!(r.date, "2022-10");
+    // 超出满分的评星只报告，不影响导入
+    assert_eq!(dump.reviews[1].supervisor, "张三");
+    assert_eq!(dump.reviews[1].ratings.academic, None);
+    assert_eq!(dump.reviews[1].ratings.funding, Some(5));
+
+    let problems: Vec<_> = dump
+        .problems
+        .iter()
+        .map(|p| (p.line, p.reason.as_str()))
+        .collect();
+    assert_eq!(
+        problems,
+        vec![
+            (22, "无法识别的评星：学术水平:9"),
+            (32, "条目缺少 YYYY-MM 格式的日期行"),
+            (40, "条目应以 Name: 开始"),
+        ]
+    );
+    assert_eq!(dump.problems[2].text, "李四");
+
+    let record = dump.reviews[0]
+        .clone()
+        .into_record(TG_SCHOOL_CATE, TG_UNIVERSITY);
+    assert_eq!(record.university, "上海交通大学");
+    assert_eq!(record.object_date.as_deref(), Some("2022-10"));
+    assert_eq!(record.ratings, Some(r.ratings));
+}
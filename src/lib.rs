@@ -1,4 +1,11 @@
 //! safc 的底层核心库
 
 pub mod db;
+pub mod error;
+pub mod export;
+pub mod import;
+pub mod link;
 pub mod sec;
+pub mod service;
+
+pub use error::{Error, Result};
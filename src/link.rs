@@ -0,0 +1,80 @@
+//! # link
+//!
+//! 指向 telegram bot 中某个客体或评价的深度链接：`https://t.me/<bot>?start=<payload>`
+//!
+//! payload 为 `obj_<object_id>` 或 `cmt_<comment_id>`，
+//! telegram 限制其最长 64 字符，且只能包含 `A-Z a-z 0-9 _ -`。
+
+use serde::{Deserialize, Serialize};
+
+const OBJECT_PREFIX: &str = "obj_";
+const COMMENT_PREFIX: &str = "cmt_";
+/// telegram 对 `/start` 参数长度的限制
+const MAX_PAYLOAD_LEN: usize = 64;
+
+/// 深度链接的目标
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "id", rename_all = "lowercase")]
+pub enum DeepLink {
+    /// 客体，内容为 object id
+    Object(String),
+    /// 评价，内容为评价 id
+    Comment(String),
+}
+
+impl DeepLink {
+    /// 解析 `/start` 的参数，格式不符时返回 `None`
+    pub fn parse(payload: &str) -> Option<Self> {
+        let payload = payload.trim();
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return None;
+        }
+        let valid = |id: &&str| {
+            !id.is_empty()
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        if let Some(id) = payload.strip_prefix(OBJECT_PREFIX).filter(valid) {
+            Some(Self::Object(id.to_string()))
+        } else {
+            payload
+                .strip_prefix(COMMENT_PREFIX)
+                .filter(valid)
+                .map(|id| Self::Comment(id.to_string()))
+        }
+    }
+
+    /// `/start` 的参数
+    pub fn payload(&self) -> String {
+        match self {
+            Self::Object(id) => format!("{OBJECT_PREFIX}{id}"),
+            Self::Comment(id) => format!("{COMMENT_PREFIX}{id}"),
+        }
+    }
+
+    /// 完整的链接，`bot_username` 不含 @
+    pub fn url(&self, bot_username: &str) -> String {
+        format!("https://t.me/{}?start={}", bot_username, self.payload())
+    }
+}
+
+#[test]
+fn test_deep_link() {
+    let obj = DeepLink::Object("2ac4ae281b9a2528".to_string());
+    assert_eq!("obj_2ac4ae281b9a2528", obj.payload());
+    assert_eq!(Some(obj.clone()), DeepLink::parse(&obj.payload()));
+    assert_eq!(
+        "https://t.me/SAFC_bot?start=obj_2ac4ae281b9a2528",
+        obj.url("SAFC_bot")
+    );
+
+    let cmt = DeepLink::Comment("9f0e1d2c3b4a5968".to_string());
+    assert_eq!(Some(cmt.clone()), DeepLink::parse(" cmt_9f0e1d2c3b4a5968 "));
+
+    assert_eq!(None, DeepLink::parse(""));
+    assert_eq!(None, DeepLink::parse("obj_"));
+    assert_eq!(None, DeepLink::parse("cmt_a b"));
+    assert_eq!(None, DeepLink::parse("xyz_2ac4ae281b9a2528"));
+    assert_eq!(None, DeepLink::parse(&format!("obj_{}", "a".repeat(64))));
+}
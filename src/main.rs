@@ -1,22 +1,33 @@
 use safc::db::*;
-use safc::sec::*;
+use safc::export::{self, ExportFormat};
+use safc::link::DeepLink;
+use safc::service;
 
 // msg 是 bot 独用的 mod
 mod msg;
 use msg::*;
+mod admin;
+use admin::AdminCommand;
+mod backup;
+mod notify;
+mod storage;
+use storage::SAFCStorage;
 
-use teloxide::types::InputFile;
 use teloxide::{
-    dispatching::{dialogue, dialogue::InMemStorage, UpdateHandler},
+    dispatching::{dialogue, UpdateHandler},
     prelude::*,
     types::{
-        InlineKeyboardButton, InlineKeyboardMarkup, KeyboardButton, KeyboardMarkup, KeyboardRemove,
-        ParseMode::MarkdownV2,
+        BotCommandScope, InlineQueryResult, InlineQueryResultArticle, InputFile,
+        InputMessageContent, InputMessageContentText, KeyboardButton, KeyboardMarkup,
+        KeyboardRemove, MessageId, ParseMode::MarkdownV2, Recipient, User,
     },
     utils::command::BotCommands,
 };
 
-type MyDialogue = Dialogue<State, InMemStorage<State>>; // ? 要使用 sqlite 存储状态吗
+type MyDialogue = Dialogue<State, SAFCStorage>;
+/// 内联查询最多返回的结果数（telegram 上限为 50）
+const INLINE_MAX_RESULTS: usize = 20;
+
 type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
 
 #[derive(BotCommands, Clone, PartialEq, Debug)]
@@ -29,17 +40,25 @@ enum Command {
     #[command(description = "显示帮助信息")]
     Help,
     #[command(description = "开始")]
-    Start,
+    Start(String),
     #[command(description = "终止对话")]
     Cancel,
     #[command(description = "信息")]
     Info,
-    #[command(description = "下载数据库")]
-    DownloadDb,
     #[command(description = "评价")]
     Comment(String),
     #[command(description = "搜索")]
     Find(String),
+    #[command(description = "修改自己发布的评价 /edit <id>")]
+    Edit(String),
+    #[command(description = "撤回自己发布的评价 /delete <id>")]
+    Delete(String),
+    #[command(description = "关注客体，有新评价时通知您 /subscribe <id>")]
+    Subscribe(String),
+    #[command(description = "查看与取消关注")]
+    Subscriptions,
+    #[command(description = "导出数据库 /export <json|jsonl|csv|md>")]
+    Export(String),
 }
 
 #[tokio::main]
@@ -52,11 +71,42 @@ async fn main() {
     bot.set_my_commands(Command::bot_commands()) // 向 telegram 注册命令
         .await
         .expect("Failed to set bot commands to telegram");
-    
+
     log::info!("Bot commands have been set");
 
+    // 管理员的命令列表额外包含管理员命令
+    let mut all_commands = Command::bot_commands();
+    all_commands.extend(AdminCommand::bot_commands());
+    for &admin_id in admin::admins() {
+        if let Err(e) = bot
+            .set_my_commands(all_commands.clone())
+            .scope(BotCommandScope::Chat {
+                chat_id: Recipient::Id(ChatId(admin_id as i64)),
+            })
+            .await
+        {
+            log::warn!("无法为管理员 {} 设置命令：{}", admin_id, e);
+        }
+    }
+
+    match bot.get_me().await {
+        Ok(me) => {
+            BOT_USERNAME.set(me.username().to_string()).ok();
+        }
+        Err(e) => log::error!("无法获取 bot 用户名，内联结果将不带深度链接：{}", e),
+    }
+
+    // 会话状态保存在数据库中，重启后不会丢失
+    let storage = SAFCStorage::new(SAFC_DB.clone());
+    tokio::spawn(storage.clone().purge_task());
+    tokio::spawn(notify::notify_task(bot.clone()));
+    tokio::spawn(backup::backup_task(
+        bot.clone(),
+        backup::BackupConfig::from_env(),
+    ));
+
     Dispatcher::builder(bot, schema())
-        .dependencies(dptree::deps![InMemStorage::<State>::new()])
+        .dependencies(dptree::deps![storage])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
@@ -71,13 +121,17 @@ fn schema() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync + 'static>>
 
     // 命令
     let command_handler = teloxide::filter_command::<Command, _>()
-        .branch(case![Command::Start].endpoint(start))
+        .branch(case![Command::Start(arg)].endpoint(start))
         .branch(case![Command::Help].endpoint(help_command))
         .branch(case![Command::Cancel].endpoint(cancel_command))
         .branch(case![Command::Info].endpoint(info_command))
-        .branch(case![Command::DownloadDb].endpoint(download_db_command))
         .branch(case![Command::Find(arg)].endpoint(find_command))
         .branch(case![Command::Comment(arg)].endpoint(comment_command))
+        .branch(case![Command::Edit(arg)].endpoint(edit_command))
+        .branch(case![Command::Delete(arg)].endpoint(delete_command))
+        .branch(case![Command::Subscribe(arg)].endpoint(subscribe_command))
+        .branch(case![Command::Subscriptions].endpoint(subscriptions_command))
+        .branch(case![Command::Export(arg)].endpoint(export_command))
         .branch(dptree::endpoint(invalid_command));
 
     // 文本消息
@@ -90,25 +144,45 @@ fn schema() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync + 'static>>
         .branch(case![State::Department { school_cate, university }].endpoint(choose_supervisor))
         .branch(case![State::Supervisor { school_cate, university, department }].endpoint(read_or_comment))
         .branch(case![State::Comment { object_id, comment_type }].endpoint(add_comment))
-        .branch(case![State::Publish { object_id, comment, comment_type }].endpoint(publish_comment));
+        .branch(case![State::Publish { object_id, comment, comment_type, ratings }].endpoint(publish_comment))
+        .branch(case![State::Edit { comment_id }].endpoint(edit_comment_text))
+        .branch(case![State::EditConfirm { comment_id, comment }].endpoint(edit_comment_otp))
+        .branch(case![State::Retract { comment_id }].endpoint(retract_comment_otp));
+
+    // 管理员命令
+    let admin_command_handler = dptree::filter(admin::is_admin_message)
+        .chain(teloxide::filter_command::<AdminCommand, _>())
+        .endpoint(admin::admin_command);
 
     // 消息
     let message_handler = Update::filter_message()
+        .branch(admin_command_handler)
         .branch(command_handler) // 命令也是消息的一种
         .branch(text_handler)
         .branch(dptree::endpoint(invalid_state));
 
-    // 回调
-    // todo 解决回调信息的串扰问题
+    // 回调，先排除旧消息上的过期按钮
+    #[rustfmt::skip]
     let callback_query_handler = Update::filter_callback_query()
-        .branch(case![State::StartCb].endpoint(start_cb))
-        .branch(case![State::Read { obj_teacher }].endpoint(read_or_comment_cb))
+        .branch(dptree::filter(is_stale_callback).endpoint(stale_callback))
+        .branch(case![State::StartCb { msg_id }].endpoint(start_cb))
+        .branch(case![State::Read { obj_teacher, msg_id }].endpoint(read_or_comment_cb))
+        .branch(case![State::Rate { object_id, comment, ratings, msg_id }].endpoint(rate_cb))
         .branch(case![State::PagingCb { data }].endpoint(paging_cb))
+        .branch(case![State::Subscriptions { msg_id }].endpoint(subscriptions_cb))
         .branch(dptree::endpoint(invalid_callback_query));
 
-    dialogue::enter::<Update, InMemStorage<State>, State, _>()
-        .branch(message_handler)
-        .branch(callback_query_handler)
+    // 内联查询不属于任何会话
+    let inline_query_handler = Update::filter_inline_query().endpoint(inline_query);
+
+    dptree::entry()
+        .branch(dptree::filter(admin::is_banned_update).endpoint(admin::banned_update))
+        .branch(inline_query_handler)
+        .branch(
+            dialogue::enter::<Update, SAFCStorage, State, _>()
+                .branch(message_handler)
+                .branch(callback_query_handler),
+        )
 }
 
 /// Send a message when the command /help is issued.
@@ -128,28 +202,6 @@ async fn info_command(bot: Bot, msg: Message) -> HandlerResult {
     Ok(())
 }
 
-async fn download_db_command(bot: Bot, msg: Message) -> HandlerResult {
-    let db_path = SAFC_DB.get_db_path();
-
-    // 发送 "正在上传数据库..." 消息
-    bot.send_message(msg.chat.id, "正在上传数据库...").await?;
-
-    // 上传数据库文件
-    let file = InputFile::file(db_path);
-    match bot.send_document(msg.chat.id, file).await {
-        Ok(_) => {
-            bot.send_message(msg.chat.id, "数据库文件已成功上传。")
-                .await?;
-        }
-        Err(err) => {
-            bot.send_message(msg.chat.id, format!("上传数据库文件时出错：{}", err))
-                .await?;
-        }
-    }
-
-    Ok(())
-}
-
 /// Cancels and ends the conversation.
 async fn cancel_command(bot: Bot, dialogue: MyDialogue, msg: Message) -> HandlerResult {
     bot.send_message(
@@ -187,16 +239,106 @@ async fn find_command(bot: Bot, dialogue: MyDialogue, arg: String, msg: Message)
         "使用方法： \n\
             - /find <客体 | 评价> <关键字 1> [关键字...]\n\
             例如：\n\
-            - /find 客体 赵__\n\
+            - /find 客体 张三 清华\n\
             - /find 评价 前途 无量\n\
-            客体的一般是导师的意思。就可选的高级操作：\n\
-            - 您可以用百分号（%）代表零个、一个或多个字符。下划线（_）代表一个单一的字符\n\n\
+            客体的一般是导师的意思。客体可按学校、学院、姓名搜索；关键字之间不分顺序\n\n\
             您也可以使用 /start 中的功能按钮使用这些功能",
     )
     .await?;
     Ok(())
 }
 
+/// 导出数据库命令处理函数，格式默认为 json
+async fn export_command(bot: Bot, arg: String, msg: Message) -> HandlerResult {
+    let arg = arg.trim();
+    let format = if arg.is_empty() {
+        ExportFormat::Json
+    } else {
+        match arg.parse::<ExportFormat>() {
+            Ok(format) => format,
+            Err(_) => {
+                bot.send_message(msg.chat.id, "使用方法： /export <json|jsonl|csv|md>")
+                    .reply_to_message_id(msg.id)
+                    .await?;
+                return Ok(());
+            }
+        }
+    };
+    if let Some(hint) = rate_limit_hint(msg.from(), RateAction::Export)? {
+        bot.send_message(msg.chat.id, hint)
+            .reply_to_message_id(msg.id)
+            .await?;
+        return Ok(());
+    }
+    let data = tokio::task::spawn_blocking(move || -> safc::Result<Vec<u8>> {
+        let mut buf = vec![];
+        export::export_to(&SAFC_DB, format, &mut buf)?;
+        Ok(buf)
+    })
+    .await??;
+    bot.send_document(
+        msg.chat.id,
+        InputFile::memory(data).file_name(format.file_name()),
+    )
+    .caption(SAFC_DB.db_status()?)
+    .reply_to_message_id(msg.id)
+    .await?;
+    Ok(())
+}
+
+/// 关注客体命令处理函数
+async fn subscribe_command(bot: Bot, arg: String, msg: Message) -> HandlerResult {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        bot.send_message(msg.chat.id, "使用方法： /subscribe <id>")
+            .await?;
+        return Ok(());
+    }
+    let text = match SAFC_DB.subscribe(msg.chat.id.0, arg) {
+        Ok(true) => "🔔 已关注，此客体有新评价时会通知您\n使用 /subscriptions 管理关注",
+        Ok(false) => "您已关注此客体",
+        Err(safc::Error::NotFound(_)) => "❌ - 非有效 id",
+        Err(e) => return Err(e.into()),
+    };
+    bot.send_message(msg.chat.id, text)
+        .reply_to_message_id(msg.id)
+        .await?;
+    Ok(())
+}
+
+/// 关注列表命令处理函数
+async fn subscriptions_command(bot: Bot, dialogue: MyDialogue, msg: Message) -> HandlerResult {
+    let (text, keyboard) = subscriptions_msg(&SAFC_DB.find_subscriptions(msg.chat.id.0)?);
+    let sent = bot
+        .send_message(msg.chat.id, text)
+        .parse_mode(MarkdownV2)
+        .reply_markup(keyboard)
+        .reply_to_message_id(msg.id)
+        .await?;
+    dialogue
+        .update(State::Subscriptions { msg_id: sent.id })
+        .await?;
+    Ok(())
+}
+
+/// 关注列表的回调：取消关注并刷新列表
+async fn subscriptions_cb(bot: Bot, q: CallbackQuery) -> HandlerResult {
+    bot.answer_callback_query(q.id).await?;
+    if let (Some(op), Some(Message { id, chat, .. })) = (&q.data, q.message) {
+        match serde_json::from_str(op)? {
+            SubscriptionOp::Unsubscribe(object_id) => {
+                SAFC_DB.unsubscribe(chat.id.0, &object_id)?;
+                let (text, keyboard) = subscriptions_msg(&SAFC_DB.find_subscriptions(chat.id.0)?);
+                bot.edit_message_text(chat.id, id, text)
+                    .parse_mode(MarkdownV2)
+                    .reply_markup(keyboard)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
 /// 直接评价命令处理函数
 async fn comment_command(
     bot: Bot,
@@ -238,6 +380,116 @@ async fn comment_command(
     }
 }
 
+/// 修改评价命令处理函数
+async fn edit_command(bot: Bot, dialogue: MyDialogue, arg: String, msg: Message) -> HandlerResult {
+    let Some(c) = signed_comment_or_reply(&bot, &msg, &arg, "/edit <id>").await? else {
+        return Ok(());
+    };
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "🆔 `{}` 的原评价：\n\n\
+            ```\n{}\n```\n\
+            请写下修改后的评价，/cancel 取消：",
+            c.id,
+            escape(c.description.as_str())
+        ),
+    )
+    .reply_to_message_id(msg.id)
+    .parse_mode(MarkdownV2)
+    .reply_markup(KeyboardRemove::new())
+    .await?;
+    dialogue.update(State::Edit { comment_id: c.id }).await?;
+    Ok(())
+}
+
+/// 撤回评价命令处理函数
+async fn delete_command(
+    bot: Bot,
+    dialogue: MyDialogue,
+    arg: String,
+    msg: Message,
+) -> HandlerResult {
+    let Some(c) = signed_comment_or_reply(&bot, &msg, &arg, "/delete <id>").await? else {
+        return Ok(());
+    };
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "确认撤回评价「`{}`」？如确认请输入发布时的「发布人 OTP」；取消请 /cancel",
+            c.id
+        ),
+    )
+    .reply_to_message_id(msg.id)
+    .parse_mode(MarkdownV2)
+    .reply_markup(KeyboardRemove::new())
+    .await?;
+    dialogue.update(State::Retract { comment_id: c.id }).await?;
+    Ok(())
+}
+
+/// 查找带有发布人签名的评价，不存在或无签名时回复用户并返回 `None`
+async fn signed_comment_or_reply(
+    bot: &Bot,
+    msg: &Message,
+    arg: &str,
+    usage: &str,
+) -> Result<Option<ObjComment>, Box<dyn std::error::Error + Send + Sync>> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        bot.send_message(msg.chat.id, format!("使用方法： {usage}"))
+            .await?;
+        return Ok(None);
+    }
+    match SAFC_DB.find_comment_with_id(arg)? {
+        None => {
+            bot.send_message(msg.chat.id, "❌ - 非有效评价 id").await?;
+            Ok(None)
+        }
+        Some(ObjComment {
+            author_sign: None, ..
+        }) => {
+            bot.send_message(msg.chat.id, "❌ - 此评价没有发布人签名，无法修改或撤回")
+                .await?;
+            Ok(None)
+        }
+        Some(ObjComment {
+            tombstone: Some(_), ..
+        }) => {
+            bot.send_message(msg.chat.id, "❌ - 此评价已被撤回或隐藏")
+                .await?;
+            Ok(None)
+        }
+        Some(c) => Ok(Some(c)),
+    }
+}
+
+/// 按用户限流，管理员不受限制
+///
+/// 超出限制时返回给用户的提示，见 [`safc::db::rate_limit`]
+fn rate_limit_hint(user: Option<&User>, action: RateAction) -> Result<Option<String>, safc::Error> {
+    let Some(user) = user.filter(|u| !admin::is_admin(Some(u))) else {
+        return Ok(None);
+    };
+    match SAFC_DB.take_token(user.id.0 as i64, action) {
+        Ok(()) => Ok(None),
+        Err(e @ safc::Error::RateLimited(_)) => Ok(Some(format!("⏳ {e}"))),
+        Err(e) => Err(e),
+    }
+}
+
+/// 可以直接展示给用户的业务错误
+fn is_user_error(e: &safc::Error) -> bool {
+    matches!(
+        e,
+        safc::Error::Validation(_)
+            | safc::Error::Forbidden(_)
+            | safc::Error::NotFound(_)
+            | safc::Error::DuplicateId(_)
+            | safc::Error::RateLimited(_)
+    )
+}
+
 async fn _unable_command(bot: Bot, msg: Message) -> HandlerResult {
     bot.send_message(msg.chat.id, TgResponse::NotImplemented.to_string())
         .await?;
@@ -259,13 +511,109 @@ async fn invalid_command(bot: Bot, msg: Message) -> HandlerResult {
 }
 
 /// 开始
-async fn start(bot: Bot, dialogue: MyDialogue, msg: Message) -> HandlerResult {
-    bot.send_message(msg.chat.id, TgResponse::Hello.to_string())
+/// 带有深度链接参数（见 [`DeepLink`]）时直接打开对应的客体或评价
+async fn start(bot: Bot, dialogue: MyDialogue, arg: String, msg: Message) -> HandlerResult {
+    match DeepLink::parse(&arg) {
+        Some(DeepLink::Object(object_id)) => {
+            if let Some(obj_teacher) = SAFC_DB.find_objteacher_with_id(&object_id)? {
+                let sent = bot
+                    .send_message(
+                        msg.chat.id,
+                        format!("{}\n请选择操作：", display_teacher_md(&obj_teacher)),
+                    )
+                    .parse_mode(MarkdownV2)
+                    .reply_markup(obj_op_keyboard())
+                    .await?;
+                dialogue
+                    .update(State::Read {
+                        obj_teacher,
+                        msg_id: sent.id,
+                    })
+                    .await?;
+                return Ok(());
+            }
+            bot.send_message(msg.chat.id, "❌ 链接中的客体不存在")
+                .await?;
+        }
+        Some(DeepLink::Comment(comment_id)) => {
+            if let Some(c) = SAFC_DB.find_comment_with_id(&comment_id)? {
+                return start_comment_thread(&bot, dialogue, &msg, c).await;
+            }
+            bot.send_message(msg.chat.id, "❌ 链接中的评价不存在")
+                .await?;
+        }
+        None => {}
+    }
+    let sent = bot
+        .send_message(msg.chat.id, TgResponse::Hello.to_string())
         .parse_mode(MarkdownV2)
         .reply_markup(start_op_keyboard())
         .reply_to_message_id(msg.id)
         .await?;
-    dialogue.update(State::StartCb).await?; // 更新会话状态
+    dialogue.update(State::StartCb { msg_id: sent.id }).await?; // 更新会话状态
+    Ok(())
+}
+
+/// 深度链接打开评价：分页显示此评价及其嵌套评价
+/// 进入分页状态，返回状态为评价所属的客体，找不到客体时为 [`State::StartCb`]
+async fn start_comment_thread(
+    bot: &Bot,
+    dialogue: MyDialogue,
+    msg: &Message,
+    c: ObjComment,
+) -> HandlerResult {
+    let action_name = "回复此评价".to_string();
+    let pages: Vec<String> = get_comment_thread_pages(&c)?
+        .iter()
+        .enumerate()
+        .map(|(i, x)| format!("*💬 评价 `{}` 第 {} 页：*\n{}", c.id, i + 1, x))
+        .collect();
+    let sent = bot
+        .send_message(msg.chat.id, &pages[0])
+        .reply_markup(build_paging_keyboard(pages.len(), 0, Some(&action_name)))
+        .parse_mode(MarkdownV2)
+        .await?;
+
+    let (prev_state, prev_msg, prev_op_keyboard) = match SAFC_DB.find_root_object(&c.id)? {
+        Some(obj_teacher) => (
+            State::Read {
+                msg_id: sent.id,
+                obj_teacher: obj_teacher.clone(),
+            },
+            format!("{}\n请选择操作：", display_teacher_md(&obj_teacher)),
+            obj_op_keyboard(),
+        ),
+        None => (
+            State::StartCb { msg_id: sent.id },
+            "请选择操作：".to_string(),
+            start_op_keyboard(),
+        ),
+    };
+    let action_states = vec![
+        State::Comment {
+            object_id: c.id.clone(),
+            comment_type: CommentType::Nest,
+        };
+        pages.len()
+    ];
+    let action_msgs = vec![format!("回复评价 `{}`\n/cancel 取消", c.id); pages.len()];
+    dialogue
+        .update(State::PagingCb {
+            data: PagingCbData {
+                msg_id: sent.id,
+                pages,
+                actions: Some(PagingCbActions {
+                    name: action_name,
+                    action_states,
+                    action_msgs,
+                    ..Default::default()
+                }),
+                prev_state: Box::new(prev_state),
+                prev_msg,
+                prev_op_keyboard,
+            },
+        })
+        .await?;
     Ok(())
 }
 
@@ -285,18 +633,16 @@ async fn start_cb(bot: Bot, dialogue: MyDialogue, q: CallbackQuery) -> HandlerRe
                 dialogue.update(State::SchoolCate).await?; // 更新会话状态
             }
             StartOp::FindSupervisor => {
-                let text = "请回复你要查找的 👔\n\
-                    可选：您可以用百分号（%）代表零个、一个或多个字符。下划线（_）代表一个单一的字符\n\n\
-                    例如：赵__\n\
-                    此功能等效使用命令 /find 客体 赵__\n";
+                let text = "请回复你要查找的 👔，可附加学校、学院，空格分隔\n\n\
+                    例如：张三 清华\n\
+                    此功能等效使用命令 /find 客体 张三 清华\n";
                 bot.send_message(dialogue.chat_id(), text).await?;
                 dialogue.update(State::FindSupervisor).await?;
             }
             StartOp::FindComment => {
-                let text = "请回复你要查找的评价关键字，空格分隔\n\
-                    可选：您可以用百分号（%）代表零个、一个或多个字符。下划线（_）代表一个单一的字符\n\n\
+                let text = "请回复你要查找的评价关键字，空格分隔，不分顺序\n\n\
                     例如：前途 无量\n\
-                    此功能等效使用命令 /find 评价 前途 无量"; // todo
+                    此功能等效使用命令 /find 评价 前途 无量";
                 bot.send_message(dialogue.chat_id(), text).await?;
                 dialogue.update(State::FindComment).await?;
             }
@@ -309,10 +655,46 @@ async fn start_cb(bot: Bot, dialogue: MyDialogue, q: CallbackQuery) -> HandlerRe
     Ok(())
 }
 
-/// 用于模糊搜索的关键字列表的处理函数
-/// todo 去除顺序的影响
-fn search_keys_helper(s: &[&str]) -> String {
-    format!("%{}%", s.join("%"))
+/// 内联模式：`@bot 张三 清华` 搜索客体，选中后发送客体卡片与回到 bot 的链接
+async fn inline_query(bot: Bot, q: InlineQuery) -> HandlerResult {
+    // 空查询没有结果，不消耗令牌
+    if q.query.trim().is_empty() {
+        bot.answer_inline_query(q.id, vec![]).await?;
+        return Ok(());
+    }
+    if let Some(hint) = rate_limit_hint(Some(&q.from), RateAction::InlineSearch)? {
+        bot.answer_inline_query(q.id, vec![])
+            .cache_time(0)
+            .switch_pm_text(hint)
+            .switch_pm_parameter("start")
+            .await?;
+        return Ok(());
+    }
+    let mut results = vec![];
+    for hit in SAFC_DB.search(&q.query, SearchKind::Object, INLINE_MAX_RESULTS)? {
+        let Some(t) = SAFC_DB.find_objteacher_with_id(&hit.id)? else {
+            continue;
+        };
+        let count = SAFC_DB.count_comments(&t.object_id)?;
+        let content = InputMessageContentText::new(display_teacher_md(&t)).parse_mode(MarkdownV2);
+        let mut article = InlineQueryResultArticle::new(
+            &t.object_id,
+            format!("👔 {}", t.supervisor),
+            InputMessageContent::Text(content),
+        )
+        .description(format!(
+            "{} {} {} · {} 条评价",
+            t.school_cate, t.university, t.department, count
+        ));
+        if let Some(keyboard) = object_link_keyboard(&t.object_id) {
+            article = article.reply_markup(keyboard);
+        }
+        results.push(InlineQueryResult::Article(article));
+    }
+    bot.answer_inline_query(q.id, results)
+        .cache_time(60)
+        .await?;
+    Ok(())
 }
 
 /// 快速查找客体的消息
@@ -323,7 +705,16 @@ async fn find_supervisor_msg(
     msg: &Message,
     dialogue: MyDialogue,
 ) -> HandlerResult {
-    let mut objs = SAFC_DB.find_supervisor_like(&search_keys_helper(args))?;
+    if let Some(hint) = rate_limit_hint(msg.from(), RateAction::Search)? {
+        bot.send_message(msg.chat.id, hint)
+            .reply_to_message_id(msg.id)
+            .await?;
+        return Ok(());
+    }
+    let mut objs = vec![];
+    for hit in SAFC_DB.search(&args.join(" "), SearchKind::Object, MSG_MAX_PAGES)? {
+        objs.extend(SAFC_DB.find_objteacher_with_id(&hit.id)?);
+    }
     if objs.is_empty() {
         bot.send_message(msg.chat.id, ":( 找不到所查询的导师")
             .reply_to_message_id(msg.id)
@@ -337,25 +728,30 @@ async fn find_supervisor_msg(
         .into_iter()
         .map(|x| display_teacher_md(&x))
         .collect();
+    let text = &pages[0]; // assert!(pages.len() >= 1);
+    let sent = bot
+        .send_message(msg.chat.id, text)
+        .reply_markup(build_paging_keyboard(pages.len(), 0, Some(&action_name)))
+        .parse_mode(MarkdownV2)
+        .reply_to_message_id(msg.id)
+        .await?;
     let action_states = objs
         .clone()
         .into_iter()
-        .map(|x| State::Read { obj_teacher: x })
+        .map(|x| State::Read {
+            obj_teacher: x,
+            msg_id: sent.id,
+        })
         .collect();
     let action_msgs = objs
         .clone()
         .into_iter()
         .map(|x| format!("{}\n请选择操作：", x.display_path()))
         .collect();
-    let text = &pages[0]; // assert!(pages.len() >= 1);
-    bot.send_message(msg.chat.id, text)
-        .reply_markup(build_paging_keyboard(pages.len(), 0, Some(&action_name)))
-        .parse_mode(MarkdownV2)
-        .reply_to_message_id(msg.id)
-        .await?;
     dialogue
         .update(State::PagingCb {
             data: PagingCbData {
+                msg_id: sent.id,
                 pages,
                 actions: Some(PagingCbActions {
                     name: action_name,
@@ -363,7 +759,7 @@ async fn find_supervisor_msg(
                     action_msgs,
                     action_op_keyboard: obj_op_keyboard(),
                 }),
-                prev_state: Box::new(State::StartCb),
+                prev_state: Box::new(State::StartCb { msg_id: sent.id }),
                 prev_msg: "请选择操作：".to_string(),
                 prev_op_keyboard: start_op_keyboard(),
             },
@@ -380,7 +776,16 @@ async fn find_comment_msg(
     msg: &Message,
     dialogue: MyDialogue,
 ) -> HandlerResult {
-    let mut objs = SAFC_DB.find_comment_like(&search_keys_helper(args))?;
+    if let Some(hint) = rate_limit_hint(msg.from(), RateAction::Search)? {
+        bot.send_message(msg.chat.id, hint)
+            .reply_to_message_id(msg.id)
+            .await?;
+        return Ok(());
+    }
+    let mut objs = vec![];
+    for hit in SAFC_DB.search(&args.join(" "), SearchKind::Comment, MSG_MAX_PAGES)? {
+        objs.extend(SAFC_DB.find_comment_with_id(&hit.id)?);
+    }
     if objs.is_empty() {
         bot.send_message(msg.chat.id, ":( 查询无结果")
             .reply_to_message_id(msg.id)
@@ -389,11 +794,11 @@ async fn find_comment_msg(
     }
     let action_name = "回复此评价".to_string();
     objs.truncate(MSG_MAX_PAGES);
-    let pages: Vec<String> = objs
-        .clone()
+    // 过长的评价拆为多页，各页都指向同一条评价
+    let comment_pages: Vec<(ObjComment, String)> = objs
         .into_iter()
-        .map(|c: ObjComment| {
-            format!(
+        .flat_map(|c: ObjComment| {
+            let md = format!(
                 "💬 *针对 object `{}` 的评价：*\n\
                 *data {} \\| from {} \\| id `{}`*\n\
                 {}\n",
@@ -402,21 +807,22 @@ async fn find_comment_msg(
                 c.source_cate,
                 c.id,
                 escape(c.description.replace("<br>", "\n").as_str())
-            )
+            );
+            split_md(&md, PAGE_MAX_LEN)
+                .into_iter()
+                .map(move |p| (c.clone(), p))
         })
         .collect();
-    let action_states = objs
-        .clone()
-        .into_iter()
-        .map(|c| State::Comment {
-            object_id: c.id,
+    let action_states = comment_pages
+        .iter()
+        .map(|(c, _)| State::Comment {
+            object_id: c.id.clone(),
             comment_type: CommentType::Nest,
         })
         .collect();
-    let action_msgs = objs
-        .clone()
-        .into_iter()
-        .map(|c| {
+    let action_msgs = comment_pages
+        .iter()
+        .map(|(c, _)| {
             format!(
                 "🆔 `{}`\n\
                 \n请写下您对此客体的评价：",
@@ -424,9 +830,11 @@ async fn find_comment_msg(
             )
         })
         .collect();
+    let pages: Vec<String> = comment_pages.into_iter().map(|(_, p)| p).collect();
     let text = &pages[0]; // assert!(pages.len() >= 1);
 
-    bot.send_message(msg.chat.id, text)
+    let sent = bot
+        .send_message(msg.chat.id, text)
         .reply_markup(build_paging_keyboard(pages.len(), 0, Some(&action_name)))
         .parse_mode(MarkdownV2)
         .reply_to_message_id(msg.id)
@@ -435,6 +843,7 @@ async fn find_comment_msg(
     dialogue
         .update(State::PagingCb {
             data: PagingCbData {
+                msg_id: sent.id,
                 pages,
                 actions: Some(PagingCbActions {
                     name: action_name,
@@ -442,7 +851,7 @@ async fn find_comment_msg(
                     action_msgs,
                     ..Default::default()
                 }),
-                prev_state: Box::new(State::StartCb),
+                prev_state: Box::new(State::StartCb { msg_id: sent.id }),
                 prev_msg: "请选择操作：".to_string(),
                 prev_op_keyboard: start_op_keyboard(),
             },
@@ -586,54 +995,60 @@ async fn read_or_comment(
         let obj = SAFC_DB.find_object_with_path(&university, &department, &supervisor)?;
         match obj {
             None => {
-                let object_id = hash_object_id(&university, &department, &supervisor);
-                bot.send_message(
-                    msg.chat.id,
-                    format!(
-                        "🧭 {school_cate} 🏫 {university} 🏢 {department} 👔 {supervisor}\n\
-                        🤗 目前还没有这个对象的信息，是否增加此对象？"
-                    ),
-                )
-                .reply_markup(InlineKeyboardMarkup::new([[
-                    InlineKeyboardButton::callback(
-                        "➕ 增加",
-                        serde_json::to_string(&ObjectOp::Add).unwrap(),
-                    ),
-                    InlineKeyboardButton::callback(
-                        "🏁 结束",
-                        serde_json::to_string(&ObjectOp::End).unwrap(),
-                    ),
-                ]]))
-                .reply_to_message_id(msg.id)
-                .await?;
+                let obj_teacher = match service::new_object(
+                    &school_cate,
+                    &university,
+                    &department,
+                    &supervisor,
+                ) {
+                    Ok(t) => t,
+                    Err(e @ safc::Error::Validation(_)) => {
+                        bot.send_message(msg.chat.id, format!("❌ {e}\n请重新输入："))
+                            .reply_to_message_id(msg.id)
+                            .await?;
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let sent = bot
+                    .send_message(
+                        msg.chat.id,
+                        format!(
+                            "{}\n\
+                        🤗 目前还没有这个对象的信息，是否增加此对象？",
+                            obj_teacher.display_path()
+                        ),
+                    )
+                    .reply_markup(add_obj_keyboard())
+                    .reply_to_message_id(msg.id)
+                    .await?;
                 dialogue
                     .update(State::Read {
-                        obj_teacher: ObjTeacher {
-                            school_cate,
-                            university,
-                            department,
-                            supervisor,
-                            date: get_current_date(),
-                            info: None,
-                            object_id,
-                        },
+                        obj_teacher,
+                        msg_id: sent.id,
                     })
                     .await?; // 更新会话状态
             }
             Some(obj_teacher) => {
-                bot.send_message(
-                    msg.chat.id,
-                    format!(
-                        "{}\n\
+                let sent = bot
+                    .send_message(
+                        msg.chat.id,
+                        format!(
+                            "{}\n\
                         请选择操作：",
-                        display_teacher_md(&obj_teacher)
-                    ),
-                )
-                .reply_to_message_id(msg.id)
-                .parse_mode(MarkdownV2)
-                .reply_markup(obj_op_keyboard())
-                .await?;
-                dialogue.update(State::Read { obj_teacher }).await?; // 更新会话状态
+                            display_teacher_md(&obj_teacher)
+                        ),
+                    )
+                    .reply_to_message_id(msg.id)
+                    .parse_mode(MarkdownV2)
+                    .reply_markup(obj_op_keyboard())
+                    .await?;
+                dialogue
+                    .update(State::Read {
+                        obj_teacher,
+                        msg_id: sent.id,
+                    })
+                    .await?; // 更新会话状态
             }
         }
     } else {
@@ -647,7 +1062,7 @@ async fn read_or_comment(
 async fn read_or_comment_cb(
     bot: Bot,
     dialogue: MyDialogue,
-    obj_teacher: ObjTeacher, // Available from `State::...`.
+    (obj_teacher, msg_id): (ObjTeacher, MessageId), // Available from `State::...`.
     q: CallbackQuery,
 ) -> HandlerResult {
     let ObjTeacher {
@@ -664,7 +1079,8 @@ async fn read_or_comment_cb(
         match serde_json::from_str(op)? {
             ObjectOp::Read => {
                 let action_name = "回复此评价".to_string();
-                let pages: Vec<String> = get_comment_pages(&object_id)?
+                let comment_pages = get_comment_pages(&object_id)?;
+                let pages: Vec<String> = comment_pages
                     .iter()
                     .enumerate()
                     .map(|(i, x)| {
@@ -675,7 +1091,7 @@ async fn read_or_comment_cb(
                             escape(&supervisor),
                             &object_id,
                             i + 1,
-                            x
+                            x.text
                         )
                     })
                     .collect();
@@ -695,15 +1111,15 @@ async fn read_or_comment_cb(
                             .parse_mode(MarkdownV2)
                             .await?;
                     }
-                    let comments = SAFC_DB.find_comment(&object_id)?;
-                    let action_msgs = comments
+                    // 拆分出的各页都指向同一条顶层评价
+                    let action_msgs = comment_pages
                         .iter()
-                        .map(|x| format!("回复评价 `{}`\n/cancel 取消", &x.id))
+                        .map(|x| format!("回复评价 `{}`\n/cancel 取消", &x.comment_id))
                         .collect();
-                    let action_states = comments
+                    let action_states = comment_pages
                         .iter()
                         .map(|x| State::Comment {
-                            object_id: x.id.clone(),
+                            object_id: x.comment_id.clone(),
                             comment_type: CommentType::Nest,
                         })
                         .collect();
@@ -712,6 +1128,7 @@ async fn read_or_comment_cb(
                     dialogue
                         .update(State::PagingCb {
                             data: PagingCbData {
+                                msg_id,
                                 pages,
                                 actions: Some(PagingCbActions {
                                     name: action_name,
@@ -719,7 +1136,10 @@ async fn read_or_comment_cb(
                                     action_msgs,
                                     ..Default::default()
                                 }),
-                                prev_state: Box::new(State::Read { obj_teacher }),
+                                prev_state: Box::new(State::Read {
+                                    obj_teacher,
+                                    msg_id,
+                                }),
                                 prev_msg: escape(
                                     format!(
                                     "🧭 {school_cate} 🏫 {university} 🏢 {department} 👔 {supervisor}\n\
@@ -734,13 +1154,31 @@ async fn read_or_comment_cb(
                 }
             }
             ObjectOp::Add => {
+                if let Some(hint) = rate_limit_hint(Some(&q.from), RateAction::CreateObject)? {
+                    // 客体尚未建立，保留增加与结束按钮，稍后可以再试
+                    if let Some(Message { id, chat, .. }) = q.message {
+                        bot.edit_message_text(
+                            chat.id,
+                            id,
+                            format!("{}\n{hint}", obj_teacher.display_path()),
+                        )
+                        .reply_markup(add_obj_keyboard())
+                        .await?;
+                    }
+                    return Ok(());
+                }
                 // 增加评价客体
-                SAFC_DB.add_object(&obj_teacher)?;
+                service::find_or_create_object(
+                    &SAFC_DB,
+                    &school_cate,
+                    &university,
+                    &department,
+                    &supervisor,
+                )?;
                 let text = format!(
                     "🧭 {school_cate} 🏫 {university} 🏢 {department} 👔 {supervisor}\n\
                     评价客体已增加！感谢您的贡献 🌷"
                 );
-                log::info!("评价客体已增加！");
                 if let Some(Message { id, chat, .. }) = q.message {
                     bot.edit_message_text(chat.id, id, text)
                         .reply_markup(obj_op_keyboard())
@@ -787,6 +1225,23 @@ async fn read_or_comment_cb(
                 } // else ... todo
                   // dialogue.update(State::Read { obj_teacher }).await?; // 更新会话状态
             }
+            ObjectOp::Subscribe => {
+                let text = match SAFC_DB.subscribe(dialogue.chat_id().0, &object_id)? {
+                    true => "🔔 已关注，此客体有新评价时会通知您\n使用 /subscriptions 管理关注",
+                    false => "您已关注此客体\n使用 /subscriptions 管理关注",
+                };
+                if let Some(Message { id, chat, .. }) = q.message {
+                    bot.edit_message_text(
+                        chat.id,
+                        id,
+                        format!(
+                            "🧭 {school_cate} 🏫 {university} 🏢 {department} 👔 {supervisor}\n{text}"
+                        ),
+                    )
+                    .reply_markup(obj_op_keyboard())
+                    .await?;
+                }
+            }
             ObjectOp::ReturnU => {
                 choose_university_msg(&school_cate, &bot, &q.message.unwrap()).await?;
                 dialogue.update(State::University { school_cate }).await?;
@@ -833,6 +1288,7 @@ async fn paging_cb(
     q: CallbackQuery,
 ) -> HandlerResult {
     let PagingCbData {
+        msg_id: _,
         pages,
         actions,
         prev_state,
@@ -886,6 +1342,19 @@ async fn paging_cb(
     Ok(())
 }
 
+/// 回调来自旧消息上的按钮，而不是当前会话状态所绑定的消息
+fn is_stale_callback(state: State, q: CallbackQuery) -> bool {
+    match (state.callback_msg_id(), q.message) {
+        (Some(msg_id), Some(m)) => m.id != msg_id,
+        _ => false,
+    }
+}
+
+async fn stale_callback(bot: Bot, q: CallbackQuery) -> HandlerResult {
+    bot.answer_callback_query(q.id).text("此按钮已过期").await?;
+    Ok(())
+}
+
 async fn invalid_callback_query(bot: Bot, q: CallbackQuery) -> HandlerResult {
     bot.answer_callback_query(q.id).await?;
     if let Some(Message { id, chat, .. }) = q.message {
@@ -904,29 +1373,51 @@ async fn add_comment(
     msg: Message,
 ) -> HandlerResult {
     if let Some(comment) = msg.text().map(ToOwned::to_owned) {
-        // let date = get_current_date();
-        // let comment_id = hash_comment_id(&object_id, &comment, &date);
-        bot.send_message(
-            msg.chat.id,
-            format!(
-                "您对 `{}` 的评价是\n\n\
-                ```\n{}\n```\n\
-                确认发布？如确认请输入「发布人 OTP」，之后将发布评价;\
-                取消请 /cancel  *您只能在此取消！*\n\
-                _注：「发布人 OTP」即一次性密钥，是可以让您日后证明本评价由您发布，由此您可以修改/销毁此评论，\
-                如不需要，输入随机值即可_",
-                &object_id,
-                escape(comment.as_str())
-            ),
-        )
-        .reply_to_message_id(msg.id)
-        .parse_mode(MarkdownV2)
-        .await?;
+        let comment = match service::validate_comment(&comment) {
+            Ok(c) => c,
+            Err(e) => {
+                bot.send_message(msg.chat.id, format!("❌ {e}\n请重新输入："))
+                    .reply_to_message_id(msg.id)
+                    .await?;
+                return Ok(());
+            }
+        };
+        // 对客体的评价可以评星，嵌套评价直接发布
+        if comment_type != CommentType::Nest {
+            let ratings = Ratings::default();
+            let sent = bot
+                .send_message(
+                    msg.chat.id,
+                    format!(
+                        "{}\n\n⭐ 请为 `{}` 评星（可选），完成后点击「✅ 完成」",
+                        escape(comment.as_str()),
+                        &object_id
+                    ),
+                )
+                .reply_to_message_id(msg.id)
+                .parse_mode(MarkdownV2)
+                .reply_markup(rate_keyboard(&ratings))
+                .await?;
+            dialogue
+                .update(State::Rate {
+                    object_id,
+                    comment,
+                    ratings,
+                    msg_id: sent.id,
+                })
+                .await?;
+            return Ok(());
+        }
+        bot.send_message(msg.chat.id, publish_prompt_md(&object_id, &comment, None))
+            .reply_to_message_id(msg.id)
+            .parse_mode(MarkdownV2)
+            .await?;
         dialogue
             .update(State::Publish {
                 object_id,
                 comment,
                 comment_type,
+                ratings: None,
             })
             .await?; // 更新会话状态
     } else {
@@ -936,39 +1427,129 @@ async fn add_comment(
     Ok(())
 }
 
+/// 评星回调：修改评星，完成后等待发布人 OTP
+async fn rate_cb(
+    bot: Bot,
+    dialogue: MyDialogue,
+    (object_id, comment, mut ratings, _msg_id): (String, String, Ratings, MessageId),
+    q: CallbackQuery,
+) -> HandlerResult {
+    bot.answer_callback_query(q.id).await?;
+    let (Some(op), Some(Message { id, chat, .. })) = (&q.data, q.message) else {
+        return Ok(());
+    };
+    let done = match serde_json::from_str(op)? {
+        RateOp::Set(d, n) => {
+            ratings.set(d, (n != 0 && ratings.get(d) != Some(n)).then_some(n));
+            bot.edit_message_reply_markup(chat.id, id)
+                .reply_markup(rate_keyboard(&ratings))
+                .await?;
+            dialogue
+                .update(State::Rate {
+                    object_id,
+                    comment,
+                    ratings,
+                    msg_id: id,
+                })
+                .await?;
+            return Ok(());
+        }
+        RateOp::Done => Some(ratings).filter(|r| !r.is_empty()),
+        RateOp::Skip => None,
+    };
+    bot.edit_message_text(
+        chat.id,
+        id,
+        publish_prompt_md(&object_id, &comment, done.as_ref()),
+    )
+    .parse_mode(MarkdownV2)
+    .await?;
+    dialogue
+        .update(State::Publish {
+            object_id,
+            comment,
+            comment_type: CommentType::Teacher,
+            ratings: done,
+        })
+        .await?;
+    Ok(())
+}
+
 /// 增加评价处理函数
 async fn publish_comment(
     bot: Bot,
     dialogue: MyDialogue,
-    (object_id, comment, comment_type): (String, String, CommentType), // Available from `State::...`.
+    (object_id, comment, comment_type, ratings): (String, String, CommentType, Option<Ratings>), // Available from `State::...`.
     msg: Message,
 ) -> HandlerResult {
     if let Some(otp) = msg.text().map(ToOwned::to_owned) {
-        let c = ObjComment::new_with_otp(
-            object_id.clone(),
-            comment,
-            SourceCate::Telegram,
-            comment_type,
-            otp,
-        );
-        SAFC_DB.add_comment(&c)?; // ? 有些可能的错误需提示用户
-        log::info!("{} 评价已发布", c.id);
-
-        match SAFC_DB.find_objteacher_with_id(object_id.as_str())? {
-            Some(obj_teacher) => {
+        // 超出限制时保持当前状态，稍后可重新发送 OTP
+        if let Some(hint) = rate_limit_hint(msg.from(), RateAction::PublishComment)? {
+            bot.send_message(msg.chat.id, format!("{hint}\n稍后重新发送 OTP 即可发布"))
+                .reply_to_message_id(msg.id)
+                .await?;
+            return Ok(());
+        }
+        // 管理员发布的评价来源为 admin
+        let source_cate = if admin::is_admin(msg.from()) {
+            SourceCate::Admin
+        } else {
+            SourceCate::Telegram
+        };
+        let r = match comment_type {
+            CommentType::Nest => service::reply_to_comment(
+                &SAFC_DB,
+                &object_id,
+                &comment,
+                source_cate,
+                &otp,
+                Some(msg.chat.id.0),
+            ),
+            _ => service::post_rated_comment(
+                &SAFC_DB,
+                &object_id,
+                &comment,
+                ratings,
+                source_cate,
+                &otp,
+                Some(msg.chat.id.0),
+            ),
+        };
+        let c = match r {
+            Err(safc::Error::DuplicateId(id)) => {
                 bot.send_message(
                     msg.chat.id,
-                    format!(
-                        "_您的 OTP 已销毁_\n\
-                        评价「`{}`」已发布！感谢您的贡献 🌷",
-                        c.id
-                    ),
+                    format!("❌ 评价「`{id}`」已存在：同一天对同一客体的相同评价不能重复发布"),
                 )
                 .reply_to_message_id(msg.id)
                 .parse_mode(MarkdownV2)
-                .reply_markup(obj_op_keyboard())
                 .await?;
-                dialogue.update(State::Read { obj_teacher }).await?;
+                return Ok(());
+            }
+            r => r?,
+        };
+
+        match SAFC_DB.find_objteacher_with_id(object_id.as_str())? {
+            Some(obj_teacher) => {
+                let sent = bot
+                    .send_message(
+                        msg.chat.id,
+                        format!(
+                            "_您的 OTP 已销毁_\n\
+                        评价「`{}`」已发布！感谢您的贡献 🌷",
+                            c.id
+                        ),
+                    )
+                    .reply_to_message_id(msg.id)
+                    .parse_mode(MarkdownV2)
+                    .reply_markup(obj_op_keyboard())
+                    .await?;
+                dialogue
+                    .update(State::Read {
+                        obj_teacher,
+                        msg_id: sent.id,
+                    })
+                    .await?;
             }
             None => {
                 bot.send_message(
@@ -999,3 +1580,144 @@ fn convert_to_n_columns_keyboard(data: Vec<String>, n: usize) -> Vec<Vec<Keyboar
         .map(|chunk| chunk.iter().map(KeyboardButton::new).collect())
         .collect()
 }
+
+/// 收到修改后的评价，询问 OTP
+async fn edit_comment_text(
+    bot: Bot,
+    dialogue: MyDialogue,
+    comment_id: String, // Available from `State::...`.
+    msg: Message,
+) -> HandlerResult {
+    let Some(text) = msg.text() else {
+        bot.send_message(msg.chat.id, TgResponse::RetryErrNone.to_string())
+            .await?;
+        return Ok(());
+    };
+    let comment = match service::validate_comment(text) {
+        Ok(c) => c,
+        Err(e) => {
+            bot.send_message(msg.chat.id, format!("❌ {e}\n请重新输入："))
+                .reply_to_message_id(msg.id)
+                .await?;
+            return Ok(());
+        }
+    };
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "评价「`{}`」将修改为\n\n\
+            ```\n{}\n```\n\
+            确认修改？如确认请输入发布时的「发布人 OTP」；取消请 /cancel",
+            comment_id,
+            escape(comment.as_str())
+        ),
+    )
+    .reply_to_message_id(msg.id)
+    .parse_mode(MarkdownV2)
+    .await?;
+    dialogue
+        .update(State::EditConfirm {
+            comment_id,
+            comment,
+        })
+        .await?;
+    Ok(())
+}
+
+/// 收到 OTP，修改评价
+async fn edit_comment_otp(
+    bot: Bot,
+    dialogue: MyDialogue,
+    (comment_id, comment): (String, String), // Available from `State::...`.
+    msg: Message,
+) -> HandlerResult {
+    let Some(otp) = msg.text() else {
+        bot.send_message(msg.chat.id, TgResponse::RetryErrNone.to_string())
+            .await?;
+        return Ok(());
+    };
+    match service::edit_comment(&SAFC_DB, &comment_id, &comment, otp) {
+        Err(e @ safc::Error::RateLimited(_)) => {
+            bot.send_message(
+                msg.chat.id,
+                format!("❌ OTP 输错次数过多，已结束本次操作\n{e}"),
+            )
+            .reply_to_message_id(msg.id)
+            .await?;
+            dialogue.exit().await?;
+        }
+        Err(e) if is_user_error(&e) => {
+            bot.send_message(
+                msg.chat.id,
+                format!("❌ {e}\n请重新输入 OTP，或 /cancel 取消"),
+            )
+            .reply_to_message_id(msg.id)
+            .await?;
+        }
+        r => {
+            r?;
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "_您的 OTP 已销毁_\n\
+                    评价「`{comment_id}`」已修改，原内容保留在修订历史中\n\
+                    使用 /start 重新开始"
+                ),
+            )
+            .reply_to_message_id(msg.id)
+            .parse_mode(MarkdownV2)
+            .await?;
+            dialogue.exit().await?;
+        }
+    }
+    Ok(())
+}
+
+/// 收到 OTP，撤回评价
+async fn retract_comment_otp(
+    bot: Bot,
+    dialogue: MyDialogue,
+    comment_id: String, // Available from `State::...`.
+    msg: Message,
+) -> HandlerResult {
+    let Some(otp) = msg.text() else {
+        bot.send_message(msg.chat.id, TgResponse::RetryErrNone.to_string())
+            .await?;
+        return Ok(());
+    };
+    match service::retract_comment(&SAFC_DB, &comment_id, otp) {
+        Err(e @ safc::Error::RateLimited(_)) => {
+            bot.send_message(
+                msg.chat.id,
+                format!("❌ OTP 输错次数过多，已结束本次操作\n{e}"),
+            )
+            .reply_to_message_id(msg.id)
+            .await?;
+            dialogue.exit().await?;
+        }
+        Err(e) if is_user_error(&e) => {
+            bot.send_message(
+                msg.chat.id,
+                format!("❌ {e}\n请重新输入 OTP，或 /cancel 取消"),
+            )
+            .reply_to_message_id(msg.id)
+            .await?;
+        }
+        r => {
+            r?;
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "_您的 OTP 已销毁_\n\
+                    评价「`{comment_id}`」已撤回\n\
+                    使用 /start 重新开始"
+                ),
+            )
+            .reply_to_message_id(msg.id)
+            .parse_mode(MarkdownV2)
+            .await?;
+            dialogue.exit().await?;
+        }
+    }
+    Ok(())
+}
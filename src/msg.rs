@@ -1,10 +1,14 @@
 use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
 use teloxide::types::InlineKeyboardButton;
 use teloxide::types::InlineKeyboardMarkup;
+use teloxide::types::MessageId;
 pub use teloxide::utils::markdown::escape;
 use url::Url;
 
+use safc::db::rating::MAX_STARS;
 use safc::db::*;
+use safc::link::DeepLink;
 
 // 有没有更优雅的方法？
 // 放在这里是为了让 `main` 引用，而不是 `msg` 引用 `main`
@@ -14,8 +18,15 @@ lazy_static! {
     pub static ref SAFC_DB: SAFCdb = SAFCdb::new();
 }
 
+/// bot 的用户名（不含 @），启动时获取，用于生成回到 bot 的深度链接
+pub static BOT_USERNAME: OnceLock<String> = OnceLock::new();
+
 /// 最大分页大小
 pub const MSG_MAX_PAGES: usize = 99;
+/// telegram 单条消息的最大长度（UTF-16 码元）
+pub const TG_MSG_MAX_LEN: usize = 4096;
+/// 每页正文的最大长度，为调用方添加的页眉页脚留出余量
+pub const PAGE_MAX_LEN: usize = TG_MSG_MAX_LEN - 512;
 
 const GITHUB_URL: &str = "https://github.com/framist/SAFC-bot";
 const WEB_URL: &str = "https://safc-web.vercel.app/";
@@ -41,10 +52,10 @@ pub enum TgResponse {
     NotImplemented,
 }
 
-impl ToString for TgResponse {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for TgResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // escape(&self.to_unescaped_string())
-        match self {
+        let s = match self {
             Self::Hello => concat!(
                 "👋 嗨！我是大学生反诈中心（SAFC @SAFC\\_group）的客服机器人\n",
                 "_目前仍为早期开发版本_ 问题敬请反馈；*越墙不易，延迟丢包敬请见谅*\n",
@@ -52,12 +63,12 @@ impl ToString for TgResponse {
                 "发送 /info 了解反诈中心\n",
                 "您可以先查询客体（一般来讲客体就是导师啦），然后查看或发起对客体的评价。\n\n",
                 "请选择以下功能之一：",
-            )
-            .to_owned(),
-            Self::Info => BOT_INFO.to_owned(),
-            Self::RetryErrNone => "空消息错误。对不起，请重试".to_owned(),
-            Self::NotImplemented => "😢 功能尚未实现，敬请期待".to_owned(),
-        }
+            ),
+            Self::Info => BOT_INFO,
+            Self::RetryErrNone => "空消息错误。对不起，请重试",
+            Self::NotImplemented => "😢 功能尚未实现，敬请期待",
+        };
+        f.write_str(s)
     }
 }
 
@@ -67,7 +78,10 @@ pub enum State {
     #[default]
     Start,
     /// 开始功能选单回调状态
-    StartCb,
+    StartCb {
+        /// 功能选单所在的消息
+        msg_id: MessageId,
+    },
     /// 快速搜索教师
     FindSupervisor,
     /// 快速搜索评论
@@ -87,25 +101,73 @@ pub enum State {
     },
     Read {
         obj_teacher: ObjTeacher,
+        /// 客体操作键盘所在的消息
+        msg_id: MessageId,
     },
     Comment {
         object_id: String, // todo 待重构为 Obj
         comment_type: CommentType,
     },
+    /// 评星回调状态，评价写好后、发布之前
+    Rate {
+        object_id: String,
+        comment: String,
+        ratings: Ratings,
+        /// 评星键盘所在的消息
+        msg_id: MessageId,
+    },
     Publish {
         object_id: String, // todo 待重构为 Obj
         comment: String,
         comment_type: CommentType,
+        #[serde(default)]
+        ratings: Option<Ratings>,
+    },
+    /// 等待修改后的评价
+    Edit {
+        comment_id: String,
+    },
+    /// 等待修改评价所需的发布人 OTP
+    EditConfirm {
+        comment_id: String,
+        comment: String,
+    },
+    /// 等待撤回评价所需的发布人 OTP
+    Retract {
+        comment_id: String,
     },
     /// 分页显示回调状态
     PagingCb {
         data: PagingCbData,
     },
+    /// 关注列表回调状态
+    Subscriptions {
+        /// 关注列表所在的消息
+        msg_id: MessageId,
+    },
+}
+
+impl State {
+    /// 回调状态所绑定的消息，只有来自这条消息的回调按钮才有效
+    ///
+    /// 旧消息上的按钮会被视为过期，以免操作到当前会话中的其他客体
+    pub fn callback_msg_id(&self) -> Option<MessageId> {
+        match self {
+            Self::StartCb { msg_id }
+            | Self::Read { msg_id, .. }
+            | Self::Rate { msg_id, .. }
+            | Self::Subscriptions { msg_id } => Some(*msg_id),
+            Self::PagingCb { data } => Some(data.msg_id),
+            _ => None,
+        }
+    }
 }
 
 /// 分页显示回调状态的数据
-#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct PagingCbData {
+    /// 分页显示所在的消息
+    pub msg_id: MessageId,
     /// 各个页面的文字
     pub pages: Vec<String>,
     /// 可选的对各页的进一步操作
@@ -144,6 +206,8 @@ pub enum StartOp {
 }
 
 /// 对象操作的回调
+///
+/// 回调数据只含操作本身，按钮所属的消息由 [`State::callback_msg_id`] 校验
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ObjectOp {
     Read,
@@ -151,12 +215,31 @@ pub enum ObjectOp {
     Info,
     End,
     Add,
+    Subscribe,
     // 最长只能 64 字符，所以选择这种 hack 的方法，有待改进
     ReturnU,
     ReturnD,
     ReturnS,
 }
 
+/// 关注列表的回调
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SubscriptionOp {
+    /// 取消关注，内容为 object id
+    Unsubscribe(String),
+}
+
+/// 评星的回调
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RateOp {
+    /// 为某项评几星，0 或再次点击同一星数则取消此项
+    Set(RatingDimension, u8),
+    /// 完成评星
+    Done,
+    /// 不评星
+    Skip,
+}
+
 /// 分页操作的回调
 #[derive(Serialize, Deserialize, Debug)]
 pub enum PagingOp {
@@ -186,6 +269,18 @@ impl From<PagingOp> for String {
     }
 }
 
+impl From<RateOp> for String {
+    fn from(val: RateOp) -> Self {
+        serde_json::to_string(&val).unwrap()
+    }
+}
+
+impl From<SubscriptionOp> for String {
+    fn from(val: SubscriptionOp) -> Self {
+        serde_json::to_string(&val).unwrap()
+    }
+}
+
 impl From<String> for ObjectOp {
     fn from(value: String) -> Self {
         serde_json::from_str(&value).unwrap()
@@ -211,6 +306,14 @@ pub fn start_op_keyboard() -> InlineKeyboardMarkup {
     ])
 }
 
+/// 客体不存在时询问是否增加
+pub fn add_obj_keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[
+        InlineKeyboardButton::callback("➕ 增加", ObjectOp::Add),
+        InlineKeyboardButton::callback("🏁 结束", ObjectOp::End),
+    ]])
+}
+
 pub fn obj_op_keyboard() -> InlineKeyboardMarkup {
     InlineKeyboardMarkup::new([
         vec![
@@ -219,6 +322,7 @@ pub fn obj_op_keyboard() -> InlineKeyboardMarkup {
         ],
         vec![
             InlineKeyboardButton::callback("🤗 详细信息", ObjectOp::Info),
+            InlineKeyboardButton::callback("🔔 关注", ObjectOp::Subscribe),
             InlineKeyboardButton::callback("🏁 结束会话", ObjectOp::End),
         ],
         vec![
@@ -229,6 +333,113 @@ pub fn obj_op_keyboard() -> InlineKeyboardMarkup {
     ])
 }
 
+/// 评星键盘：每项一行，点击第 n 颗星即评 n 星，点击项目名取消此项
+pub fn rate_keyboard(ratings: &Ratings) -> InlineKeyboardMarkup {
+    let mut rows: Vec<Vec<InlineKeyboardButton>> = RatingDimension::ALL
+        .into_iter()
+        .map(|d| {
+            let stars = ratings.get(d).unwrap_or(0);
+            std::iter::once(InlineKeyboardButton::callback(d.label(), RateOp::Set(d, 0)))
+                .chain((1..=MAX_STARS).map(|n| {
+                    InlineKeyboardButton::callback(
+                        if n <= stars { "★" } else { "☆" },
+                        RateOp::Set(d, n),
+                    )
+                }))
+                .collect()
+        })
+        .collect();
+    rows.push(vec![
+        InlineKeyboardButton::callback("⏭ 不评星", RateOp::Skip),
+        InlineKeyboardButton::callback("✅ 完成", RateOp::Done),
+    ]);
+    InlineKeyboardMarkup::new(rows)
+}
+
+/// 评价的评星，如 `学术水平 ★★★★☆ · 科研经费 ★★★★★`
+/// 纯文本，需转义后再用于 markdown
+pub fn ratings_md(ratings: &Ratings) -> String {
+    let stars = |n: u8| "★".repeat(n as usize) + &"☆".repeat(MAX_STARS.saturating_sub(n) as usize);
+    ratings
+        .iter()
+        .map(|(d, n)| format!("{} {}", d.label(), stars(n)))
+        .collect::<Vec<_>>()
+        .join(" · ")
+}
+
+/// 客体的评星汇总：各项的平均星数、评星人数与各星数的分布
+/// markdown 格式
+pub fn rating_summary_md(summary: &RatingSummary) -> String {
+    if summary.is_empty() {
+        return "评星：暂无".to_string();
+    }
+    let lines = summary
+        .dimensions
+        .iter()
+        .filter_map(|d| {
+            let distribution = (1..=MAX_STARS as usize)
+                .rev()
+                .map(|n| format!("{n}★ {}", d.distribution[n - 1]))
+                .collect::<Vec<_>>()
+                .join(" ");
+            d.mean.map(|mean| {
+                escape(&format!(
+                    "  {} {:.1} ★（{} 人：{}）",
+                    d.dimension.label(),
+                    mean,
+                    d.count,
+                    distribution
+                ))
+            })
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("评星（满分 {MAX_STARS} 星）：\n{lines}")
+}
+
+/// 确认发布评价的提示
+/// markdown 格式
+pub fn publish_prompt_md(object_id: &str, comment: &str, ratings: Option<&Ratings>) -> String {
+    format!(
+        "您对 `{}` 的评价是\n\n\
+        ```\n{}\n```\n\
+        {}\
+        确认发布？如确认请输入「发布人 OTP」，之后将发布评价;\
+        取消请 /cancel  *您只能在此取消！*\n\
+        _注：「发布人 OTP」即一次性密钥，是可以让您日后证明本评价由您发布，由此您可以用 /edit 或 /delete 修改/撤回此评论，\
+        如不需要，输入随机值即可_",
+        object_id,
+        escape(comment),
+        match ratings.filter(|r| !r.is_empty()) {
+            Some(r) => format!("{}\n\n", escape(&ratings_md(r))),
+            None => String::new(),
+        }
+    )
+}
+
+/// 深度链接的完整地址，bot 用户名未知时为 `None`
+pub fn deep_link_url(link: &DeepLink) -> Option<String> {
+    BOT_USERNAME.get().map(|u| link.url(u))
+}
+
+/// 可分享的深度链接，markdown 格式；bot 用户名未知时为空
+fn share_link_md(link: &DeepLink) -> String {
+    match deep_link_url(link) {
+        // 链接中只有字母、数字与 `_`，无需转义
+        Some(url) => format!("[🔗 分享]({url})"),
+        None => String::new(),
+    }
+}
+
+/// 打开客体的深度链接按钮，bot 用户名未知时为 `None`
+pub fn object_link_keyboard(object_id: &str) -> Option<InlineKeyboardMarkup> {
+    let url = deep_link_url(&DeepLink::Object(object_id.to_string()))?;
+    Some(InlineKeyboardMarkup::new([[InlineKeyboardButton::url(
+        "👀 在 bot 中查看评价",
+        Url::parse(&url).ok()?,
+    )]]))
+}
+
 /// `index` 从 0 开始的页码
 /// `total` 为总共的页数
 /// `action` 用于当前页的回调按钮
@@ -286,49 +497,277 @@ pub fn display_teacher_md(obj: &ObjTeacher) -> String {
         "*{}*\n\
         信息：{}\n\
         评价数： {}\n\
-        该客体的初次添加日期：{}",
+        {}\n\
+        该客体的初次添加日期：{}\n\
+        {}",
         escape(obj.display_path().as_str()),
         escape(obj.info.clone().unwrap_or("暂无".to_string()).as_str()),
-        escape(
-            SAFC_DB
-                .find_comment(&obj.object_id)
-                .unwrap()
-                .len()
-                .to_string()
-                .as_str()
-        ),
-        escape(obj.date.as_str())
+        match SAFC_DB.find_comment(&obj.object_id) {
+            Ok(c) => c.len().to_string(),
+            Err(e) => {
+                log::error!("{}", e);
+                "?".to_string()
+            }
+        },
+        match SAFC_DB.rating_summary(&obj.object_id) {
+            Ok(s) => rating_summary_md(&s),
+            Err(e) => {
+                log::error!("{}", e);
+                "评星：?".to_string()
+            }
+        },
+        escape(obj.date.as_str()),
+        share_link_md(&DeepLink::Object(obj.object_id.clone()))
+    )
+}
+
+/// 评价的一页
+#[derive(Debug, Clone)]
+pub struct CommentPage {
+    /// 此页所属的顶层评价，用于「回复此评价」
+    pub comment_id: String,
+    /// markdown 格式
+    pub text: String,
+}
+
+/// 关注列表的消息与取消关注的键盘
+/// markdown 格式
+pub fn subscriptions_msg(objs: &[ObjTeacher]) -> (String, InlineKeyboardMarkup) {
+    if objs.is_empty() {
+        let text = "🔕 您还没有关注任何客体\n\
+            _在客体页面点击「🔔 关注」或使用 /subscribe \\<id\\> 关注_";
+        return (text.to_string(), InlineKeyboardMarkup::default());
+    }
+    let list = objs
+        .iter()
+        .map(|o| {
+            format!(
+                "👔 {} `{}` {}",
+                escape(&o.display_path()),
+                o.object_id,
+                share_link_md(&DeepLink::Object(o.object_id.clone()))
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let keyboard = objs.iter().map(|o| {
+        vec![InlineKeyboardButton::callback(
+            format!("🔕 {}", o.supervisor),
+            SubscriptionOp::Unsubscribe(o.object_id.clone()),
+        )]
+    });
+    (
+        format!("🔔 *您关注的客体：*\n{list}\n\n_点击下方按钮取消关注_"),
+        InlineKeyboardMarkup::new(keyboard),
     )
 }
 
+/// 新评价通知的摘要
+/// markdown 格式
+pub fn digest_md(d: &Digest) -> String {
+    let list = d
+        .objects
+        .iter()
+        .map(|(o, n)| {
+            format!(
+                "👔 {}：{} 条新评价 {}",
+                escape(&o.display_path()),
+                n,
+                share_link_md(&DeepLink::Object(o.object_id.clone()))
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("🔔 *您关注的客体有新评价：*\n{list}\n\n_使用 /subscriptions 管理关注_")
+}
+
 /// 生成分页的评价 markdown
+///
+/// 每条顶层评价（连同其嵌套评价）至少一页，过长时拆为多页，见 [`split_md`]
 pub fn get_comment_pages(
     object_id: &String,
+) -> Result<Vec<CommentPage>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(comments_msg_helper(object_id)?
+        .into_iter()
+        .flat_map(|(comment_id, md)| {
+            split_md(&md, PAGE_MAX_LEN)
+                .into_iter()
+                .map(move |text| CommentPage {
+                    comment_id: comment_id.clone(),
+                    text,
+                })
+        })
+        .collect())
+}
+
+/// 生成单条评价（连同其嵌套评价）分页的 markdown，用于深度链接打开评价
+pub fn get_comment_thread_pages(
+    c: &ObjComment,
 ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
-    comments_msg_helper(object_id)
+    Ok(split_md(&comment_md(c)?, PAGE_MAX_LEN))
 }
 
 fn comments_msg_helper(
     object_id: &String,
-) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<Vec<(String, String)>, Box<dyn std::error::Error + Send + Sync>> {
     SAFC_DB
         .find_comment(object_id)?
         .iter()
-        .map(|c: &ObjComment| {
-            Ok(format!(
-                "💬 *data {} \\| from {} \\| id `{}`*\n\
-                {}\n\
-                {}\n",
-                escape(c.date.as_str()),
-                c.source_cate,
-                c.id,
-                escape(c.description.replace("<br>", "\n").as_str()),
-                format_nested_comments(comments_msg_helper(&c.id)?)
-            ))
-        })
+        .map(|c: &ObjComment| Ok((c.id.clone(), comment_md(c)?)))
         .collect()
 }
 
+/// 单条评价连同其嵌套评价的 markdown
+fn comment_md(c: &ObjComment) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(format!(
+        "💬 *data {} \\| from {} \\| id `{}`* {}\n\
+        {}{}\n\
+        {}\n",
+        escape(c.date.as_str()),
+        c.source_cate,
+        c.id,
+        share_link_md(&DeepLink::Comment(c.id.clone())),
+        match c.ratings.filter(|_| c.tombstone.is_none()) {
+            Some(r) => format!("_{}_\n", escape(&ratings_md(&r))),
+            None => String::new(),
+        },
+        match &c.tombstone {
+            None => escape(c.description.replace("<br>", "\n").as_str()),
+            Some(t) => tombstone_md(t),
+        },
+        format_nested_comments(
+            comments_msg_helper(&c.id)?
+                .into_iter()
+                .map(|(_, md)| md)
+                .collect()
+        )
+    ))
+}
+
+/// 将 MarkdownV2 文本拆分为长度（UTF-16 码元）不超过 `max_len` 的若干页
+///
+/// 优先在格式之外的换行处拆分，其次在粗体、斜体、下划线、删除线、剧透、行内代码之外拆分，
+/// 再次在格式之中的换行处拆分，都找不到时在当前位置硬拆；不会拆开转义序列。拆在格式之中时，
+/// 在本页末尾关闭尚未结束的格式，并在下一页开头重新打开，所以每一页都是完整的 MarkdownV2。
+/// 没有拆在格式之中时，各页按顺序拼接即为原文；空文本返回一页空文本。
+pub fn split_md(text: &str, max_len: usize) -> Vec<String> {
+    let mut pages = vec![];
+    let mut page = String::new();
+    let mut page_len = 0;
+    // 当前位置尚未结束的格式，按打开的顺序
+    let mut open: Vec<&'static str> = vec![];
+    // 本页开头重新打开格式的字节数，拆分位置必须在此之后
+    let mut reopened = 0;
+    // 本页中可拆分的位置（字节）、该位置尚未结束的格式，以及是否在换行之后
+    let mut cuts: Vec<(usize, Vec<&'static str>, bool)> = vec![];
+
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        // 一个转义序列或一个格式标记作为一个整体
+        let mut end = i + c.len_utf8();
+        let in_code = open.last() == Some(&"`");
+        let marker = match c {
+            '\\' => {
+                if let Some((j, n)) = chars.next() {
+                    end = j + n.len_utf8();
+                }
+                None
+            }
+            '`' => Some("`"),
+            _ if in_code => None,
+            '*' => Some("*"),
+            '~' => Some("~"),
+            '_' | '|' if chars.peek().map(|&(_, n)| n) == Some(c) => {
+                let (j, n) = chars.next().expect("已经看到下一个字符");
+                end = j + n.len_utf8();
+                Some(if c == '_' { "__" } else { "||" })
+            }
+            '_' => Some("_"),
+            _ => None,
+        };
+        let unit = &text[i..end];
+        let mut after = open.clone();
+        if let Some(m) = marker {
+            match after.iter().rposition(|&o| o == m) {
+                Some(k) => {
+                    after.remove(k);
+                }
+                None => after.push(m),
+            }
+        }
+
+        let len = unit.encode_utf16().count();
+        if page_len + len + md_markers_len(&after) > max_len && page.len() > reopened {
+            let fits = |(pos, at, _): &(usize, Vec<&str>, bool)| {
+                page[..*pos].encode_utf16().count() + md_markers_len(at) <= max_len
+            };
+            let pick = |pred: fn(&(usize, Vec<&str>, bool)) -> bool| {
+                cuts.iter().rev().find(|c| pred(c) && fits(c)).cloned()
+            };
+            let (cut, mut at, _) = pick(|(_, at, newline)| *newline && at.is_empty())
+                .or_else(|| pick(|(_, at, _)| at.is_empty()))
+                .or_else(|| pick(|(_, _, newline)| *newline))
+                .unwrap_or((page.len(), open.clone(), false));
+            let mut rest = page.split_off(cut);
+            let mut shift = cut;
+            // 紧接着就结束的格式不必重新打开，以免出现空的格式（如 `_` 与 `_` 连成 `__`）
+            while let Some(m) = at.last().copied() {
+                let closes = rest
+                    .strip_prefix(m)
+                    .is_some_and(|r| !r.starts_with(&m[..1]));
+                if !closes {
+                    break;
+                }
+                page.push_str(m);
+                rest.drain(..m.len());
+                shift += m.len();
+                at.pop();
+            }
+            page.extend(at.iter().rev().copied());
+            pages.push(std::mem::take(&mut page));
+            page = at.concat();
+            reopened = page.len();
+            page.push_str(&rest);
+            page_len = page.encode_utf16().count();
+            cuts = cuts
+                .into_iter()
+                .filter(|(pos, _, _)| *pos > shift)
+                .map(|(pos, at, newline)| (pos - shift + reopened, at, newline))
+                .collect();
+        }
+
+        page.push_str(unit);
+        page_len += len;
+        open = after;
+        if c == '\n' || open.is_empty() {
+            cuts.push((page.len(), open.clone(), c == '\n'));
+        }
+    }
+    if !page.is_empty() || pages.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+/// 格式标记的总长度，即关闭或重新打开这些格式所需的长度
+fn md_markers_len(markers: &[&str]) -> usize {
+    markers.iter().map(|m| m.len()).sum()
+}
+
+/// 被撤回或隐藏的评价的占位文字
+/// markdown 格式
+fn tombstone_md(t: &Tombstone) -> String {
+    let who = match t.kind {
+        TombstoneKind::Retracted => "发布人撤回",
+        TombstoneKind::Moderated => "管理员隐藏",
+    };
+    let what = format!("此评价已于 {} 被{}", t.date, who);
+    match &t.reason {
+        Some(r) => format!("🪦 _{}：{}_", escape(&what), escape(r)),
+        None => format!("🪦 _{}_", escape(&what)),
+    }
+}
+
 /// 格式化嵌套评价
 fn format_nested_comments(comments: Vec<String>) -> String {
     if !comments.is_empty() {
@@ -348,6 +787,50 @@ fn format_nested_comments(comments: Vec<String>) -> String {
     }
 }
 
+#[test]
+fn test_split_md() {
+    let text = format!(
+        "*标题* `{}`\n{}\n{}\n",
+        "a".repeat(20),
+        escape(&"很长的评价。".repeat(30)),
+        "短行"
+    );
+    let pages = split_md(&text, 64);
+    assert!(pages.len() > 3);
+    assert_eq!(text, pages.concat());
+    for p in &pages {
+        assert!(p.encode_utf16().count() <= 64);
+        // 转义序列、行内代码、粗体都没有被拆开
+        assert!(!p.ends_with('\\'));
+        assert_eq!(0, p.matches('`').count() % 2);
+        assert_eq!(0, p.matches('*').count() % 2);
+    }
+    assert_eq!(vec![text.clone()], split_md(&text, TG_MSG_MAX_LEN));
+    assert_eq!(vec![String::new()], split_md("", 64));
+}
+
+#[test]
+fn test_split_md_spans() {
+    // 比一页还长、跨行的格式在拆分处关闭，并在下一页重新打开
+    let body = escape(&"很长的评价。\n".repeat(20));
+    let text = format!("*标题*\n_{body}_ `{}`\n", "a".repeat(100));
+    let pages = split_md(&text, 64);
+    assert!(pages.len() > 3);
+    for p in &pages {
+        assert!(p.encode_utf16().count() <= 64);
+        assert!(!p.ends_with('\\'));
+        assert_eq!(0, p.matches('`').count() % 2, "{p}");
+        assert_eq!(0, p.matches('_').count() % 2, "{p}");
+        assert_eq!(0, p.matches('*').count() % 2, "{p}");
+        // 不会补出空的格式
+        assert!(!p.contains("__"), "{p}");
+    }
+    assert!(pages[1].starts_with('_'));
+    // 去掉补上的格式标记后即为原文
+    let strip = |s: &str| s.replace(['_', '`'], "");
+    assert_eq!(strip(&text), strip(&pages.concat()));
+}
+
 #[test]
 fn my_test() {
     println!("{}", serde_json::to_string(&ObjectOp::Read).unwrap());
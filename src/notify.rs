@@ -0,0 +1,49 @@
+//! # notify
+//!
+//! 关注客体的新评价通知
+//!
+//! 每隔 [`NOTIFY_INTERVAL`] 汇总一次新评价（见 [`safc::db::subscription`]），
+//! 这段时间内的一连串评价与回复只会给每个关注者发送一条摘要。
+
+use std::time::Duration;
+
+use teloxide::prelude::*;
+use teloxide::types::ParseMode::MarkdownV2;
+
+use crate::msg::{digest_md, SAFC_DB};
+
+/// 汇总新评价的间隔
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// 每次最多汇总的新评价数
+const MAX_EVENTS: usize = 1000;
+
+/// 定期发送新评价通知的任务
+pub async fn notify_task(bot: Bot) {
+    log::info!("notify_task 启动");
+    loop {
+        tokio::time::sleep(NOTIFY_INTERVAL).await;
+        if let Err(e) = notify_once(&bot).await {
+            log::error!("发送新评价通知失败：{}", e);
+        }
+    }
+}
+
+async fn notify_once(bot: &Bot) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    loop {
+        let (digests, last_seq) = SAFC_DB.pending_digests(MAX_EVENTS)?;
+        let Some(last_seq) = last_seq else {
+            return Ok(());
+        };
+        for d in digests {
+            // 用户可能已屏蔽 bot，单个会话失败不影响其他会话
+            if let Err(e) = bot
+                .send_message(ChatId(d.chat_id), digest_md(&d))
+                .parse_mode(MarkdownV2)
+                .await
+            {
+                log::warn!("无法通知 {}：{}", d.chat_id, e);
+            }
+        }
+        SAFC_DB.ack_comment_events(last_seq)?;
+    }
+}
@@ -6,6 +6,8 @@
 use hex;
 use sha2::{Digest, Sha256};
 
+use crate::db::Ratings;
+
 /// 注意：只能在新建对象的时候计算此 id，因为使用的字段未来可能可变
 pub fn hash_object_id(university: &String, department: &String, supervisor: &String) -> String {
     let s = format!("{}{}{}", university, department, supervisor);
@@ -19,13 +21,37 @@ pub fn hash_comment_id(object_id: &String, comment: &String, date: &String) -> S
 }
 
 /// 作者签名 = sha256( 评论 id | sha256( "SAFC_salt" + otp ) )
-/// TODO opt 为空时视为放弃签名
+/// otp 为空时视为放弃签名，见 [`crate::db::ObjComment::new_with_otp`]
 pub fn hash_author_sign(comment_id: &String, otp: &String) -> String {
     const SAFC_ASLT: &str = "SAFC_salt";
     let a = hex::encode(Sha256::digest(format!("{}{}", SAFC_ASLT, otp).as_bytes()));
     hex::encode(Sha256::digest(format!("{}{}", comment_id, a).as_bytes()))
 }
 
+/// 验证发布人提供的 otp 是否与评论的签名相符
+pub fn verify_author_sign(comment_id: &String, otp: &String, author_sign: &str) -> bool {
+    hash_author_sign(comment_id, otp) == author_sign
+}
+
+/// 哈希链第一条记录的 prev_hash
+pub const CHAIN_GENESIS_HASH: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// 哈希链记录的哈希 = sha256( prev_hash | 序号 | 操作 | 内容 )，见 [`crate::db::chain`]
+pub fn hash_chain_entry(prev_hash: &str, seq: i64, op: &str, payload: &str) -> String {
+    let s = format!("{}|{}|{}|{}", prev_hash, seq, op, payload);
+    hex::encode(Sha256::digest(s.as_bytes()))
+}
+
+/// 评价内容的哈希 = sha256( 正文 | 评星的 json )，哈希链中只记录此值，不记录原文
+///
+/// 评星的取值很少，与正文一起计算，避免单独记录时被穷举
+pub fn hash_comment_content(description: &str, ratings: Option<&Ratings>) -> String {
+    let ratings = serde_json::to_string(&ratings).expect("评星可以序列化");
+    let s = format!("{}|{}", description, ratings);
+    hex::encode(Sha256::digest(s.as_bytes()))
+}
+
 #[test]
 fn test_calc_object_id() {
     assert_eq!(
@@ -43,5 +69,25 @@ fn test_hash_author_sign() {
     assert_eq!(
         "633d8c27f20896ab27a9c762d4e1e9da16b54edec78de13f3c950820aca70b7c".to_string(),
         hash_author_sign(&"cba0415143b305c0".to_string(), &"201809".to_string())
-    )
+    );
+    assert!(verify_author_sign(
+        &"cba0415143b305c0".to_string(),
+        &"201809".to_string(),
+        "633d8c27f20896ab27a9c762d4e1e9da16b54edec78de13f3c950820aca70b7c"
+    ));
+}
+
+#[test]
+fn test_hash_chain_entry() {
+    let h = hash_chain_entry(CHAIN_GENESIS_HASH, 1, "add_object", "{}");
+    assert_eq!(64, h.len());
+    assert_eq!(
+        h,
+        hash_chain_entry(CHAIN_GENESIS_HASH, 1, "add_object", "{}")
+    );
+    assert_ne!(
+        h,
+        hash_chain_entry(CHAIN_GENESIS_HASH, 2, "add_object", "{}")
+    );
+    assert_ne!(h, hash_chain_entry(&h, 1, "add_object", "{}"));
 }
@@ -0,0 +1,548 @@
+//! # service
+//!
+//! 业务操作层：建立客体、发布评价、回复评价，发布人凭 OTP 修改、撤回评价，管理员隐藏、恢复评价
+//!
+//! bot 与 web 两个前端都应调用这里的函数而不是直接写库，
+//! 这样输入校验、id 的计算时机、去重等规则只有一份。
+
+use crate::db::*;
+use crate::sec::*;
+use crate::{Error, Result};
+
+/// 学校类别、学校、学院、导师等名称的最大字数
+pub const MAX_NAME_LEN: usize = 64;
+/// 评价正文的最大字数
+pub const MAX_COMMENT_LEN: usize = 4000;
+
+/// 校验并规范化名称：去除首尾空白，不能为空，不能换行，不能过长
+pub fn validate_name(field: &str, name: &str) -> Result<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(Error::Validation(format!("{field}不能为空")));
+    }
+    if name.contains(['\n', '\r']) {
+        return Err(Error::Validation(format!("{field}不能包含换行")));
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(Error::Validation(format!(
+            "{field}不能超过 {MAX_NAME_LEN} 字"
+        )));
+    }
+    Ok(name.to_string())
+}
+
+/// 校验并规范化评价正文：去除首尾空白，不能为空，不能过长
+pub fn validate_comment(content: &str) -> Result<String> {
+    let content = content.trim();
+    if content.is_empty() {
+        return Err(Error::Validation("评价不能为空".to_string()));
+    }
+    if content.chars().count() > MAX_COMMENT_LEN {
+        return Err(Error::Validation(format!(
+            "评价不能超过 {MAX_COMMENT_LEN} 字"
+        )));
+    }
+    Ok(content.to_string())
+}
+
+/// 校验路径并构造一个尚未写入数据库的客体，object id 在此计算
+pub fn new_object(
+    school_cate: &str,
+    university: &str,
+    department: &str,
+    supervisor: &str,
+) -> Result<ObjTeacher> {
+    let school_cate = validate_name("学校类别", school_cate)?;
+    let university = validate_name("学校", university)?;
+    let department = validate_name("学院", department)?;
+    let supervisor = validate_name("客体", supervisor)?;
+    let object_id = hash_object_id(&university, &department, &supervisor);
+    Ok(ObjTeacher {
+        school_cate,
+        university,
+        department,
+        supervisor,
+        date: get_current_date(),
+        info: None,
+        object_id,
+    })
+}
+
+/// 建立客体，已存在时返回 [`Error::DuplicateId`]
+pub fn create_object(
+    db: &SAFCdb,
+    school_cate: &str,
+    university: &str,
+    department: &str,
+    supervisor: &str,
+) -> Result<ObjTeacher> {
+    let obj = new_object(school_cate, university, department, supervisor)?;
+    db.add_object(&obj)?;
+    log::info!("评价客体 {} 已增加", obj.object_id);
+    Ok(obj)
+}
+
+/// 按路径查找客体，不存在则建立
+pub fn find_or_create_object(
+    db: &SAFCdb,
+    school_cate: &str,
+    university: &str,
+    department: &str,
+    supervisor: &str,
+) -> Result<ObjTeacher> {
+    let obj = new_object(school_cate, university, department, supervisor)?;
+    match db.find_object_with_path(&obj.university, &obj.department, &obj.supervisor)? {
+        Some(t) => Ok(t),
+        None => {
+            db.add_object(&obj)?;
+            log::info!("评价客体 {} 已增加", obj.object_id);
+            Ok(obj)
+        }
+    }
+}
+
+/// 对客体发布评价，客体不存在时返回 [`Error::NotFound`]
+///
+/// 评价 id 在此时计算，同一天对同一客体的相同评价返回 [`Error::DuplicateId`]
+pub fn post_comment(
+    db: &SAFCdb,
+    object_id: &str,
+    content: &str,
+    source_cate: SourceCate,
+    otp: &str,
+) -> Result<ObjComment> {
+    post_rated_comment(db, object_id, content, None, source_cate, otp, None)
+}
+
+/// 对客体发布附带评星的评价，评星超出范围时返回 [`Error::Validation`]，其余同 [`post_comment`]
+///
+/// 一项都未评的评星视为没有评星；`author_chat` 为发布人的 telegram 会话，不会收到这条评价的关注通知
+pub fn post_rated_comment(
+    db: &SAFCdb,
+    object_id: &str,
+    content: &str,
+    ratings: Option<Ratings>,
+    source_cate: SourceCate,
+    otp: &str,
+    author_chat: Option<i64>,
+) -> Result<ObjComment> {
+    if db.find_objteacher_with_id(object_id)?.is_none() {
+        return Err(Error::NotFound(format!("客体 {object_id}")));
+    }
+    let ratings = ratings.filter(|r| !r.is_empty());
+    if let Some(r) = &ratings {
+        r.validate()?;
+    }
+    add_comment(
+        db,
+        object_id,
+        content,
+        ratings,
+        source_cate,
+        CommentType::Teacher,
+        otp,
+        author_chat,
+    )
+}
+
+/// 回复一条评价（嵌套评价），被回复的评价不存在时返回 [`Error::NotFound`]，`author_chat` 同 [`post_rated_comment`]
+pub fn reply_to_comment(
+    db: &SAFCdb,
+    comment_id: &str,
+    content: &str,
+    source_cate: SourceCate,
+    otp: &str,
+    author_chat: Option<i64>,
+) -> Result<ObjComment> {
+    live_comment(db, comment_id)?;
+    add_comment(
+        db,
+        comment_id,
+        content,
+        None,
+        source_cate,
+        CommentType::Nest,
+        otp,
+        author_chat,
+    )
+}
+
+/// 发布人凭 OTP 修改评价，评价 id 保持不变，原内容保存在修订历史中
+pub fn edit_comment(db: &SAFCdb, comment_id: &str, content: &str, otp: &str) -> Result<ObjComment> {
+    check_author(db, comment_id, otp)?;
+    let content = validate_comment(content)?;
+    db.edit_comment(comment_id, &content)?;
+    log::info!("{} 评价已修改", comment_id);
+    db.find_comment_with_id(comment_id)?
+        .ok_or_else(|| Error::NotFound(format!("评价 {comment_id}")))
+}
+
+/// 发布人凭 OTP 撤回评价，原内容保存在修订历史中，评价留下墓碑
+pub fn retract_comment(db: &SAFCdb, comment_id: &str, otp: &str) -> Result<()> {
+    check_author(db, comment_id, otp)?;
+    db.tombstone_comment(comment_id, TombstoneKind::Retracted, None)?;
+    log::info!("{} 评价已撤回", comment_id);
+    Ok(())
+}
+
+/// 管理员隐藏评价，原内容保存在修订历史中，评价留下墓碑
+pub fn moderate_comment(db: &SAFCdb, comment_id: &str, reason: Option<&str>) -> Result<()> {
+    live_comment(db, comment_id)?;
+    db.tombstone_comment(comment_id, TombstoneKind::Moderated, reason)?;
+    log::info!("{} 评价已被管理员隐藏", comment_id);
+    Ok(())
+}
+
+/// 管理员恢复被撤回或隐藏的评价
+pub fn restore_comment(db: &SAFCdb, comment_id: &str) -> Result<()> {
+    db.restore_comment(comment_id)?;
+    log::info!("{} 评价已恢复", comment_id);
+    Ok(())
+}
+
+/// 查找评价，评价不存在或已被撤回、隐藏时返回错误
+fn live_comment(db: &SAFCdb, comment_id: &str) -> Result<ObjComment> {
+    let c = db
+        .find_comment_with_id(comment_id)?
+        .ok_or_else(|| Error::NotFound(format!("评价 {comment_id}")))?;
+    if c.tombstone.is_some() {
+        return Err(Error::Validation("此评价已被撤回或隐藏".to_string()));
+    }
+    Ok(c)
+}
+
+/// 查找评价并验证 OTP 与发布人签名相符
+///
+/// 空 OTP 一律拒绝：早先未给出 OTP 的评价也以空 OTP 签过名
+///
+/// 每次输错 OTP 消耗该评价的一个 [`RateAction::OtpFailure`] 令牌，令牌用完后不再验证，
+/// 返回 [`Error::RateLimited`]，以免 OTP 被穷举
+fn check_author(db: &SAFCdb, comment_id: &str, otp: &str) -> Result<ObjComment> {
+    if otp.trim().is_empty() {
+        return Err(Error::Forbidden("请提供发布时设置的 OTP".to_string()));
+    }
+    let c = live_comment(db, comment_id)?;
+    let Some(sign) = &c.author_sign else {
+        return Err(Error::Forbidden("此评价没有发布人签名".to_string()));
+    };
+    let key = rate_limit::comment_key(&c.id);
+    db.check_token(key, RateAction::OtpFailure)?;
+    if !verify_author_sign(&c.id, &otp.to_string(), sign) {
+        db.take_token(key, RateAction::OtpFailure)?;
+        // 这次输错用完了令牌，调用方应结束会话
+        db.check_token(key, RateAction::OtpFailure)?;
+        return Err(Error::Forbidden("OTP 与发布人签名不符".to_string()));
+    }
+    Ok(c)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn add_comment(
+    db: &SAFCdb,
+    object_id: &str,
+    content: &str,
+    ratings: Option<Ratings>,
+    source_cate: SourceCate,
+    comment_type: CommentType,
+    otp: &str,
+    author_chat: Option<i64>,
+) -> Result<ObjComment> {
+    let mut c = ObjComment::new_with_otp(
+        object_id.to_string(),
+        validate_comment(content)?,
+        source_cate,
+        comment_type,
+        otp.to_string(),
+    );
+    c.ratings = ratings;
+    db.add_comment(&c)?;
+    db.enqueue_comment_event(&c.id, author_chat)?;
+    log::info!("{} 评价已发布", c.id);
+    Ok(c)
+}
+
+#[test]
+fn test_validate() {
+    assert_eq!("张三", validate_name("客体", " 张三\t").unwrap());
+    assert!(matches!(
+        validate_name("客体", "  "),
+        Err(Error::Validation(_))
+    ));
+    assert!(matches!(
+        validate_name("客体", "张\n三"),
+        Err(Error::Validation(_))
+    ));
+    assert!(validate_name("客体", &"长".repeat(MAX_NAME_LEN + 1)).is_err());
+    assert!(validate_comment(&"长".repeat(MAX_COMMENT_LEN)).is_ok());
+    assert!(validate_comment(&"长".repeat(MAX_COMMENT_LEN + 1)).is_err());
+}
+
+#[test]
+fn test_object_and_comments() {
+    let db = temp_db();
+    let t = create_object(&db, "985", "清华大学", "self", "张三").unwrap();
+    assert!(matches!(
+        create_object(&db, "985", "清华大学", " self ", "张三"),
+        Err(Error::DuplicateId(_))
+    ));
+    let same = find_or_create_object(&db, "985", "清华大学", "self", "张三").unwrap();
+    assert_eq!(t.object_id, same.object_id);
+
+    let c = post_comment(&db, &t.object_id, " 很好 ", SourceCate::Web, "otp").unwrap();
+    assert_eq!("很好", c.description);
+    assert_eq!(CommentType::Teacher, c.comment_type);
+    assert!(matches!(
+        post_comment(&db, &t.object_id, "很好", SourceCate::Web, "otp"),
+        Err(Error::DuplicateId(_))
+    ));
+    assert!(matches!(
+        post_comment(&db, "nonexistent", "很好", SourceCate::Web, "otp"),
+        Err(Error::NotFound(_))
+    ));
+
+    let ratings = Ratings {
+        academic: Some(4),
+        ..Default::default()
+    };
+    let rated = post_rated_comment(
+        &db,
+        &t.object_id,
+        "有评星",
+        Some(ratings),
+        SourceCate::Web,
+        "otp",
+        None,
+    )
+    .unwrap();
+    assert_eq!(Some(ratings), rated.ratings);
+    assert!(matches!(
+        post_rated_comment(
+            &db,
+            &t.object_id,
+            "评星超出范围",
+            Some(Ratings {
+                academic: Some(6),
+                ..Default::default()
+            }),
+            SourceCate::Web,
+            "otp",
+            None
+        ),
+        Err(Error::Validation(_))
+    ));
+    let unrated = post_rated_comment(
+        &db,
+        &t.object_id,
+        "空评星",
+        Some(Ratings::default()),
+        SourceCate::Web,
+        "otp",
+        None,
+    )
+    .unwrap();
+    assert_eq!(None, unrated.ratings);
+
+    let r = reply_to_comment(&db, &c.id, "同意", SourceCate::Telegram, "otp", None).unwrap();
+    assert_eq!(CommentType::Nest, r.comment_type);
+    assert_eq!(c.id, r.object);
+    // 评价 id 不是客体
+    assert!(post_comment(&db, &c.id, "x", SourceCate::Web, "otp").is_err());
+    assert!(reply_to_comment(&db, &t.object_id, "x", SourceCate::Web, "otp", None).is_err());
+}
+
+#[test]
+fn test_edit_and_retract() {
+    let db = temp_db();
+    let t = temp_object(&db);
+    let c = post_comment(&db, &t.object_id, "初稿", SourceCate::Web, "otp").unwrap();
+
+    assert!(matches!(
+        edit_comment(&db, &c.id, "改稿", "wrong"),
+        Err(Error::Forbidden(_))
+    ));
+    let e = edit_comment(&db, &c.id, "改稿", "otp").unwrap();
+    assert_eq!(c.id, e.id);
+    let found = db.find_comment_with_id(&c.id).unwrap().unwrap();
+    assert_eq!("改稿", found.description);
+    assert_eq!(c.author_sign, found.author_sign);
+    // 返回修改后数据库中的评价
+    assert!(e.edited.is_some());
+    assert_eq!(found.edited, e.edited);
+
+    let r = reply_to_comment(&db, &c.id, "回复", SourceCate::Web, "otp", None).unwrap();
+    retract_comment(&db, &c.id, "otp").unwrap();
+    let found = db.find_comment_with_id(&c.id).unwrap().unwrap();
+    assert_eq!("", found.description);
+    assert_eq!(
+        Some(TombstoneKind::Retracted),
+        found.tombstone.map(|t| t.kind)
+    );
+    // 嵌套评价仍然挂在墓碑下
+    assert_eq!(r.id, db.find_comment(&c.id).unwrap()[0].id);
+    assert!(db
+        .search("改稿", SearchKind::Comment, 10)
+        .unwrap()
+        .is_empty());
+    let revs = db.find_comment_revisions(&c.id).unwrap();
+    assert_eq!(2, revs.len());
+    assert_eq!(
+        ("初稿", RevisionAction::Edit),
+        (revs[0].description.as_str(), revs[0].action.clone())
+    );
+    assert_eq!(
+        ("改稿", RevisionAction::Retract),
+        (revs[1].description.as_str(), revs[1].action.clone())
+    );
+    assert!(matches!(
+        retract_comment(&db, &c.id, "otp"),
+        Err(Error::Validation(_))
+    ));
+    assert!(reply_to_comment(&db, &c.id, "x", SourceCate::Web, "otp", None).is_err());
+}
+
+#[test]
+fn test_empty_otp() {
+    let db = temp_db();
+    let t = temp_object(&db);
+    let c = post_comment(&db, &t.object_id, "匿名", SourceCate::Web, " ").unwrap();
+    assert_eq!(None, c.author_sign);
+    assert!(matches!(
+        edit_comment(&db, &c.id, "改稿", ""),
+        Err(Error::Forbidden(_))
+    ));
+    // 早先以空 OTP 签名的评价同样不能修改或撤回
+    let mut old = ObjComment::new_with_otp(
+        t.object_id.clone(),
+        "旧评价".to_string(),
+        SourceCate::Web,
+        CommentType::Teacher,
+        "otp".to_string(),
+    );
+    old.author_sign = Some(hash_author_sign(&old.id, &String::new()));
+    db.add_comment(&old).unwrap();
+    assert!(matches!(
+        edit_comment(&db, &old.id, "改稿", ""),
+        Err(Error::Forbidden(_))
+    ));
+    assert!(matches!(
+        retract_comment(&db, &old.id, ""),
+        Err(Error::Forbidden(_))
+    ));
+    assert_eq!(
+        "旧评价",
+        db.find_comment_with_id(&old.id)
+            .unwrap()
+            .unwrap()
+            .description
+    );
+}
+
+#[test]
+fn test_moderate_and_restore() {
+    let db = temp_db();
+    let t = temp_object(&db);
+    let c = post_comment(&db, &t.object_id, "广告内容", SourceCate::Web, "otp").unwrap();
+    assert!(matches!(
+        restore_comment(&db, &c.id),
+        Err(Error::Validation(_))
+    ));
+
+    moderate_comment(&db, &c.id, Some("广告")).unwrap();
+    let found = db.find_comment_with_id(&c.id).unwrap().unwrap();
+    assert_eq!(
+        Some(Tombstone {
+            kind: TombstoneKind::Moderated,
+            reason: Some("广告".to_string()),
+            date: get_current_date(),
+        }),
+        found.tombstone
+    );
+    assert!(db
+        .search("广告内容", SearchKind::Comment, 10)
+        .unwrap()
+        .is_empty());
+
+    restore_comment(&db, &c.id).unwrap();
+    let found = db.find_comment_with_id(&c.id).unwrap().unwrap();
+    assert_eq!("广告内容", found.description);
+    assert!(found.tombstone.is_none());
+    assert_eq!(
+        1,
+        db.search("广告内容", SearchKind::Comment, 10)
+            .unwrap()
+            .len()
+    );
+    assert!(matches!(
+        restore_comment(&db, "nonexistent"),
+        Err(Error::NotFound(_))
+    ));
+}
+
+#[test]
+fn test_tombstone_ratings() {
+    let db = temp_db();
+    let t = temp_object(&db);
+    let ratings = Ratings {
+        academic: Some(1),
+        stipend: Some(2),
+        ..Default::default()
+    };
+    let c = post_rated_comment(
+        &db,
+        &t.object_id,
+        "差评",
+        Some(ratings),
+        SourceCate::Web,
+        "otp",
+        None,
+    )
+    .unwrap();
+
+    // 撤回后评星不再公开，只保存在修订历史中
+    retract_comment(&db, &c.id, "otp").unwrap();
+    assert_eq!(
+        None,
+        db.find_comment_with_id(&c.id).unwrap().unwrap().ratings
+    );
+    assert_eq!(
+        Some(ratings),
+        db.find_comment_revisions(&c.id).unwrap()[0].ratings
+    );
+    let mut json = vec![];
+    crate::export::export_to(&db, crate::export::ExportFormat::Jsonl, &mut json).unwrap();
+    assert!(!String::from_utf8(json).unwrap().contains("academic"));
+    assert!(db.verify_chain().unwrap().is_valid());
+
+    restore_comment(&db, &c.id).unwrap();
+    assert_eq!(
+        Some(ratings),
+        db.find_comment_with_id(&c.id).unwrap().unwrap().ratings
+    );
+    assert!(db.verify_chain().unwrap().is_valid());
+}
+
+#[test]
+fn test_otp_failures() {
+    let db = temp_db();
+    let t = temp_object(&db);
+    let c = post_comment(&db, &t.object_id, "评价", SourceCate::Web, "otp").unwrap();
+    let capacity = RateAction::OtpFailure.bucket().capacity as usize;
+    for _ in 1..capacity {
+        assert!(matches!(
+            retract_comment(&db, &c.id, "wrong"),
+            Err(Error::Forbidden(_))
+        ));
+    }
+    // 最后一次输错后不再允许尝试，正确的 OTP 也不再验证
+    assert!(matches!(
+        retract_comment(&db, &c.id, "wrong"),
+        Err(Error::RateLimited(_))
+    ));
+    assert!(matches!(
+        edit_comment(&db, &c.id, "改稿", "otp"),
+        Err(Error::RateLimited(_))
+    ));
+    // 其他评价不受影响
+    let other = post_comment(&db, &t.object_id, "另一条", SourceCate::Web, "otp").unwrap();
+    retract_comment(&db, &other.id, "otp").unwrap();
+}
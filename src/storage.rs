@@ -0,0 +1,95 @@
+//! # storage
+//!
+//! 将 bot 的会话状态保存在 SAFC 数据库的 dialogues 表中，
+//! 这样 bot 重启（如 systemd 重启）后用户不会丢失进行中的对话，例如写了一半的评价。
+//!
+//! 状态以 json 保存；超过 [`DIALOGUE_TTL_DAYS`] 天未更新的会话会被定期清理。
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use safc::db::SAFCdb;
+use serde::{de::DeserializeOwned, Serialize};
+use teloxide::dispatching::dialogue::Storage;
+use teloxide::types::ChatId;
+
+/// 会话的有效期（天）
+pub const DIALOGUE_TTL_DAYS: i64 = 7;
+/// 清理过期会话的间隔
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type StorageError = Box<dyn std::error::Error + Send + Sync>;
+
+pub struct SAFCStorage {
+    db: SAFCdb,
+}
+
+impl SAFCStorage {
+    /// 新建存储，并立即清理一次过期会话
+    pub fn new(db: SAFCdb) -> Arc<Self> {
+        let storage = Arc::new(Self { db });
+        storage.purge();
+        storage
+    }
+
+    /// 清理过期会话
+    pub fn purge(&self) {
+        match self
+            .db
+            .purge_dialogues(chrono::Duration::days(DIALOGUE_TTL_DAYS))
+        {
+            Ok(0) => {}
+            Ok(n) => log::info!("已清理 {} 个过期会话", n),
+            Err(e) => log::error!("清理过期会话失败：{}", e),
+        }
+    }
+
+    /// 定期清理过期会话的任务
+    pub async fn purge_task(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(PURGE_INTERVAL).await;
+            self.purge();
+        }
+    }
+}
+
+impl<D> Storage<D> for SAFCStorage
+where
+    D: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Error = StorageError;
+
+    fn remove_dialogue(self: Arc<Self>, chat_id: ChatId) -> BoxFuture<Result<(), Self::Error>> {
+        Box::pin(async move { Ok(self.db.remove_dialogue(chat_id.0)?) })
+    }
+
+    fn update_dialogue(
+        self: Arc<Self>,
+        chat_id: ChatId,
+        dialogue: D,
+    ) -> BoxFuture<Result<(), Self::Error>> {
+        Box::pin(async move {
+            let state = serde_json::to_string(&dialogue)?;
+            Ok(self.db.update_dialogue(chat_id.0, &state)?)
+        })
+    }
+
+    fn get_dialogue(self: Arc<Self>, chat_id: ChatId) -> BoxFuture<Result<Option<D>, Self::Error>> {
+        Box::pin(async move {
+            match self.db.get_dialogue(chat_id.0)? {
+                None => Ok(None),
+                Some(state) => match serde_json::from_str(&state) {
+                    Ok(d) => Ok(Some(d)),
+                    // 旧版本 bot 保存的状态可能无法解析，视为没有会话
+                    Err(e) => {
+                        log::warn!("无法解析 {} 的会话状态：{}", chat_id, e);
+                        Ok(None)
+                    }
+                },
+            }
+        })
+    }
+}